@@ -0,0 +1,55 @@
+use ahash::AHashMap;
+
+/// The user connections act as when `AUTH` is given only a password.
+pub const DEFAULT_USER: &str = "default";
+
+/// The users allowed to connect and their passwords.
+///
+/// The table is built at startup and shared read-only by every connection.
+/// A user without a password accepts any password, which is how the default
+/// user starts out, so a server without configured credentials behaves as
+/// if authentication were off.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    users: AHashMap<String, Option<String>>,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Acl {
+    /// Create a table holding only the default user, without a password.
+    pub fn new() -> Acl {
+        let mut users = AHashMap::new();
+        users.insert(DEFAULT_USER.to_string(), None);
+        Acl { users }
+    }
+
+    /// Require `password` for the default user, or accept any password with
+    /// `None`.
+    pub fn set_default_password(&mut self, password: Option<String>) {
+        self.users.insert(DEFAULT_USER.to_string(), password);
+    }
+
+    /// Add the user `name`, replacing any existing user of that name.
+    pub fn add_user(&mut self, name: impl Into<String>, password: impl Into<String>) {
+        self.users.insert(name.into(), Some(password.into()));
+    }
+
+    /// Returns `true` if `user` exists and `password` is theirs.
+    pub fn check(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(Some(expected)) => expected == password,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Returns `true` if new connections must `AUTH` before running commands.
+    pub fn requires_auth(&self) -> bool {
+        !matches!(self.users.get(DEFAULT_USER), Some(None))
+    }
+}
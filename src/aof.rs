@@ -0,0 +1,163 @@
+//! Append-only file of the commands that modified the dataset.
+//!
+//! Each write command is appended in RESP, exactly as a client would send
+//! it, preceded by a `SELECT` whenever it ran against a different database
+//! than the command before it. Replaying the file from the start rebuilds
+//! the dataset.
+
+use crate::protocol;
+use crate::{Command, Connection, Db, Frame};
+use bytes::{Buf, Bytes};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::Path;
+use tokio::sync::{Mutex, MutexGuard};
+use tracing::warn;
+
+/// When appended commands are forced to disk with `fsync`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AppendFsync {
+    /// After every command. Nothing acknowledged is ever lost.
+    Always,
+    /// Once a second, so a crash loses at most the last second of writes.
+    #[default]
+    EverySec,
+    /// Never. The operating system decides when the data reaches the disk.
+    No,
+}
+
+/// An open append-only file, shared by every connection.
+#[derive(Debug)]
+pub struct Aof {
+    writer: Mutex<AofWriter>,
+}
+
+/// Exclusive access to the file. Held while a write command runs, so
+/// commands are logged in the order they took effect.
+#[derive(Debug)]
+pub(crate) struct AofWriter {
+    file: File,
+    fsync: AppendFsync,
+    // Database the last logged command ran against
+    selected: Option<usize>,
+    // Whether anything was written since the last fsync
+    dirty: bool,
+    buf: Vec<u8>,
+}
+
+impl Aof {
+    /// Open the file at `path` for appending, creating it if it doesn't
+    /// exist.
+    pub fn open(path: &Path, fsync: AppendFsync) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let writer = AofWriter { file, fsync, selected: None, dirty: false, buf: Vec::new() };
+        Ok(Aof { writer: Mutex::new(writer) })
+    }
+
+    /// Wait for exclusive access to the file.
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, AofWriter> {
+        self.writer.lock().await
+    }
+
+    /// Fsync whatever was appended since the last sync.
+    pub async fn sync(&self) -> io::Result<()> {
+        let mut writer = self.writer.lock().await;
+        if writer.dirty {
+            writer.file.sync_data()?;
+            writer.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl AofWriter {
+    /// Append `frame`, a command that ran against database `db`.
+    ///
+    /// The command is handed to the operating system straight away, so only
+    /// a crash of the whole machine can lose it before the next fsync.
+    pub(crate) fn append(&mut self, db: usize, frame: &Frame) -> io::Result<()> {
+        if self.selected != Some(db) {
+            let select = Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"SELECT")),
+                Frame::Bulk(Bytes::from(db.to_string())),
+            ]);
+            encode(&mut self.buf, &select);
+        }
+        encode(&mut self.buf, frame);
+
+        let res = self.file.write_all(&self.buf);
+        self.buf.clear();
+        res?;
+        self.selected = Some(db);
+
+        if self.fsync == AppendFsync::Always {
+            self.file.sync_data()
+        } else {
+            self.dirty = true;
+            Ok(())
+        }
+    }
+}
+
+/// Encode a command frame as an array of bulk strings.
+fn encode(out: &mut Vec<u8>, frame: &Frame) {
+    match frame {
+        Frame::Array(items) => {
+            out.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+            for item in items {
+                encode(out, item);
+            }
+        }
+        Frame::Bulk(data) => encode_bulk(out, data),
+        // Commands accept simple strings and integers wherever they accept
+        // bulk strings
+        other => encode_bulk(out, other.to_string().as_bytes()),
+    }
+}
+
+fn encode_bulk(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("${}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+}
+
+/// Run every command in the file at `path` against `db`, returning how
+/// many there were.
+///
+/// A command cut short at the end of the file, as a crash in the middle of
+/// a write leaves it, is dropped with a warning and truncated away so later
+/// appends start on a clean boundary.
+pub(crate) async fn replay(path: &Path, db: &Db) -> crate::Result<usize> {
+    let data = std::fs::read(path)?;
+    let mut buf = Cursor::new(&data[..]);
+    let mut db = db.clone();
+    let mut connection = Connection::discarding();
+    let mut count = 0;
+
+    while buf.has_remaining() {
+        let start = buf.position();
+        match Frame::check(&mut buf) {
+            Ok(()) => {}
+            Err(protocol::Error::Incomplete) => {
+                warn!(path = %path.display(), offset = start, "dropping incomplete command at the end of the AOF");
+                OpenOptions::new().write(true).open(path)?.set_len(start)?;
+                break;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        buf.set_position(start);
+        let frame = Frame::parse(&mut buf)?;
+
+        match Command::from_frame(frame)? {
+            Command::Select(select) => {
+                db = usize::try_from(select.index)
+                    .ok()
+                    .and_then(|index| db.select(index))
+                    .ok_or("AOF selects a database that doesn't exist")?;
+            }
+            cmd => cmd.apply(&db, &mut connection).await?,
+        }
+        count += 1;
+    }
+    Ok(count)
+}
@@ -0,0 +1,70 @@
+use crate::db::index_range;
+
+/// What the range given to `BITCOUNT` is measured in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BitUnit {
+    #[default]
+    Byte,
+    Bit,
+}
+
+/// The bit at `offset`, or 0 past the end of `bytes`.
+///
+/// Like Redis, bit 0 is the most significant bit of the first byte.
+pub fn get(bytes: &[u8], offset: usize) -> u8 {
+    match bytes.get(offset / 8) {
+        Some(byte) => (byte >> (7 - offset % 8)) & 1,
+        None => 0,
+    }
+}
+
+/// Set the bit at `offset` to `bit`, growing `bytes` with zero bytes to
+/// reach it. Returns the bit's previous value.
+pub fn set(bytes: &mut Vec<u8>, offset: usize, bit: bool) -> u8 {
+    let index = offset / 8;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let previous = get(bytes, offset);
+    let mask = 1 << (7 - offset % 8);
+    if bit {
+        bytes[index] |= mask;
+    } else {
+        bytes[index] &= !mask;
+    }
+    previous
+}
+
+/// Number of set bits in `bytes` from `start` to `stop` inclusive, counted
+/// in `unit`s. Negative indexes count from the end, like `LRANGE`.
+pub fn count(bytes: &[u8], start: i64, stop: i64, unit: BitUnit) -> usize {
+    match unit {
+        BitUnit::Byte => match index_range(bytes.len(), start, stop) {
+            Some(range) => count_ones(&bytes[range]),
+            None => 0,
+        },
+        BitUnit::Bit => match index_range(bytes.len() * 8, start, stop) {
+            Some(range) => count_bit_range(bytes, *range.start(), *range.end()),
+            None => 0,
+        },
+    }
+}
+
+/// Number of set bits in all of `bytes`.
+pub fn count_ones(bytes: &[u8]) -> usize {
+    bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+}
+
+/// Number of set bits from bit `first` to bit `last` inclusive.
+fn count_bit_range(bytes: &[u8], first: usize, last: usize) -> usize {
+    // Masks keeping the bits of the first and last byte inside the range
+    let head = 0xff_u8 >> (first % 8);
+    let tail = 0xff_u8 << (7 - last % 8);
+    let (first, last) = (first / 8, last / 8);
+    if first == last {
+        return (bytes[first] & head & tail).count_ones() as usize;
+    }
+    (bytes[first] & head).count_ones() as usize
+        + count_ones(&bytes[first + 1..last])
+        + (bytes[last] & tail).count_ones() as usize
+}
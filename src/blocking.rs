@@ -0,0 +1,133 @@
+use ahash::AHashMap;
+use bytes::Bytes;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+
+/// Clients blocked waiting for keys, like `BLPOP` on an empty list, keyed by
+/// database and then key.
+///
+/// Every waited-on key has a single `Notify`, shared by all the clients
+/// waiting on it, which a write wakes once for each value it makes ready.
+/// `Notify` wakes its waiters in the order they started waiting, so clients
+/// are served first come, first served.
+#[derive(Debug)]
+pub struct BlockedKeys {
+    databases: Vec<Mutex<AHashMap<Bytes, Arc<Notify>>>>,
+    // Clients blocked right now, so writes can skip the lookup when there
+    // are none
+    blocked: AtomicUsize,
+}
+
+impl BlockedKeys {
+    pub fn new(databases: usize) -> BlockedKeys {
+        BlockedKeys {
+            databases: (0..databases).map(|_| Mutex::default()).collect(),
+            blocked: AtomicUsize::new(0),
+        }
+    }
+
+    /// Start waiting on `keys` in database `db`. The client stops waiting
+    /// when the returned `Blocked` is dropped.
+    pub fn block(self: &Arc<Self>, db: usize, keys: &[Bytes]) -> Blocked {
+        let mut waiting = self.databases[db].lock().unwrap();
+        let keys = keys
+            .iter()
+            .map(|key| (key.clone(), waiting.entry(key.clone()).or_default().clone()))
+            .collect();
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+        Blocked { registry: self.clone(), db, keys }
+    }
+
+    /// Wake the client that has waited longest on `key` in database `db`, if
+    /// any, to try again.
+    pub fn wake(&self, db: usize, key: &[u8]) {
+        if self.blocked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if let Some(notify) = self.databases[db].lock().unwrap().get(key) {
+            notify.notify_one();
+        }
+    }
+
+    /// Wake a client waiting on each key of database `db`, for when the
+    /// whole database changed at once.
+    pub fn wake_all(&self, db: usize) {
+        if self.blocked.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        for notify in self.databases[db].lock().unwrap().values() {
+            notify.notify_one();
+        }
+    }
+}
+
+/// A client's place in the queues of the keys it's waiting on.
+#[derive(Debug)]
+pub struct Blocked {
+    registry: Arc<BlockedKeys>,
+    db: usize,
+    keys: Vec<(Bytes, Arc<Notify>)>,
+}
+
+impl Blocked {
+    /// A future that completes once any of the keys is woken.
+    ///
+    /// The client is queued as soon as this is called, so a write made
+    /// between calling it and awaiting it isn't missed.
+    pub fn woken(&self) -> Woken<'_> {
+        let notified = self
+            .keys
+            .iter()
+            .map(|(_, notify)| {
+                let mut notified = Box::pin(notify.notified());
+                notified.as_mut().enable();
+                notified
+            })
+            .collect();
+        Woken { notified }
+    }
+
+    /// Pass a wakeup on to the next client waiting on each key, for the
+    /// values this client didn't take.
+    pub fn wake_others(&self) {
+        for (_, notify) in &self.keys {
+            notify.notify_one();
+        }
+    }
+}
+
+impl Drop for Blocked {
+    fn drop(&mut self) {
+        let mut waiting = self.registry.databases[self.db].lock().unwrap();
+        for (key, notify) in self.keys.drain(..) {
+            // The last client waiting on a key removes it
+            if Arc::strong_count(&notify) == 2 {
+                waiting.remove(&key);
+            }
+        }
+        self.registry.blocked.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returned by [`Blocked::woken`].
+pub struct Woken<'a> {
+    notified: Vec<Pin<Box<Notified<'a>>>>,
+}
+
+impl Future for Woken<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        for notified in &mut self.notified {
+            if notified.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(());
+            }
+        }
+        Poll::Pending
+    }
+}
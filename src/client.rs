@@ -0,0 +1,463 @@
+//! A typed client for talking to the server from Rust.
+//!
+//! [`Client`] wraps a [`Connection`], turning each method into a request
+//! and the reply back into a Rust value. Error replies become
+//! [`crate::Error`]s carrying the server's message. A [`Pipeline`] batches
+//! several requests into one round trip, and a [`Subscriber`] receives
+//! published messages.
+
+use crate::{Connection, Frame};
+use bytes::Bytes;
+use std::collections::VecDeque;
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+/// An established connection to a server.
+///
+/// Requests are sent one at a time, each waiting for its reply. The
+/// connection speaks RESP2, which every command's reply is decoded from.
+#[derive(Debug)]
+pub struct Client {
+    connection: Connection,
+}
+
+impl Client {
+    /// Connect to the server listening on `addr`.
+    pub async fn connect(addr: impl ToSocketAddrs) -> crate::Result<Client> {
+        let socket = TcpStream::connect(addr).await?;
+        Ok(Client { connection: Connection::new(socket) })
+    }
+
+    /// Ping the server, which replies with `msg`, or `PONG` without one.
+    pub async fn ping(&mut self, msg: Option<Bytes>) -> crate::Result<Bytes> {
+        let mut request = request(["PING"]);
+        if let Some(msg) = msg {
+            request.push_bulk(msg);
+        }
+        string(self.send(request).await?)
+    }
+
+    /// The value of `key`, or `None` if it doesn't exist.
+    pub async fn get(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_string(self.send(request(["GET", key])).await?)
+    }
+
+    /// Set `key` to `value`, replacing whatever it held.
+    pub async fn set(&mut self, key: &str, value: impl Into<Bytes>) -> crate::Result<()> {
+        let mut request = request(["SET", key]);
+        request.push_bulk(value.into());
+        ok(self.send(request).await?)
+    }
+
+    /// Delete `key`, returning whether it existed.
+    pub async fn del(&mut self, key: &str) -> crate::Result<bool> {
+        Ok(integer(self.send(request(["DEL", key])).await?)? == 1)
+    }
+
+    /// Whether `key` exists.
+    pub async fn exists(&mut self, key: &str) -> crate::Result<bool> {
+        Ok(integer(self.send(request(["EXISTS", key])).await?)? == 1)
+    }
+
+    /// Set `field` of the hash at `key`, returning whether it's a new field.
+    pub async fn hset(&mut self, key: &str, field: &str, value: impl Into<Bytes>) -> crate::Result<bool> {
+        let mut request = request(["HSET", key, field]);
+        request.push_bulk(value.into());
+        Ok(integer(self.send(request).await?)? == 1)
+    }
+
+    /// The value of `field` in the hash at `key`.
+    pub async fn hget(&mut self, key: &str, field: &str) -> crate::Result<Option<Bytes>> {
+        optional_string(self.send(request(["HGET", key, field])).await?)
+    }
+
+    /// Every field of the hash at `key`, with its value.
+    pub async fn hgetall(&mut self, key: &str) -> crate::Result<Vec<(Bytes, Bytes)>> {
+        let mut items = strings(self.send(request(["HGETALL", key])).await?)?.into_iter();
+        let mut pairs = Vec::with_capacity(items.len() / 2);
+        while let (Some(field), Some(value)) = (items.next(), items.next()) {
+            pairs.push((field, value));
+        }
+        Ok(pairs)
+    }
+
+    /// Push `values` onto the head of the list at `key`, returning its new
+    /// length.
+    pub async fn lpush(&mut self, key: &str, values: impl IntoIterator<Item = Bytes>) -> crate::Result<u64> {
+        self.push("LPUSH", key, values).await
+    }
+
+    /// Push `values` onto the tail of the list at `key`, returning its new
+    /// length.
+    pub async fn rpush(&mut self, key: &str, values: impl IntoIterator<Item = Bytes>) -> crate::Result<u64> {
+        self.push("RPUSH", key, values).await
+    }
+
+    async fn push(&mut self, command: &str, key: &str, values: impl IntoIterator<Item = Bytes>) -> crate::Result<u64> {
+        let mut request = request([command, key]);
+        for value in values {
+            request.push_bulk(value);
+        }
+        integer(self.send(request).await?)
+    }
+
+    /// Remove and return the head of the list at `key`.
+    pub async fn lpop(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_string(self.send(request(["LPOP", key])).await?)
+    }
+
+    /// Remove and return the tail of the list at `key`.
+    pub async fn rpop(&mut self, key: &str) -> crate::Result<Option<Bytes>> {
+        optional_string(self.send(request(["RPOP", key])).await?)
+    }
+
+    /// The elements of the list at `key` from `start` to `stop`, both
+    /// inclusive. Negative indexes count from the tail.
+    pub async fn lrange(&mut self, key: &str, start: i64, stop: i64) -> crate::Result<Vec<Bytes>> {
+        let (start, stop) = (start.to_string(), stop.to_string());
+        strings(self.send(request(["LRANGE", key, &start, &stop])).await?)
+    }
+
+    /// Add `members` to the set at `key`, returning how many were new.
+    pub async fn sadd(&mut self, key: &str, members: impl IntoIterator<Item = Bytes>) -> crate::Result<u64> {
+        let mut request = request(["SADD", key]);
+        for member in members {
+            request.push_bulk(member);
+        }
+        integer(self.send(request).await?)
+    }
+
+    /// Every member of the set at `key`, in no particular order.
+    pub async fn smembers(&mut self, key: &str) -> crate::Result<Vec<Bytes>> {
+        strings(self.send(request(["SMEMBERS", key])).await?)
+    }
+
+    /// Publish `message` to `channel`, returning how many subscribers
+    /// received it.
+    pub async fn publish(&mut self, channel: &str, message: impl Into<Bytes>) -> crate::Result<u64> {
+        let mut request = request(["PUBLISH", channel]);
+        request.push_bulk(message.into());
+        integer(self.send(request).await?)
+    }
+
+    /// Subscribe to `channels`, turning the client into a [`Subscriber`]
+    /// that receives what is published to them.
+    pub async fn subscribe(self, channels: &[&str]) -> crate::Result<Subscriber> {
+        let mut subscriber = Subscriber {
+            client: self,
+            channels: Vec::new(),
+            patterns: Vec::new(),
+            pending: VecDeque::new(),
+        };
+        subscriber.subscribe(channels).await?;
+        Ok(subscriber)
+    }
+
+    /// Start a pipeline, which sends several commands at once and then
+    /// reads all of their replies.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline { client: self, requests: Vec::new() }
+    }
+
+    /// Send a command the typed methods don't cover, returning the raw
+    /// reply. Error replies are returned as `Frame::Error`.
+    pub async fn command(&mut self, args: &[&str]) -> crate::Result<Frame> {
+        self.connection.write_frame(&request(args.iter().copied())).await?;
+        self.read_reply().await
+    }
+
+    /// Send `request` and read its reply, turning an error reply into an
+    /// `Err`.
+    async fn send(&mut self, request: Frame) -> crate::Result<Frame> {
+        self.connection.write_frame(&request).await?;
+        match self.read_reply().await? {
+            Frame::Error(msg) => Err(msg.into()),
+            reply => Ok(reply),
+        }
+    }
+
+    async fn read_reply(&mut self) -> crate::Result<Frame> {
+        match self.connection.read_frame().await? {
+            Some(reply) => Ok(reply),
+            None => Err("connection reset by server".into()),
+        }
+    }
+}
+
+/// Commands queued to be sent together, built with [`Client::pipeline`].
+///
+/// Nothing is sent until [`execute`](Pipeline::execute), which writes every
+/// request before reading any reply, so the whole batch costs a single round
+/// trip.
+#[derive(Debug)]
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Frame>,
+}
+
+impl Pipeline<'_> {
+    /// Queue `GET key`.
+    pub fn get(self, key: &str) -> Self {
+        self.push(request(["GET", key]))
+    }
+
+    /// Queue `SET key value`.
+    pub fn set(self, key: &str, value: impl Into<Bytes>) -> Self {
+        let mut request = request(["SET", key]);
+        request.push_bulk(value.into());
+        self.push(request)
+    }
+
+    /// Queue `DEL key`.
+    pub fn del(self, key: &str) -> Self {
+        self.push(request(["DEL", key]))
+    }
+
+    /// Queue `HSET key field value`.
+    pub fn hset(self, key: &str, field: &str, value: impl Into<Bytes>) -> Self {
+        let mut request = request(["HSET", key, field]);
+        request.push_bulk(value.into());
+        self.push(request)
+    }
+
+    /// Queue `RPUSH key value`.
+    pub fn rpush(self, key: &str, value: impl Into<Bytes>) -> Self {
+        let mut request = request(["RPUSH", key]);
+        request.push_bulk(value.into());
+        self.push(request)
+    }
+
+    /// Queue a command the typed methods don't cover.
+    pub fn command(self, args: &[&str]) -> Self {
+        self.push(request(args.iter().copied()))
+    }
+
+    fn push(mut self, request: Frame) -> Self {
+        self.requests.push(request);
+        self
+    }
+
+    /// Send every queued command, returning their replies in the same
+    /// order. A command that fails has its `Frame::Error` in its place,
+    /// without affecting the others.
+    pub async fn execute(self) -> crate::Result<Vec<Frame>> {
+        let connection = &mut self.client.connection;
+        for request in &self.requests {
+            connection.write_frame(request).await?;
+        }
+        connection.flush().await?;
+        let mut replies = Vec::with_capacity(self.requests.len());
+        for _ in &self.requests {
+            replies.push(self.client.read_reply().await?);
+        }
+        Ok(replies)
+    }
+}
+
+/// A client in subscriber mode, created by [`Client::subscribe`].
+///
+/// Only subscriptions can be managed in this mode. The server's
+/// confirmations of them are consumed here, so
+/// [`next_message`](Subscriber::next_message) only yields what was
+/// published.
+#[derive(Debug)]
+pub struct Subscriber {
+    client: Client,
+    channels: Vec<Bytes>,
+    patterns: Vec<Bytes>,
+    // Messages that arrived while waiting for a confirmation
+    pending: VecDeque<Message>,
+}
+
+/// A message published to a channel the [`Subscriber`] listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub channel: Bytes,
+    pub payload: Bytes,
+    /// The pattern the channel matched, for messages received through
+    /// [`Subscriber::psubscribe`].
+    pub pattern: Option<Bytes>,
+}
+
+impl Subscriber {
+    /// The channels subscribed to.
+    pub fn channels(&self) -> &[Bytes] {
+        &self.channels
+    }
+
+    /// The patterns subscribed to.
+    pub fn patterns(&self) -> &[Bytes] {
+        &self.patterns
+    }
+
+    /// Wait for the next message, or `None` once the server closes the
+    /// connection.
+    pub async fn next_message(&mut self) -> crate::Result<Option<Message>> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Some(message));
+        }
+        loop {
+            let Some(frame) = self.client.connection.read_frame().await? else {
+                return Ok(None);
+            };
+            match Reply::parse(frame)? {
+                Reply::Message(message) => return Ok(Some(message)),
+                // A late confirmation, or a reply to PING
+                Reply::Confirmation(..) | Reply::Pong => {}
+            }
+        }
+    }
+
+    /// Subscribe to more channels.
+    pub async fn subscribe(&mut self, channels: &[&str]) -> crate::Result<()> {
+        let confirmed = self.change("SUBSCRIBE", channels).await?;
+        for channel in confirmed {
+            if !self.channels.contains(&channel) {
+                self.channels.push(channel);
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribe to every channel matching one of the glob-style `patterns`.
+    pub async fn psubscribe(&mut self, patterns: &[&str]) -> crate::Result<()> {
+        let confirmed = self.change("PSUBSCRIBE", patterns).await?;
+        for pattern in confirmed {
+            if !self.patterns.contains(&pattern) {
+                self.patterns.push(pattern);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from `channels`, or from every channel if it's empty.
+    pub async fn unsubscribe(&mut self, channels: &[&str]) -> crate::Result<()> {
+        let all: Vec<String>;
+        let channels = if channels.is_empty() {
+            all = self.channels.iter().map(|channel| String::from_utf8_lossy(channel).into_owned()).collect();
+            all.iter().map(String::as_str).collect()
+        } else {
+            channels.to_vec()
+        };
+        let confirmed = self.change("UNSUBSCRIBE", &channels).await?;
+        self.channels.retain(|channel| !confirmed.contains(channel));
+        Ok(())
+    }
+
+    /// Unsubscribe from `patterns`, or from every pattern if it's empty.
+    pub async fn punsubscribe(&mut self, patterns: &[&str]) -> crate::Result<()> {
+        let all: Vec<String>;
+        let patterns = if patterns.is_empty() {
+            all = self.patterns.iter().map(|pattern| String::from_utf8_lossy(pattern).into_owned()).collect();
+            all.iter().map(String::as_str).collect()
+        } else {
+            patterns.to_vec()
+        };
+        let confirmed = self.change("PUNSUBSCRIBE", &patterns).await?;
+        self.patterns.retain(|pattern| !confirmed.contains(pattern));
+        Ok(())
+    }
+
+    /// Send `command` for `names` and wait for the confirmation of each,
+    /// returning the names confirmed. Messages that arrive meanwhile are
+    /// kept for `next_message`.
+    async fn change(&mut self, command: &str, names: &[&str]) -> crate::Result<Vec<Bytes>> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let request = request([command].into_iter().chain(names.iter().copied()));
+        self.client.connection.write_frame(&request).await?;
+        let kind = command.to_ascii_lowercase();
+        let mut confirmed = Vec::with_capacity(names.len());
+        while confirmed.len() < names.len() {
+            match Reply::parse(self.client.read_reply().await?)? {
+                Reply::Message(message) => self.pending.push_back(message),
+                Reply::Confirmation(reply_kind, name) if reply_kind == kind => confirmed.push(name),
+                reply => return Err(format!("protocol error; unexpected reply {:?}", reply).into()),
+            }
+        }
+        Ok(confirmed)
+    }
+}
+
+/// A frame received in subscriber mode.
+#[derive(Debug)]
+enum Reply {
+    Message(Message),
+    /// The kind of change, like `subscribe`, and the channel or pattern.
+    Confirmation(String, Bytes),
+    Pong,
+}
+
+impl Reply {
+    fn parse(frame: Frame) -> crate::Result<Reply> {
+        let items = match frame {
+            Frame::Array(items) | Frame::Push(items) => items,
+            Frame::Error(msg) => return Err(msg.into()),
+            frame => return Err(unexpected(frame)),
+        };
+        let mut parts = items.into_iter().map(|item| match item {
+            Frame::Null => Ok(Bytes::new()),
+            item => string(item),
+        });
+        let mut next = || parts.next().unwrap_or_else(|| Err("protocol error; short pub/sub reply".into()));
+        let kind = next()?;
+        Ok(match &kind[..] {
+            b"message" => Reply::Message(Message { channel: next()?, payload: next()?, pattern: None }),
+            b"pmessage" => {
+                let pattern = next()?;
+                Reply::Message(Message { channel: next()?, payload: next()?, pattern: Some(pattern) })
+            }
+            b"pong" => Reply::Pong,
+            _ => Reply::Confirmation(String::from_utf8_lossy(&kind).into_owned(), next()?),
+        })
+    }
+}
+
+/// A request made of `args`, as an array of bulk strings.
+fn request<'a>(args: impl IntoIterator<Item = &'a str>) -> Frame {
+    let mut frame = Frame::array();
+    for arg in args {
+        frame.push_bulk(Bytes::copy_from_slice(arg.as_bytes()));
+    }
+    frame
+}
+
+fn unexpected(reply: Frame) -> crate::Error {
+    format!("protocol error; unexpected reply {:?}", reply).into()
+}
+
+fn ok(reply: Frame) -> crate::Result<()> {
+    match reply {
+        Frame::Simple(status) if status == "OK" => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn integer(reply: Frame) -> crate::Result<u64> {
+    match reply {
+        Frame::Integer(n) => n.try_into().map_err(|_| unexpected(reply)),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn string(reply: Frame) -> crate::Result<Bytes> {
+    match reply {
+        Frame::Simple(s) => Ok(Bytes::from(s)),
+        Frame::Bulk(data) => Ok(data),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn optional_string(reply: Frame) -> crate::Result<Option<Bytes>> {
+    match reply {
+        Frame::Null => Ok(None),
+        reply => string(reply).map(Some),
+    }
+}
+
+fn strings(reply: Frame) -> crate::Result<Vec<Bytes>> {
+    match reply {
+        Frame::Array(items) => items.into_iter().map(string).collect(),
+        reply => Err(unexpected(reply)),
+    }
+}
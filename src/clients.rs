@@ -0,0 +1,98 @@
+use crate::Protocol;
+use bytes::Bytes;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What `CLIENT INFO` and `CLIENT LIST` report about a connection.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub addr: Option<SocketAddr>,
+    pub name: Option<Bytes>,
+    pub db: usize,
+    pub user: Option<String>,
+    pub protocol: Protocol,
+}
+
+impl ClientInfo {
+    /// The line describing the connection, as `key=value` fields like Redis
+    /// uses.
+    pub fn to_line(&self) -> String {
+        let addr = self.addr.map(|addr| addr.to_string()).unwrap_or_default();
+        let name = self.name.as_ref().map(|name| String::from_utf8_lossy(name)).unwrap_or_default();
+        let resp = match self.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        format!(
+            "id={} addr={} name={} db={} user={} resp={}\n",
+            self.id,
+            addr,
+            name,
+            self.db,
+            self.user.as_deref().unwrap_or(""),
+            resp,
+        )
+    }
+}
+
+/// The connections open to the server, shared by every `Db` handle so
+/// `CLIENT LIST` and `CLIENT KILL` can reach the other connections.
+///
+/// Each connection keeps its entry up to date, and is asked to close by
+/// notifying the `Notify` it got when it registered.
+#[derive(Debug, Default)]
+pub struct Clients {
+    clients: Mutex<BTreeMap<u64, Registered>>,
+}
+
+#[derive(Debug)]
+struct Registered {
+    info: ClientInfo,
+    kill: Arc<Notify>,
+}
+
+impl Clients {
+    pub fn new() -> Clients {
+        Clients::default()
+    }
+
+    /// Add a connection, returning the signal it's sent when it should
+    /// close.
+    pub fn register(&self, info: ClientInfo) -> Arc<Notify> {
+        let kill = Arc::new(Notify::new());
+        self.clients.lock().unwrap().insert(info.id, Registered { info, kill: kill.clone() });
+        kill
+    }
+
+    /// Replace what is known about a registered connection.
+    pub fn update(&self, info: ClientInfo) {
+        if let Some(registered) = self.clients.lock().unwrap().get_mut(&info.id) {
+            registered.info = info;
+        }
+    }
+
+    /// Remove the connection `id` once it has closed.
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Every connection, in the order they were accepted.
+    pub fn list(&self) -> Vec<ClientInfo> {
+        self.clients.lock().unwrap().values().map(|registered| registered.info.clone()).collect()
+    }
+
+    /// Ask every connection `matches` accepts to close, returning how many
+    /// there were. Each closes before reading its next command.
+    pub fn kill(&self, matches: impl Fn(&ClientInfo) -> bool) -> usize {
+        let clients = self.clients.lock().unwrap();
+        let mut killed = 0;
+        for registered in clients.values().filter(|registered| matches(&registered.info)) {
+            registered.kill.notify_one();
+            killed += 1;
+        }
+        killed
+    }
+}
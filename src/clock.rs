@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Granularity of the access clock.
+pub const TICK: Duration = Duration::from_millis(100);
+
+// Ticks elapsed since the clock started, advanced by the background thread
+static TICKS: AtomicU32 = AtomicU32::new(0);
+
+// When the clock started, set once by `start`
+static STARTED_AT: OnceLock<Instant> = OnceLock::new();
+
+/// Start the background thread advancing the access clock.
+///
+/// Key accesses are stamped with [`now`], which only loads an atomic, rather
+/// than calling `Instant::now()` on every access. Calling this again is a
+/// no-op.
+pub fn start() {
+    STARTED_AT.get_or_init(|| {
+        std::thread::Builder::new()
+            .name("rustbucket-clock".into())
+            .spawn(|| loop {
+                std::thread::sleep(TICK);
+                let elapsed = STARTED_AT.get().map_or(Duration::ZERO, Instant::elapsed);
+                TICKS.store((elapsed.as_millis() / TICK.as_millis()) as u32, Ordering::Relaxed);
+            })
+            .expect("failed to spawn the clock thread");
+        Instant::now()
+    });
+}
+
+/// The current tick.
+pub fn now() -> u32 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Time elapsed since `tick`, with [`TICK`] granularity.
+pub fn since(tick: u32) -> Duration {
+    TICK * now().saturating_sub(tick)
+}
+
+/// Milliseconds since the Unix epoch, the unit key expiry deadlines are
+/// kept in.
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
@@ -1,8 +1,21 @@
-use crate::{Connection, Db, Frame};
-use crate::db::DataType;
+use crate::{Connection, Db, Frame, Protocol};
+use crate::acl::DEFAULT_USER;
+use crate::bits::{self, BitUnit};
+use crate::config::RuntimeConfig;
+use crate::db::{
+    DataType, EvictionPolicy, Flushed, LexBound, ListEnd, MAX_RANDOM_REPEATS, Popped, ScoreBound, SetOp, WrongType, ZAddFlags,
+    ZRangeBy, ZRangeSpec, ZSetEnd,
+};
+use crate::clock;
+use crate::glob;
+use crate::json;
+use crate::notify::KeyspaceEvents;
+use crate::parse_num::{self, parse_float, parse_int, parse_uint};
+use crate::persistence::{self, PayloadError};
 use serde_json;
 use bytes::Bytes;
 use std::str;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tracing::instrument;
 
 /// Enumeration of supported Redis commands.
@@ -12,44 +25,123 @@ use tracing::instrument;
 pub enum Command {
     Get(Get),
     Set(Set),
+    MSet(MSet),
     Del(Del),
+    Unlink(Unlink),
+    Touch(Touch),
+    SetRange(SetRange),
+    Append(Append),
+    Incr(Incr),
+    Decr(Decr),
+    IncrBy(IncrBy),
+    DecrBy(DecrBy),
+    GetRange(GetRange),
+    SetBit(SetBit),
+    GetBit(GetBit),
+    BitCount(BitCount),
     Ping(Ping),
     Auth(Auth),
+    Hello(Hello),
     Info(Info),
+    ReplicaOf(ReplicaOf),
+    PSync(PSync),
+    Wait(Wait),
+    Config(Config),
+    Client(Client),
+    CommandInfo(CommandInfo),
+    Time(Time),
+    Debug(Debug),
+    Publish(Publish),
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    PSubscribe(PSubscribe),
+    PUnsubscribe(PUnsubscribe),
     Scan(Scan),
     Keys(Keys),
     Type(Type),
+    Object(Object),
     DbSize(DbSize),
+    RandomKey(RandomKey),
     FlushDb(FlushDb),
+    FlushAll(FlushAll),
+    SwapDb(SwapDb),
+    Save(Save),
+    BgSave(BgSave),
     Exists(Exists),
+    Rename(Rename),
+    RenameNx(RenameNx),
+    Copy(Copy),
+    Move(Move),
+    Dump(Dump),
+    Restore(Restore),
     HSet(HSet),
     HGet(HGet),
     HDel(HDel),
+    HGetDel(HGetDel),
+    HStrLen(HStrLen),
     HExists(HExists),
     HGetAll(HGetAll),
     HKeys(HKeys),
     HVals(HVals),
     HScan(HScan),
+    HRandField(HRandField),
     HLen(HLen),
     LPush(LPush),
     RPush(RPush),
     LPop(LPop),
     RPop(RPop),
+    BLPop(BLPop),
+    BRPop(BRPop),
+    LMove(LMove),
+    RPopLPush(LMove),
+    BLMove(BLMove),
+    BRPopLPush(BLMove),
     LRange(LRange),
+    LPos(LPos),
+    Sort(Sort),
     SAdd(SAdd),
     SMembers(SMembers),
     SRem(SRem),
+    SPop(SPop),
+    SRandMember(SRandMember),
+    SMove(SMove),
+    SInter(SInter),
+    SUnion(SUnion),
+    SDiff(SDiff),
+    SInterStore(SInterStore),
+    SInterCard(SInterCard),
+    SUnionStore(SUnionStore),
+    SDiffStore(SDiffStore),
     JsonSet(JsonSet),
     JsonGet(JsonGet),
+    JsonDel(JsonDel),
+    JsonType(JsonType),
+    JsonArrAppend(JsonArrAppend),
     ZAdd(ZAdd),
+    ZIncrBy(ZIncrBy),
     ZRange(ZRange),
+    ZRangeStore(ZRangeStore),
+    ZMPop(ZMPop),
+    BZMPop(BZMPop),
+    LMPop(LMPop),
+    BLMPop(BLMPop),
+    ZRangeByLex(ZRangeByLex),
+    ZLexCount(ZLexCount),
+    ZRandMember(ZRandMember),
     Ttl(Ttl),
     Pttl(Pttl),
+    ExpireAt(ExpireAt),
+    PexpireAt(PexpireAt),
+    ExpireTime(ExpireTime),
+    PexpireTime(PexpireTime),
     Select(Select),
     Multi(Multi),
     Exec(Exec),
     Discard(Discard),
     Watch(Watch),
+    Unwatch(Unwatch),
+    Reset(Reset),
+    Monitor(Monitor),
     Unknown(Unknown),
 }
 
@@ -60,49 +152,129 @@ impl Command {
 
         // The first frame in the array is the command name.
         let command_name = parse.next_string()?.to_lowercase();
+        parse.set_command(&command_name);
 
         // Match the command name and delegate to the specific command parser.
         let command = match &command_name[..] {
             "get" => Command::Get(Get::parse_frames(&mut parse)?),
             "set" => Command::Set(Set::parse_frames(&mut parse)?),
+            "mset" => Command::MSet(MSet::parse_frames(&mut parse)?),
             "del" => Command::Del(Del::parse_frames(&mut parse)?),
+            "unlink" => Command::Unlink(Unlink::parse_frames(&mut parse)?),
+            "touch" => Command::Touch(Touch::parse_frames(&mut parse)?),
+            "setrange" => Command::SetRange(SetRange::parse_frames(&mut parse)?),
+            "append" => Command::Append(Append::parse_frames(&mut parse)?),
+            "incr" => Command::Incr(Incr::parse_frames(&mut parse)?),
+            "decr" => Command::Decr(Decr::parse_frames(&mut parse)?),
+            "incrby" => Command::IncrBy(IncrBy::parse_frames(&mut parse)?),
+            "decrby" => Command::DecrBy(DecrBy::parse_frames(&mut parse)?),
+            "getrange" => Command::GetRange(GetRange::parse_frames(&mut parse)?),
+            "setbit" => Command::SetBit(SetBit::parse_frames(&mut parse)?),
+            "getbit" => Command::GetBit(GetBit::parse_frames(&mut parse)?),
+            "bitcount" => Command::BitCount(BitCount::parse_frames(&mut parse)?),
             "ping" => Command::Ping(Ping::parse_frames(&mut parse)?),
             "auth" => Command::Auth(Auth::parse_frames(&mut parse)?),
+            "hello" => Command::Hello(Hello::parse_frames(&mut parse)?),
             "info" => Command::Info(Info::parse_frames(&mut parse)?),
+            "replicaof" | "slaveof" => Command::ReplicaOf(ReplicaOf::parse_frames(&mut parse)?),
+            "psync" => Command::PSync(PSync::parse_frames(&mut parse)?),
+            "wait" => Command::Wait(Wait::parse_frames(&mut parse)?),
+            "config" => Command::Config(Config::parse_frames(&mut parse)?),
+            "client" => Command::Client(Client::parse_frames(&mut parse)?),
+            "command" => Command::CommandInfo(CommandInfo::parse_frames(&mut parse)?),
+            "time" => Command::Time(Time::parse_frames(&mut parse)?),
+            "debug" => Command::Debug(Debug::parse_frames(&mut parse)?),
+            "publish" => Command::Publish(Publish::parse_frames(&mut parse)?),
+            "subscribe" => Command::Subscribe(Subscribe::parse_frames(&mut parse)?),
+            "unsubscribe" => Command::Unsubscribe(Unsubscribe::parse_frames(&mut parse)?),
+            "psubscribe" => Command::PSubscribe(PSubscribe::parse_frames(&mut parse)?),
+            "punsubscribe" => Command::PUnsubscribe(PUnsubscribe::parse_frames(&mut parse)?),
             "scan" => Command::Scan(Scan::parse_frames(&mut parse)?),
             "keys" => Command::Keys(Keys::parse_frames(&mut parse)?),
             "type" => Command::Type(Type::parse_frames(&mut parse)?),
+            "object" => Command::Object(Object::parse_frames(&mut parse)?),
             "dbsize" => Command::DbSize(DbSize::parse_frames(&mut parse)?),
+            "randomkey" => Command::RandomKey(RandomKey::parse_frames(&mut parse)?),
             "flushdb" => Command::FlushDb(FlushDb::parse_frames(&mut parse)?),
+            "flushall" => Command::FlushAll(FlushAll::parse_frames(&mut parse)?),
+            "swapdb" => Command::SwapDb(SwapDb::parse_frames(&mut parse)?),
+            "save" => Command::Save(Save::parse_frames(&mut parse)?),
+            "bgsave" => Command::BgSave(BgSave::parse_frames(&mut parse)?),
             "exists" => Command::Exists(Exists::parse_frames(&mut parse)?),
+            "rename" => Command::Rename(Rename::parse_frames(&mut parse)?),
+            "renamenx" => Command::RenameNx(RenameNx::parse_frames(&mut parse)?),
+            "copy" => Command::Copy(Copy::parse_frames(&mut parse)?),
+            "move" => Command::Move(Move::parse_frames(&mut parse)?),
+            "dump" => Command::Dump(Dump::parse_frames(&mut parse)?),
+            "restore" => Command::Restore(Restore::parse_frames(&mut parse)?),
             "hset" => Command::HSet(HSet::parse_frames(&mut parse)?),
             "hget" => Command::HGet(HGet::parse_frames(&mut parse)?),
             "hdel" => Command::HDel(HDel::parse_frames(&mut parse)?),
+            "hgetdel" => Command::HGetDel(HGetDel::parse_frames(&mut parse)?),
+            "hstrlen" => Command::HStrLen(HStrLen::parse_frames(&mut parse)?),
             "hexists" => Command::HExists(HExists::parse_frames(&mut parse)?),
             "hgetall" => Command::HGetAll(HGetAll::parse_frames(&mut parse)?),
             "hkeys" => Command::HKeys(HKeys::parse_frames(&mut parse)?),
             "hvals" => Command::HVals(HVals::parse_frames(&mut parse)?),
             "hscan" => Command::HScan(HScan::parse_frames(&mut parse)?),
+            "hrandfield" => Command::HRandField(HRandField::parse_frames(&mut parse)?),
             "hlen" => Command::HLen(HLen::parse_frames(&mut parse)?),
             "lpush" => Command::LPush(LPush::parse_frames(&mut parse)?),
             "rpush" => Command::RPush(RPush::parse_frames(&mut parse)?),
             "lpop" => Command::LPop(LPop::parse_frames(&mut parse)?),
             "rpop" => Command::RPop(RPop::parse_frames(&mut parse)?),
+            "blpop" => Command::BLPop(BLPop::parse_frames(&mut parse)?),
+            "brpop" => Command::BRPop(BRPop::parse_frames(&mut parse)?),
+            "lmove" => Command::LMove(LMove::parse_frames(&mut parse)?),
+            "rpoplpush" => Command::RPopLPush(LMove::parse_rpoplpush(&mut parse)?),
+            "blmove" => Command::BLMove(BLMove::parse_frames(&mut parse)?),
+            "brpoplpush" => Command::BRPopLPush(BLMove::parse_brpoplpush(&mut parse)?),
             "lrange" => Command::LRange(LRange::parse_frames(&mut parse)?),
+            "lpos" => Command::LPos(LPos::parse_frames(&mut parse)?),
+            "sort" => Command::Sort(Sort::parse_frames(&mut parse)?),
             "sadd" => Command::SAdd(SAdd::parse_frames(&mut parse)?),
             "smembers" => Command::SMembers(SMembers::parse_frames(&mut parse)?),
             "srem" => Command::SRem(SRem::parse_frames(&mut parse)?),
+            "spop" => Command::SPop(SPop::parse_frames(&mut parse)?),
+            "srandmember" => Command::SRandMember(SRandMember::parse_frames(&mut parse)?),
+            "smove" => Command::SMove(SMove::parse_frames(&mut parse)?),
+            "sinter" => Command::SInter(SInter::parse_frames(&mut parse)?),
+            "sunion" => Command::SUnion(SUnion::parse_frames(&mut parse)?),
+            "sdiff" => Command::SDiff(SDiff::parse_frames(&mut parse)?),
+            "sinterstore" => Command::SInterStore(SInterStore::parse_frames(&mut parse)?),
+            "sintercard" => Command::SInterCard(SInterCard::parse_frames(&mut parse)?),
+            "sunionstore" => Command::SUnionStore(SUnionStore::parse_frames(&mut parse)?),
+            "sdiffstore" => Command::SDiffStore(SDiffStore::parse_frames(&mut parse)?),
             "json.set" => Command::JsonSet(JsonSet::parse_frames(&mut parse)?),
             "json.get" => Command::JsonGet(JsonGet::parse_frames(&mut parse)?),
+            "json.del" => Command::JsonDel(JsonDel::parse_frames(&mut parse)?),
+            "json.type" => Command::JsonType(JsonType::parse_frames(&mut parse)?),
+            "json.arrappend" => Command::JsonArrAppend(JsonArrAppend::parse_frames(&mut parse)?),
             "zadd" => Command::ZAdd(ZAdd::parse_frames(&mut parse)?),
+            "zincrby" => Command::ZIncrBy(ZIncrBy::parse_frames(&mut parse)?),
             "zrange" => Command::ZRange(ZRange::parse_frames(&mut parse)?),
+            "zrangestore" => Command::ZRangeStore(ZRangeStore::parse_frames(&mut parse)?),
+            "zmpop" => Command::ZMPop(ZMPop::parse_frames(&mut parse)?),
+            "bzmpop" => Command::BZMPop(BZMPop::parse_frames(&mut parse)?),
+            "lmpop" => Command::LMPop(LMPop::parse_frames(&mut parse)?),
+            "blmpop" => Command::BLMPop(BLMPop::parse_frames(&mut parse)?),
+            "zrangebylex" => Command::ZRangeByLex(ZRangeByLex::parse_frames(&mut parse)?),
+            "zlexcount" => Command::ZLexCount(ZLexCount::parse_frames(&mut parse)?),
+            "zrandmember" => Command::ZRandMember(ZRandMember::parse_frames(&mut parse)?),
             "ttl" => Command::Ttl(Ttl::parse_frames(&mut parse)?),
             "pttl" => Command::Pttl(Pttl::parse_frames(&mut parse)?),
+            "expireat" => Command::ExpireAt(ExpireAt::parse_frames(&mut parse)?),
+            "pexpireat" => Command::PexpireAt(PexpireAt::parse_frames(&mut parse)?),
+            "expiretime" => Command::ExpireTime(ExpireTime::parse_frames(&mut parse)?),
+            "pexpiretime" => Command::PexpireTime(PexpireTime::parse_frames(&mut parse)?),
             "select" => Command::Select(Select::parse_frames(&mut parse)?),
             "multi" => Command::Multi(Multi::parse_frames(&mut parse)?),
             "exec" => Command::Exec(Exec::parse_frames(&mut parse)?),
             "discard" => Command::Discard(Discard::parse_frames(&mut parse)?),
             "watch" => Command::Watch(Watch::parse_frames(&mut parse)?),
+            "unwatch" => Command::Unwatch(Unwatch::parse_frames(&mut parse)?),
+            "reset" => Command::Reset(Reset::parse_frames(&mut parse)?),
+            "monitor" => Command::Monitor(Monitor::parse_frames(&mut parse)?),
             _ => {
                 return Ok(Command::Unknown(Unknown::new(command_name)));
             }
@@ -113,53 +285,159 @@ impl Command {
         Ok(command)
     }
 
-    /// Apply the command to the specified `Db` instance.
-    #[instrument(skip(self, db, dst))]
+    /// Apply the command to the specified `Db` instance and write the reply
+    /// to `dst`.
     pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+        match self {
+            // Unsubscribing outside subscriber mode confirms each name with
+            // a reply of its own
+            Command::Unsubscribe(cmd) => {
+                for reply in cmd.apply().await? {
+                    dst.write_frame(&reply).await?;
+                }
+            }
+            Command::PUnsubscribe(cmd) => {
+                for reply in cmd.apply().await? {
+                    dst.write_frame(&reply).await?;
+                }
+            }
+            cmd => {
+                let reply = cmd.apply_to_frame(db, dst).await?;
+                dst.write_frame(&reply).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the command to the specified `Db` instance and return its
+    /// reply instead of writing it.
+    ///
+    /// `dst` is only used by commands that change the connection's state.
+    /// The several replies of an UNSUBSCRIBE outside subscriber mode are
+    /// returned as one array.
+    #[instrument(skip(self, db, dst))]
+    pub async fn apply_to_frame(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
         use Command::*;
 
         match self {
-            Get(cmd) => cmd.apply(db, dst).await,
-            Set(cmd) => cmd.apply(db, dst).await,
-            Del(cmd) => cmd.apply(db, dst).await,
-            Ping(cmd) => cmd.apply(dst).await,
-            Auth(cmd) => cmd.apply(dst).await,
-            Info(cmd) => cmd.apply(dst).await,
-            Scan(cmd) => cmd.apply(db, dst).await,
-            Keys(cmd) => cmd.apply(db, dst).await,
-            Type(cmd) => cmd.apply(db, dst).await,
-            DbSize(cmd) => cmd.apply(db, dst).await,
-            FlushDb(cmd) => cmd.apply(db, dst).await,
-            Exists(cmd) => cmd.apply(db, dst).await,
-            HSet(cmd) => cmd.apply(db, dst).await,
-            HGet(cmd) => cmd.apply(db, dst).await,
-            HDel(cmd) => cmd.apply(db, dst).await,
-            HExists(cmd) => cmd.apply(db, dst).await,
-            HGetAll(cmd) => cmd.apply(db, dst).await,
-            HKeys(cmd) => cmd.apply(db, dst).await,
-            HVals(cmd) => cmd.apply(db, dst).await,
-            HScan(cmd) => cmd.apply(db, dst).await,
-            HLen(cmd) => cmd.apply(db, dst).await,
-            LPush(cmd) => cmd.apply(db, dst).await,
-            RPush(cmd) => cmd.apply(db, dst).await,
-            LPop(cmd) => cmd.apply(db, dst).await,
-            RPop(cmd) => cmd.apply(db, dst).await,
-            LRange(cmd) => cmd.apply(db, dst).await,
-            SAdd(cmd) => cmd.apply(db, dst).await,
-            SMembers(cmd) => cmd.apply(db, dst).await,
-            SRem(cmd) => cmd.apply(db, dst).await,
-            JsonSet(cmd) => cmd.apply(db, dst).await,
-            JsonGet(cmd) => cmd.apply(db, dst).await,
-            ZAdd(cmd) => cmd.apply(db, dst).await,
+            Get(cmd) => cmd.apply(db).await,
+            Set(cmd) => cmd.apply(db).await,
+            MSet(cmd) => cmd.apply(db).await,
+            Del(cmd) => cmd.apply(db).await,
+            Unlink(cmd) => cmd.apply(db).await,
+            Touch(cmd) => cmd.apply(db).await,
+            SetRange(cmd) => cmd.apply(db).await,
+            Append(cmd) => cmd.apply(db).await,
+            Incr(cmd) => cmd.apply(db).await,
+            Decr(cmd) => cmd.apply(db).await,
+            IncrBy(cmd) => cmd.apply(db).await,
+            DecrBy(cmd) => cmd.apply(db).await,
+            GetRange(cmd) => cmd.apply(db).await,
+            SetBit(cmd) => cmd.apply(db).await,
+            GetBit(cmd) => cmd.apply(db).await,
+            BitCount(cmd) => cmd.apply(db).await,
+            Ping(cmd) => cmd.apply().await,
+            Auth(cmd) => cmd.apply(db, dst).await,
+            Hello(cmd) => cmd.apply(db, dst).await,
+            Info(cmd) => cmd.apply(db).await,
+            ReplicaOf(cmd) => cmd.apply(db).await,
+            PSync(cmd) => cmd.apply().await,
+            Wait(cmd) => cmd.apply().await,
+            Config(cmd) => cmd.apply(db).await,
+            Client(cmd) => cmd.apply(db, dst).await,
+            CommandInfo(cmd) => cmd.apply().await,
+            Time(cmd) => cmd.apply().await,
+            Debug(cmd) => cmd.apply(db).await,
+            Publish(cmd) => cmd.apply(db).await,
+            Subscribe(cmd) => cmd.apply().await,
+            Unsubscribe(cmd) => Ok(single_reply(cmd.apply().await?)),
+            PSubscribe(cmd) => cmd.apply().await,
+            PUnsubscribe(cmd) => Ok(single_reply(cmd.apply().await?)),
+            Scan(cmd) => cmd.apply(db).await,
+            Keys(cmd) => cmd.apply(db).await,
+            Type(cmd) => cmd.apply(db).await,
+            Object(cmd) => cmd.apply(db).await,
+            DbSize(cmd) => cmd.apply(db).await,
+            RandomKey(cmd) => cmd.apply(db).await,
+            FlushDb(cmd) => cmd.apply(db).await,
+            FlushAll(cmd) => cmd.apply(db).await,
+            SwapDb(cmd) => cmd.apply(db).await,
+            Save(cmd) => cmd.apply(db).await,
+            BgSave(cmd) => cmd.apply(db).await,
+            Exists(cmd) => cmd.apply(db).await,
+            Rename(cmd) => cmd.apply(db).await,
+            RenameNx(cmd) => cmd.apply(db).await,
+            Copy(cmd) => cmd.apply(db).await,
+            Move(cmd) => cmd.apply(db).await,
+            Dump(cmd) => cmd.apply(db).await,
+            Restore(cmd) => cmd.apply(db).await,
+            HSet(cmd) => cmd.apply(db).await,
+            HGet(cmd) => cmd.apply(db).await,
+            HDel(cmd) => cmd.apply(db).await,
+            HGetDel(cmd) => cmd.apply(db).await,
+            HStrLen(cmd) => cmd.apply(db).await,
+            HExists(cmd) => cmd.apply(db).await,
+            HGetAll(cmd) => cmd.apply(db).await,
+            HKeys(cmd) => cmd.apply(db).await,
+            HVals(cmd) => cmd.apply(db).await,
+            HScan(cmd) => cmd.apply(db).await,
+            HRandField(cmd) => cmd.apply(db, dst).await,
+            HLen(cmd) => cmd.apply(db).await,
+            LPush(cmd) => cmd.apply(db).await,
+            RPush(cmd) => cmd.apply(db).await,
+            LPop(cmd) => cmd.apply(db).await,
+            RPop(cmd) => cmd.apply(db).await,
+            BLPop(cmd) => cmd.apply(db).await,
+            BRPop(cmd) => cmd.apply(db).await,
+            LMove(cmd) | RPopLPush(cmd) => cmd.apply(db).await,
+            BLMove(cmd) | BRPopLPush(cmd) => cmd.apply(db).await,
+            LRange(cmd) => cmd.apply(db).await,
+            LPos(cmd) => cmd.apply(db).await,
+            Sort(cmd) => cmd.apply(db).await,
+            SAdd(cmd) => cmd.apply(db).await,
+            SMembers(cmd) => cmd.apply(db).await,
+            SRem(cmd) => cmd.apply(db).await,
+            SPop(cmd) => cmd.apply(db).await,
+            SRandMember(cmd) => cmd.apply(db).await,
+            SMove(cmd) => cmd.apply(db).await,
+            SInter(cmd) => cmd.apply(db).await,
+            SUnion(cmd) => cmd.apply(db).await,
+            SDiff(cmd) => cmd.apply(db).await,
+            SInterStore(cmd) => cmd.apply(db).await,
+            SInterCard(cmd) => cmd.apply(db).await,
+            SUnionStore(cmd) => cmd.apply(db).await,
+            SDiffStore(cmd) => cmd.apply(db).await,
+            JsonSet(cmd) => cmd.apply(db).await,
+            JsonGet(cmd) => cmd.apply(db).await,
+            JsonDel(cmd) => cmd.apply(db).await,
+            JsonType(cmd) => cmd.apply(db).await,
+            JsonArrAppend(cmd) => cmd.apply(db).await,
+            ZAdd(cmd) => cmd.apply(db).await,
+            ZIncrBy(cmd) => cmd.apply(db).await,
             ZRange(cmd) => cmd.apply(db, dst).await,
-            Ttl(cmd) => cmd.apply(db, dst).await, 
-            Pttl(cmd) => cmd.apply(db, dst).await,
-            Select(cmd) => cmd.apply(dst).await,
-            Multi(cmd) => cmd.apply(dst).await,
-            Exec(cmd) => cmd.apply(dst).await,
-            Discard(cmd) => cmd.apply(dst).await,
-            Watch(cmd) => cmd.apply(dst).await,
-            Unknown(cmd) => cmd.apply(dst).await,
+            ZRangeStore(cmd) => cmd.apply(db).await,
+            ZMPop(cmd) => cmd.apply(db).await,
+            BZMPop(cmd) => cmd.apply(db).await,
+            LMPop(cmd) => cmd.apply(db).await,
+            BLMPop(cmd) => cmd.apply(db).await,
+            ZRangeByLex(cmd) => cmd.apply(db).await,
+            ZLexCount(cmd) => cmd.apply(db).await,
+            ZRandMember(cmd) => cmd.apply(db, dst).await,
+            Ttl(cmd) => cmd.apply(db).await,
+            Pttl(cmd) => cmd.apply(db).await,
+            ExpireAt(cmd) => cmd.apply(db).await,
+            PexpireAt(cmd) => cmd.apply(db).await,
+            ExpireTime(cmd) => cmd.apply(db).await,
+            PexpireTime(cmd) => cmd.apply(db).await,
+            Select(cmd) => cmd.apply().await,
+            Multi(cmd) => cmd.apply().await,
+            Exec(cmd) => cmd.apply().await,
+            Discard(cmd) => cmd.apply().await,
+            Watch(cmd) => cmd.apply().await,
+            Unwatch(cmd) => cmd.apply().await,
+            Reset(cmd) => cmd.apply().await,
+            Monitor(cmd) => cmd.apply().await,
+            Unknown(cmd) => cmd.apply().await,
         }
     }
 
@@ -169,47 +447,201 @@ impl Command {
         match self {
             Command::Get(_) => "get",
             Command::Set(_) => "set",
+            Command::MSet(_) => "mset",
             Command::Del(_) => "del",
+            Command::Unlink(_) => "unlink",
+            Command::Touch(_) => "touch",
+            Command::SetRange(_) => "setrange",
+            Command::Append(_) => "append",
+            Command::Incr(_) => "incr",
+            Command::Decr(_) => "decr",
+            Command::IncrBy(_) => "incrby",
+            Command::DecrBy(_) => "decrby",
+            Command::GetRange(_) => "getrange",
+            Command::SetBit(_) => "setbit",
+            Command::GetBit(_) => "getbit",
+            Command::BitCount(_) => "bitcount",
             Command::Ping(_) => "ping",
             Command::Auth(_) => "auth",
+            Command::Hello(_) => "hello",
             Command::Info(_) => "info",
+            Command::ReplicaOf(_) => "replicaof",
+            Command::PSync(_) => "psync",
+            Command::Wait(_) => "wait",
+            Command::Config(_) => "config",
+            Command::Client(_) => "client",
+            Command::CommandInfo(_) => "command",
+            Command::Time(_) => "time",
+            Command::Debug(_) => "debug",
+            Command::Publish(_) => "publish",
+            Command::Subscribe(_) => "subscribe",
+            Command::Unsubscribe(_) => "unsubscribe",
+            Command::PSubscribe(_) => "psubscribe",
+            Command::PUnsubscribe(_) => "punsubscribe",
             Command::Scan(_) => "scan",
             Command::Keys(_) => "keys",
             Command::Type(_) => "type",
+            Command::Object(_) => "object",
             Command::DbSize(_) => "dbsize",
+            Command::RandomKey(_) => "randomkey",
             Command::FlushDb(_) => "flushdb",
+            Command::FlushAll(_) => "flushall",
+            Command::SwapDb(_) => "swapdb",
+            Command::Save(_) => "save",
+            Command::BgSave(_) => "bgsave",
             Command::Exists(_) => "exists",
+            Command::Rename(_) => "rename",
+            Command::RenameNx(_) => "renamenx",
+            Command::Copy(_) => "copy",
+            Command::Move(_) => "move",
+            Command::Dump(_) => "dump",
+            Command::Restore(_) => "restore",
             Command::HSet(_) => "hset",
             Command::HGet(_) => "hget",
             Command::HDel(_) => "hdel",
+            Command::HGetDel(_) => "hgetdel",
+            Command::HStrLen(_) => "hstrlen",
             Command::HExists(_) => "hexists",
             Command::HGetAll(_) => "hgetall",
             Command::HKeys(_) => "hkeys",
             Command::HVals(_) => "hvals",
             Command::HScan(_) => "hscan",
+            Command::HRandField(_) => "hrandfield",
             Command::HLen(_) => "hlen",
             Command::LPush(_) => "lpush",
             Command::RPush(_) => "rpush",
             Command::LPop(_) => "lpop",
             Command::RPop(_) => "rpop",
+            Command::BLPop(_) => "blpop",
+            Command::BRPop(_) => "brpop",
+            Command::LMove(_) => "lmove",
+            Command::RPopLPush(_) => "rpoplpush",
+            Command::BLMove(_) => "blmove",
+            Command::BRPopLPush(_) => "brpoplpush",
             Command::LRange(_) => "lrange",
+            Command::LPos(_) => "lpos",
+            Command::Sort(_) => "sort",
             Command::SAdd(_) => "sadd",
             Command::SMembers(_) => "smembers",
             Command::SRem(_) => "srem",
+            Command::SPop(_) => "spop",
+            Command::SRandMember(_) => "srandmember",
+            Command::SMove(_) => "smove",
+            Command::SInter(_) => "sinter",
+            Command::SUnion(_) => "sunion",
+            Command::SDiff(_) => "sdiff",
+            Command::SInterStore(_) => "sinterstore",
+            Command::SInterCard(_) => "sintercard",
+            Command::SUnionStore(_) => "sunionstore",
+            Command::SDiffStore(_) => "sdiffstore",
             Command::JsonSet(_) => "json.set",
             Command::JsonGet(_) => "json.get",
+            Command::JsonDel(_) => "json.del",
+            Command::JsonType(_) => "json.type",
+            Command::JsonArrAppend(_) => "json.arrappend",
             Command::ZAdd(_) => "zadd",
+            Command::ZIncrBy(_) => "zincrby",
             Command::ZRange(_) => "zrange",
+            Command::ZRangeStore(_) => "zrangestore",
+            Command::ZMPop(_) => "zmpop",
+            Command::BZMPop(_) => "bzmpop",
+            Command::LMPop(_) => "lmpop",
+            Command::BLMPop(_) => "blmpop",
+            Command::ZRangeByLex(_) => "zrangebylex",
+            Command::ZLexCount(_) => "zlexcount",
+            Command::ZRandMember(_) => "zrandmember",
             Command::Ttl(_) => "ttl",
             Command::Pttl(_) => "pttl",
+            Command::ExpireAt(_) => "expireat",
+            Command::PexpireAt(_) => "pexpireat",
+            Command::ExpireTime(_) => "expiretime",
+            Command::PexpireTime(_) => "pexpiretime",
             Command::Select(_) => "select",
             Command::Multi(_) => "multi",
             Command::Exec(_) => "exec",
             Command::Discard(_) => "discard",
             Command::Watch(_) => "watch",
+            Command::Unwatch(_) => "unwatch",
+            Command::Reset(_) => "reset",
+            Command::Monitor(_) => "monitor",
             Command::Unknown(cmd) => cmd.get_name(),
         }
     }
+
+    /// Returns `true` if the command may need more memory, so it must make
+    /// room first when memory use is over the limit. Commands that only
+    /// remove data can always run.
+    pub(crate) fn uses_memory(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self,
+            Set(_) | MSet(_) | SetRange(_) | Append(_) | SetBit(_) | Copy(_) | Restore(_)
+                | Incr(_) | Decr(_) | IncrBy(_) | DecrBy(_)
+                | HSet(_) | LPush(_) | RPush(_) | SAdd(_)
+                | SInterStore(_) | SUnionStore(_) | SDiffStore(_)
+                | JsonSet(_) | JsonArrAppend(_)
+                | ZAdd(_) | ZIncrBy(_) | ZRangeStore(_)
+        )
+    }
+
+    /// Roughly how many bytes the command adds, from the keys and values it
+    /// carries, as if none of them were stored yet. Commands whose growth
+    /// depends on data already stored, like COPY, count as zero.
+    pub(crate) fn incoming_size(&self) -> usize {
+        use Command::*;
+
+        let total = |items: &[Bytes]| items.iter().map(Bytes::len).sum::<usize>();
+        match self {
+            Set(cmd) => cmd.key.len() + cmd.value.len(),
+            MSet(cmd) => cmd.pairs.iter().map(|(key, value)| key.len() + value.len()).sum(),
+            SetRange(cmd) => cmd.key.len() + cmd.value.len(),
+            Append(cmd) => cmd.key.len() + cmd.value.len(),
+            Restore(cmd) => cmd.key.len() + cmd.payload.len(),
+            HSet(cmd) => cmd.key.len() + cmd.fields.iter().map(|(field, value)| field.len() + value.len()).sum::<usize>(),
+            LPush(cmd) => cmd.key.len() + total(&cmd.values),
+            RPush(cmd) => cmd.key.len() + total(&cmd.values),
+            SAdd(cmd) => cmd.key.len() + total(&cmd.members),
+            ZAdd(cmd) => cmd.key.len() + cmd.elements.iter().map(|(_, member)| 8 + member.len()).sum::<usize>(),
+            ZIncrBy(cmd) => cmd.key.len() + 8 + cmd.member.len(),
+            JsonSet(cmd) => cmd.key.len() + cmd.value.len(),
+            JsonArrAppend(cmd) => cmd.key.len() + cmd.values.iter().map(String::len).sum::<usize>(),
+            _ => 0,
+        }
+    }
+
+    /// The keys a blocking command waits on when it finds nothing to do, and
+    /// how long it waits for, forever if `None`. Other commands return
+    /// `None`.
+    pub(crate) fn blocks_on(&self) -> Option<(&[Bytes], Option<Duration>)> {
+        match self {
+            Command::BLPop(cmd) => Some((&cmd.keys, cmd.timeout)),
+            Command::BRPop(cmd) => Some((&cmd.keys, cmd.timeout)),
+            Command::BLMove(cmd) | Command::BRPopLPush(cmd) => Some((std::slice::from_ref(&cmd.src), cmd.timeout)),
+            Command::BZMPop(cmd) => Some((&cmd.keys, cmd.timeout)),
+            Command::BLMPop(cmd) => Some((&cmd.keys, cmd.timeout)),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the command may modify the dataset.
+    pub(crate) fn is_write(&self) -> bool {
+        use Command::*;
+
+        matches!(
+            self,
+            Set(_) | MSet(_) | SetRange(_) | Append(_) | SetBit(_) | Del(_) | Unlink(_) | FlushDb(_) | FlushAll(_) | SwapDb(_)
+                | Incr(_) | Decr(_) | IncrBy(_) | DecrBy(_)
+                | Rename(_) | RenameNx(_) | Copy(_) | Move(_) | Restore(_) | ExpireAt(_) | PexpireAt(_)
+                | HSet(_) | HDel(_) | HGetDel(_)
+                | LPush(_) | RPush(_) | LPop(_) | RPop(_) | BLPop(_) | BRPop(_)
+                | LMove(_) | RPopLPush(_) | BLMove(_) | BRPopLPush(_) | LMPop(_) | BLMPop(_)
+                | SAdd(_) | SRem(_) | SPop(_) | SMove(_)
+                | SInterStore(_) | SUnionStore(_) | SDiffStore(_)
+                | JsonSet(_) | JsonDel(_) | JsonArrAppend(_)
+                | ZAdd(_) | ZIncrBy(_) | ZRangeStore(_) | ZMPop(_) | BZMPop(_)
+        )
+    }
 }
 
 // RESTORED STRUCTS - Zero-Copy Key versions
@@ -218,10 +650,12 @@ impl Command {
 pub struct Get { key: Bytes }
 impl Get {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Get> { Ok(Get { key: parse.next_bytes()? }) }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let response = if let Some(value) = db.get(&self.key) { Frame::Bulk(value) } else { Frame::Null };
-        dst.write_frame(&response).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.get(&self.key) {
+            Ok(value) => value.map_or(Frame::Null, Frame::Bulk),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
     }
 }
 
@@ -231,661 +665,3125 @@ impl Set {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Set> { 
         Ok(Set { key: parse.next_bytes()?, value: parse.next_bytes()? }) 
     }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
         db.set(self.key, self.value);
-        dst.write_frame(&Frame::Simple("OK".into())).await?;
-        Ok(())
+        Ok(Frame::Simple("OK".into()))
     }
 }
 
+/// `MSET key value [key value ...]`, setting every pair at once.
 #[derive(Debug, Clone)]
-pub struct Del { key: Bytes }
-impl Del {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Del> { Ok(Del { key: parse.next_bytes()? }) }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let n = if db.delete(&self.key) { 1 } else { 0 };
-        dst.write_frame(&Frame::Integer(n)).await?;
-        Ok(())
+pub struct MSet { pairs: Vec<(Bytes, Bytes)> }
+impl MSet {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<MSet> {
+        let mut pairs = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+        while parse.remaining() > 0 {
+            pairs.push((parse.next_bytes()?, parse.next_bytes()?));
+        }
+        Ok(MSet { pairs })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        db.mset(self.pairs);
+        Ok(Frame::Simple("OK".into()))
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct Ping { msg: Option<String> }
-impl Ping {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Ping> {
-         match parse.next_string() { Ok(msg) => Ok(Ping { msg: Some(msg) }), Err(_) => Ok(Ping { msg: None }) }
+pub struct Del { keys: Vec<Bytes> }
+impl Del {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Del> {
+        Ok(Del { keys: parse_one_or_more(parse)? })
     }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        let response = match self.msg { None => Frame::Simple("PONG".into()), Some(msg) => Frame::Bulk(Bytes::from(msg)) };
-        dst.write_frame(&response).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        // Unlike UNLINK, the values are freed before replying
+        let deleted = db.unlink(&self.keys).len();
+        Ok(Frame::Integer(deleted as i64))
     }
 }
 
+/// Free effort, summed over the values one UNLINK removes, up to which
+/// they are dropped inline on the connection's task. Values adding up to
+/// more are dropped on a background blocking thread instead, like Redis's
+/// `LAZYFREE_THRESHOLD`. See [`DataType::free_effort`].
+const LAZYFREE_THRESHOLD: usize = 64;
+
 #[derive(Debug, Clone)]
-pub struct Auth { _password: String, _username: Option<String> }
-impl Auth {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Auth> {
-        let first = parse.next_string()?;
-        match parse.next_string() {
-            Ok(second) => Ok(Auth { _username: Some(first), _password: second }),
-            Err(_) => Ok(Auth { _username: None, _password: first }),
+pub struct Unlink { keys: Vec<Bytes> }
+impl Unlink {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Unlink> {
+        Ok(Unlink { keys: parse_one_or_more(parse)? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let values = db.unlink(&self.keys);
+        let unlinked = values.len();
+        // Dropping a huge collection can take a while, so that happens on a
+        // blocking thread while the reply goes out
+        if values.iter().map(DataType::free_effort).sum::<usize>() > LAZYFREE_THRESHOLD {
+            tokio::task::spawn_blocking(move || drop(values));
         }
+        Ok(Frame::Integer(unlinked as i64))
     }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> { dst.write_frame(&Frame::Simple("OK".into())).await?; Ok(()) }
 }
 
 #[derive(Debug, Clone)]
-pub struct Info { _section: Option<String> }
-impl Info {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Info> {
-        match parse.next_string() { Ok(s) => Ok(Info { _section: Some(s) }), Err(_) => Ok(Info { _section: None }) }
+pub struct Touch { keys: Vec<Bytes> }
+impl Touch {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Touch> {
+        Ok(Touch { keys: parse_one_or_more(parse)? })
     }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        let msg = "role:master\r\nconnected_clients:1\r\nredis_version:0.1.0\r\n";
-        dst.write_frame(&Frame::Bulk(Bytes::from(msg))).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(Frame::Integer(db.touch(&self.keys) as i64))
     }
 }
 
-
 #[derive(Debug, Clone)]
-pub struct Exists {
-    key: Bytes,
-}
-
-impl Exists {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Exists> {
+pub struct SetRange { key: Bytes, offset: usize, value: Bytes }
+impl SetRange {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SetRange> {
         let key = parse.next_bytes()?;
-        Ok(Exists { key })
+        let offset = usize::try_from(parse.next_int()?).map_err(|_| "ERR offset is out of range")?;
+        Ok(SetRange { key, offset, value: parse.next_bytes()? })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let response = if db.exists(&self.key) {
-            Frame::Integer(1)
-        } else {
-            Frame::Integer(0)
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        if !self.value.is_empty() && self.offset.saturating_add(self.value.len()) > db.max_value_len() {
+            return Ok(Frame::Error("ERR string exceeds maximum allowed size (proto-max-bulk-len)".into()));
+        }
+        let response = match db.setrange(self.key, self.offset, &self.value) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(err) => Frame::Error(err.to_string()),
         };
-        dst.write_frame(&response).await?;
-        Ok(())
+        Ok(response)
     }
 }
 
-
 #[derive(Debug, Clone)]
-pub struct HSet {
-    key: Bytes,
-    field: Bytes,
-    value: Bytes,
+pub struct Append { key: Bytes, value: Bytes }
+impl Append {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Append> {
+        Ok(Append { key: parse.next_bytes()?, value: parse.next_bytes()? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.append(self.key, &self.value) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
 }
 
-impl HSet {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HSet> {
-        let key = parse.next_bytes()?;
-        let field = parse.next_bytes()?;
-        let value = parse.next_bytes()?;
-        Ok(HSet { key, field, value })
+#[derive(Debug, Clone)]
+pub struct Incr { key: Bytes }
+impl Incr {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Incr> { Ok(Incr { key: parse.next_bytes()? }) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(incr_reply(db, self.key, 1))
     }
+}
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let result = db.hset(self.key, self.field, self.value);
-        dst.write_frame(&Frame::Integer(result as i64)).await?;
-        Ok(())
+#[derive(Debug, Clone)]
+pub struct Decr { key: Bytes }
+impl Decr {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Decr> { Ok(Decr { key: parse.next_bytes()? }) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(incr_reply(db, self.key, -1))
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct HGet {
-    key: Bytes,
-    field: Bytes,
+pub struct IncrBy { key: Bytes, increment: i64 }
+impl IncrBy {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<IncrBy> {
+        Ok(IncrBy { key: parse.next_bytes()?, increment: parse.next_int()? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(incr_reply(db, self.key, self.increment))
+    }
 }
 
-impl HGet {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HGet> {
-        let key = parse.next_bytes()?;
-        let field = parse.next_bytes()?;
-        Ok(HGet { key, field })
+#[derive(Debug, Clone)]
+pub struct DecrBy { key: Bytes, decrement: i64 }
+impl DecrBy {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<DecrBy> {
+        Ok(DecrBy { key: parse.next_bytes()?, decrement: parse.next_int()? })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let response = match db.hget(&self.key, &self.field) {
-            Some(val) => Frame::Bulk(val),
-            None => Frame::Null,
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let Some(increment) = self.decrement.checked_neg() else {
+            return Ok(Frame::Error("ERR decrement would overflow".into()));
         };
-        dst.write_frame(&response).await?;
-        Ok(())
+        Ok(incr_reply(db, self.key, increment))
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct HDel {
-    key: Bytes,
-    field: Bytes,
+/// Reply with the counter at `key` after adding `increment`, or the error.
+fn incr_reply(db: &Db, key: Bytes, increment: i64) -> Frame {
+    match db.incr_by(key, increment) {
+        Ok(value) => Frame::Integer(value),
+        Err(err) => Frame::Error(err.to_string()),
+    }
 }
 
-impl HDel {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HDel> {
-        let key = parse.next_bytes()?;
-        let field = parse.next_bytes()?;
-        Ok(HDel { key, field })
+#[derive(Debug, Clone)]
+pub struct GetRange { key: Bytes, start: i64, end: i64 }
+impl GetRange {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<GetRange> {
+        Ok(GetRange { key: parse.next_bytes()?, start: parse.next_int()?, end: parse.next_int()? })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-       let result = db.hdel(&self.key, &self.field);
-       dst.write_frame(&Frame::Integer(result as i64)).await?;
-       Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.getrange(&self.key, self.start, self.end) {
+            Ok(value) => Frame::Bulk(value),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct HExists {
-    key: Bytes,
-    field: Bytes,
+/// Parse a bit offset, which has to be non-negative.
+fn parse_bit_offset(parse: &mut Parse) -> crate::Result<usize> {
+    let offset = parse_uint(&parse.next_bytes()?).map_err(|_| "ERR bit offset is not an integer or out of range")?;
+    usize::try_from(offset).map_err(|_| "ERR bit offset is not an integer or out of range".into())
 }
 
-impl HExists {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HExists> {
+#[derive(Debug, Clone)]
+pub struct SetBit { key: Bytes, offset: usize, bit: bool }
+impl SetBit {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SetBit> {
         let key = parse.next_bytes()?;
-        let field = parse.next_bytes()?;
-        Ok(HExists { key, field })
+        let offset = parse_bit_offset(parse)?;
+        let bit = match &parse.next_string()?[..] {
+            "0" => false,
+            "1" => true,
+            _ => return Err("ERR bit is not an integer or out of range".into()),
+        };
+        Ok(SetBit { key, offset, bit })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let result = db.hexists(&self.key, &self.field);
-        dst.write_frame(&Frame::Integer(result as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        // The string grows to hold the bit, so it can't outgrow a value
+        if self.offset / 8 >= db.max_value_len() {
+            return Ok(Frame::Error("ERR bit offset is not an integer or out of range".into()));
+        }
+        let response = match db.setbit(self.key, self.offset, self.bit) {
+            Ok(previous) => Frame::Integer(previous.into()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct HGetAll {
-    key: Bytes,
+pub struct GetBit { key: Bytes, offset: usize }
+impl GetBit {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<GetBit> {
+        Ok(GetBit { key: parse.next_bytes()?, offset: parse_bit_offset(parse)? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let bit = db.read_value(&self.key, |value| match value {
+            DataType::String(bytes) => Ok(bits::get(bytes, self.offset)),
+            _ => Err(WrongType),
+        });
+        let response = match bit.unwrap_or(Ok(0)) {
+            Ok(bit) => Frame::Integer(bit.into()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
 }
 
-impl HGetAll {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HGetAll> {
+#[derive(Debug, Clone)]
+pub struct BitCount { key: Bytes, range: Option<(i64, i64, BitUnit)> }
+impl BitCount {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BitCount> {
         let key = parse.next_bytes()?;
-        Ok(HGetAll { key })
+        if parse.remaining() == 0 {
+            return Ok(BitCount { key, range: None });
+        }
+        let start = parse.next_int()?;
+        // A start without an end is a syntax error, not an arity one
+        if parse.remaining() == 0 {
+            return Err("ERR syntax error".into());
+        }
+        let end = parse.next_int()?;
+        let unit = match parse.next_string() {
+            Ok(unit) => match unit.to_uppercase().as_str() {
+                "BYTE" => BitUnit::Byte,
+                "BIT" => BitUnit::Bit,
+                _ => return Err("ERR syntax error".into()),
+            },
+            Err(_) => BitUnit::Byte,
+        };
+        Ok(BitCount { key, range: Some((start, end, unit)) })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let response = match db.hgetall(&self.key) {
-            Some(h) => {
-                let mut frames = Vec::new();
-                for (k, v) in h {
-                    frames.push(Frame::Bulk(k));
-                    frames.push(Frame::Bulk(v));
-                }
-                Frame::Array(frames)
-            }
-            None => Frame::Array(vec![]),
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let count = db.read_value(&self.key, |value| match value {
+            DataType::String(bytes) => Ok(match self.range {
+                Some((start, end, unit)) => bits::count(bytes, start, end, unit),
+                None => bits::count_ones(bytes),
+            }),
+            _ => Err(WrongType),
+        });
+        let response = match count.unwrap_or(Ok(0)) {
+            Ok(count) => Frame::Integer(count as i64),
+            Err(err) => Frame::Error(err.to_string()),
         };
-        dst.write_frame(&response).await?;
-        Ok(())
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct HKeys {
-    key: Bytes,
-}
-
-impl HKeys {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HKeys> {
-        let key = parse.next_bytes()?;
-        Ok(HKeys { key })
+pub struct Ping { msg: Option<String> }
+impl Ping {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Ping> {
+         match parse.next_string() { Ok(msg) => Ok(Ping { msg: Some(msg) }), Err(_) => Ok(Ping { msg: None }) }
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let keys = db.hkeys(&self.key);
-        let mut frames = Vec::new();
-        for k in keys {
-            frames.push(Frame::Bulk(k));
-        }
-        dst.write_frame(&Frame::Array(frames)).await?;
-        Ok(())
+    pub async fn apply(self) -> crate::Result<Frame> {
+        let response = match self.msg { None => Frame::Simple("PONG".into()), Some(msg) => Frame::Bulk(Bytes::from(msg)) };
+        Ok(response)
+    }
+    /// The reply in subscriber mode, which is always a `pong` push.
+    pub(crate) fn subscriber_reply(self) -> Frame {
+        let msg = self.msg.map(Bytes::from).unwrap_or_default();
+        Frame::Push(vec![Frame::Bulk(Bytes::from_static(b"pong")), Frame::Bulk(msg)])
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct HVals {
-    key: Bytes,
+pub struct Auth { username: Option<String>, password: String }
+impl Auth {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Auth> {
+        let first = parse.next_string()?;
+        match parse.next_string() {
+            Ok(second) => Ok(Auth { username: Some(first), password: second }),
+            Err(_) => Ok(Auth { username: None, password: first }),
+        }
+    }
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        let username = self.username.as_deref().unwrap_or(DEFAULT_USER);
+        let response = if authenticate(db, dst, username, &self.password) {
+            Frame::Simple("OK".into())
+        } else {
+            Frame::Error(WRONGPASS.into())
+        };
+        Ok(response)
+    }
 }
 
-impl HVals {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HVals> {
-        let key = parse.next_bytes()?;
-        Ok(HVals { key })
-    }
+const WRONGPASS: &str = "WRONGPASS invalid username-password pair";
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-         let vals = db.hvals(&self.key);
-         let mut frames = Vec::new();
-         for v in vals {
-             frames.push(Frame::Bulk(v));
-         }
-         dst.write_frame(&Frame::Array(frames)).await?;
-         Ok(())
+/// Check the credentials against the ACL, recording the user on the
+/// connection when they're valid.
+fn authenticate(db: &Db, dst: &mut Connection, username: &str, password: &str) -> bool {
+    let valid = db.acl().check(username, password);
+    if valid {
+        dst.set_user(username);
     }
+    valid
 }
 
+/// Version reported by `INFO` and `HELLO`.
+const SERVER_VERSION: &str = "0.1.0";
 
 #[derive(Debug, Clone)]
-pub struct HLen {
-    key: Bytes,
-    _field: String,
-} 
-impl HLen {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HLen> {
-        let key = parse.next_bytes()?;
-        Ok(HLen { key, _field: String::new() }) 
+pub struct Hello { protocol: Option<Protocol>, auth: Option<(String, String)>, name: Option<Bytes> }
+impl Hello {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Hello> {
+        let protocol = match parse.next_string() {
+            Ok(version) => match version.as_str() {
+                "2" => Some(Protocol::Resp2),
+                "3" => Some(Protocol::Resp3),
+                _ => return Err("NOPROTO unsupported protocol version".into()),
+            },
+            Err(_) => None,
+        };
+        let mut auth = None;
+        let mut name = None;
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "AUTH" => auth = Some((parse.next_string()?, parse.next_string()?)),
+                "SETNAME" => name = Some(client_name(parse.next_bytes()?)?),
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(Hello { protocol, auth, name })
     }
-    
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let len = db.hlen(&self.key);
-        dst.write_frame(&Frame::Integer(len as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        // Bad credentials leave the connection as it was
+        if let Some((username, password)) = &self.auth
+            && !authenticate(db, dst, username, password)
+        {
+            return Ok(Frame::Error(WRONGPASS.into()));
+        }
+        if dst.user().is_none() {
+            return Ok(Frame::Error("NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".into()));
+        }
+        // Without a version the protocol is left as it is
+        if let Some(protocol) = self.protocol {
+            dst.set_protocol(protocol);
+        }
+        if let Some(name) = self.name {
+            dst.set_name((!name.is_empty()).then_some(name));
+        }
+        let proto = match dst.protocol() {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        let role = if db.replication().is_replica() { "slave" } else { "master" };
+        let field = |name: &'static str| Frame::Bulk(Bytes::from_static(name.as_bytes()));
+        let response = Frame::Map(vec![
+            (field("server"), field("redis")),
+            (field("version"), field(SERVER_VERSION)),
+            (field("proto"), Frame::Integer(proto)),
+            (field("mode"), field("standalone")),
+            (field("role"), field(role)),
+            (field("modules"), Frame::Array(Vec::new())),
+        ]);
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct HScan {
-    key: Bytes,
-    _cursor: u64,
+pub struct Info { section: Option<String> }
+impl Info {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Info> {
+        match parse.next_string() { Ok(s) => Ok(Info { section: Some(s) }), Err(_) => Ok(Info { section: None }) }
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let section = self.section.map(|s| s.to_ascii_lowercase());
+        let sections: Vec<&str> = match section.as_deref() {
+            None | Some("all" | "default" | "everything") => INFO_SECTIONS.to_vec(),
+            // Like Redis, an unknown section gives an empty reply
+            Some(name) => INFO_SECTIONS.iter().copied().filter(|s| *s == name).collect(),
+        };
+        let msg = sections.into_iter().map(|name| info_section(db, name)).collect::<Vec<_>>().join("\r\n");
+        Ok(Frame::Bulk(Bytes::from(msg)))
+    }
 }
 
-impl HScan {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HScan> { 
-        let key = parse.next_bytes()?;
-        let cursor_str = parse.next_string()?;
-        Ok(HScan { key, _cursor: cursor_str.parse().unwrap_or(0) }) 
-    }
-    
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        if let Some(map) = db.hgetall(&self.key) {
-              let mut frames = Vec::new();
-                for (k, v) in map {
-                    frames.push(Frame::Bulk(k));
-                    frames.push(Frame::Bulk(v));
+/// The sections of INFO, in the order they are reported.
+const INFO_SECTIONS: &[&str] = &["server", "clients", "memory", "stats", "replication", "keyspace"];
+
+/// The INFO section `name`, with its header.
+fn info_section(db: &Db, name: &str) -> String {
+    let stats = db.stats();
+    match name {
+        "server" => {
+            let uptime = stats.uptime().as_secs();
+            format!(
+                "# Server\r\n\
+                 redis_version:{}\r\n\
+                 process_id:{}\r\n\
+                 run_id:{}\r\n\
+                 tcp_port:{}\r\n\
+                 uptime_in_seconds:{}\r\n\
+                 uptime_in_days:{}\r\n\
+                 config_file:\r\n",
+                SERVER_VERSION,
+                std::process::id(),
+                stats.run_id(),
+                stats.tcp_port(),
+                uptime,
+                uptime / 86400,
+            )
+        }
+        "clients" => format!("# Clients\r\nconnected_clients:{}\r\n", stats.connected_clients()),
+        "memory" => {
+            let used = db.used_memory();
+            let (max, policy) = db.max_memory();
+            format!(
+                "# Memory\r\n\
+                 used_memory:{}\r\n\
+                 used_memory_human:{}\r\n\
+                 maxmemory:{}\r\n\
+                 maxmemory_human:{}\r\n\
+                 maxmemory_policy:{}\r\n",
+                used,
+                human_bytes(used),
+                max,
+                human_bytes(max),
+                policy.as_str(),
+            )
+        }
+        "stats" => format!(
+            "# Stats\r\n\
+             total_connections_received:{}\r\n\
+             total_commands_processed:{}\r\n\
+             evicted_keys:{}\r\n",
+            stats.total_connections_received(),
+            stats.total_commands_processed(),
+            stats.evicted_keys(),
+        ),
+        "replication" => format!("# Replication\r\n{}", replication_info(db)),
+        "keyspace" => {
+            let mut out = "# Keyspace\r\n".to_string();
+            // Only databases holding keys are listed
+            for database in (0..db.database_count()).filter_map(|index| db.select(index)) {
+                let keys = database.len();
+                if keys > 0 {
+                    out += &format!(
+                        "db{}:keys={},expires={},avg_ttl=0\r\n",
+                        database.index(),
+                        keys,
+                        database.volatile_len(),
+                    );
                 }
-                let result = vec![
-                    Frame::Bulk(Bytes::from("0")),
-                    Frame::Array(frames),
-                ];
-                dst.write_frame(&Frame::Array(result)).await?;
-        } else {
-             let result = vec![
-                Frame::Bulk(Bytes::from("0")),
-                Frame::Array(vec![]),
-            ];
-            dst.write_frame(&Frame::Array(result)).await?;
+            }
+            out
         }
-        Ok(())
+        _ => unreachable!("unknown INFO section {}", name),
     }
 }
 
+/// `bytes` scaled to the largest unit it has at least one of, like
+/// Redis's `*_human` fields.
+fn human_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["K", "M", "G", "T"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2}{}", value, UNITS[unit])
+}
 
-// Arrays / Lists
-#[derive(Debug, Clone)]
-pub struct LPush {
-    key: Bytes,
-    values: Vec<Bytes>,
+/// The fields of INFO's replication section.
+fn replication_info(db: &Db) -> String {
+    let replication = db.replication();
+    let role = match replication.master() {
+        Some((host, port)) => format!(
+            "role:slave\r\nmaster_host:{}\r\nmaster_port:{}\r\nmaster_link_status:{}\r\n",
+            host,
+            port,
+            if replication.master_link_up() { "up" } else { "down" },
+        ),
+        None => "role:master\r\n".to_string(),
+    };
+    format!("{}connected_slaves:{}\r\n", role, replication.connected_replicas())
 }
 
-impl LPush {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LPush> {
-        let key = parse.next_bytes()?;
-        let mut values = Vec::new();
-        // Support variadic arguments
-        while let Ok(val) = parse.next_bytes() {
-            values.push(val);
+#[derive(Debug, Clone)]
+pub struct ReplicaOf { master: Option<(String, u16)> }
+impl ReplicaOf {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ReplicaOf> {
+        let host = parse.next_string()?;
+        let port = parse.next_string()?;
+        if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+            return Ok(ReplicaOf { master: None });
         }
-        Ok(LPush { key, values })
+        let port = port.parse().map_err(|_| "ERR Invalid master port")?;
+        Ok(ReplicaOf { master: Some((host, port)) })
     }
-
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let mut len = 0;
-        for val in self.values {
-            len = db.lpush(self.key.clone(), val);
-        }
-        dst.write_frame(&Frame::Integer(len as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        db.replication().set_master(db, self.master);
+        Ok(Frame::Simple("OK".into()))
     }
 }
 
+/// Sent by a replica to start replicating. The replication id and offset
+/// it asks to continue from are ignored, since every sync is a full one.
 #[derive(Debug, Clone)]
-pub struct RPush {
-    key: Bytes,
+pub struct PSync {}
+impl PSync {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<PSync> {
+        parse.next_string()?;
+        parse.next_string()?;
+        Ok(PSync {})
+    }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // server.rs turns the connection into a replica link, so this is
+        // only reached inside MULTI
+        Ok(Frame::Error("ERR Command not allowed inside a transaction".into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Wait {}
+impl Wait {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Wait> {
+        parse.next_int()?;
+        if parse.next_int()? < 0 {
+            return Err("ERR timeout is negative".into());
+        }
+        Ok(Wait {})
+    }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // Replicas don't acknowledge what they've applied, so none can be
+        // counted
+        Ok(Frame::Integer(0))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Config {
+    /// Parameters matching any of the glob patterns.
+    Get(Vec<String>),
+    /// New values for parameters, all applied or none.
+    Set(Vec<(String, String)>),
+}
+impl Config {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Config> {
+        let subcommand = parse.next_string()?.to_lowercase();
+        parse.set_command(&format!("config|{}", subcommand));
+        match &subcommand[..] {
+            "get" => {
+                let mut patterns = vec![parse.next_string()?.to_lowercase()];
+                while let Ok(pattern) = parse.next_string() {
+                    patterns.push(pattern.to_lowercase());
+                }
+                Ok(Config::Get(patterns))
+            }
+            "set" => {
+                let mut settings = vec![(parse.next_string()?.to_lowercase(), parse.next_string()?)];
+                while let Ok(name) = parse.next_string() {
+                    settings.push((name.to_lowercase(), parse.next_string()?));
+                }
+                Ok(Config::Set(settings))
+            }
+            _ => Err(format!("ERR unknown subcommand '{}'. Try CONFIG HELP.", subcommand).into()),
+        }
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match self {
+            Config::Get(patterns) => {
+                let config = db.config();
+                let mut response = Frame::array();
+                for name in CONFIG_PARAMETERS {
+                    if patterns.iter().any(|pattern| glob::matches(pattern.as_bytes(), name.as_bytes())) {
+                        response.push_bulk(Bytes::from_static(name.as_bytes()));
+                        response.push_bulk(Bytes::from(config_value(&config, name)));
+                    }
+                }
+                response
+            }
+            Config::Set(settings) => {
+                let res = db.update_config(|config| {
+                    let mut updated = *config;
+                    for (name, value) in &settings {
+                        set_config_value(&mut updated, name, value)?;
+                    }
+                    *config = updated;
+                    Ok(())
+                });
+                match res {
+                    Ok(()) => Frame::Simple("OK".into()),
+                    Err(msg) => Frame::Error(msg),
+                }
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// `CLIENT` subcommands about the calling connection and the others open
+/// to the server.
+#[derive(Debug, Clone)]
+pub enum Client {
+    /// Name the connection, or clear its name with an empty one.
+    SetName(Bytes),
+    GetName,
+    Id,
+    Info,
+    List,
+    Kill(ClientKill),
+    /// Accepted for compatibility. Evicting clients isn't supported, so
+    /// there is nothing to exempt them from.
+    NoEvict,
+}
+
+/// Which connections `CLIENT KILL` closes.
+#[derive(Debug, Clone)]
+pub struct ClientKill {
+    id: Option<u64>,
+    addr: Option<String>,
+    skip_me: bool,
+    // The old `CLIENT KILL addr` form, which replies OK or an error
+    // rather than a count
+    legacy: bool,
+}
+impl Client {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Client> {
+        let subcommand = parse.next_string()?.to_lowercase();
+        parse.set_command(&format!("client|{}", subcommand));
+        match &subcommand[..] {
+            "setname" => Ok(Client::SetName(client_name(parse.next_bytes()?)?)),
+            "getname" => Ok(Client::GetName),
+            "id" => Ok(Client::Id),
+            "info" => Ok(Client::Info),
+            "list" => Ok(Client::List),
+            "kill" => Ok(Client::Kill(ClientKill::parse_frames(parse)?)),
+            "no-evict" => match parse.next_string()?.to_lowercase().as_str() {
+                "on" | "off" => Ok(Client::NoEvict),
+                _ => Err("ERR syntax error".into()),
+            },
+            _ => Err(format!("ERR unknown subcommand '{}'. Try CLIENT HELP.", subcommand).into()),
+        }
+    }
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        let response = match self {
+            Client::SetName(name) => {
+                dst.set_name((!name.is_empty()).then_some(name));
+                Frame::Simple("OK".into())
+            }
+            Client::GetName => dst.name().cloned().map_or(Frame::Null, Frame::Bulk),
+            Client::Id => Frame::Integer(dst.id() as i64),
+            Client::Info => Frame::Bulk(Bytes::from(dst.info().to_line())),
+            Client::List => {
+                let lines: String = db.clients().list().iter().map(|info| info.to_line()).collect();
+                Frame::Bulk(Bytes::from(lines))
+            }
+            Client::Kill(kill) => kill.apply(db, dst),
+            Client::NoEvict => Frame::Simple("OK".into()),
+        };
+        Ok(response)
+    }
+}
+
+impl ClientKill {
+    fn parse_frames(parse: &mut Parse) -> crate::Result<ClientKill> {
+        let first = parse.next_string()?;
+        if parse.remaining() == 0 {
+            return Ok(ClientKill { id: None, addr: Some(first), skip_me: false, legacy: true });
+        }
+        let mut kill = ClientKill { id: None, addr: None, skip_me: true, legacy: false };
+        let mut filter = Some(first);
+        while let Some(name) = filter.take().or_else(|| parse.next_string().ok()) {
+            match name.to_uppercase().as_str() {
+                "ID" => {
+                    let id = parse.next_int()?;
+                    kill.id = Some(u64::try_from(id).map_err(|_| "ERR client-id should be greater than 0")?);
+                }
+                "ADDR" => kill.addr = Some(parse.next_string()?),
+                "SKIPME" => {
+                    kill.skip_me = match parse.next_string()?.to_lowercase().as_str() {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err("ERR syntax error".into()),
+                    }
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(kill)
+    }
+
+    fn apply(self, db: &Db, dst: &Connection) -> Frame {
+        let me = dst.id();
+        let killed = db.clients().kill(|info| {
+            self.id.is_none_or(|id| id == info.id)
+                && self.addr.as_ref().is_none_or(|addr| info.addr.is_some_and(|peer| peer.to_string() == *addr))
+                && !(self.skip_me && info.id == me)
+        });
+        match (self.legacy, killed) {
+            (false, killed) => Frame::Integer(killed as i64),
+            (true, 0) => Frame::Error("ERR No such client".into()),
+            (true, _) => Frame::Simple("OK".into()),
+        }
+    }
+}
+
+/// Check a name given to CLIENT SETNAME or HELLO SETNAME.
+fn client_name(name: Bytes) -> crate::Result<Bytes> {
+    if !name.iter().all(|byte| (b'!'..=b'~').contains(byte)) {
+        return Err("ERR Client names cannot contain spaces, newlines or special characters.".into());
+    }
+    Ok(name)
+}
+
+/// `COMMAND GETKEYS command [arg ...]`, which tells proxies and cluster
+/// clients which arguments of a command are keys.
+#[derive(Debug, Clone)]
+pub enum CommandInfo {
+    GetKeys(Vec<Bytes>),
+}
+impl CommandInfo {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<CommandInfo> {
+        let subcommand = parse.next_string()?.to_lowercase();
+        parse.set_command(&format!("command|{}", subcommand));
+        match &subcommand[..] {
+            "getkeys" => Ok(CommandInfo::GetKeys(parse_one_or_more(parse)?)),
+            _ => Err(format!("ERR unknown subcommand '{}'. Try COMMAND HELP.", subcommand).into()),
+        }
+    }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        let CommandInfo::GetKeys(args) = self;
+        let response = match command_keys(args) {
+            Ok(keys) => Frame::Array(keys.into_iter().map(Frame::Bulk).collect()),
+            Err(msg) => Frame::Error(msg.into()),
+        };
+        Ok(response)
+    }
+}
+
+/// Where the keys of each command are among its arguments, counting the
+/// command name as argument 0: every `step`th one from `first` to `last`,
+/// where a negative `last` counts back from the end.
+const KEY_SPECS: &[(&str, usize, isize, usize)] = &[
+    ("get", 1, 1, 1),
+    ("set", 1, 1, 1),
+    ("mset", 1, -1, 2),
+    ("del", 1, -1, 1),
+    ("unlink", 1, -1, 1),
+    ("touch", 1, -1, 1),
+    ("setrange", 1, 1, 1),
+    ("append", 1, 1, 1),
+    ("incr", 1, 1, 1),
+    ("decr", 1, 1, 1),
+    ("incrby", 1, 1, 1),
+    ("decrby", 1, 1, 1),
+    ("getrange", 1, 1, 1),
+    ("setbit", 1, 1, 1),
+    ("getbit", 1, 1, 1),
+    ("bitcount", 1, 1, 1),
+    ("type", 1, 1, 1),
+    ("object", 2, 2, 1),
+    ("exists", 1, 1, 1),
+    ("rename", 1, 2, 1),
+    ("renamenx", 1, 2, 1),
+    ("copy", 1, 2, 1),
+    ("move", 1, 1, 1),
+    ("dump", 1, 1, 1),
+    ("restore", 1, 1, 1),
+    ("hset", 1, 1, 1),
+    ("hget", 1, 1, 1),
+    ("hdel", 1, 1, 1),
+    ("hgetdel", 1, 1, 1),
+    ("hstrlen", 1, 1, 1),
+    ("hexists", 1, 1, 1),
+    ("hgetall", 1, 1, 1),
+    ("hkeys", 1, 1, 1),
+    ("hvals", 1, 1, 1),
+    ("hscan", 1, 1, 1),
+    ("hrandfield", 1, 1, 1),
+    ("hlen", 1, 1, 1),
+    ("lpush", 1, 1, 1),
+    ("rpush", 1, 1, 1),
+    ("lpop", 1, 1, 1),
+    ("rpop", 1, 1, 1),
+    ("blpop", 1, -2, 1),
+    ("brpop", 1, -2, 1),
+    ("lmove", 1, 2, 1),
+    ("rpoplpush", 1, 2, 1),
+    ("blmove", 1, 2, 1),
+    ("brpoplpush", 1, 2, 1),
+    ("lrange", 1, 1, 1),
+    ("lpos", 1, 1, 1),
+    ("sort", 1, 1, 1),
+    ("sadd", 1, 1, 1),
+    ("smembers", 1, 1, 1),
+    ("srem", 1, 1, 1),
+    ("spop", 1, 1, 1),
+    ("srandmember", 1, 1, 1),
+    ("smove", 1, 2, 1),
+    ("sinter", 1, -1, 1),
+    ("sunion", 1, -1, 1),
+    ("sdiff", 1, -1, 1),
+    ("sinterstore", 1, -1, 1),
+    ("sunionstore", 1, -1, 1),
+    ("sdiffstore", 1, -1, 1),
+    ("json.set", 1, 1, 1),
+    ("json.get", 1, 1, 1),
+    ("json.del", 1, 1, 1),
+    ("json.type", 1, 1, 1),
+    ("json.arrappend", 1, 1, 1),
+    ("zadd", 1, 1, 1),
+    ("zincrby", 1, 1, 1),
+    ("zrange", 1, 1, 1),
+    ("zrangestore", 1, 2, 1),
+    ("zrangebylex", 1, 1, 1),
+    ("zlexcount", 1, 1, 1),
+    ("zrandmember", 1, 1, 1),
+    ("ttl", 1, 1, 1),
+    ("pttl", 1, 1, 1),
+    ("expireat", 1, 1, 1),
+    ("pexpireat", 1, 1, 1),
+    ("expiretime", 1, 1, 1),
+    ("pexpiretime", 1, 1, 1),
+    ("watch", 1, -1, 1),
+];
+
+/// Commands whose keys are counted by an argument, with its position. The
+/// keys follow it.
+const NUMKEYS_SPECS: &[(&str, usize)] = &[
+    ("sintercard", 1),
+    ("zmpop", 1),
+    ("bzmpop", 2),
+    ("lmpop", 1),
+    ("blmpop", 2),
+];
+
+/// The keys among the arguments of a command, found with `KEY_SPECS` once
+/// the command has been checked to parse.
+fn command_keys(args: Vec<Bytes>) -> Result<Vec<Bytes>, &'static str> {
+    let frame = Frame::Array(args.iter().cloned().map(Frame::Bulk).collect());
+    let name = match Command::from_frame(frame) {
+        Ok(Command::Unknown(_)) => return Err("ERR Invalid command specified"),
+        Ok(command) => command.get_name().to_string(),
+        Err(_) => return Err("ERR Invalid arguments specified for command"),
+    };
+    if let Some(&(_, pos)) = NUMKEYS_SPECS.iter().find(|(spec, _)| *spec == name) {
+        // The command parsed, so the count is valid
+        let numkeys = parse_uint(&args[pos]).unwrap_or(0) as usize;
+        return Ok(args.into_iter().skip(pos + 1).take(numkeys).collect());
+    }
+    let Some(&(_, first, last, step)) = KEY_SPECS.iter().find(|(spec, ..)| *spec == name) else {
+        return Err("ERR The command has no key arguments");
+    };
+    let last = if last < 0 { (args.len() as isize + last) as usize } else { last as usize };
+    Ok(args.into_iter().take(last + 1).skip(first).step_by(step).collect())
+}
+
+/// The parameters known to CONFIG GET and CONFIG SET.
+const CONFIG_PARAMETERS: &[&str] = &["maxmemory", "maxmemory-policy", "timeout", "notify-keyspace-events"];
+
+/// The value of the known parameter `name`, as CONFIG GET reports it.
+fn config_value(config: &RuntimeConfig, name: &str) -> String {
+    match name {
+        "maxmemory" => config.maxmemory.to_string(),
+        "maxmemory-policy" => config.maxmemory_policy.as_str().to_string(),
+        "timeout" => config.timeout.as_secs().to_string(),
+        "notify-keyspace-events" => config.notify_keyspace_events.to_string(),
+        _ => unreachable!("unknown config parameter {}", name),
+    }
+}
+
+/// Set the parameter `name` to `value`, or describe why it can't be.
+fn set_config_value(config: &mut RuntimeConfig, name: &str, value: &str) -> Result<(), String> {
+    let invalid = || {
+        format!("ERR CONFIG SET failed (possibly related to argument '{}') - Invalid argument '{}'", name, value)
+    };
+    match name {
+        "maxmemory" => config.maxmemory = parse_memory(value).ok_or_else(invalid)?,
+        "maxmemory-policy" => config.maxmemory_policy = EvictionPolicy::from_name(value).ok_or_else(invalid)?,
+        "timeout" => config.timeout = Duration::from_secs(value.parse().map_err(|_| invalid())?),
+        "notify-keyspace-events" => config.notify_keyspace_events = KeyspaceEvents::parse(value).ok_or_else(invalid)?,
+        _ => return Err(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name)),
+    }
+    Ok(())
+}
+
+/// A number of bytes with an optional unit, like `100mb` in redis.conf.
+/// `k`, `m` and `g` are powers of 1000; `kb`, `mb` and `gb` powers of 1024.
+fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let scale: usize = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok()?.checked_mul(scale)
+}
+
+#[derive(Debug, Clone)]
+pub struct Time {}
+impl Time {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Time> { Ok(Time {}) }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let field = |value: u64| Frame::Bulk(Bytes::from(value.to_string()));
+        Ok(Frame::Array(vec![field(now.as_secs()), field(now.subsec_micros().into())]))
+    }
+}
+
+/// Subcommands of DEBUG that test tooling sends, and that have nothing to
+/// change here, so they just reply OK.
+const DEBUG_NOOPS: [&str; 3] = ["set-active-expire", "jmap", "quicklist-packed-threshold"];
+
+#[derive(Debug, Clone)]
+pub enum Debug {
+    Sleep(Duration),
+    Object(Bytes),
+    Noop,
+}
+impl Debug {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Debug> {
+        let subcommand = parse.next_string()?.to_lowercase();
+        parse.set_command(&format!("debug|{}", subcommand));
+        match &subcommand[..] {
+            "sleep" => {
+                let seconds = parse_float(&parse.next_bytes()?)?;
+                Duration::try_from_secs_f64(seconds)
+                    .map(Debug::Sleep)
+                    .map_err(|_| parse_num::NOT_A_FLOAT.into())
+            }
+            "object" => {
+                let key = parse.next_bytes()?;
+                parse.finish()?;
+                Ok(Debug::Object(key))
+            }
+            name if DEBUG_NOOPS.contains(&name) => {
+                while parse.next_bytes().is_ok() {}
+                Ok(Debug::Noop)
+            }
+            _ => Err(format!("ERR unknown subcommand '{}'. Try DEBUG HELP.", subcommand).into()),
+        }
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        match self {
+            Debug::Sleep(duration) => tokio::time::sleep(duration).await,
+            Debug::Object(key) => return Ok(debug_object(db, &key)),
+            Debug::Noop => {}
+        }
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+/// The `DEBUG OBJECT` line describing `key`.
+///
+/// Like Redis, it reports the encoding `OBJECT ENCODING` would and the
+/// length of the value's `DUMP` payload. Long lists also get the `ql_`
+/// fields of a quicklist, estimated from that length.
+fn debug_object(db: &Db, key: &[u8]) -> Frame {
+    // Packed nodes of a quicklist, as with `list-max-listpack-size -2`
+    const QUICKLIST_NODE_SIZE: usize = 8 * 1024;
+
+    let idle = db.idle_time(key).unwrap_or_default();
+    let details = db.peek_value(key, |value| {
+        let encoding = value.encoding();
+        let serialized = persistence::dump_value(value).len();
+        let mut line = format!(
+            "Value at:{:p} refcount:1 type:{} encoding:{} serializedlength:{} lru_seconds_idle:{}",
+            value,
+            value.type_name(),
+            encoding,
+            serialized,
+            idle.as_secs()
+        );
+        if let (DataType::List(list), "quicklist") = (value, encoding) {
+            let nodes = serialized.div_ceil(QUICKLIST_NODE_SIZE).max(1);
+            line.push_str(&format!(
+                " ql_nodes:{} ql_avg_node:{:.2} ql_listpack_max:-2 ql_compressed:0 ql_uncompressed_size:{}",
+                nodes,
+                list.len() as f64 / nodes as f64,
+                serialized
+            ));
+        }
+        line
+    });
+    match details {
+        Some(line) => Frame::Simple(line),
+        None => Frame::Error("ERR no such key".into()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Publish { channel: Bytes, message: Bytes }
+impl Publish {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Publish> {
+        Ok(Publish { channel: parse.next_bytes()?, message: parse.next_bytes()? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let receivers = db.pubsub().publish(self.channel, self.message);
+        Ok(Frame::Integer(receivers as i64))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Subscribe { pub channels: Vec<Bytes> }
+impl Subscribe {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Subscribe> {
+        Ok(Subscribe { channels: parse_subscription_names(parse)? })
+    }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // server.rs switches the connection into subscriber mode, so this is
+        // only reached when queued in a transaction
+        let response = Frame::Error("ERR SUBSCRIBE isn't allowed in a transaction".into());
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Unsubscribe { pub channels: Vec<Bytes> }
+impl Unsubscribe {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Unsubscribe> {
+        let mut channels = Vec::new();
+        while let Ok(channel) = parse.next_bytes() {
+            channels.push(channel);
+        }
+        Ok(Unsubscribe { channels })
+    }
+    pub async fn apply(self) -> crate::Result<Vec<Frame>> {
+        // Outside subscriber mode there is nothing to leave
+        Ok(leave_nothing("unsubscribe", self.channels))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PSubscribe { pub patterns: Vec<Bytes> }
+impl PSubscribe {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<PSubscribe> {
+        Ok(PSubscribe { patterns: parse_subscription_names(parse)? })
+    }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // Like SUBSCRIBE, only reached when queued in a transaction
+        let response = Frame::Error("ERR PSUBSCRIBE isn't allowed in a transaction".into());
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PUnsubscribe { pub patterns: Vec<Bytes> }
+impl PUnsubscribe {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<PUnsubscribe> {
+        let mut patterns = Vec::new();
+        while let Ok(pattern) = parse.next_bytes() {
+            patterns.push(pattern);
+        }
+        Ok(PUnsubscribe { patterns })
+    }
+    pub async fn apply(self) -> crate::Result<Vec<Frame>> {
+        Ok(leave_nothing("punsubscribe", self.patterns))
+    }
+}
+
+/// Parse the channels or patterns of a subscribe command, at least one.
+fn parse_subscription_names(parse: &mut Parse) -> crate::Result<Vec<Bytes>> {
+    let mut names = vec![parse.next_bytes()?];
+    while let Ok(name) = parse.next_bytes() {
+        names.push(name);
+    }
+    Ok(names)
+}
+
+/// `replies` as a single frame: the only reply, or an array of them all.
+fn single_reply(mut replies: Vec<Frame>) -> Frame {
+    if replies.len() == 1 {
+        replies.pop().unwrap()
+    } else {
+        Frame::Array(replies)
+    }
+}
+
+/// Replies to an unsubscribe from a connection without any subscriptions,
+/// one for each name.
+fn leave_nothing(kind: &'static str, names: Vec<Bytes>) -> Vec<Frame> {
+    if names.is_empty() {
+        return vec![subscription_reply(kind, None, 0)];
+    }
+    names.into_iter().map(|name| subscription_reply(kind, Some(name), 0)).collect()
+}
+
+/// Confirmation of a `kind` (un)subscription from the channel or pattern
+/// `name`, leaving `count` subscriptions. The name is `None` when an
+/// unsubscribe had nothing to leave.
+pub(crate) fn subscription_reply(kind: &'static str, name: Option<Bytes>, count: usize) -> Frame {
+    Frame::Push(vec![
+        Frame::Bulk(Bytes::from_static(kind.as_bytes())),
+        name.map_or(Frame::Null, Frame::Bulk),
+        Frame::Integer(count as i64),
+    ])
+}
+
+#[derive(Debug, Clone)]
+pub struct Exists {
+    key: Bytes,
+}
+
+impl Exists {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Exists> {
+        let key = parse.next_bytes()?;
+        Ok(Exists { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = if db.exists(&self.key) {
+            Frame::Integer(1)
+        } else {
+            Frame::Integer(0)
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Rename {
+    key: Bytes,
+    new_key: Bytes,
+}
+
+impl Rename {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Rename> {
+        let key = parse.next_bytes()?;
+        let new_key = parse.next_bytes()?;
+        Ok(Rename { key, new_key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.rename(&self.key, self.new_key, true) {
+            Some(_) => Frame::Simple("OK".into()),
+            None => Frame::Error("ERR no such key".into()),
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenameNx {
+    key: Bytes,
+    new_key: Bytes,
+}
+
+impl RenameNx {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RenameNx> {
+        let key = parse.next_bytes()?;
+        let new_key = parse.next_bytes()?;
+        Ok(RenameNx { key, new_key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.rename(&self.key, self.new_key, false) {
+            Some(renamed) => Frame::Integer(renamed as i64),
+            None => Frame::Error("ERR no such key".into()),
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Copy {
+    key: Bytes,
+    new_key: Bytes,
+    db: Option<i64>,
+    replace: bool,
+}
+
+impl Copy {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Copy> {
+        let key = parse.next_bytes()?;
+        let new_key = parse.next_bytes()?;
+        let mut db = None;
+        let mut replace = false;
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "DB" => db = Some(parse.next_int()?),
+                "REPLACE" => replace = true,
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(Copy { key, new_key, db, replace })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let dst_db = match self.db {
+            None => Some(db.index()),
+            Some(index) => usize::try_from(index).ok().filter(|&i| i < db.database_count()),
+        };
+        let response = match dst_db {
+            None => Frame::Error("ERR DB index is out of range".into()),
+            Some(index) => match db.copy(&self.key, index, self.new_key, self.replace) {
+                Ok(copied) => Frame::Integer(copied as i64),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+        };
+        Ok(response)
+    }
+}
+
+/// `MOVE key db`, which moves a key from the selected database to another.
+#[derive(Debug, Clone)]
+pub struct Move {
+    key: Bytes,
+    db: i64,
+}
+
+impl Move {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Move> {
+        let key = parse.next_bytes()?;
+        let db = parse.next_int()?;
+        Ok(Move { key, db })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let dst_db = usize::try_from(self.db).ok().filter(|&i| i < db.database_count());
+        let response = match dst_db {
+            None => Frame::Error("ERR DB index is out of range".into()),
+            Some(index) => match db.move_key(self.key, index) {
+                Ok(moved) => Frame::Integer(moved as i64),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Dump {
+    key: Bytes,
+}
+
+impl Dump {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Dump> {
+        Ok(Dump { key: parse.next_bytes()? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.read_value(&self.key, persistence::dump_value) {
+            Some(payload) => Frame::Bulk(payload.into()),
+            None => Frame::Null,
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Restore {
+    key: Bytes,
+    // Milliseconds to live, or the Unix time in milliseconds with ABSTTL.
+    // Zero means no expiry.
+    ttl: i64,
+    payload: Bytes,
+    replace: bool,
+    absttl: bool,
+}
+
+impl Restore {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Restore> {
+        let key = parse.next_bytes()?;
+        let ttl = parse.next_int()?;
+        let payload = parse.next_bytes()?;
+        let mut replace = false;
+        let mut absttl = false;
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "REPLACE" => replace = true,
+                "ABSTTL" => absttl = true,
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(Restore { key, ttl, payload, replace, absttl })
+    }
+
+    /// The same command with its TTL turned into an absolute deadline, so
+    /// running it later doesn't extend the key's life.
+    pub(crate) fn into_absolute(self) -> Restore {
+        if self.absttl || self.ttl <= 0 {
+            return self;
+        }
+        let ttl = (clock::unix_millis() as i64).saturating_add(self.ttl);
+        Restore { ttl, absttl: true, ..self }
+    }
+
+    /// The request that runs this command.
+    pub(crate) fn to_frame(&self) -> Frame {
+        let mut frame = vec![
+            Frame::Bulk(Bytes::from_static(b"RESTORE")),
+            Frame::Bulk(self.key.clone()),
+            Frame::Bulk(Bytes::from(self.ttl.to_string())),
+            Frame::Bulk(self.payload.clone()),
+        ];
+        if self.replace {
+            frame.push(Frame::Bulk(Bytes::from_static(b"REPLACE")));
+        }
+        if self.absttl {
+            frame.push(Frame::Bulk(Bytes::from_static(b"ABSTTL")));
+        }
+        Frame::Array(frame)
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        if self.ttl < 0 {
+            return Ok(Frame::Error("ERR Invalid TTL value, must be >= 0".into()));
+        }
+        let value = match persistence::restore_value(&self.payload) {
+            Ok(value) => value,
+            Err(PayloadError::Checksum) => {
+                return Ok(Frame::Error("ERR DUMP payload version or checksum are wrong".into()));
+            }
+            Err(PayloadError::BadData) => return Ok(Frame::Error("ERR Bad data format".into())),
+        };
+
+        let deadline = match self.ttl {
+            0 => None,
+            ttl if self.absttl => Some(ttl as u64),
+            ttl => Some(clock::unix_millis().saturating_add(ttl as u64)),
+        };
+        let response = if db.restore(self.key, value, deadline, self.replace) {
+            Frame::Simple("OK".into())
+        } else {
+            Frame::Error("BUSYKEY Target key name already exists".into())
+        };
+        Ok(response)
+    }
+}
+
+/// Parse the remaining arguments, of which there must be at least one.
+fn parse_one_or_more(parse: &mut Parse) -> crate::Result<Vec<Bytes>> {
+    let mut values = vec![parse.next_bytes()?];
+    while parse.remaining() > 0 {
+        values.push(parse.next_bytes()?);
+    }
+    Ok(values)
+}
+
+/// Reply with the count a write returned, or its error.
+fn count_reply(count: Result<usize, WrongType>) -> Frame {
+    match count {
+        Ok(count) => Frame::Integer(count as i64),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HSet {
+    key: Bytes,
+    fields: Vec<(Bytes, Bytes)>,
+}
+
+impl HSet {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HSet> {
+        let key = parse.next_bytes()?;
+        let mut fields = vec![(parse.next_bytes()?, parse.next_bytes()?)];
+        while parse.remaining() > 0 {
+            fields.push((parse.next_bytes()?, parse.next_bytes()?));
+        }
+        Ok(HSet { key, fields })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.hset(self.key, self.fields)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HGet {
+    key: Bytes,
+    field: Bytes,
+}
+
+impl HGet {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HGet> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        Ok(HGet { key, field })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.hget(&self.key, &self.field) {
+            Some(val) => Frame::Bulk(val),
+            None => Frame::Null,
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HDel {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+impl HDel {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HDel> {
+        let key = parse.next_bytes()?;
+        let fields = parse_one_or_more(parse)?;
+        Ok(HDel { key, fields })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.hdel(&self.key, &self.fields)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetDel {
+    key: Bytes,
+    fields: Vec<Bytes>,
+}
+
+impl HGetDel {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HGetDel> {
+        let key = parse.next_bytes()?;
+        if !parse.next_string()?.eq_ignore_ascii_case("fields") {
+            return Err("ERR Mandatory argument FIELDS is missing or not at the right position".into());
+        }
+        let num_fields = parse.next_int()?;
+        if num_fields <= 0 {
+            return Err("ERR Parameter `numFields` should be greater than 0".into());
+        }
+        if usize::try_from(num_fields).ok() != Some(parse.remaining()) {
+            return Err("ERR The `numfields` parameter must match the number of arguments".into());
+        }
+        let mut fields = Vec::new();
+        while parse.remaining() > 0 {
+            fields.push(parse.next_bytes()?);
+        }
+        Ok(HGetDel { key, fields })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.hgetdel(&self.key, &self.fields) {
+            Ok(values) => Frame::Array(values.into_iter().map(|v| v.map_or(Frame::Null, Frame::Bulk)).collect()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HStrLen {
+    key: Bytes,
+    field: Bytes,
+}
+
+impl HStrLen {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HStrLen> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        Ok(HStrLen { key, field })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = db.read_value(&self.key, |value| match value {
+            DataType::Hash(map) => Frame::Integer(map.get(&self.field).map_or(0, |v| v.len() as i64)),
+            _ => Frame::Error(WrongType.to_string()),
+        });
+        Ok(response.unwrap_or(Frame::Integer(0)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HExists {
+    key: Bytes,
+    field: Bytes,
+}
+
+impl HExists {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HExists> {
+        let key = parse.next_bytes()?;
+        let field = parse.next_bytes()?;
+        Ok(HExists { key, field })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let result = db.hexists(&self.key, &self.field);
+        Ok(Frame::Integer(result as i64))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HGetAll {
+    key: Bytes,
+}
+
+impl HGetAll {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HGetAll> {
+        let key = parse.next_bytes()?;
+        Ok(HGetAll { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = db.read_value(&self.key, |value| match value {
+            DataType::Hash(map) => {
+                Frame::Map(map.iter().map(|(k, v)| (Frame::Bulk(k.clone()), Frame::Bulk(v.clone()))).collect())
+            }
+            _ => Frame::Error(WrongType.to_string()),
+        });
+        let response = response.unwrap_or(Frame::Map(vec![]));
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HKeys {
+    key: Bytes,
+}
+
+impl HKeys {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HKeys> {
+        let key = parse.next_bytes()?;
+        Ok(HKeys { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let keys = db.hkeys(&self.key);
+        let mut frames = Vec::new();
+        for k in keys {
+            frames.push(Frame::Bulk(k));
+        }
+        Ok(Frame::Array(frames))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HVals {
+    key: Bytes,
+}
+
+impl HVals {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HVals> {
+        let key = parse.next_bytes()?;
+        Ok(HVals { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         let vals = db.hvals(&self.key);
+         let mut frames = Vec::new();
+         for v in vals {
+             frames.push(Frame::Bulk(v));
+         }
+         Ok(Frame::Array(frames))
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct HLen {
+    key: Bytes,
+    _field: String,
+} 
+impl HLen {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HLen> {
+        let key = parse.next_bytes()?;
+        Ok(HLen { key, _field: String::new() }) 
+    }
+    
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let len = db.hlen(&self.key);
+        Ok(Frame::Integer(len as i64))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HScan {
+    key: Bytes,
+    _cursor: u64,
+    options: ScanOptions,
+}
+
+impl HScan {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HScan> { 
+        let key = parse.next_bytes()?;
+        let cursor = parse_cursor(&parse.next_bytes()?)?;
+        let options = ScanOptions::parse(parse)?;
+        Ok(HScan { key, _cursor: cursor, options })
+    }
+    
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let frames = db.read_value(&self.key, |value| {
+            let mut frames = Vec::new();
+            if let DataType::Hash(map) = value {
+                for (k, v) in map {
+                    if self.options.matches(k) {
+                        frames.push(Frame::Bulk(k.clone()));
+                        frames.push(Frame::Bulk(v.clone()));
+                    }
+                }
+            }
+            frames
+        });
+        let result = vec![
+            Frame::Bulk(Bytes::from("0")),
+            Frame::Array(frames.unwrap_or_default()),
+        ];
+        Ok(Frame::Array(result))
+    }
+}
+
+
+/// Parse the optional count of HRANDFIELD and ZRANDMEMBER, and the flag
+/// named `with` that may follow it.
+fn parse_random_picks(parse: &mut Parse, with: &str) -> crate::Result<(Option<i64>, bool)> {
+    let count = parse_optional_count(parse)?;
+    if count.is_none() || parse.remaining() == 0 {
+        return Ok((count, false));
+    }
+    if !parse.next_string()?.eq_ignore_ascii_case(with) {
+        return Err("ERR syntax error".into());
+    }
+    Ok((count, true))
+}
+
+/// Reply to HRANDFIELD or ZRANDMEMBER with the picks and their values.
+///
+/// Without a count only the first pick is returned, or nil. With values,
+/// RESP3 pairs each pick with its value and RESP2 flattens them.
+fn random_picks_reply(picks: Vec<(Bytes, Frame)>, count: Option<i64>, with_values: bool, protocol: Protocol) -> Frame {
+    if count.is_none() {
+        return picks.into_iter().next().map_or(Frame::Null, |(pick, _)| Frame::Bulk(pick));
+    }
+    let pairs = with_values && protocol == Protocol::Resp3;
+    let mut frames = Vec::new();
+    for (pick, value) in picks {
+        if pairs {
+            frames.push(Frame::Array(vec![Frame::Bulk(pick), value]));
+        } else {
+            frames.push(Frame::Bulk(pick));
+            if with_values {
+                frames.push(value);
+            }
+        }
+    }
+    Frame::Array(frames)
+}
+
+#[derive(Debug, Clone)]
+pub struct HRandField {
+    key: Bytes,
+    count: Option<i64>,
+    with_values: bool,
+}
+
+impl HRandField {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<HRandField> {
+        let key = parse.next_bytes()?;
+        let (count, with_values) = parse_random_picks(parse, "withvalues")?;
+        Ok(HRandField { key, count, with_values })
+    }
+
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        let response = match db.hrandfield(&self.key, self.count.unwrap_or(1), &mut rand::rng()) {
+            Ok(fields) => {
+                let picks = fields.into_iter().map(|(field, value)| (field, Frame::Bulk(value))).collect();
+                random_picks_reply(picks, self.count, self.with_values, dst.protocol())
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+
+// Arrays / Lists
+
+/// Reply to LPOP or RPOP with the popped element, or nil.
+fn pop_reply(popped: Result<Option<Bytes>, WrongType>) -> Frame {
+    match popped {
+        Ok(value) => value.map_or(Frame::Null, Frame::Bulk),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+#[derive(Debug, Clone)]
+pub struct LPush {
+    key: Bytes,
     values: Vec<Bytes>,
 }
 
-impl RPush {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RPush> {
+impl LPush {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LPush> {
+        let key = parse.next_bytes()?;
+        let values = parse_one_or_more(parse)?;
+        Ok(LPush { key, values })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.lpush(self.key, self.values)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RPush {
+    key: Bytes,
+    values: Vec<Bytes>,
+}
+
+impl RPush {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RPush> {
+        let key = parse.next_bytes()?;
+        let values = parse_one_or_more(parse)?;
+        Ok(RPush { key, values })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.rpush(self.key, self.values)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LPop {
+    key: Bytes,
+}
+
+impl LPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LPop> {
+        let key = parse.next_bytes()?;
+        Ok(LPop { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(pop_reply(db.lpop(&self.key)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RPop {
+    key: Bytes,
+}
+
+impl RPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RPop> {
+        let key = parse.next_bytes()?;
+        Ok(RPop { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(pop_reply(db.rpop(&self.key)))
+    }
+}
+
+/// `BLPOP key [key ...] timeout`, which pops the head of the first of
+/// `keys` holding a list, waiting for one to be pushed to if none does.
+///
+/// The connection does the waiting, calling `apply` each time one of the
+/// keys is written to. `apply` makes a single attempt, replying with a null
+/// array when there was nothing to pop, which is also the reply inside
+/// MULTI, where commands never block.
+#[derive(Debug, Clone)]
+pub struct BLPop {
+    keys: Vec<Bytes>,
+    timeout: Option<Duration>,
+}
+
+impl BLPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BLPop> {
+        let (keys, timeout) = parse_blocking_keys(parse)?;
+        Ok(BLPop { keys, timeout })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(self.apply_popped(db).await?.0)
+    }
+
+    /// Like `apply`, but also returns the key popped from, if any.
+    pub(crate) async fn apply_popped(self, db: &Db) -> crate::Result<(Frame, Option<Bytes>)> {
+        Ok(blocking_pop(db, self.keys, Db::lpop))
+    }
+}
+
+/// `BRPOP key [key ...] timeout`, which pops from the tail like `RPOP`, and
+/// otherwise works like [`BLPop`].
+#[derive(Debug, Clone)]
+pub struct BRPop {
+    keys: Vec<Bytes>,
+    timeout: Option<Duration>,
+}
+
+impl BRPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BRPop> {
+        let (keys, timeout) = parse_blocking_keys(parse)?;
+        Ok(BRPop { keys, timeout })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(self.apply_popped(db).await?.0)
+    }
+
+    /// Like `apply`, but also returns the key popped from, if any.
+    pub(crate) async fn apply_popped(self, db: &Db) -> crate::Result<(Frame, Option<Bytes>)> {
+        Ok(blocking_pop(db, self.keys, Db::rpop))
+    }
+}
+
+/// `LMOVE source destination LEFT|RIGHT LEFT|RIGHT`, and `RPOPLPUSH source
+/// destination`, which is the same as moving from the right to the left.
+#[derive(Debug, Clone)]
+pub struct LMove {
+    src: Bytes,
+    dst: Bytes,
+    from: ListEnd,
+    to: ListEnd,
+}
+
+impl LMove {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LMove> {
+        let src = parse.next_bytes()?;
+        let dst = parse.next_bytes()?;
+        let from = parse_list_end(parse)?;
+        let to = parse_list_end(parse)?;
+        Ok(LMove { src, dst, from, to })
+    }
+
+    pub(crate) fn parse_rpoplpush(parse: &mut Parse) -> crate::Result<LMove> {
+        let src = parse.next_bytes()?;
+        let dst = parse.next_bytes()?;
+        Ok(LMove { src, dst, from: ListEnd::Right, to: ListEnd::Left })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(pop_reply(db.lmove(&self.src, self.dst, self.from, self.to)))
+    }
+}
+
+/// `BLMOVE source destination LEFT|RIGHT LEFT|RIGHT timeout`, and
+/// `BRPOPLPUSH source destination timeout`, which wait for the source to be
+/// pushed to when it's empty and otherwise work like [`LMove`].
+///
+/// Like [`BLPop`], `apply` makes a single attempt, replying with a null
+/// array when there was nothing to move.
+#[derive(Debug, Clone)]
+pub struct BLMove {
+    src: Bytes,
+    dst: Bytes,
+    from: ListEnd,
+    to: ListEnd,
+    timeout: Option<Duration>,
+}
+
+impl BLMove {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BLMove> {
+        let LMove { src, dst, from, to } = LMove::parse_frames(parse)?;
+        let timeout = parse_timeout(&parse.next_bytes()?)?;
+        Ok(BLMove { src, dst, from, to, timeout })
+    }
+
+    pub(crate) fn parse_brpoplpush(parse: &mut Parse) -> crate::Result<BLMove> {
+        let LMove { src, dst, from, to } = LMove::parse_rpoplpush(parse)?;
+        let timeout = parse_timeout(&parse.next_bytes()?)?;
+        Ok(BLMove { src, dst, from, to, timeout })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(self.apply_moved(db).await?.0)
+    }
+
+    /// Like `apply`, but also returns whether an element was moved.
+    pub(crate) async fn apply_moved(self, db: &Db) -> crate::Result<(Frame, bool)> {
+        let response = match db.lmove(&self.src, self.dst, self.from, self.to) {
+            Ok(Some(value)) => (Frame::Bulk(value), true),
+            Ok(None) => (Frame::NullArray, false),
+            Err(err) => (Frame::Error(err.to_string()), false),
+        };
+        Ok(response)
+    }
+
+    /// The LMOVE request that makes the same move without blocking.
+    pub(crate) fn to_lmove_frame(&self) -> Frame {
+        let end = |end| match end {
+            ListEnd::Left => Bytes::from_static(b"LEFT"),
+            ListEnd::Right => Bytes::from_static(b"RIGHT"),
+        };
+        Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"LMOVE")),
+            Frame::Bulk(self.src.clone()),
+            Frame::Bulk(self.dst.clone()),
+            Frame::Bulk(end(self.from)),
+            Frame::Bulk(end(self.to)),
+        ])
+    }
+}
+
+/// Parse a `LEFT` or `RIGHT` argument.
+fn parse_list_end(parse: &mut Parse) -> crate::Result<ListEnd> {
+    match parse.next_string()?.to_uppercase().as_str() {
+        "LEFT" => Ok(ListEnd::Left),
+        "RIGHT" => Ok(ListEnd::Right),
+        _ => Err("ERR syntax error".into()),
+    }
+}
+
+/// Parse the `key [key ...] timeout` arguments of a blocking command.
+fn parse_blocking_keys(parse: &mut Parse) -> crate::Result<(Vec<Bytes>, Option<Duration>)> {
+    let mut keys = vec![parse.next_bytes()?];
+    let mut timeout = parse.next_bytes()?;
+    while parse.remaining() > 0 {
+        keys.push(std::mem::replace(&mut timeout, parse.next_bytes()?));
+    }
+    Ok((keys, parse_timeout(&timeout)?))
+}
+
+/// Parse a blocking command's timeout, in seconds with a fraction allowed.
+/// Zero means wait forever, and becomes `None`.
+fn parse_timeout(timeout: &[u8]) -> crate::Result<Option<Duration>> {
+    let seconds = parse_float(timeout)
+        .ok()
+        .filter(|seconds| seconds.is_finite())
+        .ok_or("ERR timeout is not a float or out of range")?;
+    if seconds < 0.0 {
+        return Err("ERR timeout is negative".into());
+    }
+    if seconds == 0.0 {
+        return Ok(None);
+    }
+    let timeout = Duration::try_from_secs_f64(seconds).map_err(|_| "ERR timeout is out of range")?;
+    Ok(Some(timeout))
+}
+
+/// Pop with `pop` from the first of `keys` holding a list, replying with
+/// the key and the value, and return the key too.
+fn blocking_pop(
+    db: &Db,
+    keys: Vec<Bytes>,
+    pop: fn(&Db, &[u8]) -> Result<Option<Bytes>, WrongType>,
+) -> (Frame, Option<Bytes>) {
+    for key in keys {
+        match pop(db, &key) {
+            Ok(Some(value)) => return (Frame::Array(vec![Frame::Bulk(key.clone()), Frame::Bulk(value)]), Some(key)),
+            Ok(None) => {}
+            Err(err) => return (Frame::Error(err.to_string()), None),
+        }
+    }
+    (Frame::NullArray, None)
+}
+
+#[derive(Debug, Clone)]
+pub struct LRange {
+    key: Bytes,
+    start: i64,
+    stop: i64,
+}
+
+impl LRange {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LRange> {
+        let key = parse.next_bytes()?;
+        let start = parse.next_int()?;
+        let stop = parse.next_int()?;
+        Ok(LRange { key, start, stop })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.lrange(&self.key, self.start, self.stop) {
+            Ok(values) => Frame::Array(values.into_iter().map(Frame::Bulk).collect()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LPos {
+    key: Bytes,
+    element: Bytes,
+    rank: i64,
+    // `None` replies with a single index rather than an array
+    count: Option<usize>,
+    maxlen: usize,
+}
+
+impl LPos {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LPos> {
+        let key = parse.next_bytes()?;
+        let element = parse.next_bytes()?;
+        let (mut rank, mut count, mut maxlen) = (1, None, 0);
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "RANK" => {
+                    rank = parse.next_int()?;
+                    if rank == 0 {
+                        return Err("ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list".into());
+                    }
+                }
+                "COUNT" => {
+                    let value = usize::try_from(parse.next_int()?).map_err(|_| "ERR COUNT can't be negative")?;
+                    count = Some(value);
+                }
+                "MAXLEN" => {
+                    maxlen = usize::try_from(parse.next_int()?).map_err(|_| "ERR MAXLEN can't be negative")?;
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(LPos { key, element, rank, count, maxlen })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let found = db.lpos(&self.key, &self.element, self.rank, self.count.unwrap_or(1), self.maxlen);
+        let response = match (found, self.count) {
+            (Err(err), _) => Frame::Error(err.to_string()),
+            (Ok(indexes), Some(_)) => Frame::Array(indexes.into_iter().map(|i| Frame::Integer(i as i64)).collect()),
+            (Ok(indexes), None) => indexes.first().map_or(Frame::Null, |&i| Frame::Integer(i as i64)),
+        };
+        Ok(response)
+    }
+}
+
+/// `SORT key [ALPHA] [ASC|DESC] [LIMIT offset count]` over a list, set or
+/// sorted set.
+///
+/// Sorting by the values of other keys with `BY` and `GET`, and storing the
+/// result with `STORE`, aren't supported.
+#[derive(Debug, Clone)]
+pub struct Sort {
+    key: Bytes,
+    alpha: bool,
+    desc: bool,
+    limit: Option<(i64, i64)>,
+}
+
+impl Sort {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Sort> {
+        let key = parse.next_bytes()?;
+        let mut sort = Sort { key, alpha: false, desc: false, limit: None };
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "ALPHA" => sort.alpha = true,
+                "ASC" => sort.desc = false,
+                "DESC" => sort.desc = true,
+                "LIMIT" => sort.limit = Some((parse.next_int()?, parse.next_int()?)),
+                "BY" | "GET" | "STORE" => {
+                    return Err(format!("ERR SORT {} is not supported", option.to_uppercase()).into());
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(sort)
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let mut elements = match db.sortable_elements(&self.key) {
+            Ok(elements) => elements,
+            Err(err) => return Ok(Frame::Error(err.to_string())),
+        };
+        if self.alpha {
+            elements.sort();
+        } else {
+            let score = |element: &Bytes| -> Option<f64> {
+                let score: f64 = std::str::from_utf8(element).ok()?.trim().parse().ok()?;
+                (!score.is_nan()).then_some(score)
+            };
+            let scores: Option<Vec<f64>> = elements.iter().map(score).collect();
+            let Some(scores) = scores else {
+                return Ok(Frame::Error("ERR One or more scores can't be converted into double".into()));
+            };
+            // Equal scores fall back to comparing the elements, so the order
+            // is the same every time
+            let mut scored: Vec<(f64, Bytes)> = scores.into_iter().zip(elements).collect();
+            scored.sort_by(|(a, x), (b, y)| a.total_cmp(b).then_with(|| x.cmp(y)));
+            elements = scored.into_iter().map(|(_, element)| element).collect();
+        }
+        if self.desc {
+            elements.reverse();
+        }
+        if let Some((offset, count)) = self.limit {
+            let offset = usize::try_from(offset).unwrap_or(0);
+            let count = usize::try_from(count).unwrap_or(usize::MAX);
+            elements = elements.into_iter().skip(offset).take(count).collect();
+        }
+        Ok(Frame::Array(elements.into_iter().map(Frame::Bulk).collect()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SAdd {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl SAdd {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SAdd> {
+        let key = parse.next_bytes()?;
+        let members = parse_one_or_more(parse)?;
+        Ok(SAdd { key, members })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.sadd(self.key, self.members)))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SMembers {
+    key: Bytes,
+}
+
+impl SMembers {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SMembers> {
+        let key = parse.next_bytes()?;
+        Ok(SMembers { key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = db.read_value(&self.key, |value| match value {
+            DataType::Set(set) => Frame::Array(set.iter().map(|m| Frame::Bulk(m.clone())).collect()),
+            _ => Frame::Error(WrongType.to_string()),
+        });
+        Ok(response.unwrap_or(Frame::Array(vec![])))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SRem {
+    key: Bytes,
+    members: Vec<Bytes>,
+}
+
+impl SRem {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SRem> {
         let key = parse.next_bytes()?;
-        let mut values = Vec::new();
-        while let Ok(val) = parse.next_bytes() {
-            values.push(val);
-        }
-        Ok(RPush { key, values })
+        let members = parse_one_or_more(parse)?;
+        Ok(SRem { key, members })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let mut len = 0;
-        for val in self.values {
-            len = db.rpush(self.key.clone(), val);
-        }
-        dst.write_frame(&Frame::Integer(len as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.srem(&self.key, &self.members)))
+    }
+}
+
+/// Parse the optional integer `count` argument of SPOP and the random pick
+/// commands. A negative one asks for that many picks with repeats, which
+/// are limited to `MAX_RANDOM_REPEATS`.
+fn parse_optional_count(parse: &mut Parse) -> crate::Result<Option<i64>> {
+    let Ok(count) = parse.next_bytes() else {
+        return Ok(None);
+    };
+    let count = parse_int(&count)?;
+    if count < 0 && count.unsigned_abs() > MAX_RANDOM_REPEATS {
+        return Err("ERR value is out of range".into());
     }
+    Ok(Some(count))
 }
 
 #[derive(Debug, Clone)]
-pub struct LPop {
+pub struct SPop {
     key: Bytes,
+    count: Option<i64>,
 }
 
-impl LPop {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LPop> {
+impl SPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SPop> {
         let key = parse.next_bytes()?;
-        Ok(LPop { key })
+        let count = parse_optional_count(parse)?;
+        Ok(SPop { key, count })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        match db.lpop(&self.key) {
-            Some(val) => dst.write_frame(&Frame::Bulk(val)).await?,
-            None => dst.write_frame(&Frame::Null).await?,
-        }
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let (response, _, _) = self.apply_popped(db).await?;
+        Ok(response)
+    }
+
+    /// Like `apply`, but also returns the key and the members that were
+    /// popped, so the random choice can be recorded.
+    pub(crate) async fn apply_popped(self, db: &Db) -> crate::Result<(Frame, Bytes, Vec<Bytes>)> {
+        let (response, popped) = match self.count {
+            Some(count) if count < 0 => {
+                (Frame::Error(parse_num::NOT_POSITIVE.into()), Vec::new())
+            }
+            Some(count) => match db.spop(&self.key, count as usize) {
+                Ok(members) => (Frame::Array(members.iter().cloned().map(Frame::Bulk).collect()), members),
+                Err(err) => (Frame::Error(err.to_string()), Vec::new()),
+            },
+            None => match db.spop(&self.key, 1) {
+                Ok(members) => (members.first().cloned().map(Frame::Bulk).unwrap_or(Frame::Null), members),
+                Err(err) => (Frame::Error(err.to_string()), Vec::new()),
+            },
+        };
+        Ok((response, self.key, popped))
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct RPop {
+pub struct SRandMember {
     key: Bytes,
+    count: Option<i64>,
 }
 
-impl RPop {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<RPop> {
+impl SRandMember {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SRandMember> {
         let key = parse.next_bytes()?;
-        Ok(RPop { key })
+        let count = parse_optional_count(parse)?;
+        Ok(SRandMember { key, count })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        match db.rpop(&self.key) {
-            Some(val) => dst.write_frame(&Frame::Bulk(val)).await?,
-            None => dst.write_frame(&Frame::Null).await?,
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match self.count {
+            Some(count) => match db.srandmember(&self.key, count) {
+                Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+            None => match db.srandmember(&self.key, 1) {
+                Ok(mut members) => members.pop().map(Frame::Bulk).unwrap_or(Frame::Null),
+                Err(err) => Frame::Error(err.to_string()),
+            },
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SMove {
+    source: Bytes,
+    destination: Bytes,
+    member: Bytes,
+}
+
+impl SMove {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SMove> {
+        let source = parse.next_bytes()?;
+        let destination = parse.next_bytes()?;
+        let member = parse.next_bytes()?;
+        Ok(SMove { source, destination, member })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.smove(&self.source, self.destination, self.member) {
+            Ok(moved) => Frame::Integer(moved as i64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// Parse the `key [key ...]` arguments shared by the set algebra commands.
+fn parse_set_keys(parse: &mut Parse) -> crate::Result<Vec<Bytes>> {
+    let mut keys = vec![parse.next_bytes()?];
+    while let Ok(key) = parse.next_bytes() {
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+/// Run a set algebra operation and reply with the resulting members.
+fn set_op_reply(op: SetOp, keys: &[Bytes], db: &Db) -> Frame {
+    match db.set_op(op, keys) {
+        Ok(members) => Frame::Array(members.into_iter().map(Frame::Bulk).collect()),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SInter {
+    keys: Vec<Bytes>,
+}
+
+impl SInter {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SInter> {
+        Ok(SInter { keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_reply(SetOp::Inter, &self.keys, db))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SUnion {
+    keys: Vec<Bytes>,
+}
+
+impl SUnion {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SUnion> {
+        Ok(SUnion { keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_reply(SetOp::Union, &self.keys, db))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SDiff {
+    keys: Vec<Bytes>,
+}
+
+impl SDiff {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SDiff> {
+        Ok(SDiff { keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_reply(SetOp::Diff, &self.keys, db))
+    }
+}
+
+/// Run a set algebra operation, store the result at `dest` and reply with
+/// its cardinality.
+fn set_op_store_reply(op: SetOp, dest: Bytes, keys: &[Bytes], db: &Db) -> Frame {
+    match db.set_op_store(op, dest, keys) {
+        Ok(len) => Frame::Integer(len as i64),
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+
+/// `SINTERCARD numkeys key [key ...] [LIMIT limit]`, which counts the
+/// members the sets have in common without building their intersection.
+#[derive(Debug, Clone)]
+pub struct SInterCard {
+    keys: Vec<Bytes>,
+    // Zero counts every common member
+    limit: usize,
+}
+
+impl SInterCard {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SInterCard> {
+        let keys = parse_numkeys(parse)?;
+        let mut limit = 0;
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "LIMIT" => {
+                    limit = usize::try_from(parse.next_int()?).map_err(|_| "ERR LIMIT can't be negative")?;
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
         }
-        Ok(())
+        Ok(SInterCard { keys, limit })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(count_reply(db.set_inter_card(&self.keys, self.limit)))
+    }
+}
+
+/// Parse the `numkeys key [key ...]` arguments of commands like SINTERCARD,
+/// which count their keys up front.
+fn parse_numkeys(parse: &mut Parse) -> crate::Result<Vec<Bytes>> {
+    let numkeys = parse.next_int()?;
+    if numkeys <= 0 {
+        return Err("ERR numkeys should be greater than 0".into());
     }
+    if numkeys as usize > parse.remaining() {
+        return Err("ERR Number of keys can't be greater than number of args".into());
+    }
+    (0..numkeys).map(|_| parse.next_bytes()).collect()
 }
 
 #[derive(Debug, Clone)]
-pub struct LRange {
+pub struct SInterStore {
+    dest: Bytes,
+    keys: Vec<Bytes>,
+}
+
+impl SInterStore {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SInterStore> {
+        let dest = parse.next_bytes()?;
+        Ok(SInterStore { dest, keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_store_reply(SetOp::Inter, self.dest, &self.keys, db))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SUnionStore {
+    dest: Bytes,
+    keys: Vec<Bytes>,
+}
+
+impl SUnionStore {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SUnionStore> {
+        let dest = parse.next_bytes()?;
+        Ok(SUnionStore { dest, keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_store_reply(SetOp::Union, self.dest, &self.keys, db))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SDiffStore {
+    dest: Bytes,
+    keys: Vec<Bytes>,
+}
+
+impl SDiffStore {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SDiffStore> {
+        let dest = parse.next_bytes()?;
+        Ok(SDiffStore { dest, keys: parse_set_keys(parse)? })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(set_op_store_reply(SetOp::Diff, self.dest, &self.keys, db))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ZAdd {
     key: Bytes,
-    start: i64,
-    stop: i64,
+    flags: ZAddFlags,
+    // Reply with the number of members added or changed, not just added
+    ch: bool,
+    elements: Vec<(f64, Bytes)>,
 }
 
-impl LRange {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LRange> {
+impl ZAdd {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZAdd> {
         let key = parse.next_bytes()?;
-        let start = parse.next_int()?;
-        let stop = parse.next_int()?;
-        Ok(LRange { key, start, stop })
+        let mut flags = ZAddFlags::default();
+        let mut ch = false;
+        // Options come first, up to the first score
+        let mut first_score = None;
+        while let Ok(arg) = parse.next_string() {
+            match arg.to_uppercase().as_str() {
+                "NX" => flags.nx = true,
+                "XX" => flags.xx = true,
+                "GT" => flags.gt = true,
+                "LT" => flags.lt = true,
+                "CH" => ch = true,
+                _ => {
+                    first_score = Some(arg);
+                    break;
+                }
+            }
+        }
+        let Some(first_score) = first_score else {
+            return Err("ERR wrong number of arguments for 'zadd' command".into());
+        };
+        // The rest is strictly score and member pairs
+        if parse.remaining().is_multiple_of(2) {
+            return Err("ERR syntax error".into());
+        }
+        if flags.nx && flags.xx {
+            return Err("ERR XX and NX options at the same time are not compatible".into());
+        }
+        if [flags.nx, flags.gt, flags.lt].iter().filter(|&&set| set).count() > 1 {
+            return Err("ERR GT, LT, and/or NX options at the same time are not compatible".into());
+        }
+
+        let mut elements = vec![(parse_float(first_score.as_bytes())?, parse.next_bytes()?)];
+        while parse.remaining() > 0 {
+            let score = parse_float(&parse.next_bytes()?)?;
+            elements.push((score, parse.next_bytes()?));
+        }
+        Ok(ZAdd { key, flags, ch, elements })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let values = db.lrange(&self.key, self.start, self.stop);
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.zadd_members(self.key, self.elements, self.flags) {
+            Ok((added, changed)) if self.ch => Frame::Integer((added + changed) as i64),
+            Ok((added, _)) => Frame::Integer(added as i64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// `ZINCRBY key increment member`, which adds to a member's score, adding
+/// the member if it's missing, and replies with the new score.
+#[derive(Debug, Clone)]
+pub struct ZIncrBy {
+    key: Bytes,
+    increment: f64,
+    member: Bytes,
+}
+
+impl ZIncrBy {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZIncrBy> {
+        let key = parse.next_bytes()?;
+        let increment = parse_float(&parse.next_bytes()?)?;
+        let member = parse.next_bytes()?;
+        Ok(ZIncrBy { key, increment, member })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.zincrby(self.key, self.increment, self.member) {
+            Ok(score) => Frame::Double(score),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// `ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count]
+/// [WITHSCORES]`, which lists the members of a sorted set in a range.
+#[derive(Debug, Clone)]
+pub struct ZRange {
+    key: Bytes,
+    spec: ZRangeSpec,
+    with_scores: bool,
+}
+
+impl ZRange {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZRange> {
+        let key = parse.next_bytes()?;
+        let (spec, with_scores) = parse_zrange_spec(parse)?;
+        Ok(ZRange { key, spec, with_scores })
+    }
+
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        let values = match db.zrange_by(&self.key, &self.spec) {
+            Ok(values) => values,
+            Err(err) => return Ok(Frame::Error(err.to_string())),
+        };
+        // RESP3 pairs each member with its score, RESP2 flattens them
+        let pairs = self.with_scores && dst.protocol() == Protocol::Resp3;
         let mut frames = Vec::new();
-        for v in values {
-            frames.push(Frame::Bulk(v));
+        for (member, score) in values {
+            if pairs {
+                frames.push(Frame::Array(vec![Frame::Bulk(member), Frame::Double(score)]));
+            } else {
+                frames.push(Frame::Bulk(member));
+                if self.with_scores {
+                    frames.push(Frame::Double(score));
+                }
+            }
         }
-        dst.write_frame(&Frame::Array(frames)).await?;
-        Ok(())
+        Ok(Frame::Array(frames))
+    }
+}
+
+/// `ZRANGESTORE dst src start stop [BYSCORE|BYLEX] [REV] [LIMIT offset
+/// count]`, which stores the members ZRANGE would list, with their scores,
+/// as a new sorted set.
+#[derive(Debug, Clone)]
+pub struct ZRangeStore {
+    dest: Bytes,
+    src: Bytes,
+    spec: ZRangeSpec,
+}
+
+impl ZRangeStore {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZRangeStore> {
+        let dest = parse.next_bytes()?;
+        let src = parse.next_bytes()?;
+        let (spec, with_scores) = parse_zrange_spec(parse)?;
+        if with_scores {
+            return Err("ERR syntax error".into());
+        }
+        Ok(ZRangeStore { dest, src, spec })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.zrange_store(self.dest, &self.src, &self.spec) {
+            Ok(len) => Frame::Integer(len as i64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// Parse the range ZRANGE and ZRANGESTORE take, `start stop
+/// [BYSCORE|BYLEX] [REV] [LIMIT offset count]`, and whether `WITHSCORES`
+/// came with it.
+fn parse_zrange_spec(parse: &mut Parse) -> crate::Result<(ZRangeSpec, bool)> {
+    let start = parse.next_bytes()?;
+    let stop = parse.next_bytes()?;
+    let (mut by_score, mut by_lex, mut rev, mut limit, mut with_scores) = (false, false, false, None, false);
+    while let Ok(option) = parse.next_string() {
+        match option.to_uppercase().as_str() {
+            "BYSCORE" => by_score = true,
+            "BYLEX" => by_lex = true,
+            "REV" => rev = true,
+            "LIMIT" => limit = Some((parse.next_int()?, parse.next_int()?)),
+            "WITHSCORES" => with_scores = true,
+            _ => return Err("ERR syntax error".into()),
+        }
+    }
+
+    let by = match (by_score, by_lex) {
+        (true, true) => return Err("ERR syntax error".into()),
+        (true, false) => ZRangeBy::Score(parse_score_bound(&start)?, parse_score_bound(&stop)?),
+        (false, true) => {
+            if with_scores {
+                return Err("ERR syntax error, WITHSCORES not supported in combination with BYLEX".into());
+            }
+            ZRangeBy::Lex(parse_lex_bound(&start)?, parse_lex_bound(&stop)?)
+        }
+        (false, false) => {
+            if limit.is_some() {
+                return Err("ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX".into());
+            }
+            ZRangeBy::Rank(parse_int(&start)?, parse_int(&stop)?)
+        }
+    };
+    Ok((ZRangeSpec { by, rev, limit }, with_scores))
+}
+
+/// Parse one end of a score range: a score, which `(` before it leaves out
+/// of the range.
+fn parse_score_bound(arg: &Bytes) -> crate::Result<ScoreBound> {
+    let (score, exclusive) = match arg.strip_prefix(b"(") {
+        Some(score) => (score, true),
+        None => (&arg[..], false),
+    };
+    match parse_float(score) {
+        Ok(score) => Ok(ScoreBound { score, exclusive }),
+        Err(_) => Err("ERR min or max is not a float".into()),
+    }
+}
+
+/// `ZRANGEBYLEX key min max [LIMIT offset count]`, which lists the members
+/// of a sorted set between two members, for sets whose scores are all equal.
+#[derive(Debug, Clone)]
+pub struct ZRangeByLex {
+    key: Bytes,
+    spec: ZRangeSpec,
+}
+
+impl ZRangeByLex {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZRangeByLex> {
+        let key = parse.next_bytes()?;
+        let min = parse_lex_bound(&parse.next_bytes()?)?;
+        let max = parse_lex_bound(&parse.next_bytes()?)?;
+        let mut limit = None;
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "LIMIT" => limit = Some((parse.next_int()?, parse.next_int()?)),
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(ZRangeByLex { key, spec: ZRangeSpec { by: ZRangeBy::Lex(min, max), rev: false, limit } })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.zrange_by(&self.key, &self.spec) {
+            Ok(members) => Frame::Array(members.into_iter().map(|(member, _)| Frame::Bulk(member)).collect()),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// `ZLEXCOUNT key min max`, which counts the members of a sorted set
+/// between two members, picked like ZRANGEBYLEX does.
+#[derive(Debug, Clone)]
+pub struct ZLexCount {
+    key: Bytes,
+    min: LexBound,
+    max: LexBound,
+}
+
+impl ZLexCount {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZLexCount> {
+        let key = parse.next_bytes()?;
+        let min = parse_lex_bound(&parse.next_bytes()?)?;
+        let max = parse_lex_bound(&parse.next_bytes()?)?;
+        Ok(ZLexCount { key, min, max })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.zlexcount(&self.key, &self.min, &self.max) {
+            Ok(count) => Frame::Integer(count as i64),
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
+    }
+}
+
+/// Parse one end of a lexicographic range: `-` or `+` for the start or end
+/// of the set, or a member prefixed with `[` to include it or `(` to
+/// exclude it.
+fn parse_lex_bound(arg: &Bytes) -> crate::Result<LexBound> {
+    match &arg[..] {
+        b"-" => Ok(LexBound::Min),
+        b"+" => Ok(LexBound::Max),
+        [b'[', ..] => Ok(LexBound::Inclusive(arg.slice(1..))),
+        [b'(', ..] => Ok(LexBound::Exclusive(arg.slice(1..))),
+        _ => Err("ERR min or max not valid string range item".into()),
+    }
+}
+
+/// `ZMPOP numkeys key [key ...] MIN|MAX [COUNT count]`, which pops members
+/// from the first of the sorted sets that isn't empty.
+#[derive(Debug, Clone)]
+pub struct ZMPop {
+    keys: Vec<Bytes>,
+    end: ZSetEnd,
+    count: usize,
+}
+
+impl ZMPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZMPop> {
+        let keys = parse_numkeys(parse)?;
+        let end = match parse.next_string()?.to_uppercase().as_str() {
+            "MIN" => ZSetEnd::Min,
+            "MAX" => ZSetEnd::Max,
+            _ => return Err("ERR syntax error".into()),
+        };
+        let count = parse_mpop_count(parse)?;
+        Ok(ZMPop { keys, end, count })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(zmpop_reply(db.zmpop(&self.keys, self.end, self.count)))
+    }
+}
+
+/// `BZMPOP timeout numkeys key [key ...] MIN|MAX [COUNT count]`, which waits
+/// for one of the sorted sets to be added to when they're all empty, and
+/// otherwise works like [`ZMPop`].
+///
+/// Like [`BLPop`], `apply` makes a single attempt, replying with a null
+/// array when there was nothing to pop.
+#[derive(Debug, Clone)]
+pub struct BZMPop {
+    keys: Vec<Bytes>,
+    end: ZSetEnd,
+    count: usize,
+    timeout: Option<Duration>,
+}
+
+impl BZMPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BZMPop> {
+        let timeout = parse_timeout(&parse.next_bytes()?)?;
+        let ZMPop { keys, end, count } = ZMPop::parse_frames(parse)?;
+        Ok(BZMPop { keys, end, count, timeout })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(self.apply_popped(db).await?.0)
+    }
+
+    /// Like `apply`, but also returns the ZMPOP request that pops the same
+    /// members without blocking, if any were popped.
+    pub(crate) async fn apply_popped(self, db: &Db) -> crate::Result<(Frame, Option<Frame>)> {
+        let popped = db.zmpop(&self.keys, self.end, self.count);
+        let end: &'static [u8] = match self.end {
+            ZSetEnd::Min => b"MIN",
+            ZSetEnd::Max => b"MAX",
+        };
+        let zmpop = match &popped {
+            Ok(Some((key, members))) => Some(mpop_frame(b"ZMPOP", key, end, members.len())),
+            _ => None,
+        };
+        Ok((zmpop_reply(popped), zmpop))
+    }
+}
+
+/// Reply with the key ZMPOP popped from and the members it popped, each
+/// paired with its score, or a null array when there was nothing to pop.
+fn zmpop_reply(popped: Result<Option<Popped<(Bytes, f64)>>, WrongType>) -> Frame {
+    mpop_reply(popped, |(member, score)| Frame::Array(vec![Frame::Bulk(member), Frame::Double(score)]))
+}
+
+/// `LMPOP numkeys key [key ...] LEFT|RIGHT [COUNT count]`, which pops
+/// elements from the first of the lists that isn't empty.
+#[derive(Debug, Clone)]
+pub struct LMPop {
+    keys: Vec<Bytes>,
+    end: ListEnd,
+    count: usize,
+}
+
+impl LMPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<LMPop> {
+        let keys = parse_numkeys(parse)?;
+        let end = parse_list_end(parse)?;
+        let count = parse_mpop_count(parse)?;
+        Ok(LMPop { keys, end, count })
     }
-}
 
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(mpop_reply(db.lmpop(&self.keys, self.end, self.count), Frame::Bulk))
+    }
+}
 
+/// `BLMPOP timeout numkeys key [key ...] LEFT|RIGHT [COUNT count]`, which
+/// waits for one of the lists to be pushed to when they're all empty, and
+/// otherwise works like [`LMPop`].
+///
+/// Like [`BLPop`], `apply` makes a single attempt, replying with a null
+/// array when there was nothing to pop.
 #[derive(Debug, Clone)]
-pub struct SAdd {
-    key: Bytes,
-    members: Vec<Bytes>,
+pub struct BLMPop {
+    keys: Vec<Bytes>,
+    end: ListEnd,
+    count: usize,
+    timeout: Option<Duration>,
 }
 
-impl SAdd {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SAdd> {
-        let key = parse.next_bytes()?;
-        let mut members = Vec::new();
-        while let Ok(val) = parse.next_bytes() {
-            members.push(val);
-        }
-        Ok(SAdd { key, members })
+impl BLMPop {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<BLMPop> {
+        let timeout = parse_timeout(&parse.next_bytes()?)?;
+        let LMPop { keys, end, count } = LMPop::parse_frames(parse)?;
+        Ok(BLMPop { keys, end, count, timeout })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let mut count = 0;
-        for member in self.members {
-             count += db.sadd(self.key.clone(), member);
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(self.apply_popped(db).await?.0)
+    }
+
+    /// Like `apply`, but also returns the LMPOP request that pops the same
+    /// elements without blocking, if any were popped.
+    pub(crate) async fn apply_popped(self, db: &Db) -> crate::Result<(Frame, Option<Frame>)> {
+        let popped = db.lmpop(&self.keys, self.end, self.count);
+        let end: &'static [u8] = match self.end {
+            ListEnd::Left => b"LEFT",
+            ListEnd::Right => b"RIGHT",
+        };
+        let lmpop = match &popped {
+            Ok(Some((key, values))) => Some(mpop_frame(b"LMPOP", key, end, values.len())),
+            _ => None,
+        };
+        Ok((mpop_reply(popped, Frame::Bulk), lmpop))
+    }
+}
+
+/// Parse the `[COUNT count]` option of LMPOP, ZMPOP and their blocking
+/// forms, which is 1 when missing.
+fn parse_mpop_count(parse: &mut Parse) -> crate::Result<usize> {
+    let mut count = 1;
+    while let Ok(option) = parse.next_string() {
+        match option.to_uppercase().as_str() {
+            "COUNT" => {
+                count = usize::try_from(parse.next_int()?)
+                    .ok()
+                    .filter(|&count| count > 0)
+                    .ok_or("ERR count should be greater than 0")?;
+            }
+            _ => return Err("ERR syntax error".into()),
         }
-        dst.write_frame(&Frame::Integer(count as i64)).await?;
-        Ok(())
     }
+    Ok(count)
+}
+
+/// Reply with the key a pop like LMPOP took values from and the values,
+/// each turned into a frame by `value`, or a null array when there was
+/// nothing to pop.
+fn mpop_reply<T>(popped: Result<Option<Popped<T>>, WrongType>, value: impl Fn(T) -> Frame) -> Frame {
+    match popped {
+        Ok(Some((key, values))) => Frame::Array(vec![Frame::Bulk(key), Frame::Array(values.into_iter().map(value).collect())]),
+        Ok(None) => Frame::NullArray,
+        Err(err) => Frame::Error(err.to_string()),
+    }
+}
+
+/// The `command 1 key end COUNT count` request popping `count` values from
+/// `key`, which is how a blocking pop like BLMPOP is logged.
+fn mpop_frame(command: &'static [u8], key: &Bytes, end: &'static [u8], count: usize) -> Frame {
+    Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(command)),
+        Frame::Bulk(Bytes::from_static(b"1")),
+        Frame::Bulk(key.clone()),
+        Frame::Bulk(Bytes::from_static(end)),
+        Frame::Bulk(Bytes::from_static(b"COUNT")),
+        Frame::Bulk(count.to_string().into()),
+    ])
 }
 
 #[derive(Debug, Clone)]
-pub struct SMembers {
+pub struct ZRandMember {
     key: Bytes,
+    count: Option<i64>,
+    with_scores: bool,
 }
 
-impl SMembers {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SMembers> {
+impl ZRandMember {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZRandMember> {
         let key = parse.next_bytes()?;
-        Ok(SMembers { key })
+        let (count, with_scores) = parse_random_picks(parse, "withscores")?;
+        Ok(ZRandMember { key, count, with_scores })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let members = db.smembers(&self.key);
-        let mut frames = Vec::new();
-        for m in members {
-            frames.push(Frame::Bulk(m));
-        }
-        dst.write_frame(&Frame::Array(frames)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<Frame> {
+        let response = match db.zrandmember(&self.key, self.count.unwrap_or(1), &mut rand::rng()) {
+            Ok(members) => {
+                let picks = members.into_iter().map(|(member, score)| (member, Frame::Double(score))).collect();
+                random_picks_reply(picks, self.count, self.with_scores, dst.protocol())
+            }
+            Err(err) => Frame::Error(err.to_string()),
+        };
+        Ok(response)
     }
 }
 
+
 #[derive(Debug, Clone)]
-pub struct SRem {
+pub struct JsonSet {
     key: Bytes,
-    members: Vec<Bytes>,
+    path: String,
+    value: String,
+    // Only set when the path is absent (NX) or present (XX)
+    nx: bool,
+    xx: bool,
 }
 
-impl SRem {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SRem> {
+impl JsonSet {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonSet> {
         let key = parse.next_bytes()?;
-        let mut members = Vec::new();
-        while let Ok(val) = parse.next_bytes() {
-            members.push(val);
+        let path = parse.next_string()?;
+        let value = parse.next_string()?;
+        let (mut nx, mut xx) = (false, false);
+        while let Ok(flag) = parse.next_string() {
+            match flag.to_uppercase().as_str() {
+                "NX" if !xx => nx = true,
+                "XX" if !nx => xx = true,
+                _ => return Err("ERR syntax error".into()),
+            }
         }
-        Ok(SRem { key, members })
+        Ok(JsonSet { key, path, value, nx, xx })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let mut count = 0;
-        for member in self.members {
-            count += db.srem(&self.key, &member);
-        }
-        dst.write_frame(&Frame::Integer(count as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match (json::parse_path(&self.path), serde_json::from_str(&self.value)) {
+            (Err(err), _) => Frame::Error(err.to_string()),
+            (_, Err(_)) => Frame::Error("ERR invalid json".into()),
+            (Ok(path), Ok(new)) => {
+                let result = db.update_json(self.key, |doc| {
+                    let exists = doc.as_ref().is_some_and(|doc| json::get(doc, &path).is_some());
+                    if (self.nx && exists) || (self.xx && !exists) {
+                        return (Frame::Null, false);
+                    }
+                    match doc {
+                        Some(doc) => match json::set(doc, &path, new) {
+                            Ok(()) => (Frame::Simple("OK".into()), true),
+                            Err(err) => (Frame::Error(err.to_string()), false),
+                        },
+                        None if path.is_empty() => {
+                            *doc = Some(new);
+                            (Frame::Simple("OK".into()), true)
+                        }
+                        None => (Frame::Error("ERR new objects must be created at the root".into()), false),
+                    }
+                });
+                result.unwrap_or_else(|err| Frame::Error(err.to_string()))
+            }
+        };
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ZAdd {
+pub struct JsonGet {
     key: Bytes,
-    elements: Vec<(f64, Bytes)>,
+    path: String,
 }
 
-impl ZAdd {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZAdd> {
+impl JsonGet {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonGet> {
         let key = parse.next_bytes()?;
-        let mut elements = Vec::new();
-        while let Ok(score_str) = parse.next_string() {
-            let score = score_str.parse::<f64>().map_err(|_| "ERR value is not a valid float")?;
-            let member = parse.next_bytes()?;
-            elements.push((score, member));
-        }
-        Ok(ZAdd { key, elements })
+        // The path is optional and defaults to the document root.
+        let path = parse.next_string().unwrap_or_else(|_| "$".to_string());
+        Ok(JsonGet { key, path })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let mut count = 0;
-        for (score, member) in self.elements {
-            count += db.zadd(self.key.clone(), score, member);
-        }
-        dst.write_frame(&Frame::Integer(count as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let path = match json::parse_path(&self.path) {
+            Ok(path) => path,
+            Err(err) => {
+                return Ok(Frame::Error(err.to_string()));
+            }
+        };
+        let response = db.read_value(&self.key, |value| match value {
+            DataType::Json(v) => match json::get(v, &path) {
+                Some(value) => Frame::Bulk(Bytes::from(value.to_string())),
+                None => Frame::Null,
+            },
+            _ => Frame::Error(WrongType.to_string()),
+        });
+        let response = response.unwrap_or(Frame::Null);
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct ZRange {
+pub struct JsonDel {
     key: Bytes,
-    start: i64,
-    stop: i64,
-    with_scores: bool,
+    path: String,
 }
 
-impl ZRange {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ZRange> {
+impl JsonDel {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonDel> {
         let key = parse.next_bytes()?;
-        let start = parse.next_int()?;
-        let stop = parse.next_int()?;
-        let mut with_scores = false;
-        if let Ok(arg) = parse.next_string() {
-            if arg.to_lowercase() == "withscores" {
-                with_scores = true;
-            }
-        }
-        Ok(ZRange { key, start, stop, with_scores })
+        let path = parse.next_string().unwrap_or_else(|_| "$".to_string());
+        Ok(JsonDel { key, path })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let values = db.zrange(&self.key, self.start, self.stop, self.with_scores);
-        let mut frames = Vec::new();
-        for (member, score) in values {
-            frames.push(Frame::Bulk(member));
-            if self.with_scores {
-                frames.push(Frame::Bulk(Bytes::from(score.to_string())));
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match json::parse_path(&self.path) {
+            Err(err) => Frame::Error(err.to_string()),
+            Ok(path) => {
+                let result = db.update_json(self.key, |doc| {
+                    let deleted = match doc {
+                        // Deleting the root deletes the key
+                        Some(_) if path.is_empty() => doc.take().is_some() as i64,
+                        Some(doc) => json::delete(doc, &path) as i64,
+                        None => 0,
+                    };
+                    (deleted, deleted > 0)
+                });
+                match result {
+                    Ok(deleted) => Frame::Integer(deleted),
+                    Err(err) => Frame::Error(err.to_string()),
+                }
             }
-        }
-        dst.write_frame(&Frame::Array(frames)).await?;
-        Ok(())
+        };
+        Ok(response)
     }
 }
 
-
 #[derive(Debug, Clone)]
-pub struct JsonSet {
+pub struct JsonType {
     key: Bytes,
     path: String,
-    value: String,
 }
 
-impl JsonSet {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonSet> {
+impl JsonType {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonType> {
         let key = parse.next_bytes()?;
-        let path = parse.next_string()?;
-        let value = parse.next_string()?;
-        Ok(JsonSet { key, path, value })
-    }
-     pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-         let _json_val = match db.get_value_clone(&self.key) {
-             Some(DataType::Json(v)) => v,
-             None => serde_json::Value::Null,
-             _ => {
-                  dst.write_frame(&Frame::Error("WRONGTYPE".into())).await?;
-                  return Ok(());
-             }
-         };
-         
-         if self.path == "$" {
-              if let Ok(v) = serde_json::from_str(&self.value) {
-                  db.set_value(self.key, DataType::Json(v));
-                  dst.write_frame(&Frame::Simple("OK".into())).await?;
-              } else {
-                   dst.write_frame(&Frame::Error("ERR invalid json".into())).await?;
-              }
-         } else {
-            dst.write_frame(&Frame::Error("ERR only root path $ supported for now".into())).await?;
-         }
-         Ok(())
+        let path = parse.next_string().unwrap_or_else(|_| "$".to_string());
+        Ok(JsonType { key, path })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let path = match json::parse_path(&self.path) {
+            Ok(path) => path,
+            Err(err) => {
+                return Ok(Frame::Error(err.to_string()));
+            }
+        };
+        let response = db.read_value(&self.key, |value| match value {
+            DataType::Json(v) => match json::get(v, &path) {
+                Some(value) => Frame::Bulk(Bytes::from_static(json::type_name(value).as_bytes())),
+                None => Frame::Null,
+            },
+            _ => Frame::Error(WrongType.to_string()),
+        });
+        let response = response.unwrap_or(Frame::Null);
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
-pub struct JsonGet {
+pub struct JsonArrAppend {
     key: Bytes,
     path: String,
+    values: Vec<String>,
 }
 
-impl JsonGet {
-    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonGet> {
+impl JsonArrAppend {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<JsonArrAppend> {
         let key = parse.next_bytes()?;
         let path = parse.next_string()?;
-        Ok(JsonGet { key, path })
+        let mut values = vec![parse.next_string()?];
+        while let Ok(value) = parse.next_string() {
+            values.push(value);
+        }
+        Ok(JsonArrAppend { key, path, values })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        if let Some(DataType::Json(v)) = db.get_value_clone(&self.key) {
-             if self.path == "$" {
-                 let s = v.to_string();
-                 dst.write_frame(&Frame::Bulk(Bytes::from(s))).await?;
-             } else {
-                 dst.write_frame(&Frame::Null).await?;
-             }
-        } else {
-             dst.write_frame(&Frame::Null).await?;
-        }
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let values: Result<Vec<serde_json::Value>, _> =
+            self.values.iter().map(|v| serde_json::from_str(v)).collect();
+        let response = match (json::parse_path(&self.path), values) {
+            (Err(err), _) => Frame::Error(err.to_string()),
+            (_, Err(_)) => Frame::Error("ERR invalid json".into()),
+            (Ok(path), Ok(values)) => {
+                let result = db.update_json(self.key, |doc| {
+                    let Some(doc) = doc else {
+                        return (Frame::Error("ERR no such key".into()), false);
+                    };
+                    match json::get_mut(doc, &path) {
+                        Some(serde_json::Value::Array(list)) => {
+                            list.extend(values);
+                            (Frame::Integer(list.len() as i64), true)
+                        }
+                        Some(_) => (Frame::Error("ERR path is not an array".into()), false),
+                        None => (Frame::Null, false),
+                    }
+                });
+                result.unwrap_or_else(|err| Frame::Error(err.to_string()))
+            }
+        };
+        Ok(response)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Keys {
-    pattern: String,
+    pattern: Bytes,
 }
 
 impl Keys {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Keys> {
-        let pattern = parse.next_string()?;
+        let pattern = parse.next_bytes()?;
         Ok(Keys { pattern })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
         // Warning: This is O(N) over all keys
-        let keys = db.keys();
-        let mut frames = Vec::new();
-        
-        // Simple pattern matching (only support "*")
-        let match_all = self.pattern == "*";
-        
-        for k in keys {
-            if match_all {
-                frames.push(Frame::Bulk(k));
-            } else {
-                // Convert bytes to string for pattern match (expensive but compatible)
-                // If invalid utf8, skip? or include? Redis matches bytes.
-                // Assuming keys are utf8 for pattern matching for now.
-                if let Ok(s) = String::from_utf8(k.to_vec()) {
-                    if s == self.pattern {
-                         frames.push(Frame::Bulk(k));
-                    }
-                }
-            }
-        }
-        dst.write_frame(&Frame::Array(frames)).await?;
-        Ok(())
+        let frames = db
+            .keys()
+            .into_iter()
+            .filter(|k| glob::matches(&self.pattern, k))
+            .map(Frame::Bulk)
+            .collect();
+        Ok(Frame::Array(frames))
     }
 }
 
@@ -900,18 +3798,68 @@ impl Type {
         Ok(Type { key })
     }
 
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        let t = match db.get_value_clone(&self.key) {
-            Some(DataType::String(_)) => "string",
-            Some(DataType::List(_)) => "list",
-            Some(DataType::Set(_)) => "set",
-            Some(DataType::Hash(_)) => "hash",
-            Some(DataType::ZSet(_)) => "zset",
-            Some(DataType::Json(_)) => "ReJSON-RL",
-            None => "none",
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let t = db.read_value(&self.key, DataType::type_name);
+        let t = t.unwrap_or("none");
+       Ok(Frame::Simple(t.into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    subcommand: String,
+    key: Bytes,
+}
+
+impl Object {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Object> {
+        let subcommand = parse.next_string()?;
+        let key = parse.next_bytes()?;
+        Ok(Object { subcommand, key })
+    }
+
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let lfu = db.config().maxmemory_policy == EvictionPolicy::AllKeysLfu;
+        let response = match self.subcommand.to_uppercase().as_str() {
+            "ENCODING" => match db.encoding(&self.key) {
+                Some(encoding) => Frame::Bulk(Bytes::from_static(encoding.as_bytes())),
+                None => Frame::Null,
+            },
+            // Each is only reported under the policy that evicts by it
+            "IDLETIME" => match db.idle_time(&self.key) {
+                Some(_) if lfu => Frame::Error(
+                    "ERR An LFU maxmemory policy is selected, idle time not tracked. \
+                     Please note that when switching between policies at runtime LRU and LFU data \
+                     will take some time to adjust."
+                        .into(),
+                ),
+                Some(idle) => Frame::Integer(idle.as_secs() as i64),
+                None => Frame::Null,
+            },
+            "FREQ" => match db.access_frequency(&self.key) {
+                Some(_) if !lfu => Frame::Error(
+                    "ERR An LFU maxmemory policy is not selected, access frequency not tracked. \
+                     Please note that when switching between policies at runtime LRU and LFU data \
+                     will take some time to adjust."
+                        .into(),
+                ),
+                Some(frequency) => Frame::Integer(frequency.into()),
+                None => Frame::Null,
+            },
+            // Values are never shared between keys
+            "REFCOUNT" => {
+                if db.exists(&self.key) {
+                    Frame::Integer(1)
+                } else {
+                    Frame::Null
+                }
+            }
+            _ => Frame::Error(format!(
+                "ERR unknown subcommand '{}'. Try OBJECT HELP.",
+                self.subcommand
+            )),
         };
-       dst.write_frame(&Frame::Simple(t.into())).await?;
-       Ok(())
+        Ok(response)
     }
 }
 
@@ -919,34 +3867,157 @@ impl Type {
 pub struct DbSize {}
 impl DbSize {
     pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<DbSize> { Ok(DbSize {}) }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        dst.write_frame(&Frame::Integer(db.len() as i64)).await?;
-        Ok(())
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        Ok(Frame::Integer(db.len() as i64))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RandomKey {}
+impl RandomKey {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<RandomKey> { Ok(RandomKey {}) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match db.random_key() {
+            Some(key) => Frame::Bulk(key),
+            None => Frame::Null,
+        };
+        Ok(response)
     }
 }
 
+/// `FLUSHDB [ASYNC|SYNC]`, which deletes every key in the selected
+/// database. With `ASYNC` the values are freed in the background.
 #[derive(Debug, Clone)]
-pub struct FlushDb {}
+pub struct FlushDb { lazy: bool }
 impl FlushDb {
-    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<FlushDb> { Ok(FlushDb {}) }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-        db.clear();
-        dst.write_frame(&Frame::Simple("OK".into())).await?;
-        Ok(())
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<FlushDb> { Ok(FlushDb { lazy: parse_flush_mode(parse)? }) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        free_flushed(db.clear(), self.lazy);
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+/// `FLUSHALL [ASYNC|SYNC]`, which deletes every key in every database.
+#[derive(Debug, Clone)]
+pub struct FlushAll { lazy: bool }
+impl FlushAll {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<FlushAll> { Ok(FlushAll { lazy: parse_flush_mode(parse)? }) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        free_flushed(db.clear_all(), self.lazy);
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+/// `SWAPDB index1 index2`, which swaps the contents of two databases.
+#[derive(Debug, Clone)]
+pub struct SwapDb { a: i64, b: i64 }
+impl SwapDb {
+    pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<SwapDb> {
+        Ok(SwapDb { a: parse.next_int()?, b: parse.next_int()? })
+    }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let index = |i: i64| usize::try_from(i).ok().filter(|&i| i < db.database_count());
+        let response = match (index(self.a), index(self.b)) {
+            (Some(a), Some(b)) => {
+                db.swap_databases(a, b);
+                Frame::Simple("OK".into())
+            }
+            _ => Frame::Error("ERR DB index is out of range".into()),
+        };
+        Ok(response)
+    }
+}
+
+/// Parse the optional `ASYNC` or `SYNC` argument of FLUSHDB and FLUSHALL,
+/// returning whether it's `ASYNC`.
+fn parse_flush_mode(parse: &mut Parse) -> crate::Result<bool> {
+    let lazy = match parse.next_string() {
+        Ok(mode) if mode.eq_ignore_ascii_case("async") => true,
+        Ok(mode) if mode.eq_ignore_ascii_case("sync") => false,
+        Ok(_) => return Err("ERR syntax error".into()),
+        Err(_) => false,
+    };
+    if parse.remaining() > 0 {
+        return Err("ERR syntax error".into());
+    }
+    Ok(lazy)
+}
+
+/// Free what a flush removed, on a blocking thread when `lazy` so the reply
+/// doesn't wait for it.
+fn free_flushed(flushed: Flushed, lazy: bool) {
+    if lazy {
+        tokio::task::spawn_blocking(move || drop(flushed));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Save {}
+impl Save {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Save> { Ok(Save {}) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match start_save(db) {
+            Err(response) => response,
+            Ok(path) => {
+                // Written off the async workers, but the reply waits for it
+                let saver = db.clone();
+                let result = tokio::task::spawn_blocking(move || saver.snapshot_to(path)).await?;
+                db.snapshots().finish();
+                match result {
+                    Ok(()) => Frame::Simple("OK".into()),
+                    Err(err) => Frame::Error(format!("ERR {}", err)),
+                }
+            }
+        };
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BgSave {}
+impl BgSave {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<BgSave> { Ok(BgSave {}) }
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+        let response = match start_save(db) {
+            Err(response) => response,
+            Ok(path) => {
+                let saver = db.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = saver.snapshot_to(path) {
+                        tracing::error!(cause = ?err, "background save failed");
+                    }
+                    saver.snapshots().finish();
+                });
+                Frame::Simple("Background saving started".into())
+            }
+        };
+        Ok(response)
+    }
+}
+
+/// Claim the snapshot file for a save, or return the error reply if there
+/// is none or another save is using it.
+fn start_save(db: &Db) -> Result<std::path::PathBuf, Frame> {
+    let Some(path) = db.snapshots().path() else {
+        return Err(Frame::Error("ERR no snapshot file is configured".into()));
+    };
+    if !db.snapshots().try_start() {
+        return Err(Frame::Error("ERR Background save already in progress".into()));
     }
+    Ok(path.to_path_buf())
 }
 
 #[derive(Debug, Clone)]
 pub struct Ttl { key: Bytes }
 impl Ttl {
      pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Ttl> { Ok(Ttl { key: parse.next_bytes()? }) }
-     pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-         if db.exists(&self.key) {
-             dst.write_frame(&Frame::Integer(-1)).await?; // No expiry support yet
-         } else {
-             dst.write_frame(&Frame::Integer(-2)).await?;
-         }
-         Ok(())
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         // Rounded to the nearest second, like Redis
+         let ttl = match remaining_millis(db, &self.key) {
+             ms if ms < 0 => ms,
+             ms => (ms + 500) / 1000,
+         };
+         Ok(Frame::Integer(ttl))
      }
 }
 
@@ -954,49 +4025,144 @@ impl Ttl {
 pub struct Pttl { key: Bytes }
 impl Pttl {
      pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Pttl> { Ok(Pttl { key: parse.next_bytes()? }) }
-     pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-         if db.exists(&self.key) {
-             dst.write_frame(&Frame::Integer(-1)).await?; 
-         } else {
-             dst.write_frame(&Frame::Integer(-2)).await?;
-         }
-         Ok(())
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         Ok(Frame::Integer(remaining_millis(db, &self.key)))
+     }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpireAt { key: Bytes, seconds: i64 }
+impl ExpireAt {
+     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ExpireAt> {
+         Ok(ExpireAt { key: parse.next_bytes()?, seconds: parse.next_int()? })
      }
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         let Some(deadline) = self.seconds.checked_mul(1000) else {
+             return Ok(Frame::Error("ERR invalid expire time in 'expireat' command".into()));
+         };
+         Ok(Frame::Integer(db.expire_at(&self.key, deadline) as i64))
+     }
+}
+
+#[derive(Debug, Clone)]
+pub struct PexpireAt { key: Bytes, millis: i64 }
+impl PexpireAt {
+     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<PexpireAt> {
+         Ok(PexpireAt { key: parse.next_bytes()?, millis: parse.next_int()? })
+     }
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         Ok(Frame::Integer(db.expire_at(&self.key, self.millis) as i64))
+     }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExpireTime { key: Bytes }
+impl ExpireTime {
+     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<ExpireTime> { Ok(ExpireTime { key: parse.next_bytes()? }) }
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         let time = match db.expiry(&self.key) {
+             Some(Some(deadline)) => (deadline / 1000) as i64,
+             Some(None) => -1,
+             None => -2,
+         };
+         Ok(Frame::Integer(time))
+     }
+}
+
+#[derive(Debug, Clone)]
+pub struct PexpireTime { key: Bytes }
+impl PexpireTime {
+     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<PexpireTime> { Ok(PexpireTime { key: parse.next_bytes()? }) }
+     pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         let time = match db.expiry(&self.key) {
+             Some(Some(deadline)) => deadline as i64,
+             Some(None) => -1,
+             None => -2,
+         };
+         Ok(Frame::Integer(time))
+     }
+}
+
+/// Milliseconds until `key` expires, -1 if it never does and -2 if it
+/// doesn't exist.
+fn remaining_millis(db: &Db, key: &[u8]) -> i64 {
+    match db.expiry(key) {
+        Some(Some(deadline)) => deadline.saturating_sub(clock::unix_millis()) as i64,
+        Some(None) => -1,
+        None => -2,
+    }
 }
 
 #[derive(Debug, Clone)]
-pub struct Scan { _cursor: u64 }
+pub struct Scan { cursor: u64, options: ScanOptions }
 impl Scan {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Scan> {
-        let _cursor = parse.next_string()?;
-        Ok(Scan { _cursor: 0 })
+        let cursor = parse_cursor(&parse.next_bytes()?)?;
+        let options = ScanOptions::parse(parse)?;
+        Ok(Scan { cursor, options })
     }
-    pub async fn apply(self, db: &Db, dst: &mut Connection) -> crate::Result<()> {
-         // Full Scan O(N) for now
-         let keys = db.keys();
+    pub async fn apply(self, db: &Db) -> crate::Result<Frame> {
+         // MATCH filters the page after it is fetched, so pages may come back short
+         let (cursor, keys) = db.scan(self.cursor, self.options.count);
          let mut frames = Vec::new();
          for k in keys {
-             frames.push(Frame::Bulk(k));
+             if self.options.matches(&k) {
+                 frames.push(Frame::Bulk(k));
+             }
          }
          let result = vec![
-            Frame::Bulk(Bytes::from("0")),
+            Frame::Bulk(Bytes::from(cursor.to_string())),
             Frame::Array(frames),
          ];
-         dst.write_frame(&Frame::Array(result)).await?;
-         Ok(())
+         Ok(Frame::Array(result))
+    }
+}
+
+/// Parse the cursor of the SCAN family.
+fn parse_cursor(cursor: &[u8]) -> crate::Result<u64> {
+    Ok(parse_uint(cursor).map_err(|_| "ERR invalid cursor")?)
+}
+
+/// The `MATCH` and `COUNT` options of the SCAN family.
+#[derive(Debug, Clone)]
+struct ScanOptions {
+    pattern: Option<Bytes>,
+    count: usize,
+}
+
+impl ScanOptions {
+    fn parse(parse: &mut Parse) -> crate::Result<ScanOptions> {
+        let mut options = ScanOptions { pattern: None, count: 10 };
+        while let Ok(option) = parse.next_string() {
+            match option.to_uppercase().as_str() {
+                "MATCH" => options.pattern = Some(parse.next_bytes()?),
+                "COUNT" => {
+                    options.count = usize::try_from(parse.next_int()?)
+                        .ok()
+                        .filter(|&count| count > 0)
+                        .ok_or("ERR syntax error")?;
+                }
+                _ => return Err("ERR syntax error".into()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn matches(&self, key: &[u8]) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| glob::matches(pattern, key))
     }
 }
 
 
 #[derive(Debug, Clone)]
-pub struct Select { _db: i64 }
+pub struct Select { pub index: i64 }
 impl Select {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Select> {
-        Ok(Select { _db: parse.next_int()? })
+        Ok(Select { index: parse.next_int()? })
     }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        dst.write_frame(&Frame::Simple("OK".into())).await?;
-        Ok(())
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // Should be intercepted by server.rs, which owns the selected database
+        Ok(Frame::Simple("OK".into()))
     }
 }
 
@@ -1017,10 +4183,9 @@ impl Unknown {
         &self.command_name
     }
 
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+    pub async fn apply(self) -> crate::Result<Frame> {
         let response = Frame::Error(format!("ERR unknown command '{}'", self.command_name));
-        dst.write_frame(&response).await?;
-        Ok(())
+        Ok(response)
     }
 }
 
@@ -1028,9 +4193,8 @@ impl Unknown {
 pub struct Multi {}
 impl Multi {
     pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Multi> { Ok(Multi {}) }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        dst.write_frame(&Frame::Simple("OK".into())).await?;
-        Ok(())
+    pub async fn apply(self) -> crate::Result<Frame> {
+        Ok(Frame::Simple("OK".into()))
     }
 }
 
@@ -1038,10 +4202,9 @@ impl Multi {
 pub struct Exec {}
 impl Exec {
     pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Exec> { Ok(Exec {}) }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
+    pub async fn apply(self) -> crate::Result<Frame> {
          // Should be intercepted by server.rs
-         dst.write_frame(&Frame::Error("ERR EXEC without MULTI".into())).await?;
-         Ok(())
+         Ok(Frame::Error("ERR EXEC without MULTI".into()))
     }
 }
 
@@ -1049,9 +4212,8 @@ impl Exec {
 pub struct Discard {}
 impl Discard {
     pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Discard> { Ok(Discard {}) }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        dst.write_frame(&Frame::Error("ERR DISCARD without MULTI".into())).await?;
-        Ok(())
+    pub async fn apply(self) -> crate::Result<Frame> {
+        Ok(Frame::Error("ERR DISCARD without MULTI".into()))
     }
 }
 
@@ -1060,23 +4222,52 @@ pub struct Watch { pub match_keys: Vec<Bytes> }
 impl Watch {
     pub(crate) fn parse_frames(parse: &mut Parse) -> crate::Result<Watch> {
         let mut match_keys = Vec::new();
-        loop {
-            match parse.next_bytes() {
-                Ok(s) => match_keys.push(s),
-                Err(_) => break, // End of args
-            }
+        while let Ok(s) = parse.next_bytes() {
+            match_keys.push(s);
         }
         Ok(Watch { match_keys })
     }
-    pub async fn apply(self, dst: &mut Connection) -> crate::Result<()> {
-        dst.write_frame(&Frame::Simple("OK".into())).await?;
-        Ok(())
+    pub async fn apply(self) -> crate::Result<Frame> {
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Unwatch {}
+impl Unwatch {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Unwatch> { Ok(Unwatch {}) }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // server.rs forgets the watched keys, nothing else to do
+        Ok(Frame::Simple("OK".into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reset {}
+impl Reset {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Reset> { Ok(Reset {}) }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // server.rs resets the connection state, nothing else to do
+        Ok(Frame::Simple("RESET".into()))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Monitor {}
+impl Monitor {
+    pub(crate) fn parse_frames(_parse: &mut Parse) -> crate::Result<Monitor> { Ok(Monitor {}) }
+    pub async fn apply(self) -> crate::Result<Frame> {
+        // server.rs switches the connection to monitor mode, so this is
+        // only reached inside MULTI
+        Ok(Frame::Error("ERR Command not allowed inside a transaction".into()))
     }
 }
 
 /// Utility for extracting data from a `Frame` array.
 pub(crate) struct Parse {
     parts: std::vec::IntoIter<Frame>,
+    // Lowercased name of the command being parsed, once it has been read.
+    command: Option<String>,
 }
 
 impl Parse {
@@ -1088,11 +4279,28 @@ impl Parse {
 
         Ok(Parse {
             parts: array.into_iter(),
+            command: None,
         })
     }
 
+    /// Record the command being parsed, so running out of arguments or
+    /// having some left over reports the canonical arity error for it.
+    pub(crate) fn set_command(&mut self, name: &str) {
+        self.command = Some(name.to_string());
+    }
+
+    fn arity_error(&self, fallback: &str) -> crate::Error {
+        match &self.command {
+            Some(name) => format!("ERR wrong number of arguments for '{}' command", name).into(),
+            None => fallback.into(),
+        }
+    }
+
     pub(crate) fn next(&mut self) -> crate::Result<Frame> {
-        self.parts.next().ok_or_else(|| "protocol error; end of stream".into())
+        match self.parts.next() {
+            Some(frame) => Ok(frame),
+            None => Err(self.arity_error("protocol error; end of stream")),
+        }
     }
 
     pub(crate) fn next_string(&mut self) -> crate::Result<String> {
@@ -1114,23 +4322,24 @@ impl Parse {
     }
 
     pub(crate) fn next_int(&mut self) -> crate::Result<i64> {
-        use atoi::atoi;
-
-        const MSG: &str = "protocol error; invalid number";
-
         match self.next()? {
             Frame::Integer(v) => Ok(v),
-            Frame::Simple(data) => atoi::<i64>(data.as_bytes()).ok_or_else(|| MSG.into()),
-            Frame::Bulk(data) => atoi::<i64>(&data).ok_or_else(|| MSG.into()),
+            Frame::Simple(data) => Ok(parse_int(data.as_bytes())?),
+            Frame::Bulk(data) => Ok(parse_int(&data)?),
             frame => Err(format!("protocol error; expected int frame but got {:?}", frame).into()),
         }
     }
 
+    /// The number of arguments left to parse.
+    pub(crate) fn remaining(&self) -> usize {
+        self.parts.len()
+    }
+
     pub(crate) fn finish(&mut self) -> crate::Result<()> {
         if self.parts.next().is_none() {
             Ok(())
         } else {
-            Err("protocol error; expected end of frame".into())
+            Err(self.arity_error("protocol error; expected end of frame"))
         }
     }
 }
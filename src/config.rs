@@ -0,0 +1,125 @@
+use crate::acl::Acl;
+use crate::aof::AppendFsync;
+use crate::db::{EvictionPolicy, DEFAULT_DATABASES};
+use crate::notify::KeyspaceEvents;
+use crate::protocol::Limits;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings for a server run, passed to [`run_with_config`].
+///
+/// [`run_with_config`]: crate::run_with_config
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Address `main` binds the listener to.
+    pub bind: String,
+    /// Number of logical databases available to `SELECT`.
+    pub databases: usize,
+    /// Password of the default user. Without one, clients don't need to
+    /// authenticate.
+    pub password: Option<String>,
+    /// Named users and their passwords, in addition to the default user.
+    pub users: Vec<(String, String)>,
+    /// Connections beyond this many wait until an open one closes.
+    pub max_connections: usize,
+    /// Largest number of bytes a single request may take up.
+    pub max_frame_size: usize,
+    /// Longest bulk string a request may declare, and longest string value
+    /// commands like `SETRANGE` may build, in bytes.
+    pub max_bulk_len: usize,
+    /// Most arguments a request may declare.
+    pub max_array_len: usize,
+    /// Connections that send nothing for this long are closed. Zero keeps
+    /// them open forever.
+    pub idle_timeout: Duration,
+    /// File `SAVE` and `BGSAVE` write to, loaded at startup if it exists
+    /// and there is no append-only file.
+    pub snapshot_path: Option<PathBuf>,
+    /// Append-only file write commands are logged to, replayed at startup
+    /// if it exists. `None` turns logging off.
+    pub aof_path: Option<PathBuf>,
+    /// How often the append-only file is fsynced.
+    pub aof_fsync: AppendFsync,
+    /// Limit on the estimated memory taken up by keys and values, in bytes.
+    /// Zero means no limit.
+    pub maxmemory: usize,
+    /// How to stay under `maxmemory`.
+    pub maxmemory_policy: EvictionPolicy,
+    /// Keyspace notifications published as keys change.
+    pub notify_keyspace_events: KeyspaceEvents,
+    /// How often keys past their expiry are swept out in the background,
+    /// even if nobody looks them up. Zero leaves them to lazy expiry.
+    pub active_expire_interval: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "127.0.0.1:6379".to_string(),
+            databases: DEFAULT_DATABASES,
+            password: None,
+            users: Vec::new(),
+            max_connections: 10_000,
+            max_frame_size: 512 * 1024 * 1024,
+            max_bulk_len: Limits::default().max_bulk_len,
+            max_array_len: Limits::default().max_array_len,
+            idle_timeout: Duration::ZERO,
+            snapshot_path: None,
+            aof_path: None,
+            aof_fsync: AppendFsync::default(),
+            maxmemory: 0,
+            maxmemory_policy: EvictionPolicy::default(),
+            notify_keyspace_events: KeyspaceEvents::default(),
+            active_expire_interval: Duration::from_millis(100),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// The settings `CONFIG SET` can change later, as first configured.
+    pub fn runtime(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            maxmemory: self.maxmemory,
+            maxmemory_policy: self.maxmemory_policy,
+            timeout: self.idle_timeout,
+            notify_keyspace_events: self.notify_keyspace_events,
+        }
+    }
+
+    /// The limits on the sizes and lengths of requests.
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_bulk_len: self.max_bulk_len,
+            max_array_len: self.max_array_len,
+            max_frame_size: self.max_frame_size,
+            ..Limits::default()
+        }
+    }
+
+    /// The user table described by `password` and `users`.
+    pub fn acl(&self) -> Acl {
+        let mut acl = Acl::new();
+        acl.set_default_password(self.password.clone());
+        for (name, password) in &self.users {
+            acl.add_user(name.clone(), password.clone());
+        }
+        acl
+    }
+}
+
+/// The settings that can change while the server runs, with `CONFIG SET`.
+///
+/// Shared by every `Db` handle; see [`Db::config`].
+///
+/// [`Db::config`]: crate::Db::config
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuntimeConfig {
+    /// See [`ServerConfig::maxmemory`].
+    pub maxmemory: usize,
+    /// See [`ServerConfig::maxmemory_policy`].
+    pub maxmemory_policy: EvictionPolicy,
+    /// See [`ServerConfig::idle_timeout`].
+    pub timeout: Duration,
+    /// See [`ServerConfig::notify_keyspace_events`].
+    pub notify_keyspace_events: KeyspaceEvents,
+}
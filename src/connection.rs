@@ -1,10 +1,16 @@
-use crate::protocol::{self, Frame};
+use crate::clients::{ClientInfo, Clients};
+use crate::protocol::{self, Frame, Limits, Protocol};
 use async_recursion::async_recursion;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
+use std::fmt;
 use std::io::Cursor;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use std::sync::Arc;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf};
 use tokio::net::TcpStream;
+use tokio::sync::Notify;
 
 /// Send and receive `Frame` values from a remote peer.
 ///
@@ -20,26 +26,134 @@ use tokio::net::TcpStream;
 /// The contents of the write buffer are then written to the socket.
 #[derive(Debug)]
 pub struct Connection {
-    // The `TcpStream`. It is decorated with a `BufWriter`, which provides write
-    // level buffering. The `BufWriter` implementation provided by Tokio is
-    // sufficient for our needs.
-    stream: BufWriter<TcpStream>,
+    // The `TcpStream`, or a stand-in when there is no client. It is decorated
+    // with a `BufWriter`, which provides write level buffering. The
+    // `BufWriter` implementation provided by Tokio is sufficient for our
+    // needs.
+    stream: BufWriter<Box<dyn Stream>>,
 
     // The buffer for reading frames.
     buffer: BytesMut,
+
+    // The RESP version replies are encoded with, the user the client
+    // authenticated as and the rest of what CLIENT reports about it.
+    info: ClientInfo,
+
+    // The registry `info` is kept up to date in, once the server has
+    // registered the connection.
+    clients: Option<Arc<Clients>>,
+
+    // Largest size and lengths an incoming frame may declare. The size also
+    // bounds the bytes buffered while waiting for a complete frame.
+    limits: Limits,
 }
 
 impl Connection {
     /// Create a new `Connection`, backed by `socket`. Read and write buffers
     /// are initialized.
     pub fn new(socket: TcpStream) -> Connection {
+        let addr = socket.peer_addr().ok();
+        let mut connection = Connection::with_stream(Box::new(socket));
+        connection.info.addr = addr;
+        connection
+    }
+
+    /// Create a `Connection` with no peer, which reads nothing and discards
+    /// everything written to it. Used to run commands on behalf of the
+    /// server itself, like replaying the append-only file.
+    pub(crate) fn discarding() -> Connection {
+        Connection::with_stream(Box::new(Discard))
+    }
+
+    fn with_stream(stream: Box<dyn Stream>) -> Connection {
         Connection {
-            stream: BufWriter::new(socket),
+            stream: BufWriter::new(stream),
             // Default to a 16kb read buffer.
             buffer: BytesMut::with_capacity(16 * 1024),
+            info: ClientInfo::default(),
+            clients: None,
+            limits: Limits { max_frame_size: usize::MAX, ..Limits::default() },
+        }
+    }
+
+    /// The RESP version replies are encoded with. Starts as RESP2.
+    pub fn protocol(&self) -> Protocol {
+        self.info.protocol
+    }
+
+    /// Switch the RESP version used to encode subsequent replies.
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.info.protocol = protocol;
+        self.sync();
+    }
+
+    /// The user the client authenticated as, `None` until it has.
+    pub fn user(&self) -> Option<&str> {
+        self.info.user.as_deref()
+    }
+
+    /// Record the user the client authenticated as.
+    pub fn set_user(&mut self, user: impl Into<String>) {
+        self.info.user = Some(user.into());
+        self.sync();
+    }
+
+    /// Forget the user the client authenticated as.
+    pub fn clear_user(&mut self) {
+        self.info.user = None;
+        self.sync();
+    }
+
+    /// The id the server gave this connection, or 0 if it hasn't.
+    pub fn id(&self) -> u64 {
+        self.info.id
+    }
+
+    /// The name set with CLIENT SETNAME, if any.
+    pub fn name(&self) -> Option<&Bytes> {
+        self.info.name.as_ref()
+    }
+
+    /// Name the connection, or clear its name with `None`.
+    pub fn set_name(&mut self, name: Option<Bytes>) {
+        self.info.name = name;
+        self.sync();
+    }
+
+    /// Record the database the client has selected.
+    pub fn set_db(&mut self, db: usize) {
+        self.info.db = db;
+        self.sync();
+    }
+
+    /// What CLIENT INFO reports about the connection.
+    pub fn info(&self) -> &ClientInfo {
+        &self.info
+    }
+
+    /// Add the connection to `clients` as `id`, so other connections can
+    /// list it. Returns the signal it's sent when CLIENT KILL closes it.
+    /// It's removed again when the connection is dropped.
+    pub fn register(&mut self, clients: Arc<Clients>, id: u64) -> Arc<Notify> {
+        self.info.id = id;
+        let kill = clients.register(self.info.clone());
+        self.clients = Some(clients);
+        kill
+    }
+
+    /// Copy a change to `info` into the registry.
+    fn sync(&self) {
+        if let Some(clients) = &self.clients {
+            clients.update(self.info.clone());
         }
     }
 
+    /// Limit how large a single incoming frame may be, and the lengths it
+    /// may declare. The frame size is unlimited by default.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
     /// Read a single `Frame` value from the underlying stream.
     ///
     /// The function waits until it has retrieved enough data to parse a frame.
@@ -50,7 +164,9 @@ impl Connection {
     ///
     /// On success, the received frame is returned. If the `TcpStream`
     /// is closed in a way that doesn't break a frame in half, it returns
-    /// `None`. Otherwise, an error is returned.
+    /// `None`. Otherwise, an error is returned. Frames that break the size
+    /// limits give a `protocol::Error`, which the caller can report to the
+    /// peer before closing.
     pub async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
         loop {
             // Attempt to parse a frame from the buffered data. If enough data
@@ -59,15 +175,20 @@ impl Connection {
                 return Ok(Some(frame));
             }
 
+            if self.buffer.len() > self.limits.max_frame_size {
+                return Err(frame_too_large().into());
+            }
+
             // There is not enough buffered data to read a frame. Attempt to
             // read more data from the socket.
             //
             // On success, the number of bytes is returned. `0` indicates "end
             // of stream".
             
-            // OPTIMIZATION: Flush any pending writes before waiting for more data.
-            // This enables smart pipelining: we buffer responses while processing
-            // the read buffer, and only flush when we run dry.
+            // Flush the replies written so far before waiting for more data.
+            // While pipelined requests are still buffered they are answered
+            // without touching the socket, so a whole batch of replies goes
+            // out together.
             self.stream.flush().await?;
 
             if 0 == self.stream.read_buf(&mut self.buffer).await? {
@@ -101,12 +222,15 @@ impl Connection {
         // a single frame. This step is usually much faster than doing a full
         // parse of the frame, and allows us to skip allocating data structures
         // if the buffer doesn't contain a full frame yet.
-        match Frame::check(&mut buf) {
+        match Frame::check_with_limits(&mut buf, &self.limits) {
             Ok(_) => {
                 // The `check` function will have advanced the cursor until the
                 // end of the frame. Since the cursor had been eagerly advanced,
                 // we reset the position to 0 before calling `parse`.
                 let len = buf.position() as usize;
+                if len > self.limits.max_frame_size {
+                    return Err(frame_too_large().into());
+                }
 
                 // Reset the position to 0
                 buf.set_position(0);
@@ -137,32 +261,57 @@ impl Connection {
 
     /// Write a single `Frame` value to the underlying stream.
     ///
-    /// The `Frame` + End of Line is written to the socket.
+    /// The `Frame` + End of Line is written to the socket. RESP3-only frames
+    /// are downgraded to their RESP2 equivalent unless the connection speaks
+    /// RESP3, so commands only check the protocol when the reply's shape
+    /// differs between versions.
     #[async_recursion]
     pub async fn write_frame(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let resp3 = self.info.protocol == Protocol::Resp3;
         match frame {
-            Frame::Array(val) => {
-                // Encode the array prefix.
-                self.stream.write_u8(b'*').await?;
-
-                // Encode the length of the array.
-                self.write_decimal(val.len() as i64).await?;
+            Frame::Array(val) | Frame::Set(val) | Frame::Push(val) => {
+                // Encode the aggregate prefix and its length.
+                let prefix = match frame {
+                    Frame::Set(_) if resp3 => b'~',
+                    Frame::Push(_) if resp3 => b'>',
+                    _ => b'*',
+                };
+                self.write_header(prefix, val.len()).await?;
 
                 // Iterate and encode each entry in the array.
                 for entry in val {
                     self.write_frame(entry).await?;
                 }
             }
+            Frame::Map(entries) => {
+                // RESP2 has no map type, keys and values are sent as a flat array.
+                if resp3 {
+                    self.write_header(b'%', entries.len()).await?;
+                } else {
+                    self.write_header(b'*', entries.len() * 2).await?;
+                }
+
+                for (key, value) in entries {
+                    self.write_frame(key).await?;
+                    self.write_frame(value).await?;
+                }
+            }
             _ => self.write_value(frame).await?,
         }
 
-        // Ensure the encoded frame is written to the socket. The calls above
-        // are to the buffered stream and writes. Calling `flush` writes the
-        // remaining contents of the buffer to the socket.
-        // self.stream.flush().await <-- REMOVED
+        // The frame stays in the write buffer. `read_frame` flushes it once
+        // every pipelined request already received has been answered.
         Ok(())
     }
 
+    /// Flush buffered replies to the socket.
+    ///
+    /// `read_frame` flushes before waiting for more input, so this is only
+    /// needed when nothing is read afterwards.
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush().await
+    }
+
     /// Write a frame literal to the stream/
     async fn write_value(&mut self, frame: &Frame) -> std::io::Result<()> {
         match frame {
@@ -180,9 +329,43 @@ impl Connection {
                 self.stream.write_u8(b':').await?;
                 self.write_decimal(*val).await?;
             }
+            Frame::Null | Frame::NullArray if self.info.protocol == Protocol::Resp3 => {
+                self.stream.write_all(b"_\r\n").await?;
+            }
             Frame::Null => {
                 self.stream.write_all(b"$-1\r\n").await?;
             }
+            Frame::NullArray => {
+                self.stream.write_all(b"*-1\r\n").await?;
+            }
+            Frame::Boolean(val) if self.info.protocol == Protocol::Resp3 => {
+                self.stream.write_all(if *val { b"#t\r\n" } else { b"#f\r\n" }).await?;
+            }
+            Frame::Boolean(val) => {
+                self.stream.write_u8(b':').await?;
+                self.write_decimal(*val as i64).await?;
+            }
+            Frame::BigNumber(digits) => {
+                if self.info.protocol == Protocol::Resp3 {
+                    self.stream.write_u8(b'(').await?;
+                } else {
+                    self.stream.write_u8(b'$').await?;
+                    self.write_decimal(digits.len() as i64).await?;
+                }
+                self.stream.write_all(digits.as_bytes()).await?;
+                self.stream.write_all(b"\r\n").await?;
+            }
+            Frame::Double(val) => {
+                let repr = protocol::format_double(*val);
+                if self.info.protocol == Protocol::Resp3 {
+                    self.stream.write_u8(b',').await?;
+                } else {
+                    self.stream.write_u8(b'$').await?;
+                    self.write_decimal(repr.len() as i64).await?;
+                }
+                self.stream.write_all(repr.as_bytes()).await?;
+                self.stream.write_all(b"\r\n").await?;
+            }
             Frame::Bulk(val) => {
                 let len = val.len();
 
@@ -195,14 +378,15 @@ impl Connection {
             // recursive strategy. In general, async fns do not support
             // recursion. Mini-redis has not needed this capability, so we
             // simply error.
-            Frame::Array(_val) => unreachable!(),
+            Frame::Array(_) | Frame::Set(_) | Frame::Push(_) | Frame::Map(_) => unreachable!(),
         }
 
         Ok(())
     }
 
-    pub async fn start_array(&mut self, len: usize) -> std::io::Result<()> {
-        self.stream.write_u8(b'*').await?;
+    /// Write the type prefix and length of an aggregate frame.
+    async fn write_header(&mut self, prefix: u8, len: usize) -> std::io::Result<()> {
+        self.stream.write_u8(prefix).await?;
         self.write_decimal(len as i64).await?;
         Ok(())
     }
@@ -223,3 +407,45 @@ impl Connection {
         Ok(())
     }
 }
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(clients) = &self.clients {
+            clients.unregister(self.info.id);
+        }
+    }
+}
+
+fn frame_too_large() -> protocol::Error {
+    "protocol error; frame too large".into()
+}
+
+/// A byte stream a `Connection` can run over.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug> Stream for T {}
+
+/// Stream behind `Connection::discarding`: always at end of stream, and
+/// accepts every write.
+#[derive(Debug)]
+struct Discard;
+
+impl AsyncRead for Discard {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context<'_>, _: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Discard {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -1,11 +1,32 @@
 use bytes::Bytes;
 use std::collections::{HashSet, VecDeque};
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use serde_json;
-use std::hash::{Hash, Hasher, BuildHasher};
 use ahash::{AHashMap, RandomState};
-use std::sync::atomic::{AtomicU64, Ordering};
+use indexmap::IndexMap;
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
 use tokio::sync::RwLock as AsyncRwLock;
+use std::fmt;
+use crate::acl::Acl;
+use crate::aof::Aof;
+use crate::bits;
+use crate::clock;
+use crate::notify::{EventClass, Notifier};
+use crate::parse_num;
+use crate::config::RuntimeConfig;
+use crate::protocol::Limits;
+use crate::persistence::{self, SnapshotWriter, Snapshots};
+use crate::blocking::{Blocked, BlockedKeys};
+use crate::clients::Clients;
+use crate::pubsub::PubSub;
+use crate::replication::Replication;
+use crate::stats::ServerStats;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::ops::RangeInclusive;
+use std::time::Duration;
+use rand::Rng;
+use rand::seq::{IndexedRandom, IteratorRandom};
 
 /// Supported Redis data types.
 /// Keys and Fields are now Bytes (Zero-Copy).
@@ -13,145 +34,2183 @@ use tokio::sync::RwLock as AsyncRwLock;
 pub enum DataType {
     String(Bytes),
     List(VecDeque<Bytes>),
-    Set(HashSet<Bytes>),
+    Set(SetValue),
     Hash(AHashMap<Bytes, Bytes>),
     ZSet(AHashMap<Bytes, f64>), // Simplified ZSet
     Json(serde_json::Value),
 }
 
+impl DataType {
+    /// The name `TYPE` reports for this value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataType::String(_) => "string",
+            DataType::List(_) => "list",
+            DataType::Set(_) => "set",
+            DataType::Hash(_) => "hash",
+            DataType::ZSet(_) => "zset",
+            DataType::Json(_) => "ReJSON-RL",
+        }
+    }
+
+    /// Roughly how much work dropping the value takes, counted in the
+    /// allocations it owns, like Redis's lazyfree effort.
+    pub fn free_effort(&self) -> usize {
+        match self {
+            DataType::String(_) => 1,
+            DataType::List(list) => list.len(),
+            DataType::Set(set) => set.len(),
+            DataType::Hash(map) => map.len(),
+            DataType::ZSet(map) => map.len(),
+            DataType::Json(serde_json::Value::Array(items)) => items.len(),
+            DataType::Json(serde_json::Value::Object(map)) => map.len(),
+            DataType::Json(_) => 1,
+        }
+    }
+
+    /// The encoding Redis would report for this value in `OBJECT ENCODING`.
+    ///
+    /// Values are always stored the same way here; this mirrors the default
+    /// thresholds Redis uses to pick a compact encoding.
+    pub fn encoding(&self) -> &'static str {
+        const MAX_LISTPACK_ENTRIES: usize = 128;
+        const MAX_LISTPACK_VALUE: usize = 64;
+        const MAX_EMBSTR_LEN: usize = 44;
+
+        fn small<'a>(len: usize, mut items: impl Iterator<Item = &'a Bytes>) -> bool {
+            len <= MAX_LISTPACK_ENTRIES && items.all(|item| item.len() <= MAX_LISTPACK_VALUE)
+        }
+        match self {
+            DataType::String(b) if is_canonical_int(b) => "int",
+            DataType::String(b) if b.len() <= MAX_EMBSTR_LEN => "embstr",
+            DataType::String(_) => "raw",
+            DataType::List(list) if small(list.len(), list.iter()) => "listpack",
+            DataType::List(_) => "quicklist",
+            DataType::Set(set) => set.encoding().as_str(),
+            DataType::Hash(map) if small(map.len(), map.iter().flat_map(|(k, v)| [k, v])) => "listpack",
+            DataType::Hash(_) => "hashtable",
+            DataType::ZSet(zset) if small(zset.len(), zset.keys()) => "listpack",
+            DataType::ZSet(_) => "skiplist",
+            DataType::Json(_) => "raw",
+        }
+    }
+
+    /// Roughly how many bytes the value takes up, as counted against the
+    /// memory limit.
+    ///
+    /// Like Redis's `MEMORY USAGE`, collections are estimated from a sample
+    /// of their elements rather than walked in full, so this stays cheap
+    /// enough to run after every write.
+    pub fn estimated_size(&self) -> usize {
+        const BYTES: usize = std::mem::size_of::<Bytes>();
+        // Hash tables keep some spare capacity per entry
+        const HASH_ENTRY: usize = 16;
+
+        std::mem::size_of::<DataType>()
+            + match self {
+                DataType::String(b) => b.len(),
+                DataType::List(list) => sample_total(list.len(), list.iter().map(|item| BYTES + item.len())),
+                DataType::Set(set) => {
+                    sample_total(set.len(), set.iter().map(|member| HASH_ENTRY + BYTES + member.len()))
+                }
+                DataType::Hash(map) => sample_total(
+                    map.len(),
+                    map.iter().map(|(field, value)| HASH_ENTRY + 2 * BYTES + field.len() + value.len()),
+                ),
+                DataType::ZSet(zset) => {
+                    sample_total(zset.len(), zset.keys().map(|member| HASH_ENTRY + BYTES + 8 + member.len()))
+                }
+                DataType::Json(json) => json_size(json),
+            }
+    }
+}
+
+/// The value held by one of the collection variants of [`DataType`], which
+/// [`Db::with_typed_mut`] checks for and hands out.
+trait Collection {
+    /// The collection in `value`, or `None` if it holds another type.
+    fn of(value: &DataType) -> Option<&Self>;
+    fn of_mut(value: &mut DataType) -> Option<&mut Self>;
+    /// A new, empty value of this type.
+    fn empty() -> DataType;
+    fn is_empty(&self) -> bool;
+}
+
+impl Collection for VecDeque<Bytes> {
+    fn of(value: &DataType) -> Option<&Self> {
+        if let DataType::List(list) = value { Some(list) } else { None }
+    }
+    fn of_mut(value: &mut DataType) -> Option<&mut Self> {
+        if let DataType::List(list) = value { Some(list) } else { None }
+    }
+    fn empty() -> DataType {
+        DataType::List(VecDeque::new())
+    }
+    fn is_empty(&self) -> bool {
+        VecDeque::is_empty(self)
+    }
+}
+
+impl Collection for SetValue {
+    fn of(value: &DataType) -> Option<&Self> {
+        if let DataType::Set(set) = value { Some(set) } else { None }
+    }
+    fn of_mut(value: &mut DataType) -> Option<&mut Self> {
+        if let DataType::Set(set) = value { Some(set) } else { None }
+    }
+    fn empty() -> DataType {
+        DataType::Set(SetValue::new())
+    }
+    fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+}
+
+impl Collection for AHashMap<Bytes, Bytes> {
+    fn of(value: &DataType) -> Option<&Self> {
+        if let DataType::Hash(map) = value { Some(map) } else { None }
+    }
+    fn of_mut(value: &mut DataType) -> Option<&mut Self> {
+        if let DataType::Hash(map) = value { Some(map) } else { None }
+    }
+    fn empty() -> DataType {
+        DataType::Hash(AHashMap::new())
+    }
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+}
+
+impl Collection for AHashMap<Bytes, f64> {
+    fn of(value: &DataType) -> Option<&Self> {
+        if let DataType::ZSet(scores) = value { Some(scores) } else { None }
+    }
+    fn of_mut(value: &mut DataType) -> Option<&mut Self> {
+        if let DataType::ZSet(scores) = value { Some(scores) } else { None }
+    }
+    fn empty() -> DataType {
+        DataType::ZSet(AHashMap::new())
+    }
+    fn is_empty(&self) -> bool {
+        (**self).is_empty()
+    }
+}
+
+/// Elements of a collection measured by `estimated_size`.
+const SIZE_SAMPLES: usize = 5;
+
+/// Estimate the sum of `sizes`, which has one item per element of a
+/// collection of `len` elements, from its first few items.
+fn sample_total(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (count, total) = sizes.take(SIZE_SAMPLES).fold((0, 0), |(count, total), size| (count + 1, total + size));
+    (total * len).checked_div(count).unwrap_or(0)
+}
+
+/// `estimated_size` for a JSON document, sampling each array and object.
+fn json_size(value: &serde_json::Value) -> usize {
+    use serde_json::Value;
+
+    std::mem::size_of::<Value>()
+        + match value {
+            Value::String(s) => s.len(),
+            Value::Array(items) => sample_total(items.len(), items.iter().map(json_size)),
+            Value::Object(map) => sample_total(map.len(), map.iter().map(|(k, v)| k.len() + json_size(v))),
+            _ => 0,
+        }
+}
+
+/// Whether `b` is an integer in its canonical form, the only form Redis
+/// stores as an integer.
+fn is_canonical_int(b: &[u8]) -> bool {
+    parse_num::parse_int(b).is_ok()
+}
+
+/// The encodings a set moves through as it grows, mirroring Redis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetEncoding {
+    /// Only integers, at most 512 of them.
+    IntSet,
+    /// At most 128 members of at most 64 bytes each.
+    ListPack,
+    HashTable,
+}
+
+impl SetEncoding {
+    const MAX_INTSET_ENTRIES: usize = 512;
+    const MAX_LISTPACK_ENTRIES: usize = 128;
+    const MAX_LISTPACK_VALUE: usize = 64;
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SetEncoding::IntSet => "intset",
+            SetEncoding::ListPack => "listpack",
+            SetEncoding::HashTable => "hashtable",
+        }
+    }
+}
+
+/// The members of a set along with the encoding Redis would use for it.
+///
+/// Like in Redis, the encoding only ever grows: removing members never
+/// converts a set back to a more compact encoding. Read access goes through
+/// `Deref` to the underlying `HashSet`; mutations go through `insert` and
+/// `remove` so the encoding stays up to date.
+#[derive(Clone, Debug)]
+pub struct SetValue {
+    members: HashSet<Bytes>,
+    encoding: SetEncoding,
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SetValue {
+    pub fn new() -> SetValue {
+        SetValue { members: HashSet::new(), encoding: SetEncoding::IntSet }
+    }
+
+    pub fn encoding(&self) -> SetEncoding {
+        self.encoding
+    }
+
+    /// Add `member`, returning `true` if it wasn't already present.
+    pub fn insert(&mut self, member: Bytes) -> bool {
+        let is_int = is_canonical_int(&member);
+        let member_len = member.len();
+        if !self.members.insert(member) {
+            return false;
+        }
+
+        let len = self.members.len();
+        self.encoding = match self.encoding {
+            SetEncoding::IntSet if is_int && len <= SetEncoding::MAX_INTSET_ENTRIES => SetEncoding::IntSet,
+            SetEncoding::IntSet | SetEncoding::ListPack
+                if len <= SetEncoding::MAX_LISTPACK_ENTRIES && member_len <= SetEncoding::MAX_LISTPACK_VALUE =>
+            {
+                SetEncoding::ListPack
+            }
+            _ => SetEncoding::HashTable,
+        };
+        true
+    }
+
+    /// Remove `member`, returning `true` if it was present.
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        self.members.remove(member)
+    }
+}
+
+impl From<HashSet<Bytes>> for SetValue {
+    fn from(members: HashSet<Bytes>) -> SetValue {
+        let encoding = if members.len() <= SetEncoding::MAX_INTSET_ENTRIES
+            && members.iter().all(|m| is_canonical_int(m))
+        {
+            SetEncoding::IntSet
+        } else if members.len() <= SetEncoding::MAX_LISTPACK_ENTRIES
+            && members.iter().all(|m| m.len() <= SetEncoding::MAX_LISTPACK_VALUE)
+        {
+            SetEncoding::ListPack
+        } else {
+            SetEncoding::HashTable
+        };
+        SetValue { members, encoding }
+    }
+}
+
+impl std::ops::Deref for SetValue {
+    type Target = HashSet<Bytes>;
+
+    fn deref(&self) -> &HashSet<Bytes> {
+        &self.members
+    }
+}
+
+/// Error returned when an operation targets a key holding the wrong kind of value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "WRONGTYPE Operation against a key holding the wrong kind of value".fmt(fmt)
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+/// Error returned when a command's source and destination are the same key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SameObject;
+
+impl fmt::Display for SameObject {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "ERR source and destination objects are the same".fmt(fmt)
+    }
+}
+
+impl std::error::Error for SameObject {}
+
+/// Error returned when a command needs memory the limit doesn't leave room
+/// for, and the eviction policy can't free any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfMemory;
+
+impl fmt::Display for OutOfMemory {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        "OOM command not allowed when used memory > 'maxmemory'".fmt(fmt)
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// Error returned by commands that update a string in place, like INCR and
+/// APPEND.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StringError {
+    WrongType,
+    /// The string doesn't hold a 64-bit integer.
+    NotInteger,
+    /// The result doesn't fit in a 64-bit integer.
+    Overflow,
+    /// The string would grow past the longest value allowed.
+    TooLong,
+}
+
+impl From<WrongType> for StringError {
+    fn from(_: WrongType) -> StringError {
+        StringError::WrongType
+    }
+}
+
+impl fmt::Display for StringError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StringError::WrongType => WrongType.fmt(fmt),
+            StringError::NotInteger => "ERR value is not an integer or out of range".fmt(fmt),
+            StringError::Overflow => "ERR increment or decrement would overflow".fmt(fmt),
+            StringError::TooLong => "ERR string exceeds maximum allowed size (proto-max-bulk-len)".fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for StringError {}
+
+/// Error returned by ZINCRBY.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreError {
+    WrongType,
+    /// The new score would be NaN, as adding `-inf` to `inf` is.
+    NotANumber,
+}
+
+impl From<WrongType> for ScoreError {
+    fn from(_: WrongType) -> ScoreError {
+        ScoreError::WrongType
+    }
+}
+
+impl fmt::Display for ScoreError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScoreError::WrongType => WrongType.fmt(fmt),
+            ScoreError::NotANumber => "ERR resulting score is not a number (NaN)".fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for ScoreError {}
+
+/// Which keys are evicted once memory use goes over the limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evict nothing; commands that need more memory fail instead.
+    #[default]
+    NoEviction,
+    /// Evict the least recently used keys.
+    AllKeysLru,
+    /// Evict the least frequently used keys.
+    AllKeysLfu,
+    /// Evict random keys.
+    AllKeysRandom,
+    /// Evict the keys with an expiry that expire soonest.
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    /// The policy's name in Redis's `maxmemory-policy`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+
+    /// The policy called `name` in `maxmemory-policy`, ignoring case.
+    pub fn from_name(name: &str) -> Option<EvictionPolicy> {
+        [
+            EvictionPolicy::NoEviction,
+            EvictionPolicy::AllKeysLru,
+            EvictionPolicy::AllKeysLfu,
+            EvictionPolicy::AllKeysRandom,
+            EvictionPolicy::VolatileTtl,
+        ]
+        .into_iter()
+        .find(|policy| policy.as_str().eq_ignore_ascii_case(name))
+    }
+}
+
+/// Estimated memory use of all databases together.
+#[derive(Debug, Default)]
+struct Memory {
+    used: AtomicUsize,
+}
+
+/// Everything a flush removed from the databases, freed when dropped, which
+/// can take a while for a large database.
+pub struct Flushed {
+    // Only held to be dropped
+    _shards: Vec<IndexMap<Bytes, Entry, RandomState>>,
+}
+
+/// Set algebra operations combining several sets into one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// Members present in every set.
+    Inter,
+    /// Members present in any set.
+    Union,
+    /// Members of the first set not present in any later set.
+    Diff,
+}
+
+/// Which members ZADD may add, and which existing ones it may update.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZAddFlags {
+    /// Only add new members (`NX`).
+    pub nx: bool,
+    /// Only update existing members (`XX`).
+    pub xx: bool,
+    /// Only update a member to a greater score (`GT`).
+    pub gt: bool,
+    /// Only update a member to a lesser score (`LT`).
+    pub lt: bool,
+}
+
+/// One end of a lexicographic range of sorted set members, as ZRANGEBYLEX
+/// takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    /// Before every member (`-`).
+    Min,
+    /// After every member (`+`).
+    Max,
+    /// Up to and including this member (`[member`).
+    Inclusive(Bytes),
+    /// Up to but excluding this member (`(member`).
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    /// Whether `member` is past this bound, taken as a range's start.
+    fn is_below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= &bound[..],
+            LexBound::Exclusive(bound) => member > &bound[..],
+        }
+    }
+
+    /// Whether `member` is within this bound, taken as a range's end.
+    fn is_above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= &bound[..],
+            LexBound::Exclusive(bound) => member < &bound[..],
+        }
+    }
+}
+
+/// One end of a range of sorted set scores, as ZRANGE BYSCORE takes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    /// Whether the score itself is left out of the range (`(score`).
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    /// Whether `score` is past this bound, taken as a range's start.
+    fn is_below(&self, score: f64) -> bool {
+        if self.exclusive { self.score < score } else { self.score <= score }
+    }
+
+    /// Whether `score` is within this bound, taken as a range's end.
+    fn is_above(&self, score: f64) -> bool {
+        if self.exclusive { self.score > score } else { self.score >= score }
+    }
+}
+
+/// How a ZRANGE-style query picks the members of a sorted set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZRangeBy {
+    /// By rank, from the first index through the second, counting from the
+    /// end when negative.
+    Rank(i64, i64),
+    /// By score, between two bounds (`BYSCORE`).
+    Score(ScoreBound, ScoreBound),
+    /// By member, between two bounds (`BYLEX`), for sets whose scores are
+    /// all equal.
+    Lex(LexBound, LexBound),
+}
+
+/// The members of a sorted set a ZRANGE-style query picks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZRangeSpec {
+    pub by: ZRangeBy,
+    /// Walk the set from the highest member down (`REV`). Score and lex
+    /// bounds are then given highest first.
+    pub rev: bool,
+    /// How many of the picked members to skip, and how many to return
+    /// after that, all of them if negative (`LIMIT`).
+    pub limit: Option<(i64, i64)>,
+}
+
+/// One end of a sorted set, for commands like ZMPOP that take either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZSetEnd {
+    /// The members with the lowest scores.
+    Min,
+    /// The members with the highest scores.
+    Max,
+}
+
+/// The key a pop from several keys, like LMPOP or ZMPOP, took values from,
+/// and the values.
+pub type Popped<T> = (Bytes, Vec<T>);
+
+/// One end of a list, for commands like LMOVE that take either.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    /// The head, where LPUSH and LPOP work.
+    Left,
+    /// The tail, where RPUSH and RPOP work.
+    Right,
+}
+
+impl ListEnd {
+    fn pop(self, list: &mut VecDeque<Bytes>) -> Option<Bytes> {
+        match self {
+            ListEnd::Left => list.pop_front(),
+            ListEnd::Right => list.pop_back(),
+        }
+    }
+
+    fn push(self, list: &mut VecDeque<Bytes>, value: Bytes) {
+        match self {
+            ListEnd::Left => list.push_front(value),
+            ListEnd::Right => list.push_back(value),
+        }
+    }
+
+    /// The events publishing a pop and a push at this end.
+    fn events(self) -> (&'static str, &'static str) {
+        match self {
+            ListEnd::Left => ("lpop", "lpush"),
+            ListEnd::Right => ("rpop", "rpush"),
+        }
+    }
+}
+
+/// A stored value together with the tick it was last accessed at and how
+/// often it's accessed.
+struct Entry {
+    value: DataType,
+    accessed: AtomicU32,
+    // Logarithmic access counter, as Redis keeps for its LFU policies
+    frequency: AtomicU8,
+    // Estimated bytes taken up by the entry and its key
+    size: usize,
+}
+
+impl Entry {
+    /// A fresh entry, not yet measured.
+    fn new(value: DataType) -> Entry {
+        Entry { value, accessed: AtomicU32::new(clock::now()), frequency: AtomicU8::new(LFU_INIT), size: 0 }
+    }
+
+    fn touch(&self) {
+        let last = self.accessed.swap(clock::now(), Ordering::Relaxed);
+        let counter = decayed_frequency(self.frequency.load(Ordering::Relaxed), last);
+        // The busier a key already is, the less likely an access counts
+        let odds = f64::from(counter.saturating_sub(LFU_INIT)) * LFU_LOG_FACTOR + 1.0;
+        let counter = if counter < u8::MAX && rand::rng().random::<f64>() * odds < 1.0 { counter + 1 } else { counter };
+        self.frequency.store(counter, Ordering::Relaxed);
+    }
+
+    /// The access counter, decayed for the time since the last access.
+    fn frequency(&self) -> u8 {
+        decayed_frequency(self.frequency.load(Ordering::Relaxed), self.accessed.load(Ordering::Relaxed))
+    }
+}
+
+/// The access counter a new key starts with, so it isn't evicted before it
+/// has had a chance to be used.
+const LFU_INIT: u8 = 5;
+
+/// How quickly the access counter saturates; Redis's `lfu-log-factor`.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// Idle time that takes one off the access counter; Redis's
+/// `lfu-decay-time`.
+const LFU_DECAY_TIME: Duration = Duration::from_secs(60);
+
+/// `counter` less one for every `LFU_DECAY_TIME` since tick `last`.
+fn decayed_frequency(counter: u8, last: u32) -> u8 {
+    let periods = clock::since(last).as_secs() / LFU_DECAY_TIME.as_secs();
+    counter.saturating_sub(periods.min(u64::from(u8::MAX)) as u8)
+}
+
+/// One shard of a keyspace.
+///
+/// Mirrors the `HashMap` API the operations below rely on, stamping every
+/// key looked up through `get`/`get_mut` with the current clock tick. Keys,
+/// and the deadlines of those that expire, are kept in `IndexMap`s so a
+/// random one can be picked by position.
+///
+/// Keys past their expiry deadline are expired lazily: lookups treat them
+/// as missing, and lookups through `&mut self` remove them. Until then they
+/// still count towards `len`. `expire_sample` also removes them in the
+/// background, for keys that are never looked up again.
+///
+/// The estimated size of every entry is counted towards the shared memory
+/// use. A value changed through `get_mut` or `get_or_insert_with` is
+/// re-measured by the next call that modifies the shard, once the change is
+/// done.
+///
+/// Every call that modifies a key bumps its version if it is watched.
+/// `get_mut` and `get_or_insert_with` hand out a value that may be left as
+/// it is, so their callers bump the version once they've changed it.
+///
+/// Shards are plain `RwLock`s rather than a `DashMap`: SMOVE, RENAME and
+/// COPY lock two shards at once, and random keys are picked by position,
+/// neither of which `DashMap` supports.
+struct Shard {
+    entries: IndexMap<Bytes, Entry, RandomState>,
+    // Deadlines of the keys that expire, in Unix milliseconds
+    expires: IndexMap<Bytes, u64, RandomState>,
+    memory: Arc<Memory>,
+    // Estimated size of all entries, the shard's share of `memory.used`
+    size: usize,
+    // Key last handed out for modification, whose size may be stale
+    modified: Option<Bytes>,
+    // Keys clients WATCH, with their versions
+    watched: AHashMap<Bytes, WatchedVersion>,
+    // Publishes the expiry of keys, tagged with the shard's database
+    notifier: Arc<Notifier>,
+    db: usize,
+}
+
+/// The version of a watched key, shared by everyone watching it.
+struct WatchedVersion {
+    version: u64,
+    watchers: usize,
+}
+
+impl Shard {
+    fn new(memory: Arc<Memory>, notifier: Arc<Notifier>, db: usize) -> Shard {
+        Shard {
+            entries: IndexMap::default(),
+            expires: IndexMap::default(),
+            memory,
+            size: 0,
+            modified: None,
+            watched: AHashMap::new(),
+            notifier,
+            db,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<&DataType> {
+        if self.is_expired(key) {
+            return None;
+        }
+        let entry = self.entries.get(key)?;
+        entry.touch();
+        Some(&entry.value)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut DataType> {
+        self.settle();
+        self.remove_if_expired(key);
+        self.modified = Some(self.entries.get_key_value(key)?.0.clone());
+        let entry = self.entries.get_mut(key)?;
+        entry.touch();
+        Some(&mut entry.value)
+    }
+
+    /// Like `get_mut`, inserting the value returned by `default` first if
+    /// `key` is missing.
+    fn get_or_insert_with(&mut self, key: Bytes, default: impl FnOnce() -> DataType) -> &mut DataType {
+        self.settle();
+        self.remove_if_expired(&key);
+        self.modified = Some(key.clone());
+        let entry = self.entries.entry(key).or_insert_with(|| Entry::new(default()));
+        entry.touch();
+        &mut entry.value
+    }
+
+    /// Store `value` at `key` in place of its current value, keeping its
+    /// expiry, or as a new key if it's missing.
+    fn replace(&mut self, key: Bytes, value: DataType) {
+        self.bump_version(&key);
+        *self.get_or_insert_with(key, || DataType::String(Bytes::new())) = value;
+    }
+
+    /// Fail if `key` holds a value `is_type` rejects. Checking this before
+    /// `get_mut` keeps a failed write from counting as a modification.
+    fn check_type(&self, key: &[u8], is_type: fn(&DataType) -> bool) -> Result<(), WrongType> {
+        match self.get(key) {
+            Some(value) if !is_type(value) => Err(WrongType),
+            _ => Ok(()),
+        }
+    }
+
+    /// Like `get_mut` for a collection of type `T`, failing without counting
+    /// as a modification if `key` holds another type.
+    fn typed_mut<T: Collection>(&mut self, key: &[u8]) -> Result<Option<&mut T>, WrongType> {
+        self.check_type(key, |value| T::of(value).is_some())?;
+        Ok(self.get_mut(key).and_then(T::of_mut))
+    }
+
+    /// Like `typed_mut`, inserting an empty `T` first if `key` is missing.
+    fn typed_or_insert<T: Collection>(&mut self, key: Bytes) -> Result<&mut T, WrongType> {
+        self.check_type(&key, |value| T::of(value).is_some())?;
+        Ok(T::of_mut(self.get_or_insert_with(key, T::empty)).expect("type checked above"))
+    }
+
+    /// Store `value` at `key`, replacing any previous value and its expiry.
+    fn insert(&mut self, key: Bytes, value: DataType) {
+        self.settle();
+        self.bump_version(&key);
+        self.expires.swap_remove(&key);
+        let entry = Entry { size: entry_size(&key, &value), ..Entry::new(value) };
+        self.grow(entry.size);
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.shrink(old.size);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<DataType> {
+        self.settle();
+        self.remove_if_expired(key);
+        self.expires.swap_remove(key);
+        let entry = self.entries.swap_remove(key)?;
+        self.bump_version(key);
+        self.shrink(entry.size);
+        Some(entry.value)
+    }
+
+    /// Checks for `key` without counting as an access.
+    fn contains_key(&self, key: &[u8]) -> bool {
+        !self.is_expired(key) && self.entries.contains_key(key)
+    }
+
+    /// Like `get`, without counting as an access.
+    fn peek(&self, key: &[u8]) -> Option<&DataType> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    /// The tick `key` was last accessed at, without counting as an access.
+    fn accessed(&self, key: &[u8]) -> Option<u32> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.entries.get(key).map(|entry| entry.accessed.load(Ordering::Relaxed))
+    }
+
+    /// The access counter of `key`, without counting as an access.
+    fn frequency(&self, key: &[u8]) -> Option<u8> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.entries.get(key).map(Entry::frequency)
+    }
+
+    /// The deadline `key` expires at, if it exists and has one.
+    fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.expires.get(key).copied().filter(|&deadline| deadline > clock::unix_millis())
+    }
+
+    /// Make `key` expire at `deadline`, or never with `None`. Returns
+    /// `false` if the key doesn't exist.
+    fn set_expiry(&mut self, key: &[u8], deadline: Option<u64>) -> bool {
+        self.remove_if_expired(key);
+        let Some((key, _)) = self.entries.get_key_value(key) else {
+            return false;
+        };
+        let key = key.clone();
+        self.bump_version(&key);
+        match deadline {
+            Some(deadline) => {
+                self.expires.insert(key, deadline);
+            }
+            None => {
+                self.expires.swap_remove(&key);
+            }
+        }
+        true
+    }
+
+    fn is_expired(&self, key: &[u8]) -> bool {
+        self.expires.get(key).is_some_and(|&deadline| deadline <= clock::unix_millis())
+    }
+
+    fn remove_if_expired(&mut self, key: &[u8]) {
+        if self.is_expired(key) {
+            self.expires.swap_remove(key);
+            if let Some(entry) = self.entries.swap_remove(key) {
+                self.shrink(entry.size);
+                self.notifier.notify(self.db, EventClass::Expired, "expired", key);
+            }
+        }
+    }
+
+    /// Check up to `samples` random keys that have an expiry, removing those
+    /// past their deadline. Returns how many were checked and removed.
+    fn expire_sample(&mut self, samples: usize) -> (usize, usize) {
+        self.settle();
+        let len = self.expires.len();
+        let now = clock::unix_millis();
+        let expired: Vec<Bytes> = rand::seq::index::sample(&mut rand::rng(), len, samples.min(len))
+            .into_iter()
+            .filter_map(|index| self.expires.get_index(index))
+            .filter(|&(_, &deadline)| deadline <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove_if_expired(key);
+        }
+        (samples.min(len), expired.len())
+    }
+
+    /// Re-measure the value last handed out for modification.
+    fn settle(&mut self) {
+        let Some(key) = self.modified.take() else {
+            return;
+        };
+        if let Some(entry) = self.entries.get_mut(&key) {
+            let (old, new) = (entry.size, entry_size(&key, &entry.value));
+            entry.size = new;
+            self.grow(new);
+            self.shrink(old);
+        }
+    }
+
+    fn grow(&mut self, size: usize) {
+        self.size += size;
+        self.memory.used.fetch_add(size, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, size: usize) {
+        self.size -= size;
+        self.memory.used.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.entries.keys().filter(|key| !self.is_expired(key))
+    }
+
+    /// The key at `index` in the shard's own order, for random picks, unless
+    /// it has expired.
+    fn key_at(&self, index: usize) -> Option<&Bytes> {
+        self.entries.get_index(index).map(|(key, _)| key).filter(|key| !self.is_expired(key))
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of keys with an expiry that hasn't passed yet.
+    fn volatile_len(&self) -> usize {
+        let now = clock::unix_millis();
+        self.expires.values().filter(|&&deadline| deadline > now).count()
+    }
+
+    /// Remove every key, handing back the entries so the caller can choose
+    /// where they're dropped.
+    fn take_entries(&mut self) -> IndexMap<Bytes, Entry, RandomState> {
+        for (key, watched) in self.watched.iter_mut() {
+            if self.entries.contains_key(key) {
+                watched.version += 1;
+            }
+        }
+        let entries = std::mem::take(&mut self.entries);
+        self.expires.clear();
+        self.modified = None;
+        self.shrink(self.size);
+        entries
+    }
+
+    /// Trade keys with `other`, the same shard in another database, as
+    /// SWAPDB does. The maps themselves change hands, so nothing is copied.
+    /// Watched keys stay with their database, and the ones present on either
+    /// side count as modified.
+    fn swap_keys(&mut self, other: &mut Shard) {
+        self.settle();
+        other.settle();
+        std::mem::swap(&mut self.entries, &mut other.entries);
+        std::mem::swap(&mut self.expires, &mut other.expires);
+        std::mem::swap(&mut self.size, &mut other.size);
+        for (key, watched) in self.watched.iter_mut().chain(other.watched.iter_mut()) {
+            if self.entries.contains_key(key) || other.entries.contains_key(key) {
+                watched.version += 1;
+            }
+        }
+    }
+
+    /// Record a change to `key` for anyone watching it. Nearly free while
+    /// nothing in the shard is watched.
+    fn bump_version(&mut self, key: &[u8]) {
+        if self.watched.is_empty() {
+            return;
+        }
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.version += 1;
+        }
+    }
+
+    /// Start watching `key`, returning its current version.
+    fn watch(&mut self, key: &Bytes) -> u64 {
+        let watched = self.watched.entry(key.clone()).or_insert(WatchedVersion { version: 0, watchers: 0 });
+        watched.watchers += 1;
+        watched.version
+    }
+
+    fn unwatch(&mut self, key: &[u8]) {
+        if let Some(watched) = self.watched.get_mut(key) {
+            watched.watchers -= 1;
+            if watched.watchers == 0 {
+                self.watched.remove(key);
+            }
+        }
+    }
+}
+
+/// The indexes `start` to `stop` of LRANGE and friends select from a
+/// sequence of `len` elements, or `None` if they select nothing. Negative
+/// indexes count from the end.
+pub fn index_range(len: usize, start: i64, stop: i64) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let stop = if stop < 0 { len + stop } else { stop }.min(len - 1);
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+/// The order of sorted set members: by score, with `-inf` first and `inf`
+/// last, then by member for equal scores. Scores are never NaN, so every
+/// pair of members compares the same way each time.
+pub fn zset_order(a: (&Bytes, f64), b: (&Bytes, f64)) -> std::cmp::Ordering {
+    a.1.total_cmp(&b.1).then_with(|| a.0.cmp(b.0))
+}
+
+/// Pop with `pop` from each of `keys` in turn, returning the first key it
+/// pops anything from with what it popped. Stops at the first error, like a
+/// key of the wrong type.
+fn first_popped<T>(keys: &[Bytes], mut pop: impl FnMut(&[u8]) -> Result<Vec<T>, WrongType>) -> Result<Option<Popped<T>>, WrongType> {
+    for key in keys {
+        let popped = pop(key)?;
+        if !popped.is_empty() {
+            return Ok(Some((key.clone(), popped)));
+        }
+    }
+    Ok(None)
+}
+
+/// The members of `scores` that `spec` picks, in order.
+fn zrange_select<'a>(scores: &'a AHashMap<Bytes, f64>, spec: &ZRangeSpec) -> Vec<(&'a Bytes, f64)> {
+    let mut sorted: Vec<(&Bytes, f64)> = scores.iter().map(|(member, score)| (member, *score)).collect();
+    sorted.sort_by(|a, b| zset_order(*a, *b));
+    if spec.rev {
+        sorted.reverse();
+    }
+
+    let selected: Vec<_> = match &spec.by {
+        ZRangeBy::Rank(start, stop) => {
+            let len = sorted.len() as i64;
+            let start = if *start < 0 { len + start } else { *start }.max(0);
+            let stop = if *stop < 0 { len + stop } else { *stop }.min(len - 1);
+            if start > stop {
+                return Vec::new();
+            }
+            sorted[start as usize..=stop as usize].to_vec()
+        }
+        // Reversed ranges give their bounds highest first
+        ZRangeBy::Score(first, last) => {
+            let (min, max) = if spec.rev { (last, first) } else { (first, last) };
+            sorted.into_iter().filter(|(_, score)| min.is_below(*score) && max.is_above(*score)).collect()
+        }
+        ZRangeBy::Lex(first, last) => {
+            let (min, max) = if spec.rev { (last, first) } else { (first, last) };
+            sorted.into_iter().filter(|(member, _)| min.is_below(member) && max.is_above(member)).collect()
+        }
+    };
+    let Some((offset, count)) = spec.limit else {
+        return selected;
+    };
+    // A negative offset selects nothing, and a negative count everything after it
+    let Ok(offset) = usize::try_from(offset) else {
+        return Vec::new();
+    };
+    let selected = selected.into_iter().skip(offset);
+    match usize::try_from(count) {
+        Ok(count) => selected.take(count).collect(),
+        Err(_) => selected.collect(),
+    }
+}
+
+/// Estimated bytes taken up by an entry holding `value` at `key`.
+fn entry_size(key: &[u8], value: &DataType) -> usize {
+    std::mem::size_of::<(Bytes, Entry)>() + key.len() + value.estimated_size()
+}
+
+/// A key watched with [`Db::watch`].
+pub struct WatchedKey {
+    db: Db,
+    key: Bytes,
+    // Version of the key when the watch started
+    version: u64,
+    existed: bool,
+}
+
+impl WatchedKey {
+    /// Index of the database holding the key.
+    pub fn db_index(&self) -> usize {
+        self.db.index
+    }
+
+    pub fn key(&self) -> &Bytes {
+        &self.key
+    }
+
+    /// Whether the key was modified since the watch started. A key that
+    /// expired in the meantime counts as modified.
+    pub fn is_modified(&self) -> bool {
+        let shard = self.db.shards()[self.db.get_shard(&self.key)].read().unwrap();
+        let version = shard.watched.get(&self.key).map(|watched| watched.version);
+        version != Some(self.version) || (self.existed && !shard.contains_key(&self.key))
+    }
+}
+
+impl Drop for WatchedKey {
+    fn drop(&mut self) {
+        let mut shard = self.db.shards()[self.db.get_shard(&self.key)].write().unwrap();
+        shard.unwatch(&self.key);
+    }
+}
+
+/// Write guards over the shards holding two keys, acquired in ascending
+/// (database, shard) order so concurrent multi-key operations cannot
+/// deadlock. When both keys live on the same shard it is only locked once.
+struct ShardPair<'a> {
+    first: ((usize, usize), RwLockWriteGuard<'a, Shard>),
+    second: Option<((usize, usize), RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl ShardPair<'_> {
+    /// The locked shard with index `idx`, for pairs within one database.
+    fn get(&mut self, idx: usize) -> &mut Shard {
+        match &mut self.second {
+            Some(((_, i), guard)) if *i == idx => guard,
+            _ => &mut self.first.1,
+        }
+    }
+
+    /// The locked shard with index `idx` in database `db`.
+    fn get_in(&mut self, db: usize, idx: usize) -> &mut Shard {
+        match &mut self.second {
+            Some((slot, guard)) if *slot == (db, idx) => guard,
+            _ => &mut self.first.1,
+        }
+    }
+}
+
+/// A random key of `shard`, expired or not, with what `rank` reads from its
+/// entry.
+fn random_key<T>(shard: &Shard, rank: impl Fn(&Entry) -> T) -> Option<(Bytes, T)> {
+    if shard.entries.is_empty() {
+        return None;
+    }
+    let index = rand::rng().random_range(0..shard.entries.len());
+    let (key, entry) = shard.entries.get_index(index)?;
+    Some((key.clone(), rank(entry)))
+}
+
+/// A random key of `shard` that has an expiry, with its deadline.
+fn random_volatile_key(shard: &Shard) -> Option<(Bytes, u64)> {
+    if shard.expires.is_empty() {
+        return None;
+    }
+    let index = rand::rng().random_range(0..shard.expires.len());
+    shard.expires.get_index(index).map(|(key, deadline)| (key.clone(), *deadline))
+}
+
+/// A single logical database: a sharded keyspace.
+struct Keyspace {
+    // Shards for data storage using fast AHashMap and Bytes keys
+    shards: Vec<RwLock<Shard>>,
+}
+
+impl Keyspace {
+    fn new(memory: &Arc<Memory>, notifier: &Arc<Notifier>, index: usize) -> Keyspace {
+        let shards = (0..SHARD_COUNT)
+            .map(|_| RwLock::new(Shard::new(memory.clone(), notifier.clone(), index)))
+            .collect();
+        Keyspace { shards }
+    }
+}
+
 /// A thread-safe, sharded Redis-like database.
+///
+/// The server holds several logical databases. A `Db` is a handle to one of
+/// them, the one selected with `SELECT`; handles to the others are obtained
+/// with [`Db::select`]. All handles share the same underlying storage.
 #[derive(Clone)]
 pub struct Db {
-    // Shards for data storage using fast AHashMap and Bytes keys
-    shards: Vec<Arc<RwLock<AHashMap<Bytes, DataType>>>>,
+    // Every logical database, shared by all handles
+    databases: Arc<Vec<Keyspace>>,
+    // Index of the database this handle operates on
+    index: usize,
     // Hasher builder for consistent sharding
     hasher: RandomState,
-    // Version counters for each shard (for WATCH)
-    shard_versions: Arc<Vec<AtomicU64>>,
+    // Process-wide information reported by INFO
+    stats: Arc<ServerStats>,
+    // Pub/sub channels, which aren't scoped to a database
+    pubsub: Arc<PubSub>,
+    // Open connections, for CLIENT LIST and CLIENT KILL
+    clients: Arc<Clients>,
+    // Clients waiting in commands like BLPOP, woken by writes to their keys
+    blocked: Arc<BlockedKeys>,
+    // Users that may authenticate, fixed at startup
+    acl: Arc<Acl>,
+    // Snapshot file used by SAVE and BGSAVE
+    snapshots: Arc<Snapshots>,
+    // Append-only file write commands are logged to, if enabled
+    aof: Option<Arc<Aof>>,
+    // Replicas write commands are forwarded to
+    replication: Arc<Replication>,
+    // Memory use of every database
+    memory: Arc<Memory>,
+    // Settings CONFIG SET can change, shared by every handle
+    config: Arc<RwLock<RuntimeConfig>>,
+    // Keyspace notifications, published to `pubsub` as `config` selects
+    notifier: Arc<Notifier>,
+    // Longest string commands like SETRANGE may grow a value to
+    max_value_len: usize,
     // Global lock for transaction atomicity (Executor)
     // Normal commands take read lock (concurrent), EXEC takes write lock (exclusive)
     pub batch_lock: Arc<AsyncRwLock<()>>, 
 }
 
-const SHARD_COUNT: usize = 64;
+const SHARD_COUNT: usize = 64;
+
+/// Draws `Db::random_key` makes before falling back to a scan.
+const RANDOM_KEY_TRIES: usize = 100;
+
+/// Most members a random pick with repeats, like `SRANDMEMBER key -count`,
+/// returns. Redis streams such replies out, but here they're built in
+/// memory first, so the count has to be bounded.
+pub const MAX_RANDOM_REPEATS: u64 = 1 << 20;
+
+/// Pick random elements of a collection, as SRANDMEMBER, HRANDFIELD and
+/// ZRANDMEMBER do.
+///
+/// A non-negative `count` picks up to `count` distinct elements. A negative
+/// `count` picks exactly `-count` elements with replacement, up to
+/// [`MAX_RANDOM_REPEATS`].
+fn random_picks<T: Clone>(items: impl ExactSizeIterator<Item = T>, count: i64, rng: &mut impl Rng) -> Vec<T> {
+    if count >= 0 {
+        let count = usize::try_from(count).unwrap_or(usize::MAX).min(items.len());
+        items.choose_multiple(rng, count)
+    } else {
+        let items: Vec<T> = items.collect();
+        (0..count.unsigned_abs().min(MAX_RANDOM_REPEATS))
+            .filter_map(|_| items.choose(rng).cloned())
+            .collect()
+    }
+}
+
+/// Keys compared to pick each one evicted under `allkeys-lru`,
+/// `allkeys-lfu` and `volatile-ttl`, like Redis's `maxmemory-samples`.
+const EVICTION_SAMPLES: usize = 5;
+
+/// Keys with an expiry checked per shard in each round of
+/// `Db::expire_cycle`, like Redis's `ACTIVE_EXPIRE_CYCLE_KEYS_PER_LOOP`.
+const EXPIRE_SAMPLES: usize = 20;
+
+/// Rounds `Db::expire_cycle` spends on a shard at most, so a shard full of
+/// expired keys can't hold up the others.
+const EXPIRE_ROUNDS: usize = 16;
+
+/// Number of logical databases created by [`Db::new`].
+pub const DEFAULT_DATABASES: usize = 16;
+
+impl Default for Db {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Db {
+    /// Create a new, empty `Db` instance with sharding and the default
+    /// number of databases. The returned handle selects database 0.
+    pub fn new() -> Db {
+        Db::with_databases(DEFAULT_DATABASES)
+    }
+
+    /// Create a new, empty `Db` instance holding `count` logical databases.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero.
+    pub fn with_databases(count: usize) -> Db {
+        assert!(count > 0, "at least one database is required");
+        clock::start();
+        let memory = Arc::new(Memory::default());
+        let pubsub = Arc::new(PubSub::new());
+        let config = Arc::new(RwLock::new(RuntimeConfig::default()));
+        let notifier = Arc::new(Notifier::new(pubsub.clone(), config.clone()));
+        Db {
+            databases: Arc::new((0..count).map(|index| Keyspace::new(&memory, &notifier, index)).collect()),
+            index: 0,
+            hasher: RandomState::new(),
+            stats: Arc::new(ServerStats::new()),
+            pubsub,
+            clients: Arc::new(Clients::new()),
+            blocked: Arc::new(BlockedKeys::new(count)),
+            acl: Arc::new(Acl::new()),
+            snapshots: Arc::new(Snapshots::default()),
+            aof: None,
+            replication: Arc::new(Replication::new()),
+            memory,
+            config,
+            notifier,
+            max_value_len: Limits::default().max_bulk_len,
+            batch_lock: Arc::new(AsyncRwLock::new(())),
+        }
+    }
+
+    /// Returns a handle to database `index`, or `None` if it doesn't exist.
+    pub fn select(&self, index: usize) -> Option<Db> {
+        if index >= self.databases.len() {
+            return None;
+        }
+        Some(Db { index, ..self.clone() })
+    }
+
+    /// Index of the database this handle operates on.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Number of logical databases.
+    pub fn database_count(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// Server-wide statistics shared by every handle.
+    pub fn stats(&self) -> &ServerStats {
+        &self.stats
+    }
+
+    /// Pub/sub channels shared by every handle.
+    pub fn pubsub(&self) -> &PubSub {
+        &self.pubsub
+    }
+
+    /// Connections open to the server, shared by every handle.
+    pub fn clients(&self) -> &Arc<Clients> {
+        &self.clients
+    }
+
+    /// Start waiting for a write to one of `keys` in this database, as
+    /// blocking commands like BLPOP do when they find nothing to take.
+    pub fn block(&self, keys: &[Bytes]) -> Blocked {
+        self.blocked.block(self.index, keys)
+    }
+
+    /// Users that may authenticate, shared by every handle.
+    pub fn acl(&self) -> &Acl {
+        &self.acl
+    }
+
+    /// Replace the user table. Only meant for startup, since handles cloned
+    /// earlier keep the old table.
+    pub fn with_acl(self, acl: Acl) -> Db {
+        Db { acl: Arc::new(acl), ..self }
+    }
+
+    /// The snapshot file and whether a save is running, shared by every
+    /// handle.
+    pub fn snapshots(&self) -> &Snapshots {
+        &self.snapshots
+    }
+
+    /// Set the file `SAVE` and `BGSAVE` write to. Like `with_acl`, only
+    /// meant for startup.
+    pub fn with_snapshot_path(self, path: Option<PathBuf>) -> Db {
+        Db { snapshots: Arc::new(Snapshots::new(path)), ..self }
+    }
+
+    /// The append-only file shared by every handle, if enabled.
+    pub fn aof(&self) -> Option<&Aof> {
+        self.aof.as_deref()
+    }
+
+    /// Log write commands to `aof`. Like `with_acl`, only meant for
+    /// startup.
+    pub fn with_aof(self, aof: Aof) -> Db {
+        Db { aof: Some(Arc::new(aof)), ..self }
+    }
+
+    /// The replicas of this server, shared by every handle.
+    pub fn replication(&self) -> &Replication {
+        &self.replication
+    }
+
+    /// Longest string commands like `SETRANGE` may grow a value to, in bytes.
+    pub fn max_value_len(&self) -> usize {
+        self.max_value_len
+    }
+
+    /// Set the longest string commands may grow a value to. Like
+    /// `with_acl`, only meant for startup.
+    pub fn with_max_value_len(self, max_value_len: usize) -> Db {
+        Db { max_value_len, ..self }
+    }
+
+    /// Estimated bytes taken up by the keys and values of every database.
+    pub fn used_memory(&self) -> usize {
+        self.memory.used.load(Ordering::Relaxed)
+    }
+
+    /// The memory limit, zero if there is none, and the policy for staying
+    /// under it.
+    pub fn max_memory(&self) -> (usize, EvictionPolicy) {
+        let config = self.config();
+        (config.maxmemory, config.maxmemory_policy)
+    }
+
+    /// The settings currently in effect.
+    pub fn config(&self) -> RuntimeConfig {
+        *self.config.read().unwrap()
+    }
+
+    /// Change the settings in effect with `f`. Applies to every handle.
+    pub fn update_config<R>(&self, f: impl FnOnce(&mut RuntimeConfig) -> R) -> R {
+        f(&mut self.config.write().unwrap())
+    }
+
+    /// Publish the keyspace notification for `event`, of `class`, happening
+    /// to `key` in this database.
+    fn notify(&self, class: EventClass, event: &str, key: &[u8]) {
+        self.notifier.notify(self.index, class, event, key);
+    }
+
+    /// Publish `event` like `notify`, followed by `del` if it deleted `key`
+    /// by emptying its value.
+    fn notify_emptied(&self, class: EventClass, event: &str, key: &[u8], deleted: bool) {
+        self.notify(class, event, key);
+        if deleted {
+            self.notify(EventClass::Generic, "del", key);
+        }
+    }
+
+    /// Evict keys under the eviction policy until memory use is back within
+    /// the limit, passing each evicted key to `evicted` with the index of its
+    /// database.
+    ///
+    /// Fails with `OutOfMemory` if the policy doesn't allow evicting enough.
+    /// Keys evicted until then stay evicted.
+    ///
+    /// `incoming` is an estimate of the bytes the write about to run adds.
+    /// Under `noeviction` a write that would take memory use over the limit
+    /// is refused up front, so it never applies partway or leaves memory
+    /// over the limit behind it.
+    pub fn make_room(&self, incoming: usize, mut evicted: impl FnMut(usize, Bytes)) -> Result<(), OutOfMemory> {
+        let (max, policy) = self.max_memory();
+        if max == 0 {
+            return Ok(());
+        }
+        if policy == EvictionPolicy::NoEviction && self.used_memory().saturating_add(incoming) > max {
+            return Err(OutOfMemory);
+        }
+        while self.used_memory() > max {
+            let victim = match policy {
+                EvictionPolicy::NoEviction => None,
+                EvictionPolicy::AllKeysRandom => {
+                    self.sample_shards(|shard| random_key(shard, |_| ())).map(|(index, (key, _))| (index, key))
+                }
+                EvictionPolicy::AllKeysLru => (0..EVICTION_SAMPLES)
+                    .filter_map(|_| self.sample_shards(|shard| random_key(shard, |entry| entry.accessed.load(Ordering::Relaxed))))
+                    .min_by_key(|(_, (_, accessed))| *accessed)
+                    .map(|(index, (key, _))| (index, key)),
+                EvictionPolicy::AllKeysLfu => (0..EVICTION_SAMPLES)
+                    .filter_map(|_| self.sample_shards(|shard| random_key(shard, Entry::frequency)))
+                    .min_by_key(|(_, (_, frequency))| *frequency)
+                    .map(|(index, (key, _))| (index, key)),
+                EvictionPolicy::VolatileTtl => (0..EVICTION_SAMPLES)
+                    .filter_map(|_| self.sample_shards(random_volatile_key))
+                    .min_by_key(|(_, (_, deadline))| *deadline)
+                    .map(|(index, (key, _))| (index, key)),
+            };
+            let Some((index, key)) = victim else {
+                return Err(OutOfMemory);
+            };
+            let db = Db { index, ..self.clone() };
+            if db.remove(&key) {
+                db.notify(EventClass::Evicted, "evicted", &key);
+                self.stats.key_evicted();
+                evicted(index, key);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove keys past their expiry that nobody has looked up, in every
+    /// database, publishing an `expired` event for each. Returns how many
+    /// were removed.
+    ///
+    /// Like Redis, this samples random keys with an expiry from each shard,
+    /// and samples the shard again while more than a quarter of the sample
+    /// had expired. Each round takes the shard's lock on its own.
+    pub fn expire_cycle(&self) -> usize {
+        let mut removed = 0;
+        for keyspace in self.databases.iter() {
+            for shard in &keyspace.shards {
+                for _ in 0..EXPIRE_ROUNDS {
+                    let (checked, expired) = shard.write().unwrap().expire_sample(EXPIRE_SAMPLES);
+                    removed += expired;
+                    if expired * 4 <= checked {
+                        break;
+                    }
+                }
+            }
+        }
+        removed
+    }
 
-impl Db {
-    /// Create a new, empty `Db` instance with sharding.
-    pub fn new() -> Db {
-        let mut shards = Vec::with_capacity(SHARD_COUNT);
-        let mut shard_versions = Vec::with_capacity(SHARD_COUNT);
+    /// Pick something from a random shard of a random database with `pick`,
+    /// returning it with the database's index.
+    ///
+    /// Random shards are tried first, falling back to scanning every shard
+    /// in order, since most shards may be empty.
+    fn sample_shards<T>(&self, pick: impl Fn(&Shard) -> Option<T>) -> Option<(usize, T)> {
+        let mut rng = rand::rng();
         for _ in 0..SHARD_COUNT {
-            shards.push(Arc::new(RwLock::new(AHashMap::new())));
-            shard_versions.push(AtomicU64::new(0));
+            let index = rng.random_range(0..self.databases.len());
+            let shard = self.databases[index].shards[rng.random_range(0..SHARD_COUNT)].read().unwrap();
+            if let Some(found) = pick(&shard) {
+                return Some((index, found));
+            }
         }
-        Db { 
-            shards,
-            hasher: RandomState::new(),
-            shard_versions: Arc::new(shard_versions),
-            batch_lock: Arc::new(AsyncRwLock::new(())),
+        self.databases.iter().enumerate().find_map(|(index, keyspace)| {
+            keyspace.shards.iter().find_map(|shard| pick(&shard.read().unwrap())).map(|found| (index, found))
+        })
+    }
+
+    /// Write every database to a snapshot at `path`.
+    ///
+    /// Shards are read-locked one at a time, so writes to other shards can
+    /// carry on while the snapshot is taken.
+    pub fn snapshot_to(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        persistence::write_snapshot_file(path.as_ref(), |writer| self.write_snapshot(writer))
+    }
+
+    /// Encode every database as a snapshot in memory, like `snapshot_to`.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let mut writer = SnapshotWriter::new(Vec::new()).expect("writing to a Vec can't fail");
+        self.write_snapshot(&mut writer).expect("writing to a Vec can't fail");
+        writer.finish().expect("writing to a Vec can't fail")
+    }
+
+    fn write_snapshot<W: Write>(&self, writer: &mut SnapshotWriter<W>) -> io::Result<()> {
+        for (index, keyspace) in self.databases.iter().enumerate() {
+            writer.select_db(index)?;
+            for shard in &keyspace.shards {
+                let shard = shard.read().unwrap();
+                for (key, entry) in &shard.entries {
+                    if !shard.is_expired(key) {
+                        writer.write_entry(key, &entry.value, shard.expiry(key))?;
+                    }
+                }
+            }
         }
+        Ok(())
     }
 
-    fn get_shard(&self, key: &[u8]) -> usize {
-        let mut hasher = self.hasher.build_hasher();
-        key.hash(&mut hasher);
-        (hasher.finish() as usize) % SHARD_COUNT
+    /// Add the contents of the snapshot at `path`, replacing keys that
+    /// already exist. Keys that expired since the snapshot was taken are
+    /// skipped.
+    pub fn load_from(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.load_snapshot(&std::fs::read(path)?)
     }
 
-    fn increment_version(&self, shard_idx: usize) {
-        self.shard_versions[shard_idx].fetch_add(1, Ordering::Relaxed);
+    /// Add the contents of the snapshot in `data`, like `load_from`.
+    pub fn load_snapshot(&self, data: &[u8]) -> io::Result<()> {
+        persistence::read_snapshot(data, |index, key, value, deadline| {
+            let db = self.select(index).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "snapshot database index is out of range")
+            })?;
+            db.restore(key, value, deadline, true);
+            Ok(())
+        })
     }
 
-    pub fn get_shard_version(&self, shard_idx: usize) -> u64 {
-        self.shard_versions[shard_idx].load(Ordering::Relaxed)
+    fn shards(&self) -> &[RwLock<Shard>] {
+        &self.databases[self.index].shards
+    }
+
+    /// Write-lock the shards with indexes `a` and `b` in ascending order.
+    fn lock_pair(&self, a: usize, b: usize) -> ShardPair<'_> {
+        self.lock_slots((self.index, a), (self.index, b))
+    }
+
+    /// Write-lock two shards given as `(database, shard)` slots, possibly in
+    /// different databases, in ascending order.
+    fn lock_slots(&self, a: (usize, usize), b: (usize, usize)) -> ShardPair<'_> {
+        let (lo, hi) = if a <= b { (a, b) } else { (b, a) };
+        let lock = |(db, idx): (usize, usize)| self.databases[db].shards[idx].write().unwrap();
+        let first = (lo, lock(lo));
+        let second = (lo != hi).then(|| (hi, lock(hi)));
+        ShardPair { first, second }
+    }
+
+    /// Read-lock the shard holding `key`.
+    fn read_shard(&self, key: &[u8]) -> RwLockReadGuard<'_, Shard> {
+        self.shards()[self.get_shard(key)].read().unwrap()
+    }
+
+    /// Write-lock the shard holding `key`.
+    fn write_shard(&self, key: &[u8]) -> RwLockWriteGuard<'_, Shard> {
+        self.shards()[self.get_shard(key)].write().unwrap()
+    }
+
+    fn get_shard(&self, key: &[u8]) -> usize {
+        (self.hasher.hash_one(key) as usize) % SHARD_COUNT
+    }
+
+    /// Watch `key` for changes, like WATCH does, until the returned handle
+    /// is dropped.
+    pub fn watch(&self, key: Bytes) -> WatchedKey {
+        let mut shard = self.write_shard(&key);
+        let version = shard.watch(&key);
+        let existed = shard.contains_key(&key);
+        drop(shard);
+        WatchedKey { db: self.clone(), key, version, existed }
     }
 
     pub fn get_shard_index(&self, key: &[u8]) -> usize {
         self.get_shard(key)
     }
 
-    /// Get the value associated with a key.
-    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+    /// Get the string associated with a key, or `None` if it's missing.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        let shard = self.read_shard(key);
         match shard.get(key) {
-            Some(DataType::String(b)) => Some(b.clone()),
-            _ => None,
+            Some(DataType::String(b)) => Ok(Some(b.clone())),
+            Some(_) => Err(WrongType),
+            None => Ok(None),
         }
     }
 
     /// Set the value associated with a key.
     pub fn set(&self, key: Bytes, value: Bytes) {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        shard.insert(key, DataType::String(value));
-        self.increment_version(shard_idx);
+        let mut shard = self.write_shard(&key);
+        shard.insert(key.clone(), DataType::String(value));
+        drop(shard);
+        self.notify(EventClass::String, "set", &key);
+    }
+
+    /// Set every pair like `set`, with all their shards write-locked
+    /// together in ascending order, so no one sees some of the pairs set
+    /// and not others. A key given twice ends up with its last value.
+    pub fn mset(&self, pairs: Vec<(Bytes, Bytes)>) {
+        let mut indexes: Vec<usize> = pairs.iter().map(|(key, _)| self.get_shard(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let mut shards: Vec<_> = indexes.iter().map(|&idx| self.shards()[idx].write().unwrap()).collect();
+
+        let mut keys = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let Ok(pos) = indexes.binary_search(&self.get_shard(&key)) else {
+                unreachable!("every key's shard is locked");
+            };
+            shards[pos].insert(key.clone(), DataType::String(value));
+            keys.push(key);
+        }
+        drop(shards);
+        for key in &keys {
+            self.notify(EventClass::String, "set", key);
+        }
+    }
+
+    /// The string at `key` for a read-modify-write command like INCR, APPEND
+    /// or SETRANGE, returned with its shard locked so the result can be
+    /// stored with `Shard::replace` before anyone else sees the key.
+    ///
+    /// A key that has expired but not been swept yet is deleted first, so it
+    /// reads as missing rather than handing back its stale value.
+    fn get_live_value(&self, key: &[u8]) -> Result<(RwLockWriteGuard<'_, Shard>, Option<Bytes>), WrongType> {
+        let mut shard = self.write_shard(key);
+        shard.settle();
+        shard.remove_if_expired(key);
+        let value = match shard.get(key) {
+            Some(DataType::String(value)) => Some(value.clone()),
+            Some(_) => return Err(WrongType),
+            None => None,
+        };
+        Ok((shard, value))
+    }
+
+    /// Overwrite the string at `key` with `value`, starting `offset` bytes
+    /// in and padding it with zero bytes if it's shorter than that. A missing
+    /// key counts as an empty string.
+    ///
+    /// Returns the length of the string afterwards. An empty `value` changes
+    /// nothing, so it doesn't create the key either.
+    pub fn setrange(&self, key: Bytes, offset: usize, value: &[u8]) -> Result<usize, WrongType> {
+        let (mut shard, current) = self.get_live_value(&key)?;
+        let current = current.unwrap_or_default();
+        if value.is_empty() {
+            return Ok(current.len());
+        }
+
+        let end = offset + value.len();
+        let mut bytes = current.to_vec();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
+        }
+        bytes[offset..end].copy_from_slice(value);
+        let len = bytes.len();
+        shard.replace(key.clone(), DataType::String(Bytes::from(bytes)));
+        drop(shard);
+        self.notify(EventClass::String, "setrange", &key);
+        Ok(len)
+    }
+
+    /// The bytes of the string at `key` from `start` to `stop` inclusive.
+    /// Negative indexes count from the end, like `LRANGE`.
+    pub fn getrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Bytes, WrongType> {
+        let shard = self.read_shard(key);
+        match shard.get(key) {
+            Some(DataType::String(value)) => {
+                Ok(index_range(value.len(), start, stop).map(|range| value.slice(range)).unwrap_or_default())
+            }
+            Some(_) => Err(WrongType),
+            None => Ok(Bytes::new()),
+        }
+    }
+
+    /// Set the bit at `offset` in the string at `key`, growing it as needed.
+    /// A missing key counts as an empty string.
+    ///
+    /// Returns the bit's previous value.
+    pub fn setbit(&self, key: Bytes, offset: usize, bit: bool) -> Result<u8, WrongType> {
+        let (mut shard, current) = self.get_live_value(&key)?;
+        let mut bytes = current.unwrap_or_default().to_vec();
+        let previous = bits::set(&mut bytes, offset, bit);
+        shard.replace(key.clone(), DataType::String(Bytes::from(bytes)));
+        drop(shard);
+        self.notify(EventClass::String, "setbit", &key);
+        Ok(previous)
+    }
+
+    /// Add `delta` to the integer stored as a string at `key`, as INCRBY
+    /// does. A missing key counts as 0.
+    ///
+    /// Returns the new value.
+    pub fn incr_by(&self, key: Bytes, delta: i64) -> Result<i64, StringError> {
+        let (mut shard, current) = self.get_live_value(&key)?;
+        let current = match current {
+            Some(current) => parse_num::parse_int(&current).map_err(|_| StringError::NotInteger)?,
+            None => 0,
+        };
+        let value = current.checked_add(delta).ok_or(StringError::Overflow)?;
+        shard.replace(key.clone(), DataType::String(Bytes::from(value.to_string())));
+        drop(shard);
+        self.notify(EventClass::String, "incrby", &key);
+        Ok(value)
+    }
+
+    /// Append `value` to the string at `key`, which counts as empty if it's
+    /// missing.
+    ///
+    /// Returns the length of the string afterwards.
+    pub fn append(&self, key: Bytes, value: &[u8]) -> Result<usize, StringError> {
+        let (mut shard, current) = self.get_live_value(&key)?;
+        let current = current.unwrap_or_default();
+        let len = current.len() + value.len();
+        if len > self.max_value_len {
+            return Err(StringError::TooLong);
+        }
+        let mut bytes = Vec::with_capacity(len);
+        bytes.extend_from_slice(&current);
+        bytes.extend_from_slice(value);
+        shard.replace(key.clone(), DataType::String(Bytes::from(bytes)));
+        drop(shard);
+        self.notify(EventClass::String, "append", &key);
+        Ok(len)
     }
 
     /// Delete the value associated with `key`.
     pub fn delete(&self, key: &[u8]) -> bool {
-        let shard_idx = self.get_shard(key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        let res = shard.remove(key).is_some();
-        if res { self.increment_version(shard_idx); }
-        res
+        let deleted = self.remove(key);
+        if deleted {
+            self.notify(EventClass::Generic, "del", key);
+        }
+        deleted
+    }
+
+    /// Delete `keys`, returning the values of those that existed for the
+    /// caller to drop, so UNLINK can free large ones off the connection's
+    /// task. Keys named more than once count once.
+    pub fn unlink(&self, keys: &[Bytes]) -> Vec<DataType> {
+        keys.iter()
+            .filter_map(|key| {
+                let value = self.write_shard(key).remove(key)?;
+                self.notify(EventClass::Generic, "del", key);
+                Some(value)
+            })
+            .collect()
+    }
+
+    /// Like `delete`, without publishing anything.
+    fn remove(&self, key: &[u8]) -> bool {
+        let mut shard = self.write_shard(key);
+        shard.remove(key).is_some()
     }
 
     pub fn exists(&self, key: &[u8]) -> bool {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+        let shard = self.read_shard(key);
         shard.contains_key(key)
     }
 
+    /// Count `keys` as accessed, as TOUCH does, without reading their values.
+    /// Returns how many of them exist, counting repeats each time.
+    pub fn touch(&self, keys: &[Bytes]) -> usize {
+        keys.iter().filter(|key| self.read_shard(key).get(key).is_some()).count()
+    }
+
+    /// The deadline `key` expires at, in Unix milliseconds.
+    ///
+    /// Returns `None` if the key doesn't exist, and `Some(None)` if it never
+    /// expires.
+    pub fn expiry(&self, key: &[u8]) -> Option<Option<u64>> {
+        let shard = self.read_shard(key);
+        shard.contains_key(key).then(|| shard.expiry(key))
+    }
+
+    /// Make `key` expire at `deadline`, in Unix milliseconds. A deadline that
+    /// has already passed deletes the key straight away.
+    ///
+    /// Returns `false` if the key doesn't exist.
+    pub fn expire_at(&self, key: &[u8], deadline: i64) -> bool {
+        let mut shard = self.write_shard(key);
+        if deadline <= clock::unix_millis() as i64 {
+            let deleted = shard.remove(key).is_some();
+            drop(shard);
+            if deleted {
+                self.notify(EventClass::Generic, "del", key);
+            }
+            return deleted;
+        }
+        let set = shard.set_expiry(key, Some(deadline as u64));
+        drop(shard);
+        if set {
+            self.notify(EventClass::Generic, "expire", key);
+        }
+        set
+    }
+
+    /// Store `value` at `key`, expiring at `deadline` in Unix milliseconds,
+    /// or never with `None`.
+    ///
+    /// Returns `false` without touching anything if `key` exists and
+    /// `replace` isn't set. A deadline that has already passed only deletes
+    /// the existing key.
+    pub fn restore(&self, key: Bytes, value: DataType, deadline: Option<u64>, replace: bool) -> bool {
+        let mut shard = self.write_shard(&key);
+        if !replace && shard.contains_key(&key) {
+            return false;
+        }
+        if deadline.is_some_and(|deadline| deadline <= clock::unix_millis()) {
+            shard.remove(&key);
+        } else {
+            shard.insert(key.clone(), value);
+            shard.set_expiry(&key, deadline);
+            drop(shard);
+            self.notify(EventClass::Generic, "restore", &key);
+            self.blocked.wake(self.index, &key);
+        }
+        true
+    }
+
+    /// Time since `key` was last read or written, or `None` if it doesn't
+    /// exist. Measured with the coarse access clock, so accurate to
+    /// [`clock::TICK`].
+    pub fn idle_time(&self, key: &[u8]) -> Option<Duration> {
+        let shard = self.read_shard(key);
+        shard.accessed(key).map(clock::since)
+    }
+
+    /// The access counter of `key` that the LFU policy evicts by, or `None`
+    /// if it doesn't exist. It grows logarithmically with accesses and
+    /// decays by one for every minute the key sits idle.
+    pub fn access_frequency(&self, key: &[u8]) -> Option<u8> {
+        let shard = self.read_shard(key);
+        shard.frequency(key)
+    }
+
+    /// The `OBJECT ENCODING` of the value at `key`, or `None` if it doesn't
+    /// exist. Doesn't count as an access.
+    pub fn encoding(&self, key: &[u8]) -> Option<&'static str> {
+        self.peek_value(key, DataType::encoding)
+    }
+
+    /// Like `read_value`, but doesn't count as an access, for commands that
+    /// inspect keys like `DEBUG OBJECT`.
+    pub fn peek_value<R>(&self, key: &[u8], f: impl FnOnce(&DataType) -> R) -> Option<R> {
+        let shard = self.read_shard(key);
+        shard.peek(key).map(f)
+    }
+
+    /// Rename `src` to `dst`, moving its value and expiry as-is.
+    ///
+    /// Returns `None` when `src` doesn't exist. Otherwise returns whether the
+    /// rename happened: an existing `dst` is overwritten when `replace` is
+    /// set and left untouched (returning `false`) when it isn't. Renaming a
+    /// key to itself changes nothing.
+    pub fn rename(&self, src: &[u8], dst: Bytes, replace: bool) -> Option<bool> {
+        let src_idx = self.get_shard(src);
+        let dst_idx = self.get_shard(&dst);
+        let mut shards = self.lock_pair(src_idx, dst_idx);
+
+        if !shards.get(src_idx).contains_key(src) {
+            return None;
+        }
+        if src == &dst[..] {
+            return Some(replace);
+        }
+        if !replace && shards.get(dst_idx).contains_key(&dst[..]) {
+            return Some(false);
+        }
+
+        // The value keeps whatever time it had left
+        let deadline = shards.get(src_idx).expiry(src);
+        let value = shards.get(src_idx).remove(src)?;
+        let dst_shard = shards.get(dst_idx);
+        dst_shard.insert(dst.clone(), value);
+        dst_shard.set_expiry(&dst, deadline);
+        drop(shards);
+        self.notify(EventClass::Generic, "rename_from", src);
+        self.notify(EventClass::Generic, "rename_to", &dst);
+        self.blocked.wake(self.index, &dst);
+        Some(true)
+    }
+
+    /// Copy the value of `src` to `dst` in database `dst_db`.
+    ///
+    /// Returns whether the copy happened: it doesn't when `src` is missing,
+    /// or when `dst` exists and `replace` isn't set. An existing `dst` of any
+    /// type is overwritten when `replace` is set. The copy is a clone of the
+    /// stored value, so it keeps the source's encoding, and it expires when
+    /// the source does. Copying a key onto itself is an error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dst_db` is not a valid database index.
+    pub fn copy(&self, src: &[u8], dst_db: usize, dst: Bytes, replace: bool) -> Result<bool, SameObject> {
+        if dst_db == self.index && src == &dst[..] {
+            return Err(SameObject);
+        }
+
+        let src_slot = (self.index, self.get_shard(src));
+        let dst_slot = (dst_db, self.get_shard(&dst));
+        let mut shards = self.lock_slots(src_slot, dst_slot);
+
+        let src_shard = shards.get_in(src_slot.0, src_slot.1);
+        let Some(value) = src_shard.get(src).cloned() else {
+            return Ok(false);
+        };
+        let deadline = src_shard.expiry(src);
+        let dst_shard = shards.get_in(dst_slot.0, dst_slot.1);
+        if !replace && dst_shard.contains_key(&dst[..]) {
+            return Ok(false);
+        }
+        dst_shard.insert(dst.clone(), value);
+        dst_shard.set_expiry(&dst, deadline);
+        drop(shards);
+        self.notifier.notify(dst_db, EventClass::Generic, "copy_to", &dst);
+        self.blocked.wake(dst_db, &dst);
+        Ok(true)
+    }
+
+    /// Move `key` to database `dst_db`, keeping its value and expiry as-is.
+    ///
+    /// Returns whether the move happened: it doesn't when `key` is missing
+    /// here or already exists in `dst_db`. Moving a key to the database it's
+    /// in is an error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dst_db` is not a valid database index.
+    pub fn move_key(&self, key: Bytes, dst_db: usize) -> Result<bool, SameObject> {
+        if dst_db == self.index {
+            return Err(SameObject);
+        }
+
+        // Keys hash to the same shard index in every database
+        let idx = self.get_shard(&key);
+        let mut shards = self.lock_slots((self.index, idx), (dst_db, idx));
+
+        if shards.get_in(dst_db, idx).contains_key(&key) {
+            return Ok(false);
+        }
+        let src_shard = shards.get_in(self.index, idx);
+        let deadline = src_shard.expiry(&key);
+        let Some(value) = src_shard.remove(&key) else {
+            return Ok(false);
+        };
+        let dst_shard = shards.get_in(dst_db, idx);
+        dst_shard.insert(key.clone(), value);
+        dst_shard.set_expiry(&key, deadline);
+        drop(shards);
+        self.notify(EventClass::Generic, "move_from", &key);
+        self.notifier.notify(dst_db, EventClass::Generic, "move_to", &key);
+        self.blocked.wake(dst_db, &key);
+        Ok(true)
+    }
+
     /// Return all keys in the database.
     pub fn keys(&self) -> Vec<Bytes> {
         let mut keys = Vec::new();
-        for shard in &self.shards {
+        for shard in self.shards() {
              let state = shard.read().unwrap();
              keys.extend(state.keys().cloned());
         }
         keys
     }
 
+    /// Return a random key, or `None` if the database is empty.
+    ///
+    /// Shards are picked with probability proportional to their size, then a
+    /// position within the shard, so every key is equally likely and a draw
+    /// doesn't walk any keys. Only read locks are taken; if a shard shrinks
+    /// between sizing it and picking from it, or the key picked has expired,
+    /// the draw is retried, up to `RANDOM_KEY_TRIES` times before settling
+    /// for the first live key found.
+    pub fn random_key(&self) -> Option<Bytes> {
+        let mut rng = rand::rng();
+        for _ in 0..RANDOM_KEY_TRIES {
+            let sizes: Vec<usize> = self.shards().iter().map(|shard| shard.read().unwrap().len()).collect();
+            let total: usize = sizes.iter().sum();
+            if total == 0 {
+                return None;
+            }
+
+            let mut offset = rng.random_range(0..total);
+            let shard_idx = sizes.iter().position(|&size| {
+                if offset < size {
+                    return true;
+                }
+                offset -= size;
+                false
+            })?;
+            let shard = self.shards()[shard_idx].read().unwrap();
+            if let Some(key) = shard.key_at(offset) {
+                return Some(key.clone());
+            }
+        }
+        self.shards().iter().find_map(|shard| shard.read().unwrap().keys().next().cloned())
+    }
+
+    /// Return up to `count` keys starting at `cursor`, and the cursor to
+    /// resume from, which is 0 once the scan is complete.
+    ///
+    /// Keys are visited shard by shard, and within a shard in the order of
+    /// the hash bits not used to pick the shard. The cursor records the
+    /// shard in its top bits and the hash position of the next key in the
+    /// rest, so it stays valid however the shards change in between calls: a
+    /// key present for the whole scan is returned exactly once. Keys sharing
+    /// a hash position are always returned together, so a page may rarely
+    /// exceed `count`.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Bytes>) {
+        const SHARD_SHIFT: u32 = 64 - SHARD_COUNT.trailing_zeros();
+        const POSITION_MASK: u64 = (1 << SHARD_SHIFT) - 1;
+
+        let mut keys = Vec::new();
+        let mut last = None;
+        let mut position = cursor & POSITION_MASK;
+        for shard_idx in (cursor >> SHARD_SHIFT) as usize..SHARD_COUNT {
+            let shard = self.shards()[shard_idx].read().unwrap();
+            let mut candidates: Vec<(u64, &Bytes)> = shard
+                .keys()
+                .map(|key| (self.hasher.hash_one(key) / SHARD_COUNT as u64, key))
+                .filter(|(pos, _)| *pos >= position)
+                .collect();
+            candidates.sort_unstable_by_key(|(pos, _)| *pos);
+
+            for (pos, key) in candidates {
+                if keys.len() >= count && last != Some((shard_idx, pos)) {
+                    return (((shard_idx as u64) << SHARD_SHIFT) | pos, keys);
+                }
+                keys.push(key.clone());
+                last = Some((shard_idx, pos));
+            }
+            position = 0;
+        }
+        (0, keys)
+    }
+
     /// Return the number of keys in the database.
     pub fn len(&self) -> usize {
         let mut count = 0;
-        for shard in &self.shards {
+        for shard in self.shards() {
              let state = shard.read().unwrap();
              count += state.len();
         }
         count
     }
 
-    /// Clear the database.
-    pub fn clear(&self) {
-        for shard in &self.shards {
-            let mut state = shard.write().unwrap();
-            state.clear();
+    /// Return the number of keys in the database with an expiry.
+    pub fn volatile_len(&self) -> usize {
+        self.shards().iter().map(|shard| shard.read().unwrap().volatile_len()).sum()
+    }
+
+    /// Returns `true` if the database holds no keys.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Clear the database, returning what it held. The keys are gone as
+    /// soon as this returns, but their values are only freed once the
+    /// result is dropped.
+    pub fn clear(&self) -> Flushed {
+        Flushed { _shards: self.shards().iter().map(|shard| shard.write().unwrap().take_entries()).collect() }
+    }
+
+    /// Clear every database, not just the one this handle selects, like
+    /// `clear`.
+    pub fn clear_all(&self) -> Flushed {
+        let shards = self.databases.iter().flat_map(|keyspace| &keyspace.shards);
+        Flushed { _shards: shards.map(|shard| shard.write().unwrap().take_entries()).collect() }
+    }
+
+    /// Swap the contents of databases `a` and `b`, as SWAPDB does.
+    ///
+    /// Every shard of both databases is write-locked, in ascending
+    /// (database, shard) order, for the duration, so no client sees the swap
+    /// half done. Clients blocked on keys in either database are woken to
+    /// look again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `a` or `b` is not a valid database index.
+    pub fn swap_databases(&self, a: usize, b: usize) {
+        if a == b {
+            return;
         }
+        let lock = |db: usize| -> Vec<_> { self.databases[db].shards.iter().map(|shard| shard.write().unwrap()).collect() };
+        let mut first = lock(a.min(b));
+        let mut second = lock(a.max(b));
+        for (x, y) in first.iter_mut().zip(second.iter_mut()) {
+            x.swap_keys(y);
+        }
+        drop((first, second));
+        self.blocked.wake_all(a);
+        self.blocked.wake_all(b);
     }
 
     // --- Type Specific Operations (Atomic) ---
 
-    // Hash Operations
-    pub fn hset(&self, key: Bytes, field: Bytes, value: Bytes) -> usize {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        let entry = shard.entry(key).or_insert_with(|| DataType::Hash(AHashMap::new()));
-        
-        if let DataType::Hash(map) = entry {
-            map.insert(field, value);
-            self.increment_version(shard_idx);
-            1 
-        } else {
-            0 
+    /// Run `f` on the collection of type `T` at `key` while its shard is
+    /// locked, returning what `f` returned and whether the key was deleted
+    /// for being left empty. A missing or expired key returns `None` without
+    /// calling `f`.
+    ///
+    /// `f` also returns whether it changed the collection, and only then
+    /// does the key count as modified. A key holding another type fails
+    /// with `WrongType` before that, so a WATCH isn't disturbed and every
+    /// command reports the mismatch the same way.
+    fn with_typed_mut<T: Collection, R>(
+        &self,
+        key: &[u8],
+        f: impl FnOnce(&mut T) -> (R, bool),
+    ) -> Result<Option<(R, bool)>, WrongType> {
+        let mut shard = self.write_shard(key);
+        let Some(value) = shard.typed_mut::<T>(key)? else {
+            return Ok(None);
+        };
+        let (result, changed) = f(value);
+        let emptied = value.is_empty();
+        if changed {
+            shard.bump_version(key);
+        }
+        if emptied {
+            shard.remove(key);
+        }
+        Ok(Some((result, emptied)))
+    }
+
+    /// Like `with_typed_mut`, creating an empty collection at `key` first
+    /// if it's missing.
+    fn with_typed_or_insert<T: Collection, R>(
+        &self,
+        key: Bytes,
+        f: impl FnOnce(&mut T) -> (R, bool),
+    ) -> Result<(R, bool), WrongType> {
+        let mut shard = self.write_shard(&key);
+        let value = shard.typed_or_insert::<T>(key.clone())?;
+        let (result, changed) = f(value);
+        let emptied = value.is_empty();
+        if changed {
+            shard.bump_version(&key);
+        }
+        if emptied {
+            shard.remove(&key);
         }
+        Ok((result, emptied))
+    }
+
+    // Hash Operations
+    /// Set `fields` of the hash at `key` to their values, creating it if
+    /// missing, and return how many of them are new.
+    pub fn hset(&self, key: Bytes, fields: Vec<(Bytes, Bytes)>) -> Result<usize, WrongType> {
+        let (added, _) = self.with_typed_or_insert(key.clone(), |map: &mut AHashMap<Bytes, Bytes>| {
+            let mut added = 0;
+            for (field, value) in fields {
+                if map.insert(field, value).is_none() {
+                    added += 1;
+                }
+            }
+            (added, true)
+        })?;
+        self.notify(EventClass::Hash, "hset", &key);
+        Ok(added)
     }
 
     pub fn hget(&self, key: &[u8], field: &[u8]) -> Option<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+        let shard = self.read_shard(key);
         
         match shard.get(key) {
             Some(DataType::Hash(map)) => map.get(field).cloned(),
@@ -159,33 +2218,53 @@ impl Db {
         }
     }
 
-    pub fn hdel(&self, key: &[u8], field: &[u8]) -> usize {
-        let shard_idx = self.get_shard(key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        match shard.get_mut(key) {
-            Some(DataType::Hash(map)) => {
-                if map.remove(field).is_some() { 
-                    self.increment_version(shard_idx);
-                    1 
-                } else { 0 }
-            },
-            _ => 0,
+    /// Remove `fields` from the hash at `key` and return how many of them
+    /// existed.
+    ///
+    /// The key is deleted once its hash becomes empty.
+    pub fn hdel(&self, key: &[u8], fields: &[Bytes]) -> Result<usize, WrongType> {
+        let removed = self.with_typed_mut(key, |map: &mut AHashMap<Bytes, Bytes>| {
+            let removed = fields.iter().filter(|field| map.remove(*field).is_some()).count();
+            (removed, removed > 0)
+        })?;
+        let Some((removed, emptied)) = removed else {
+            return Ok(0);
+        };
+        if removed > 0 {
+            self.notify_emptied(EventClass::Hash, "hdel", key, emptied);
+        }
+        Ok(removed)
+    }
+
+    /// Remove `fields` from the hash at `key`, returning the value each one
+    /// had, or `None` where it was missing.
+    ///
+    /// The key is deleted once its hash becomes empty.
+    pub fn hgetdel(&self, key: &[u8], fields: &[Bytes]) -> Result<Vec<Option<Bytes>>, WrongType> {
+        let values = self.with_typed_mut(key, |map: &mut AHashMap<Bytes, Bytes>| {
+            let values: Vec<_> = fields.iter().map(|field| map.remove(field)).collect();
+            let changed = values.iter().any(Option::is_some);
+            (values, changed)
+        })?;
+        let Some((values, emptied)) = values else {
+            return Ok(vec![None; fields.len()]);
+        };
+        if values.iter().any(Option::is_some) {
+            self.notify_emptied(EventClass::Hash, "hgetdel", key, emptied);
         }
+        Ok(values)
     }
 
     pub fn hexists(&self, key: &[u8], field: &[u8]) -> usize {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+        let shard = self.read_shard(key);
          match shard.get(key) {
-            Some(DataType::Hash(map)) => if map.contains_key(field) { 1 } else { 0 },
+            Some(DataType::Hash(map)) if map.contains_key(field) => 1,
             _ => 0,
         }
     }
 
     pub fn hgetall(&self, key: &[u8]) -> Option<AHashMap<Bytes, Bytes>> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+        let shard = self.read_shard(key);
         match shard.get(key) {
              Some(DataType::Hash(map)) => Some(map.clone()),
              _ => None
@@ -193,8 +2272,7 @@ impl Db {
     }
     
     pub fn hkeys(&self, key: &[u8]) -> Vec<Bytes> {
-         let shard_idx = self.get_shard(key);
-         let shard = self.shards[shard_idx].read().unwrap();
+         let shard = self.read_shard(key);
          match shard.get(key) {
              Some(DataType::Hash(map)) => map.keys().cloned().collect(),
              _ => Vec::new(),
@@ -202,8 +2280,7 @@ impl Db {
     }
 
     pub fn hvals(&self, key: &[u8]) -> Vec<Bytes> {
-         let shard_idx = self.get_shard(key);
-         let shard = self.shards[shard_idx].read().unwrap();
+         let shard = self.read_shard(key);
          match shard.get(key) {
              Some(DataType::Hash(map)) => map.values().cloned().collect(),
              _ => Vec::new(),
@@ -211,8 +2288,7 @@ impl Db {
     }
     
     pub fn hlen(&self, key: &[u8]) -> usize {
-         let shard_idx = self.get_shard(key);
-         let shard = self.shards[shard_idx].read().unwrap();
+         let shard = self.read_shard(key);
          match shard.get(key) {
              Some(DataType::Hash(map)) => map.len(),
              _ => 0,
@@ -220,111 +2296,224 @@ impl Db {
     }
 
     // List Operations
-    pub fn lpush(&self, key: Bytes, value: Bytes) -> usize {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        let entry = shard.entry(key).or_insert_with(|| DataType::List(VecDeque::new()));
-        
-        if let DataType::List(list) = entry {
-            list.push_front(value);
-            self.increment_version(shard_idx);
-            list.len()
-        } else {
-            0
-        }
+
+    /// Push `values` onto the head of the list at `key` one after another,
+    /// creating it if missing, and return its length afterwards.
+    pub fn lpush(&self, key: Bytes, values: Vec<Bytes>) -> Result<usize, WrongType> {
+        self.push(key, values, VecDeque::push_front, "lpush")
     }
-    
-    pub fn rpush(&self, key: Bytes, value: Bytes) -> usize {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        let entry = shard.entry(key).or_insert_with(|| DataType::List(VecDeque::new()));
-        
-        if let DataType::List(list) = entry {
-            list.push_back(value);
-            self.increment_version(shard_idx);
-            list.len()
-        } else {
-            0
-        }
+
+    /// Push `values` onto the tail of the list at `key`, creating it if
+    /// missing, and return its length afterwards.
+    pub fn rpush(&self, key: Bytes, values: Vec<Bytes>) -> Result<usize, WrongType> {
+        self.push(key, values, VecDeque::push_back, "rpush")
     }
 
-    pub fn lpop(&self, key: &[u8]) -> Option<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        match shard.get_mut(key) {
-            Some(DataType::List(list)) => {
-                let ret = list.pop_front();
-                if ret.is_some() { self.increment_version(shard_idx); }
-                if list.is_empty() { shard.remove(key); }
-                ret
-            },
-            _ => None,
+    /// Push `values` onto the list at `key` with `push`, publishing `event`.
+    fn push(
+        &self,
+        key: Bytes,
+        values: Vec<Bytes>,
+        push: fn(&mut VecDeque<Bytes>, Bytes),
+        event: &str,
+    ) -> Result<usize, WrongType> {
+        let (len, _) = self.with_typed_or_insert(key.clone(), |list: &mut VecDeque<Bytes>| {
+            for value in values {
+                push(list, value);
+            }
+            (list.len(), true)
+        })?;
+        self.notify(EventClass::List, event, &key);
+        self.blocked.wake(self.index, &key);
+        Ok(len)
+    }
+
+    /// Remove and return the head of the list at `key`.
+    ///
+    /// The key is deleted once its list becomes empty.
+    pub fn lpop(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        self.pop(key, VecDeque::pop_front, "lpop")
+    }
+
+    /// Remove and return the tail of the list at `key`.
+    ///
+    /// The key is deleted once its list becomes empty.
+    pub fn rpop(&self, key: &[u8]) -> Result<Option<Bytes>, WrongType> {
+        self.pop(key, VecDeque::pop_back, "rpop")
+    }
+
+    /// Pop from the list at `key` with `pop`, publishing `event`.
+    fn pop(
+        &self,
+        key: &[u8],
+        pop: fn(&mut VecDeque<Bytes>) -> Option<Bytes>,
+        event: &str,
+    ) -> Result<Option<Bytes>, WrongType> {
+        let popped = self.with_typed_mut(key, |list: &mut VecDeque<Bytes>| {
+            let popped = pop(list);
+            let changed = popped.is_some();
+            (popped, changed)
+        })?;
+        let Some((popped, emptied)) = popped else {
+            return Ok(None);
+        };
+        if popped.is_some() {
+            self.notify_emptied(EventClass::List, event, key, emptied);
         }
+        Ok(popped)
     }
 
-    pub fn rpop(&self, key: &[u8]) -> Option<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        match shard.get_mut(key) {
-             Some(DataType::List(list)) => {
-                let ret = list.pop_back();
-                if ret.is_some() { self.increment_version(shard_idx); }
-                if list.is_empty() { shard.remove(key); }
-                ret
-             },
-             _ => None,
+    /// Remove and return up to `count` elements from the `end` of the list at
+    /// `key`, in the order they're popped.
+    ///
+    /// The key is deleted once its list becomes empty.
+    pub fn pop_count(&self, key: &[u8], end: ListEnd, count: usize) -> Result<Vec<Bytes>, WrongType> {
+        let popped = self.with_typed_mut(key, |list: &mut VecDeque<Bytes>| {
+            let popped: Vec<_> = std::iter::from_fn(|| end.pop(list)).take(count).collect();
+            let changed = !popped.is_empty();
+            (popped, changed)
+        })?;
+        let Some((popped, emptied)) = popped else {
+            return Ok(Vec::new());
+        };
+        if !popped.is_empty() {
+            self.notify_emptied(EventClass::List, end.events().0, key, emptied);
         }
+        Ok(popped)
     }
 
-    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Vec<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
-        
-        match shard.get(key) {
-            Some(DataType::List(list)) => {
-                let len = list.len() as i64;
-                if len == 0 { return Vec::new(); }
-                
-                let start = if start < 0 { len + start } else { start };
-                let stop = if stop < 0 { len + stop } else { stop };
-                
-                let start = start.max(0) as usize;
-                let stop = stop.min(len - 1) as usize;
-                
-                if start > stop || start >= list.len() {
-                    return Vec::new();
-                }
-                
-                list.iter().skip(start).take(stop - start + 1).cloned().collect()
-            },
-            _ => Vec::new(),
+    /// Pop up to `count` elements from the `end` of the first of `keys`
+    /// holding a non-empty list, like [`Db::pop_count`], returning that key
+    /// with them.
+    pub fn lmpop(&self, keys: &[Bytes], end: ListEnd, count: usize) -> Result<Option<Popped<Bytes>>, WrongType> {
+        first_popped(keys, |key| self.pop_count(key, end, count))
+    }
+
+    /// Atomically pop an element from the `from` end of the list at `src` and
+    /// push it onto the `to` end of the list at `dst`, creating it if
+    /// missing. Returns the element, or `None` if `src` doesn't exist.
+    ///
+    /// When `src` and `dst` are the same key the list is rotated. The source
+    /// key is deleted once its list becomes empty.
+    pub fn lmove(&self, src: &[u8], dst: Bytes, from: ListEnd, to: ListEnd) -> Result<Option<Bytes>, WrongType> {
+        let src_idx = self.get_shard(src);
+        let dst_idx = self.get_shard(&dst);
+        let mut shards = self.lock_pair(src_idx, dst_idx);
+
+        // Validate both keys before mutating anything
+        if let Some(value) = shards.get(dst_idx).get(&dst[..])
+            && !matches!(value, DataType::List(_))
+        {
+            return Err(WrongType);
         }
+        let src_shard = shards.get(src_idx);
+        let Some(list) = src_shard.typed_mut::<VecDeque<Bytes>>(src)? else {
+            return Ok(None);
+        };
+        let Some(value) = from.pop(list) else {
+            return Ok(None);
+        };
+        let rotate = src == &dst[..];
+        let emptied = list.is_empty() && !rotate;
+        if rotate {
+            to.push(list, value.clone());
+        }
+        src_shard.bump_version(src);
+        if !rotate {
+            if emptied {
+                src_shard.remove(src);
+            }
+            let dst_shard = shards.get(dst_idx);
+            if let DataType::List(list) = dst_shard.get_or_insert_with(dst.clone(), || DataType::List(VecDeque::new())) {
+                to.push(list, value.clone());
+            }
+            dst_shard.bump_version(&dst);
+        }
+        drop(shards);
+        self.notify_emptied(EventClass::List, from.events().0, src, emptied);
+        self.notify(EventClass::List, to.events().1, &dst);
+        self.blocked.wake(self.index, &dst);
+        Ok(Some(value))
+    }
+
+    /// Return the elements of the list at `key` between `start` and `stop`,
+    /// inclusive, counting negative indices from the end. Only the requested
+    /// slice is copied out of the list.
+    pub fn lrange(&self, key: &[u8], start: i64, stop: i64) -> Result<Vec<Bytes>, WrongType> {
+        self.read_value(key, |value| match value {
+            DataType::List(list) => Ok(match index_range(list.len(), start, stop) {
+                Some(range) => list.range(range).cloned().collect(),
+                None => Vec::new(),
+            }),
+            _ => Err(WrongType),
+        })
+        .unwrap_or(Ok(Vec::new()))
+    }
+
+    /// Return the indexes of the elements of the list at `key` equal to
+    /// `element`, as LPOS does.
+    ///
+    /// A positive `rank` skips the first `rank - 1` matches from the head, a
+    /// negative one scans from the tail instead. Up to `count` indexes are
+    /// returned, all of them if it's zero, and only the first `maxlen`
+    /// elements scanned are compared, all of them if it's zero.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `rank` is zero.
+    pub fn lpos(&self, key: &[u8], element: &[u8], rank: i64, count: usize, maxlen: usize) -> Result<Vec<usize>, WrongType> {
+        assert!(rank != 0, "LPOS ranks start at 1 or -1");
+        let skip = usize::try_from(rank.unsigned_abs() - 1).unwrap_or(usize::MAX);
+        let count = if count == 0 { usize::MAX } else { count };
+        self.read_value(key, |value| match value {
+            DataType::List(list) => {
+                let limit = if maxlen == 0 { list.len() } else { maxlen };
+                let matching = |(_, item): &(usize, &Bytes)| item[..] == *element;
+                let found = if rank > 0 {
+                    list.iter().enumerate().take(limit).filter(matching).skip(skip).take(count).map(|(i, _)| i).collect()
+                } else {
+                    list.iter().enumerate().rev().take(limit).filter(matching).skip(skip).take(count).map(|(i, _)| i).collect()
+                };
+                Ok(found)
+            }
+            _ => Err(WrongType),
+        })
+        .unwrap_or(Ok(Vec::new()))
+    }
+
+    /// Copy out the elements of the list, set or sorted set at `key`, for
+    /// SORT. A missing key has none.
+    pub fn sortable_elements(&self, key: &[u8]) -> Result<Vec<Bytes>, WrongType> {
+        self.read_value(key, |value| match value {
+            DataType::List(list) => Ok(list.iter().cloned().collect()),
+            DataType::Set(set) => Ok(set.iter().cloned().collect()),
+            DataType::ZSet(zset) => Ok(zset.keys().cloned().collect()),
+            _ => Err(WrongType),
+        })
+        .unwrap_or(Ok(Vec::new()))
     }
 
     // Set Operations
-    pub fn sadd(&self, key: Bytes, member: Bytes) -> usize {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        let entry = shard.entry(key).or_insert_with(|| DataType::Set(HashSet::new()));
-        
-        if let DataType::Set(set) = entry {
-            if set.insert(member) { 
-                self.increment_version(shard_idx);
-                1 
-            } else { 0 }
-        } else {
-            0
+    /// Add `members` to the set at `key`, creating it if missing, and
+    /// return how many of them are new.
+    pub fn sadd(&self, key: Bytes, members: Vec<Bytes>) -> Result<usize, WrongType> {
+        let (added, _) = self.with_typed_or_insert(key.clone(), |set: &mut SetValue| {
+            let mut added = 0;
+            for member in members {
+                if set.insert(member) {
+                    added += 1;
+                }
+            }
+            (added, added > 0)
+        })?;
+        if added > 0 {
+            self.notify(EventClass::Set, "sadd", &key);
         }
+        Ok(added)
     }
 
     pub fn smembers(&self, key: &[u8]) -> Vec<Bytes> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
+        let shard = self.read_shard(key);
         
         match shard.get(key) {
             Some(DataType::Set(set)) => set.iter().cloned().collect(),
@@ -332,75 +2521,453 @@ impl Db {
         }
     }
 
-    pub fn srem(&self, key: &[u8], member: &Bytes) -> usize {
-        let shard_idx = self.get_shard(key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        match shard.get_mut(key) {
-            Some(DataType::Set(set)) => {
-                let ret = if set.remove(member) { 1 } else { 0 };
-                if ret > 0 { self.increment_version(shard_idx); }
-                if set.is_empty() { shard.remove(key); }
-                ret
-            },
-             _ => 0,
+    /// Remove `members` from the set at `key` and return how many of them
+    /// were present.
+    ///
+    /// The key is deleted once its set becomes empty.
+    pub fn srem(&self, key: &[u8], members: &[Bytes]) -> Result<usize, WrongType> {
+        let removed = self.with_typed_mut(key, |set: &mut SetValue| {
+            let removed = members.iter().filter(|member| set.remove(member)).count();
+            (removed, removed > 0)
+        })?;
+        let Some((removed, emptied)) = removed else {
+            return Ok(0);
+        };
+        if removed > 0 {
+            self.notify_emptied(EventClass::Set, "srem", key, emptied);
+        }
+        Ok(removed)
+    }
+
+    /// Remove and return up to `count` random members of the set at `key`.
+    ///
+    /// The key is deleted once its set becomes empty.
+    pub fn spop(&self, key: &[u8], count: usize) -> Result<Vec<Bytes>, WrongType> {
+        let popped = self.with_typed_mut(key, |set: &mut SetValue| {
+            let popped: Vec<Bytes> = set.iter().cloned().choose_multiple(&mut rand::rng(), count.min(set.len()));
+            for member in &popped {
+                set.remove(member);
+            }
+            let changed = !popped.is_empty();
+            (popped, changed)
+        })?;
+        let Some((popped, emptied)) = popped else {
+            return Ok(Vec::new());
+        };
+        if !popped.is_empty() {
+            self.notify_emptied(EventClass::Set, "spop", key, emptied);
+        }
+        Ok(popped)
+    }
+
+    /// Return random members of the set at `key` without removing them.
+    ///
+    /// A non-negative `count` returns up to `count` distinct members. A
+    /// negative `count` returns exactly `-count` members sampled with
+    /// replacement, so members may repeat, up to [`MAX_RANDOM_REPEATS`].
+    pub fn srandmember(&self, key: &[u8], count: i64) -> Result<Vec<Bytes>, WrongType> {
+        let shard = self.read_shard(key);
+
+        let set = match shard.get(key) {
+            Some(DataType::Set(set)) => set,
+            Some(_) => return Err(WrongType),
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(random_picks(set.iter().cloned(), count, &mut rand::rng()))
+    }
+
+    /// Return random fields of the hash at `key`, with their values.
+    ///
+    /// `count` works like in [`Db::srandmember`]. The picks are drawn from
+    /// `rng`, so a seeded one makes them reproducible.
+    pub fn hrandfield(&self, key: &[u8], count: i64, rng: &mut impl Rng) -> Result<Vec<(Bytes, Bytes)>, WrongType> {
+        let shard = self.read_shard(key);
+
+        match shard.get(key) {
+            Some(DataType::Hash(map)) => Ok(random_picks(map.iter().map(|(k, v)| (k.clone(), v.clone())), count, rng)),
+            Some(_) => Err(WrongType),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Return random members of the sorted set at `key`, with their scores.
+    ///
+    /// `count` works like in [`Db::srandmember`]. The picks are drawn from
+    /// `rng`, so a seeded one makes them reproducible.
+    pub fn zrandmember(&self, key: &[u8], count: i64, rng: &mut impl Rng) -> Result<Vec<(Bytes, f64)>, WrongType> {
+        let shard = self.read_shard(key);
+
+        match shard.get(key) {
+            Some(DataType::ZSet(scores)) => Ok(random_picks(scores.iter().map(|(m, s)| (m.clone(), *s)), count, rng)),
+            Some(_) => Err(WrongType),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Atomically move `member` from the set at `src` to the set at `dst`.
+    ///
+    /// Returns `true` if the member was moved (or was already present when
+    /// `src` and `dst` are the same key), and `false` if it wasn't a member
+    /// of `src`. The source key is deleted once its set becomes empty.
+    pub fn smove(&self, src: &[u8], dst: Bytes, member: Bytes) -> Result<bool, WrongType> {
+        let src_idx = self.get_shard(src);
+        let dst_idx = self.get_shard(&dst);
+        let mut shards = self.lock_pair(src_idx, dst_idx);
+
+        // Validate both keys before mutating anything
+        if let Some(value) = shards.get(dst_idx).get(&dst[..])
+            && !matches!(value, DataType::Set(_))
+        {
+            return Err(WrongType);
+        }
+        let src_shard = shards.get(src_idx);
+        let Some(set) = src_shard.typed_mut::<SetValue>(src)? else {
+            return Ok(false);
+        };
+        if src == &dst[..] {
+            return Ok(set.contains(&member));
+        }
+        if !set.remove(&member) {
+            return Ok(false);
+        }
+        let emptied = set.is_empty();
+        src_shard.bump_version(src);
+        if emptied {
+            src_shard.remove(src);
+        }
+
+        let dst_shard = shards.get(dst_idx);
+        if let DataType::Set(set) = dst_shard.get_or_insert_with(dst.clone(), || DataType::Set(SetValue::new()))
+            && set.insert(member)
+        {
+            dst_shard.bump_version(&dst);
+        }
+        drop(shards);
+        self.notify_emptied(EventClass::Set, "srem", src, emptied);
+        self.notify(EventClass::Set, "sadd", &dst);
+        Ok(true)
+    }
+
+    /// Combine the sets stored at `keys` using `op`.
+    ///
+    /// Missing keys are treated as empty sets. Each shard is only locked
+    /// while its set is read, the result is computed afterwards.
+    pub fn set_op(&self, op: SetOp, keys: &[Bytes]) -> Result<HashSet<Bytes>, WrongType> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            let shard = self.read_shard(key);
+            match shard.get(key) {
+                Some(DataType::Set(set)) => sets.push(HashSet::clone(set)),
+                Some(_) => return Err(WrongType),
+                None => sets.push(HashSet::new()),
+            }
+        }
+
+        let mut sets = sets.into_iter();
+        let Some(mut result) = sets.next() else {
+            return Ok(HashSet::new());
+        };
+        for set in sets {
+            match op {
+                SetOp::Inter => result.retain(|m| set.contains(m)),
+                SetOp::Union => result.extend(set),
+                SetOp::Diff => result.retain(|m| !set.contains(m)),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Count the members the sets at `keys` have in common, stopping once
+    /// `limit` are found unless it's zero. Missing keys are treated as empty
+    /// sets.
+    ///
+    /// The shards holding the keys are read-locked together, in ascending
+    /// order, so the smallest set can be checked against the others in place
+    /// rather than copying them out.
+    pub fn set_inter_card(&self, keys: &[Bytes], limit: usize) -> Result<usize, WrongType> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| self.get_shard(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        let shards: Vec<_> = indexes.iter().map(|&idx| self.shards()[idx].read().unwrap()).collect();
+
+        let mut sets = Vec::with_capacity(keys.len());
+        let mut missing = false;
+        for key in keys {
+            let Ok(pos) = indexes.binary_search(&self.get_shard(key)) else {
+                unreachable!("every key's shard is locked");
+            };
+            match shards[pos].get(key) {
+                Some(DataType::Set(set)) => sets.push(set),
+                Some(_) => return Err(WrongType),
+                None => missing = true,
+            }
+        }
+        if missing {
+            return Ok(0);
+        }
+
+        sets.sort_unstable_by_key(|set| set.len());
+        let Some((smallest, others)) = sets.split_first() else {
+            return Ok(0);
+        };
+        let common = smallest.iter().filter(|member| others.iter().all(|set| set.contains(*member)));
+        Ok(if limit == 0 { common.count() } else { common.take(limit).count() })
+    }
+
+    /// Combine the sets stored at `keys` using `op` and store the result at
+    /// `dest`, returning its cardinality.
+    ///
+    /// The full result is computed before the destination shard is locked,
+    /// so readers never observe a partially written destination. An empty
+    /// result deletes `dest`.
+    pub fn set_op_store(&self, op: SetOp, dest: Bytes, keys: &[Bytes]) -> Result<usize, WrongType> {
+        let result = self.set_op(op, keys)?;
+        let len = result.len();
+
+        let mut shard = self.write_shard(&dest);
+        if result.is_empty() {
+            let deleted = shard.remove(&dest).is_some();
+            drop(shard);
+            if deleted {
+                self.notify(EventClass::Generic, "del", &dest);
+            }
+        } else {
+            shard.insert(dest.clone(), DataType::Set(result.into()));
+            drop(shard);
+            let event = match op {
+                SetOp::Inter => "sinterstore",
+                SetOp::Union => "sunionstore",
+                SetOp::Diff => "sdiffstore",
+            };
+            self.notify(EventClass::Set, event, &dest);
         }
+        Ok(len)
     }
 
     // ZSet Operations
+
+    /// Add `member` to the sorted set at `key` with `score`, or update its
+    /// score, returning 1 if it was added. A key of another type is left
+    /// alone.
     pub fn zadd(&self, key: Bytes, score: f64, member: Bytes) -> usize {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
-        
-        let entry = shard.entry(key).or_insert_with(|| DataType::ZSet(AHashMap::new()));
-        
-        if let DataType::ZSet(scores) = entry {
-            let ret = scores.insert(member, score);
-            self.increment_version(shard_idx);
-            if ret.is_none() { 1 } else { 0 }
+        self.zadd_members(key, vec![(score, member)], ZAddFlags::default()).map_or(0, |(added, _)| added)
+    }
+
+    /// Add each of `members` to the sorted set at `key` with its score, or
+    /// update the score of those already there, as `flags` allow. The set is
+    /// created if missing and anything gets added.
+    ///
+    /// Returns how many members were added, and how many existing ones had
+    /// their score changed. Scores must not be NaN.
+    pub fn zadd_members(&self, key: Bytes, members: Vec<(f64, Bytes)>, flags: ZAddFlags) -> Result<(usize, usize), WrongType> {
+        let update = |scores: &mut AHashMap<Bytes, f64>| {
+            let (mut added, mut changed) = (0, 0);
+            for (score, member) in members {
+                match scores.get_mut(&member) {
+                    Some(current) => {
+                        let allowed = !flags.nx && (!flags.gt || score > *current) && (!flags.lt || score < *current);
+                        if allowed && score != *current {
+                            *current = score;
+                            changed += 1;
+                        }
+                    }
+                    None if !flags.xx => {
+                        scores.insert(member, score);
+                        added += 1;
+                    }
+                    None => {}
+                }
+            }
+            ((added, changed), added + changed > 0)
+        };
+        // XX never creates the set
+        let (added, changed) = if flags.xx {
+            self.with_typed_mut(&key, update)?.map_or((0, 0), |(counts, _)| counts)
         } else {
-            0
+            self.with_typed_or_insert(key.clone(), update)?.0
+        };
+        if added + changed > 0 {
+            self.notify(EventClass::ZSet, "zadd", &key);
+        }
+        if added > 0 {
+            self.blocked.wake(self.index, &key);
+        }
+        Ok((added, changed))
+    }
+
+    /// Add `increment` to the score of `member` in the sorted set at `key`,
+    /// adding it with that score if missing, and return the new score. The
+    /// set is left alone if the score would become NaN.
+    pub fn zincrby(&self, key: Bytes, increment: f64, member: Bytes) -> Result<f64, ScoreError> {
+        let mut shard = self.write_shard(&key);
+
+        // Checked before anything counts as a modification, which a NaN
+        // score isn't
+        shard.check_type(&key, |value| matches!(value, DataType::ZSet(_)))?;
+        let current = match shard.peek(&key) {
+            Some(DataType::ZSet(scores)) => scores.get(&member).copied().unwrap_or(0.0),
+            _ => 0.0,
+        };
+        let score = current + increment;
+        if score.is_nan() {
+            return Err(ScoreError::NotANumber);
         }
+        shard.typed_or_insert::<AHashMap<Bytes, f64>>(key.clone())?.insert(member, score);
+        shard.bump_version(&key);
+        drop(shard);
+        self.notify(EventClass::ZSet, "zincr", &key);
+        self.blocked.wake(self.index, &key);
+        Ok(score)
     }
 
+    /// The members ranked `start` through `stop` in the sorted set at `key`,
+    /// with their scores, as [`Db::zrange_by`] picks them. A key of another
+    /// type has none.
     pub fn zrange(&self, key: &[u8], start: i64, stop: i64, _with_scores: bool) -> Vec<(Bytes, f64)> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
-        
+        let spec = ZRangeSpec { by: ZRangeBy::Rank(start, stop), rev: false, limit: None };
+        self.zrange_by(key, &spec).unwrap_or_default()
+    }
+
+    /// The members of the sorted set at `key` that `spec` picks, with their
+    /// scores, in order.
+    pub fn zrange_by(&self, key: &[u8], spec: &ZRangeSpec) -> Result<Vec<(Bytes, f64)>, WrongType> {
+        let shard = self.read_shard(key);
         match shard.get(key) {
             Some(DataType::ZSet(scores)) => {
-                 let mut sorted: Vec<(&Bytes, &f64)> = scores.iter().collect();
-                 sorted.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal));
-                 
-                 let len = sorted.len() as i64;
-                 if len == 0 { return Vec::new(); }
-
-                 let start = if start < 0 { len + start } else { start };
-                 let stop = if stop < 0 { len + stop } else { stop };
-                 
-                 let start = start.max(0) as usize;
-                 let stop = stop.min(len - 1) as usize;
-                 
-                 if start > stop || start >= sorted.len() {
-                     return Vec::new();
-                 }
-                 
-                 sorted[start..=stop].iter().map(|(k, v)| ((*k).clone(), **v)).collect()
-            },
-            _ => Vec::new(),
+                Ok(zrange_select(scores, spec).into_iter().map(|(member, score)| (member.clone(), score)).collect())
+            }
+            Some(_) => Err(WrongType),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Store the members of the sorted set at `src` that `spec` picks, with
+    /// their scores, as a new sorted set at `dest`, replacing whatever was
+    /// there, and return how many there are. `dest` is deleted instead when
+    /// none are picked.
+    pub fn zrange_store(&self, dest: Bytes, src: &[u8], spec: &ZRangeSpec) -> Result<usize, WrongType> {
+        let src_idx = self.get_shard(src);
+        let dst_idx = self.get_shard(&dest);
+        let mut shards = self.lock_pair(src_idx, dst_idx);
+
+        let picked: AHashMap<Bytes, f64> = match shards.get(src_idx).get(src) {
+            Some(DataType::ZSet(scores)) => {
+                zrange_select(scores, spec).into_iter().map(|(member, score)| (member.clone(), score)).collect()
+            }
+            Some(_) => return Err(WrongType),
+            None => AHashMap::new(),
+        };
+        let len = picked.len();
+        let dst_shard = shards.get(dst_idx);
+        if picked.is_empty() {
+            let deleted = dst_shard.remove(&dest).is_some();
+            drop(shards);
+            if deleted {
+                self.notify(EventClass::Generic, "del", &dest);
+            }
+        } else {
+            dst_shard.insert(dest.clone(), DataType::ZSet(picked));
+            drop(shards);
+            self.notify(EventClass::ZSet, "zrangestore", &dest);
+            self.blocked.wake(self.index, &dest);
+        }
+        Ok(len)
+    }
+
+    /// Remove and return up to `count` members, with their scores, from the
+    /// `end` of the sorted set at `key`, lowest or highest first as `end`
+    /// has it.
+    ///
+    /// The key is deleted once its set becomes empty.
+    pub fn zpop(&self, key: &[u8], end: ZSetEnd, count: usize) -> Result<Vec<(Bytes, f64)>, WrongType> {
+        let popped = self.with_typed_mut(key, |scores: &mut AHashMap<Bytes, f64>| {
+            let mut sorted: Vec<(&Bytes, f64)> = scores.iter().map(|(member, score)| (member, *score)).collect();
+            sorted.sort_by(|a, b| zset_order(*a, *b));
+            if end == ZSetEnd::Max {
+                sorted.reverse();
+            }
+            let popped: Vec<(Bytes, f64)> = sorted.into_iter().take(count).map(|(member, score)| (member.clone(), score)).collect();
+            for (member, _) in &popped {
+                scores.remove(member);
+            }
+            let changed = !popped.is_empty();
+            (popped, changed)
+        })?;
+        let Some((popped, emptied)) = popped else {
+            return Ok(Vec::new());
+        };
+        if !popped.is_empty() {
+            let event = match end {
+                ZSetEnd::Min => "zpopmin",
+                ZSetEnd::Max => "zpopmax",
+            };
+            self.notify_emptied(EventClass::ZSet, event, key, emptied);
+        }
+        Ok(popped)
+    }
+
+    /// Pop up to `count` members from the `end` of the first of `keys`
+    /// holding a non-empty sorted set, like [`Db::zpop`], returning that key
+    /// with them.
+    pub fn zmpop(&self, keys: &[Bytes], end: ZSetEnd, count: usize) -> Result<Option<Popped<(Bytes, f64)>>, WrongType> {
+        first_popped(keys, |key| self.zpop(key, end, count))
+    }
+
+    /// How many members of the sorted set at `key` are between `min` and
+    /// `max`, counted like ZRANGEBYLEX picks them.
+    pub fn zlexcount(&self, key: &[u8], min: &LexBound, max: &LexBound) -> Result<usize, WrongType> {
+        let spec = ZRangeSpec { by: ZRangeBy::Lex(min.clone(), max.clone()), rev: false, limit: None };
+        let shard = self.read_shard(key);
+        match shard.get(key) {
+            Some(DataType::ZSet(scores)) => Ok(zrange_select(scores, &spec).len()),
+            Some(_) => Err(WrongType),
+            None => Ok(0),
+        }
+    }
+
+    /// Run `f` on the JSON document at `key` while its shard is locked.
+    ///
+    /// `f` receives `None` when the key doesn't exist. Whatever `f` leaves
+    /// behind is stored back: a document is (re)inserted and `None` deletes
+    /// the key. `f` also returns whether it changed the document, and only
+    /// then does the key count as modified.
+    pub fn update_json<R>(
+        &self,
+        key: Bytes,
+        f: impl FnOnce(&mut Option<serde_json::Value>) -> (R, bool),
+    ) -> Result<R, WrongType> {
+        let mut shard = self.write_shard(&key);
+
+        shard.check_type(&key, |value| matches!(value, DataType::Json(_)))?;
+        let mut doc = match shard.get_mut(&key) {
+            Some(DataType::Json(value)) => Some(std::mem::take(value)),
+            _ => None,
+        };
+        let (result, changed) = f(&mut doc);
+        if changed {
+            shard.bump_version(&key);
+        }
+        match doc {
+            // Written back in place, so the key keeps its expiry
+            Some(value) => *shard.get_or_insert_with(key, || DataType::Json(Default::default())) = DataType::Json(value),
+            None => {
+                shard.remove(&key);
+            }
         }
+        Ok(result)
     }
 
-    pub fn get_value_clone(&self, key: &[u8]) -> Option<DataType> {
-        let shard_idx = self.get_shard(key);
-        let shard = self.shards[shard_idx].read().unwrap();
-        shard.get(key).cloned()
+    /// Run `f` on the value at `key` while its shard is read-locked, so
+    /// replies can be built from large values without cloning them. Returns
+    /// `None` if the key doesn't exist.
+    pub fn read_value<R>(&self, key: &[u8], f: impl FnOnce(&DataType) -> R) -> Option<R> {
+        let shard = self.read_shard(key);
+        shard.get(key).map(f)
     }
     
     pub fn set_value(&self, key: Bytes, value: DataType) {
-        let shard_idx = self.get_shard(&key);
-        let mut shard = self.shards[shard_idx].write().unwrap();
+        let mut shard = self.write_shard(&key);
         shard.insert(key, value);
-        self.increment_version(shard_idx);
     }
 }
@@ -0,0 +1,85 @@
+/// Returns `true` if `string` matches the glob-style `pattern` as a whole.
+///
+/// Supports the same syntax as Redis `KEYS`:
+///
+/// * `*` matches any sequence of bytes, including an empty one
+/// * `?` matches exactly one byte
+/// * `[abc]`, `[a-z]` and `[^a-z]` match one byte in, or not in, a class
+/// * `\x` matches `x` literally
+///
+/// Matching works on raw bytes, so keys need not be valid UTF-8.
+pub fn matches(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+    // Position just after the last `*` seen and the string position it
+    // currently stands in for, so a failed match can let it swallow one more
+    // byte and retry.
+    let mut star: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                p += 1;
+                star = Some((p, s));
+                continue;
+            }
+            if let Some(next) = match_one(pattern, p, string[s]) {
+                p = next;
+                s += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((after_star, matched)) => {
+                p = after_star;
+                s = matched + 1;
+                star = Some((after_star, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match the single token starting at `pattern[p]` against `c`, returning
+/// the index just past the token if it matches.
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b'[' => match_class(pattern, p + 1, c),
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+/// Match a `[...]` class whose body starts at `pattern[p]`. An unterminated
+/// class extends to the end of the pattern.
+fn match_class(pattern: &[u8], mut p: usize, c: u8) -> Option<usize> {
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            matched |= (lo..=hi).contains(&c);
+            p += 3;
+        } else {
+            matched |= pattern[p] == c;
+            p += 1;
+        }
+    }
+
+    let next = if p < pattern.len() { p + 1 } else { p };
+    (matched != negate).then_some(next)
+}
@@ -0,0 +1,200 @@
+use serde_json::Value;
+use std::fmt;
+
+/// One step of a path into a JSON document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// A member of an object.
+    Key(String),
+    /// An element of an array, negative indexes count from the end.
+    Index(i64),
+}
+
+/// Error returned when a JSON path can't be parsed or applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// The path isn't valid syntax.
+    Invalid,
+    /// The path steps into a value that isn't a matching object or array.
+    NotContainer,
+    /// The path indexes past the end of an array.
+    IndexOutOfRange,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Invalid => "ERR invalid JSON path".fmt(fmt),
+            PathError::NotContainer => "ERR path traverses a value that is not an object or array".fmt(fmt),
+            PathError::IndexOutOfRange => "ERR array index out of range".fmt(fmt),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+/// Parse a path into its segments. The root is the empty path.
+///
+/// Accepts JSONPath style paths (`$`, `$.a.b`, `$.list[0]`, `$["a key"]`)
+/// as well as the legacy forms (`.`, `.a.b`, `a.b`).
+pub fn parse_path(path: &str) -> Result<Vec<Segment>, PathError> {
+    let mut rest = path.strip_prefix('$').unwrap_or(path);
+    if rest == "." {
+        return Ok(Vec::new());
+    }
+    // The legacy syntax allows the first key without a leading dot
+    let mut segments = Vec::new();
+    if !rest.is_empty() && !rest.starts_with(['.', '[']) {
+        let end = rest.find(['.', '[']).unwrap_or(rest.len());
+        segments.push(Segment::Key(rest[..end].to_string()));
+        rest = &rest[end..];
+    }
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            if end == 0 {
+                return Err(PathError::Invalid);
+            }
+            segments.push(Segment::Key(after[..end].to_string()));
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']').ok_or(PathError::Invalid)?;
+            let inner = after[..end].trim();
+            let quoted = ['"', '\''].iter().find_map(|&q| {
+                inner.strip_prefix(q).and_then(|s| s.strip_suffix(q))
+            });
+            let segment = match quoted {
+                Some(key) => Segment::Key(key.to_string()),
+                _ => Segment::Index(inner.parse().map_err(|_| PathError::Invalid)?),
+            };
+            segments.push(segment);
+            rest = &after[end + 1..];
+        } else {
+            return Err(PathError::Invalid);
+        }
+    }
+    Ok(segments)
+}
+
+/// Resolve a possibly negative `index` into an array of length `len`.
+fn resolve_index(len: usize, index: i64) -> Option<usize> {
+    let index = if index < 0 { len as i64 + index } else { index };
+    usize::try_from(index).ok().filter(|&i| i < len)
+}
+
+/// The value at `path`, if there is one.
+pub fn get<'a>(mut value: &'a Value, path: &[Segment]) -> Option<&'a Value> {
+    for segment in path {
+        value = match (value, segment) {
+            (Value::Object(map), Segment::Key(key)) => map.get(key)?,
+            (Value::Array(list), Segment::Index(i)) => list.get(resolve_index(list.len(), *i)?)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// The value at `path`, if there is one, for modification.
+pub fn get_mut<'a>(mut value: &'a mut Value, path: &[Segment]) -> Option<&'a mut Value> {
+    for segment in path {
+        value = match (value, segment) {
+            (Value::Object(map), Segment::Key(key)) => map.get_mut(key)?,
+            (Value::Array(list), Segment::Index(i)) => {
+                let i = resolve_index(list.len(), *i)?;
+                list.get_mut(i)?
+            }
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Set the value at `path` to `new`.
+///
+/// Missing object members along the way are created as empty objects.
+/// Array elements must already exist. On error `root` is left unchanged.
+pub fn set(root: &mut Value, path: &[Segment], new: Value) -> Result<(), PathError> {
+    check_settable(root, path)?;
+    let Some((last, parents)) = path.split_last() else {
+        *root = new;
+        return Ok(());
+    };
+
+    let mut value = root;
+    for segment in parents {
+        value = match (value, segment) {
+            (Value::Object(map), Segment::Key(key)) => {
+                map.entry(key.clone()).or_insert_with(|| Value::Object(Default::default()))
+            }
+            (Value::Array(list), Segment::Index(i)) => {
+                let i = resolve_index(list.len(), *i).ok_or(PathError::IndexOutOfRange)?;
+                &mut list[i]
+            }
+            _ => return Err(PathError::NotContainer),
+        };
+    }
+
+    match (value, last) {
+        (Value::Object(map), Segment::Key(key)) => {
+            map.insert(key.clone(), new);
+        }
+        (Value::Array(list), Segment::Index(i)) => {
+            let i = resolve_index(list.len(), *i).ok_or(PathError::IndexOutOfRange)?;
+            list[i] = new;
+        }
+        _ => return Err(PathError::NotContainer),
+    }
+    Ok(())
+}
+
+/// Remove the value at `path` from its parent, returning whether it existed.
+///
+/// The root can't be removed this way; callers delete the whole key instead.
+pub fn delete(root: &mut Value, path: &[Segment]) -> bool {
+    let Some((last, parents)) = path.split_last() else {
+        return false;
+    };
+    match (get_mut(root, parents), last) {
+        (Some(Value::Object(map)), Segment::Key(key)) => map.remove(key).is_some(),
+        (Some(Value::Array(list)), Segment::Index(i)) => match resolve_index(list.len(), *i) {
+            Some(i) => {
+                list.remove(i);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+/// The name `JSON.TYPE` reports for `value`.
+pub fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Check that `set` can succeed before it starts creating members.
+fn check_settable(root: &Value, path: &[Segment]) -> Result<(), PathError> {
+    // `None` once the path has left the existing document, where only new
+    // object members can follow
+    let mut value = Some(root);
+    for segment in path {
+        value = match (value, segment) {
+            (None, Segment::Key(_)) => None,
+            (Some(Value::Object(map)), Segment::Key(key)) => map.get(key),
+            (Some(Value::Array(list)), Segment::Index(i)) => {
+                Some(&list[resolve_index(list.len(), *i).ok_or(PathError::IndexOutOfRange)?])
+            }
+            _ => return Err(PathError::NotContainer),
+        };
+    }
+    Ok(())
+}
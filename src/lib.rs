@@ -1,14 +1,33 @@
+pub mod acl;
+pub mod aof;
+pub mod bits;
+pub mod blocking;
+pub mod client;
+pub mod clients;
+pub mod clock;
 pub mod cmd;
+pub mod config;
 pub mod connection;
 pub mod db;
+pub mod glob;
+pub mod json;
+pub mod notify;
+pub mod parse_num;
+pub mod persistence;
 pub mod protocol;
+pub mod pubsub;
+pub mod replication;
 pub mod server;
+mod shutdown;
+pub mod stats;
 
+pub use client::{Client, Pipeline, Subscriber};
 pub use cmd::Command;
+pub use config::ServerConfig;
 pub use connection::Connection;
 pub use db::Db;
-pub use protocol::Frame;
-pub use server::run;
+pub use protocol::{Frame, Protocol};
+pub use server::{run, run_with_config};
 
 /// A specialized `Result` type for this crate.
 pub type Result<T> = std::result::Result<T, Error>;
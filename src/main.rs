@@ -3,6 +3,7 @@ use mimalloc::MiMalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+use rustbucket::ServerConfig;
 use tokio::net::TcpListener;
 use tracing::{info, Level};
 use tracing_subscriber::FmtSubscriber;
@@ -17,10 +18,15 @@ async fn main() -> rustbucket::Result<()> {
     tracing::subscriber::set_global_default(subscriber)
         .expect("setting default subscriber failed");
 
+    let config = ServerConfig {
+        snapshot_path: Some("dump.rdb".into()),
+        ..ServerConfig::default()
+    };
+
     // Bind the listener to the address
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
+    let listener = TcpListener::bind(&config.bind).await?;
 
-    info!("Listening on 127.0.0.1:6379");
+    info!("Listening on {}", config.bind);
 
-    rustbucket::run(listener).await
+    rustbucket::run_with_config(listener, config, tokio::signal::ctrl_c()).await
 }
@@ -0,0 +1,139 @@
+use crate::config::RuntimeConfig;
+use crate::pubsub::PubSub;
+use bytes::Bytes;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+
+/// Classes of keyspace events, each selected by a letter of
+/// `notify-keyspace-events`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// Commands that work on any type, like `DEL` and `RENAME`.
+    Generic,
+    String,
+    List,
+    Set,
+    Hash,
+    ZSet,
+    /// Keys removed because their expiry passed.
+    Expired,
+    /// Keys removed to stay under `maxmemory`.
+    Evicted,
+}
+
+impl EventClass {
+    const ALL: [EventClass; 8] = [
+        EventClass::Generic,
+        EventClass::String,
+        EventClass::List,
+        EventClass::Set,
+        EventClass::Hash,
+        EventClass::ZSet,
+        EventClass::Expired,
+        EventClass::Evicted,
+    ];
+
+    /// The letter selecting this class.
+    fn flag(self) -> char {
+        match self {
+            EventClass::Generic => 'g',
+            EventClass::String => '$',
+            EventClass::List => 'l',
+            EventClass::Set => 's',
+            EventClass::Hash => 'h',
+            EventClass::ZSet => 'z',
+            EventClass::Expired => 'x',
+            EventClass::Evicted => 'e',
+        }
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+// Every event class, what the `A` flag selects
+const ALL_CLASSES: u16 = (1 << EventClass::ALL.len()) - 1;
+// Publish to `__keyspace@<db>__:<key>`, the `K` flag
+const KEYSPACE: u16 = 1 << 8;
+// Publish to `__keyevent@<db>__:<event>`, the `E` flag
+const KEYEVENT: u16 = 1 << 9;
+
+/// Which keyspace notifications are published, as selected with
+/// `notify-keyspace-events`. Nothing is published by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyspaceEvents(u16);
+
+impl KeyspaceEvents {
+    /// Parse flags like `KEA`, or return `None` if one of them is unknown.
+    pub fn parse(flags: &str) -> Option<KeyspaceEvents> {
+        let mut bits = 0;
+        for flag in flags.chars() {
+            bits |= match flag {
+                'K' => KEYSPACE,
+                'E' => KEYEVENT,
+                'A' => ALL_CLASSES,
+                flag => EventClass::ALL.into_iter().find(|class| class.flag() == flag)?.bit(),
+            };
+        }
+        Some(KeyspaceEvents(bits))
+    }
+
+    /// Whether events of `class` are published to any channel.
+    pub fn publishes(self, class: EventClass) -> bool {
+        self.0 & class.bit() != 0 && self.0 & (KEYSPACE | KEYEVENT) != 0
+    }
+}
+
+/// Formats the flags the way `CONFIG GET` reports them, with `A` standing
+/// for every class.
+impl fmt::Display for KeyspaceEvents {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 & ALL_CLASSES == ALL_CLASSES {
+            fmt.write_str("A")?;
+        } else {
+            for class in EventClass::ALL.into_iter().filter(|class| self.0 & class.bit() != 0) {
+                write!(fmt, "{}", class.flag())?;
+            }
+        }
+        if self.0 & KEYSPACE != 0 {
+            fmt.write_str("K")?;
+        }
+        if self.0 & KEYEVENT != 0 {
+            fmt.write_str("E")?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes keyspace notifications for every database, shared by all `Db`
+/// handles and their shards.
+#[derive(Debug)]
+pub(crate) struct Notifier {
+    pubsub: Arc<PubSub>,
+    config: Arc<RwLock<RuntimeConfig>>,
+}
+
+impl Notifier {
+    pub(crate) fn new(pubsub: Arc<PubSub>, config: Arc<RwLock<RuntimeConfig>>) -> Notifier {
+        Notifier { pubsub, config }
+    }
+
+    /// Publish that `event`, of `class`, happened to `key` in database
+    /// `db`, if `notify-keyspace-events` selects it.
+    pub(crate) fn notify(&self, db: usize, class: EventClass, event: &str, key: &[u8]) {
+        let events = self.config.read().unwrap().notify_keyspace_events;
+        if !events.publishes(class) {
+            return;
+        }
+        if events.0 & KEYSPACE != 0 {
+            let mut channel = format!("__keyspace@{}__:", db).into_bytes();
+            channel.extend_from_slice(key);
+            self.pubsub.publish(Bytes::from(channel), Bytes::copy_from_slice(event.as_bytes()));
+        }
+        if events.0 & KEYEVENT != 0 {
+            let channel = format!("__keyevent@{}__:{}", db, event);
+            self.pubsub.publish(Bytes::from(channel), Bytes::copy_from_slice(key));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Parsing of numeric command arguments, failing with the errors Redis
+//! replies with for each kind of number.
+
+/// Error for an argument that isn't a 64-bit integer.
+pub const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+
+/// Error for a negative argument where only positive ones make sense.
+pub const NOT_POSITIVE: &str = "ERR value is out of range, must be positive";
+
+/// Error for an argument that isn't a float.
+pub const NOT_A_FLOAT: &str = "ERR value is not a valid float";
+
+/// Parse a 64-bit signed integer. Only the canonical form is accepted: no
+/// `+` sign, leading zeros or surrounding spaces, so every integer has
+/// exactly one spelling, like Redis.
+pub fn parse_int(arg: &[u8]) -> Result<i64, &'static str> {
+    if !is_canonical(arg) {
+        return Err(NOT_AN_INTEGER);
+    }
+    std::str::from_utf8(arg).ok().and_then(|arg| arg.parse().ok()).ok_or(NOT_AN_INTEGER)
+}
+
+/// Parse a 64-bit unsigned integer, in the canonical form like
+/// [`parse_int`].
+pub fn parse_uint(arg: &[u8]) -> Result<u64, &'static str> {
+    if arg.starts_with(b"-") {
+        // Still an integer, just not one allowed here
+        return Err(parse_int(arg).map_or(NOT_AN_INTEGER, |_| NOT_POSITIVE));
+    }
+    if !is_canonical(arg) {
+        return Err(NOT_AN_INTEGER);
+    }
+    std::str::from_utf8(arg).ok().and_then(|arg| arg.parse().ok()).ok_or(NOT_AN_INTEGER)
+}
+
+/// Whether `arg` is shaped like a canonical integer: an optional `-`, then
+/// digits without leading zeros. Zero itself is only ever `0`.
+fn is_canonical(arg: &[u8]) -> bool {
+    let digits = arg.strip_prefix(b"-").unwrap_or(arg);
+    match digits {
+        [b'0'] => arg.len() == 1,
+        [b'1'..=b'9', rest @ ..] => rest.iter().all(u8::is_ascii_digit),
+        _ => false,
+    }
+}
+
+/// Parse a double precision float. `inf` and `-inf` may be spelled out,
+/// but NaN and numbers too large to represent are rejected.
+pub fn parse_float(arg: &[u8]) -> Result<f64, &'static str> {
+    let text = std::str::from_utf8(arg).map_err(|_| NOT_A_FLOAT)?;
+    let value: f64 = text.parse().map_err(|_| NOT_A_FLOAT)?;
+    if value.is_nan() {
+        return Err(NOT_A_FLOAT);
+    }
+    // Overflowing to infinity is out of range, unlike asking for it
+    if value.is_infinite() && !text.trim_start_matches(['+', '-']).to_ascii_lowercase().starts_with("inf") {
+        return Err(NOT_A_FLOAT);
+    }
+    Ok(value)
+}
@@ -0,0 +1,404 @@
+//! Binary snapshot format used by `SAVE` and `BGSAVE`.
+//!
+//! A snapshot is the magic bytes `RBKT` and a format version, followed by a
+//! sequence of opcodes:
+//!
+//! * `SELECT_DB` and a length switches the database later entries go to
+//! * `EXPIRETIME_MS` and an 8-byte deadline in Unix milliseconds sets the
+//!   expiry of the next entry
+//! * a type byte, the key and the encoded value adds one entry
+//! * `EOF` ends the snapshot, followed by the CRC-64 of everything before it
+//!
+//! Lengths are LEB128 varints, strings are length-prefixed and fixed-size
+//! numbers are little-endian.
+//!
+//! `DUMP` payloads reuse the value encoding: a type byte and the encoded
+//! value, followed by the 2-byte format version and the CRC-64 of everything
+//! before it.
+
+use crate::db::{DataType, SetValue};
+use ahash::AHashMap;
+use bytes::Bytes;
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const MAGIC: &[u8] = b"RBKT";
+const VERSION: u8 = 1;
+
+const OP_EXPIRETIME_MS: u8 = 0xFC;
+const OP_SELECT_DB: u8 = 0xFE;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 3;
+const TYPE_ZSET: u8 = 4;
+const TYPE_JSON: u8 = 5;
+
+/// Where snapshots are written, and whether one is being written right now.
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    path: Option<PathBuf>,
+    // Only one save may write the file at a time
+    in_progress: AtomicBool,
+}
+
+impl Snapshots {
+    pub fn new(path: Option<PathBuf>) -> Snapshots {
+        Snapshots { path, in_progress: AtomicBool::new(false) }
+    }
+
+    /// The snapshot file, if one is configured.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Mark a save as started, returning `false` if one is already running.
+    pub fn try_start(&self) -> bool {
+        !self.in_progress.swap(true, Ordering::AcqRel)
+    }
+
+    /// Mark the running save as finished.
+    pub fn finish(&self) {
+        self.in_progress.store(false, Ordering::Release);
+    }
+}
+
+/// Encodes a snapshot into `out`, a file or a buffer sent to a replica.
+pub(crate) struct SnapshotWriter<W> {
+    out: W,
+    crc: u64,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SnapshotWriter<W> {
+    pub(crate) fn new(out: W) -> io::Result<SnapshotWriter<W>> {
+        let mut writer = SnapshotWriter { out, crc: 0, buf: Vec::new() };
+        writer.buf.extend_from_slice(MAGIC);
+        writer.buf.push(VERSION);
+        writer.flush_buf()?;
+        Ok(writer)
+    }
+
+    /// Send the following entries to database `index`.
+    pub(crate) fn select_db(&mut self, index: usize) -> io::Result<()> {
+        self.buf.push(OP_SELECT_DB);
+        write_len(&mut self.buf, index);
+        self.flush_buf()
+    }
+
+    /// Add `key`, expiring at `deadline` if it has one.
+    pub(crate) fn write_entry(&mut self, key: &[u8], value: &DataType, deadline: Option<u64>) -> io::Result<()> {
+        if let Some(deadline) = deadline {
+            self.buf.push(OP_EXPIRETIME_MS);
+            self.buf.extend_from_slice(&deadline.to_le_bytes());
+        }
+        self.buf.push(type_byte(value));
+        write_bytes(&mut self.buf, key);
+        encode_value(&mut self.buf, value);
+        self.flush_buf()
+    }
+
+    /// Write the trailer, returning the output.
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.buf.push(OP_EOF);
+        self.flush_buf()?;
+        let crc = self.crc;
+        self.out.write_all(&crc.to_le_bytes())?;
+        Ok(self.out)
+    }
+
+    fn flush_buf(&mut self) -> io::Result<()> {
+        self.crc = crc64(self.crc, &self.buf);
+        self.out.write_all(&self.buf)?;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+/// Write a snapshot to `path` with `f`. It goes to a temporary file first,
+/// which replaces `path` only once it's complete.
+pub(crate) fn write_snapshot_file(
+    path: &Path,
+    f: impl FnOnce(&mut SnapshotWriter<BufWriter<File>>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut writer = SnapshotWriter::new(BufWriter::new(File::create(&tmp_path)?))?;
+    f(&mut writer)?;
+    let file = writer.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Read the snapshot in `data`, passing each entry to `f` with the index of
+/// its database and its expiry deadline.
+pub(crate) fn read_snapshot(
+    data: &[u8],
+    mut f: impl FnMut(usize, Bytes, DataType, Option<u64>) -> io::Result<()>,
+) -> io::Result<()> {
+    let body_len = data.len().checked_sub(8).ok_or_else(|| invalid("snapshot is truncated"))?;
+    let (body, trailer) = data.split_at(body_len);
+    if crc64(0, body).to_le_bytes() != trailer {
+        return Err(invalid("snapshot checksum mismatch"));
+    }
+
+    let mut input = body
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| invalid("not a snapshot file"))?;
+    match read_u8(&mut input)? {
+        VERSION => {}
+        version => return Err(invalid(&format!("unsupported snapshot version {}", version))),
+    }
+
+    let mut db = 0;
+    let mut deadline = None;
+    loop {
+        match read_u8(&mut input)? {
+            OP_EOF => break,
+            OP_SELECT_DB => db = read_len(&mut input)?,
+            OP_EXPIRETIME_MS => deadline = Some(read_u64(&mut input)?),
+            type_byte => {
+                let key = read_bytes(&mut input)?;
+                let value = decode_value(type_byte, &mut input)?;
+                f(db, key, value, deadline.take())?;
+            }
+        }
+    }
+    if !input.is_empty() {
+        return Err(invalid("trailing data after snapshot"));
+    }
+    Ok(())
+}
+
+/// Serialize `value` as a `DUMP` payload.
+pub(crate) fn dump_value(value: &DataType) -> Vec<u8> {
+    let mut payload = vec![type_byte(value)];
+    encode_value(&mut payload, value);
+    payload.extend_from_slice(&u16::from(VERSION).to_le_bytes());
+    let crc = crc64(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    payload
+}
+
+/// Why a `DUMP` payload couldn't be restored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadError {
+    /// The version or checksum is wrong.
+    Checksum,
+    /// The payload is intact but doesn't hold a valid value.
+    BadData,
+}
+
+/// Deserialize a `DUMP` payload, checking its version and checksum.
+pub(crate) fn restore_value(payload: &[u8]) -> Result<DataType, PayloadError> {
+    let body_len = payload.len().checked_sub(10).ok_or(PayloadError::Checksum)?;
+    let (body, trailer) = payload.split_at(body_len + 2);
+    if crc64(0, body).to_le_bytes() != trailer {
+        return Err(PayloadError::Checksum);
+    }
+    let (mut input, version) = body.split_at(body_len);
+    if version != u16::from(VERSION).to_le_bytes() {
+        return Err(PayloadError::Checksum);
+    }
+
+    let type_byte = read_u8(&mut input).map_err(|_| PayloadError::BadData)?;
+    let value = decode_value(type_byte, &mut input).map_err(|_| PayloadError::BadData)?;
+    if !input.is_empty() {
+        return Err(PayloadError::BadData);
+    }
+    Ok(value)
+}
+
+/// The type byte written before a value of this type.
+pub(crate) fn type_byte(value: &DataType) -> u8 {
+    match value {
+        DataType::String(_) => TYPE_STRING,
+        DataType::List(_) => TYPE_LIST,
+        DataType::Set(_) => TYPE_SET,
+        DataType::Hash(_) => TYPE_HASH,
+        DataType::ZSet(_) => TYPE_ZSET,
+        DataType::Json(_) => TYPE_JSON,
+    }
+}
+
+/// Append the encoding of `value`, without its type byte, to `out`.
+pub(crate) fn encode_value(out: &mut Vec<u8>, value: &DataType) {
+    match value {
+        DataType::String(s) => write_bytes(out, s),
+        DataType::List(list) => {
+            write_len(out, list.len());
+            for item in list {
+                write_bytes(out, item);
+            }
+        }
+        DataType::Set(set) => {
+            write_len(out, set.len());
+            for member in set.iter() {
+                write_bytes(out, member);
+            }
+        }
+        DataType::Hash(hash) => {
+            write_len(out, hash.len());
+            for (field, value) in hash {
+                write_bytes(out, field);
+                write_bytes(out, value);
+            }
+        }
+        DataType::ZSet(zset) => {
+            write_len(out, zset.len());
+            for (member, score) in zset {
+                write_bytes(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        DataType::Json(json) => write_bytes(out, json.to_string().as_bytes()),
+    }
+}
+
+/// Decode a value of the type `type_byte` from the front of `input`.
+///
+/// Values the keyspace can't hold are rejected: empty collections, which
+/// are deleted rather than stored, and NaN scores.
+pub(crate) fn decode_value(type_byte: u8, input: &mut &[u8]) -> io::Result<DataType> {
+    let value = match type_byte {
+        TYPE_STRING => DataType::String(read_bytes(input)?),
+        TYPE_LIST => {
+            let len = read_collection_len(input)?;
+            let mut list = VecDeque::new();
+            for _ in 0..len {
+                list.push_back(read_bytes(input)?);
+            }
+            DataType::List(list)
+        }
+        TYPE_SET => {
+            let len = read_collection_len(input)?;
+            let mut set = HashSet::new();
+            for _ in 0..len {
+                set.insert(read_bytes(input)?);
+            }
+            DataType::Set(SetValue::from(set))
+        }
+        TYPE_HASH => {
+            let len = read_collection_len(input)?;
+            let mut hash = AHashMap::new();
+            for _ in 0..len {
+                hash.insert(read_bytes(input)?, read_bytes(input)?);
+            }
+            DataType::Hash(hash)
+        }
+        TYPE_ZSET => {
+            let len = read_collection_len(input)?;
+            let mut zset = AHashMap::new();
+            for _ in 0..len {
+                let member = read_bytes(input)?;
+                let score = f64::from_bits(read_u64(input)?);
+                if score.is_nan() {
+                    return Err(invalid("NaN score"));
+                }
+                zset.insert(member, score);
+            }
+            DataType::ZSet(zset)
+        }
+        TYPE_JSON => {
+            let json = read_bytes(input)?;
+            DataType::Json(serde_json::from_slice(&json).map_err(|_| invalid("invalid JSON value"))?)
+        }
+        _ => return Err(invalid("unknown value type")),
+    };
+    Ok(value)
+}
+
+/// Update `crc` with `data` using the CRC-64/Jones polynomial, as Redis does.
+pub fn crc64(mut crc: u64, data: &[u8]) -> u64 {
+    for &byte in data {
+        crc = CRC64_TABLE[((crc ^ byte as u64) & 0xff) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+const CRC64_TABLE: [u64; 256] = {
+    // Reflected form of the Jones polynomial 0xad93d23594c935a9
+    const POLY: u64 = 0x95ac9329ac4bc9b5;
+    let mut table = [0u64; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_len(out, bytes.len());
+    out.extend_from_slice(bytes);
+}
+
+fn take<'a>(input: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if input.len() < n {
+        return Err(invalid("snapshot is truncated"));
+    }
+    let (head, rest) = input.split_at(n);
+    *input = rest;
+    Ok(head)
+}
+
+fn read_u8(input: &mut &[u8]) -> io::Result<u8> {
+    Ok(take(input, 1)?[0])
+}
+
+fn read_u64(input: &mut &[u8]) -> io::Result<u64> {
+    Ok(u64::from_le_bytes(take(input, 8)?.try_into().unwrap()))
+}
+
+fn read_len(input: &mut &[u8]) -> io::Result<usize> {
+    let mut len: usize = 0;
+    for shift in (0..usize::BITS).step_by(7) {
+        let byte = read_u8(input)?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(len);
+        }
+    }
+    Err(invalid("length is too long"))
+}
+
+/// Read the length of a list, set, hash or sorted set, which can't be empty.
+fn read_collection_len(input: &mut &[u8]) -> io::Result<usize> {
+    match read_len(input)? {
+        0 => Err(invalid("empty collection")),
+        len => Ok(len),
+    }
+}
+
+fn read_bytes(input: &mut &[u8]) -> io::Result<Bytes> {
+    let len = read_len(input)?;
+    Ok(Bytes::copy_from_slice(take(input, len)?))
+}
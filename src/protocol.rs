@@ -5,6 +5,10 @@ use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
 
 /// A frame in the Redis protocol.
+///
+/// Commands build the logical reply using the RESP3 types where they apply.
+/// `Connection` downgrades the RESP3-only variants when the peer speaks
+/// RESP2, see [`Protocol`].
 #[derive(Clone, Debug)]
 pub enum Frame {
     Simple(String),
@@ -13,6 +17,34 @@ pub enum Frame {
     Bulk(Bytes),
     Null,
     Array(Vec<Frame>),
+    /// A missing array, like the reply to an aborted `EXEC`. `*-1` in RESP2,
+    /// where `Frame::Null` is a missing bulk string instead.
+    NullArray,
+    /// RESP3 double, a bulk string in RESP2.
+    Double(f64),
+    /// RESP3 boolean, the integer 1 or 0 in RESP2.
+    Boolean(bool),
+    /// RESP3 map, a flat array of keys and values in RESP2.
+    Map(Vec<(Frame, Frame)>),
+    /// RESP3 set, an array in RESP2.
+    Set(Vec<Frame>),
+    /// RESP3 out-of-band push, an array in RESP2.
+    Push(Vec<Frame>),
+    /// RESP3 arbitrary precision integer, given as its decimal digits. A
+    /// bulk string in RESP2.
+    BigNumber(String),
+}
+
+/// The RESP version spoken on a connection.
+///
+/// Determines how `Connection` encodes the RESP3-only frame variants. A
+/// `Frame::Null` is sent as `$-1` in RESP2 and a `Frame::NullArray` as `*-1`,
+/// both as `_` in RESP3.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
 }
 
 #[derive(Debug)]
@@ -26,7 +58,6 @@ pub enum Error {
 
 impl Frame {
     /// Returns an empty array
-    #[allow(dead_code)]
     pub(crate) fn array() -> Frame {
         Frame::Array(vec![])
     }
@@ -36,7 +67,6 @@ impl Frame {
     /// # Panics
     ///
     /// Panics if `self` is not an array frame.
-    #[allow(dead_code)]
     pub(crate) fn push_bulk(&mut self, bytes: Bytes) {
         match self {
             Frame::Array(vec) => {
@@ -81,9 +111,9 @@ impl Frame {
         matches!(self, Frame::Bulk(_))
     }
 
-    /// Checks if the frame is Null.
+    /// Checks if the frame is Null, or a null array.
     pub fn is_null(&self) -> bool {
-        matches!(self, Frame::Null)
+        matches!(self, Frame::Null | Frame::NullArray)
     }
 
     /// Checks if the frame is an Array.
@@ -91,16 +121,42 @@ impl Frame {
         matches!(self, Frame::Array(_))
     }
 
-    /// Checks if an entire message can be decoded from `src`.
+    /// Parse a single frame from the start of `bytes`.
+    ///
+    /// Returns `Ok(None)` when `bytes` holds an incomplete frame. This wraps
+    /// the cursor based `check` + `parse` pair used by `Connection` and is the
+    /// entry point for fuzzing the parser.
+    pub fn parse_from_bytes(bytes: &[u8]) -> Result<Option<Frame>, Error> {
+        let mut buf = Cursor::new(bytes);
+        match Frame::check(&mut buf) {
+            Ok(()) => {
+                buf.set_position(0);
+                Frame::parse(&mut buf).map(Some)
+            }
+            Err(Error::Incomplete) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks if an entire message can be decoded from `src`, within the
+    /// default [`Limits`].
+    ///
+    /// Any input accepted here is guaranteed to be accepted by `parse`.
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Frame::check_with_limits(src, &Limits::default())
+    }
+
+    /// Checks if an entire message can be decoded from `src`, rejecting
+    /// declared lengths over `limits` before waiting for the data.
+    pub fn check_with_limits(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<(), Error> {
+        Frame::check_nested(src, limits, 0)
+    }
+
+    /// `check_with_limits` for a frame inside `depth` aggregates.
+    fn check_nested(src: &mut Cursor<&[u8]>, limits: &Limits, depth: usize) -> Result<(), Error> {
         match get_u8(src)? {
-            b'+' => {
-                get_line(src)?;
-                Ok(())
-            }
-            b'-' => {
-                get_line(src)?;
-                Ok(())
+            b'+' | b'-' => {
+                check_utf8(get_line(src)?)
             }
             b':' => {
                 let _ = get_signed_decimal(src)?;
@@ -108,36 +164,65 @@ impl Frame {
             }
             b'$' => {
                 if b'-' == peek_u8(src)? {
-                    // Skip '-1\r\n'
-                    skip(src, 4)
+                    // Only the null bulk string '-1\r\n' is valid
+                    if get_line(src)? != b"-1" {
+                        return Err("protocol error; invalid bulk string format".into());
+                    }
+                    Ok(())
                 } else {
                     // Read the bulk string length.
                     let len: usize = get_decimal(src)?.try_into()?;
+                    if len > limits.max_bulk_len {
+                        return Err("protocol error; invalid bulk length".into());
+                    }
 
                     // Skip the bulk string + \r\n
-                    skip(src, len + 2)
+                    let n = len.checked_add(2).ok_or("protocol error; invalid frame format")?;
+                    skip(src, n)
                 }
             }
-            b'*' => {
-                let len = get_decimal(src)?;
+            prefix @ (b'*' | b'~' | b'>') => {
+                if prefix == b'*' && is_null_array(src)? {
+                    return Ok(());
+                }
+                check_depth(depth, limits)?;
+                let len = get_aggregate_len(src, limits)?;
+
+                for _ in 0..len {
+                    Frame::check_nested(src, limits, depth + 1)?;
+                }
+
+                Ok(())
+            }
+            b'%' => {
+                check_depth(depth, limits)?;
+                let len = get_aggregate_len(src, limits)?;
 
                 for _ in 0..len {
-                    Frame::check(src)?;
+                    Frame::check_nested(src, limits, depth + 1)?;
+                    Frame::check_nested(src, limits, depth + 1)?;
                 }
 
                 Ok(())
             }
+            b'_' => match get_line(src)? {
+                b"" => Ok(()),
+                _ => Err("protocol error; invalid null format".into()),
+            },
+            b'#' => parse_boolean(get_line(src)?).map(|_| ()),
+            b',' => parse_double(get_line(src)?).map(|_| ()),
+            b'(' => parse_big_number(get_line(src)?).map(|_| ()),
             _ => {
                 // Inline command support
                 // Reset position to include the first byte we just read
                 src.set_position(src.position() - 1);
-                get_line(src)?;
-                Ok(())
+                split_inline(get_line(src)?).map(|_| ())
             }
         }
     }
 
-    /// The message has already been validated with `check`.
+    /// The message has already been validated with `check`, which also
+    /// bounds how deeply this recurses.
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
         match get_u8(src)? {
             b'+' => {
@@ -183,29 +268,47 @@ impl Frame {
                     Ok(Frame::Bulk(data))
                 }
             }
-            b'*' => {
+            prefix @ (b'*' | b'~' | b'>') => {
+                if prefix == b'*' && is_null_array(src)? {
+                    return Ok(Frame::NullArray);
+                }
                 let len = get_decimal(src)?.try_into()?;
-                let mut out = Vec::with_capacity(len);
+                let mut out = Vec::with_capacity(aggregate_capacity(src, len));
 
                 for _ in 0..len {
                     out.push(Frame::parse(src)?);
                 }
 
-                Ok(Frame::Array(out))
+                Ok(match prefix {
+                    b'~' => Frame::Set(out),
+                    b'>' => Frame::Push(out),
+                    _ => Frame::Array(out),
+                })
             }
+            b'%' => {
+                let len = get_decimal(src)?.try_into()?;
+                let mut out = Vec::with_capacity(aggregate_capacity(src, len));
+
+                for _ in 0..len {
+                    let key = Frame::parse(src)?;
+                    let value = Frame::parse(src)?;
+                    out.push((key, value));
+                }
+
+                Ok(Frame::Map(out))
+            }
+            b'_' => {
+                get_line(src)?;
+                Ok(Frame::Null)
+            }
+            b'#' => Ok(Frame::Boolean(parse_boolean(get_line(src)?)?)),
+            b',' => Ok(Frame::Double(parse_double(get_line(src)?)?)),
+            b'(' => Ok(Frame::BigNumber(parse_big_number(get_line(src)?)?)),
             _ => {
                 // Inline command support
                 src.set_position(src.position() - 1);
-                let line = get_line(src)?;
-                let line_str = String::from_utf8(line.to_vec())?;
-                
-                // Split by space and create Array of Bulk strings
-                let parts: Vec<Frame> = line_str
-                    .split_whitespace()
-                    .map(|s| Frame::Bulk(Bytes::from(s.to_string())))
-                    .collect();
-                
-                Ok(Frame::Array(parts))
+                let args = split_inline(get_line(src)?)?;
+                Ok(Frame::Array(args.into_iter().map(Frame::Bulk).collect()))
             }
         }
     }
@@ -240,8 +343,20 @@ impl fmt::Display for Frame {
                 Ok(string) => string.fmt(fmt),
                 Err(_) => write!(fmt, "{:?}", msg),
             },
-            Frame::Null => "nil".fmt(fmt),
-            Frame::Array(parts) => {
+            Frame::Null | Frame::NullArray => "nil".fmt(fmt),
+            Frame::Double(num) => format_double(*num).fmt(fmt),
+            Frame::Boolean(b) => b.fmt(fmt),
+            Frame::BigNumber(digits) => digits.fmt(fmt),
+            Frame::Map(entries) => {
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        write!(fmt, " ")?;
+                    }
+                    write!(fmt, "{} {}", key, value)?;
+                }
+                Ok(())
+            }
+            Frame::Array(parts) | Frame::Set(parts) | Frame::Push(parts) => {
                 for (i, part) in parts.iter().enumerate() {
                     if i > 0 {
                         write!(fmt, " ")?;
@@ -254,6 +369,48 @@ impl fmt::Display for Frame {
     }
 }
 
+/// Largest lengths a peer may declare in a frame.
+///
+/// Lengths are checked before the data arrives, so a client can't make the
+/// server buffer a huge frame it has only announced.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Longest bulk string, in bytes.
+    pub max_bulk_len: usize,
+    /// Most entries in an array, set, push or map.
+    pub max_array_len: usize,
+    /// Largest whole frame, in bytes. Aggregates declaring more entries
+    /// than could fit are rejected straight away.
+    pub max_frame_size: usize,
+    /// Most aggregates nested inside each other. Checking and parsing
+    /// recurse into each one, so this bounds the stack they use.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // The same as Redis' `proto-max-bulk-len` and multibulk limit
+        Limits {
+            max_bulk_len: 512 * 1024 * 1024,
+            max_array_len: i32::MAX as usize,
+            max_frame_size: 512 * 1024 * 1024,
+            max_depth: 128,
+        }
+    }
+}
+
+/// Format a double the way Redis replies with it: the shortest
+/// representation that round-trips, and `inf`, `-inf` or `nan` otherwise.
+pub fn format_double(num: f64) -> String {
+    if num.is_nan() {
+        "nan".to_string()
+    } else if num.is_infinite() {
+        if num > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        num.to_string()
+    }
+}
+
 impl From<String> for Error {
     fn from(src: String) -> Error {
         Error::Other(src.into())
@@ -313,12 +470,152 @@ fn get_decimal(src: &mut Cursor<&[u8]>) -> Result<u64, Error> {
     atoi::<u64>(line).ok_or_else(|| "protocol error; invalid frame format".into())
 }
 
+/// Read the length of an array, set, push or map, which must be within
+/// `limits`.
+///
+/// Every entry takes at least 3 bytes, so a length that couldn't fit in
+/// `max_frame_size` is rejected before any entry is waited for.
+fn get_aggregate_len(src: &mut Cursor<&[u8]>, limits: &Limits) -> Result<u64, Error> {
+    if peek_u8(src)? == b'-' {
+        get_line(src)?;
+        return Err("protocol error; invalid multibulk length".into());
+    }
+    let len = get_decimal(src)?;
+    if len > limits.max_array_len as u64 || len.saturating_mul(3) > limits.max_frame_size as u64 {
+        return Err("protocol error; invalid multibulk length".into());
+    }
+    Ok(len)
+}
+
+/// Fail if an aggregate inside `depth` others would nest too deeply.
+fn check_depth(depth: usize, limits: &Limits) -> Result<(), Error> {
+    if depth >= limits.max_depth {
+        return Err("protocol error; aggregates nested too deeply".into());
+    }
+    Ok(())
+}
+
+/// Whether an array header declares the null array, `*-1`. Consumes the
+/// header if it does.
+fn is_null_array(src: &mut Cursor<&[u8]>) -> Result<bool, Error> {
+    let start = src.position();
+    if peek_u8(src)? == b'-' && get_line(src)? == b"-1" {
+        return Ok(true);
+    }
+    src.set_position(start);
+    Ok(false)
+}
+
+/// Capacity to reserve for an aggregate that declares `len` entries.
+///
+/// The declared length comes from the peer, so it's capped by what the
+/// remaining input could hold. Every entry takes at least 3 bytes.
+fn aggregate_capacity(src: &Cursor<&[u8]>, len: usize) -> usize {
+    len.min(src.remaining() / 3)
+}
+
+fn check_utf8(line: &[u8]) -> Result<(), Error> {
+    std::str::from_utf8(line)
+        .map(|_| ())
+        .map_err(|_| "protocol error; invalid frame format".into())
+}
+
+/// Split an inline command into its arguments the way `redis-cli` does.
+///
+/// Arguments are separated by whitespace. Double quotes take the escapes
+/// `\n`, `\r`, `\t`, `\b`, `\a` and `\xHH`, any other escaped byte
+/// standing for itself. Single quotes only take `\'`. A closing quote must
+/// end the argument.
+fn split_inline(line: &[u8]) -> Result<Vec<Bytes>, Error> {
+    let unbalanced = || Error::from("protocol error; unbalanced quotes in request");
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+        let mut arg = Vec::new();
+        let mut quote = None;
+        while let Some(&byte) = line.get(i) {
+            i += 1;
+            match (quote, byte) {
+                (None, b'"' | b'\'') => quote = Some(byte),
+                (None, byte) if byte.is_ascii_whitespace() => break,
+                (None, byte) => arg.push(byte),
+                (Some(b'"'), b'\\') => {
+                    let escaped = *line.get(i).ok_or_else(unbalanced)?;
+                    i += 1;
+                    let hex = line
+                        .get(i..i + 2)
+                        .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+                        .and_then(|digits| u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok());
+                    arg.push(match (escaped, hex) {
+                        (b'x', Some(byte)) => {
+                            i += 2;
+                            byte
+                        }
+                        (b'n', _) => b'\n',
+                        (b'r', _) => b'\r',
+                        (b't', _) => b'\t',
+                        (b'b', _) => 0x08,
+                        (b'a', _) => 0x07,
+                        (other, _) => other,
+                    });
+                }
+                (Some(b'\''), b'\\') if line.get(i) == Some(&b'\'') => {
+                    i += 1;
+                    arg.push(b'\'');
+                }
+                (Some(open), byte) if byte == open => {
+                    // The closing quote must be followed by a space or the end
+                    if line.get(i).is_some_and(|next| !next.is_ascii_whitespace()) {
+                        return Err(unbalanced());
+                    }
+                    quote = None;
+                    break;
+                }
+                (Some(_), byte) => arg.push(byte),
+            }
+        }
+        if quote.is_some() {
+            return Err(unbalanced());
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+fn parse_boolean(line: &[u8]) -> Result<bool, Error> {
+    match line {
+        b"t" => Ok(true),
+        b"f" => Ok(false),
+        _ => Err("protocol error; invalid boolean format".into()),
+    }
+}
+
+fn parse_double(line: &[u8]) -> Result<f64, Error> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| "protocol error; invalid double format".into())
+}
+
+fn parse_big_number(line: &[u8]) -> Result<String, Error> {
+    let digits = line.strip_prefix(b"-").unwrap_or(line);
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Err("protocol error; invalid big number format".into());
+    }
+    Ok(String::from_utf8(line.to_vec())?)
+}
+
 fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
     // Scan the bytes directly
     let start = src.position() as usize;
     let end = src.get_ref().len();
 
-    for i in start..end - 1 {
+    for i in start..end.saturating_sub(1) {
         if src.get_ref()[i] == b'\r' && src.get_ref()[i + 1] == b'\n' {
             // We found a line, update the position to be *after* the \n
             src.set_position((i + 2) as u64);
@@ -0,0 +1,117 @@
+use crate::glob;
+use ahash::AHashMap;
+use bytes::Bytes;
+use std::sync::Mutex;
+use tokio::sync::broadcast;
+
+/// Messages buffered per channel before slow subscribers start missing them.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Registry of pub/sub channels, shared by every `Db` handle.
+///
+/// Each channel or pattern with subscribers has a `broadcast` sender.
+/// Subscribers hold a receiver, so the subscriber count is the receiver
+/// count. Connections in MONITOR mode listen on one more sender, fed with
+/// every command the server processes.
+#[derive(Debug)]
+pub struct PubSub {
+    channels: Mutex<AHashMap<Bytes, broadcast::Sender<Bytes>>>,
+    // Pattern subscribers also need the channel each message was sent to
+    patterns: Mutex<AHashMap<Bytes, broadcast::Sender<(Bytes, Bytes)>>>,
+    monitors: broadcast::Sender<String>,
+}
+
+impl Default for PubSub {
+    fn default() -> PubSub {
+        PubSub {
+            channels: Mutex::default(),
+            patterns: Mutex::default(),
+            monitors: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub::default()
+    }
+
+    /// Start receiving a line describing each command processed, as MONITOR
+    /// does.
+    pub fn monitor(&self) -> broadcast::Receiver<String> {
+        self.monitors.subscribe()
+    }
+
+    /// Whether any connection is in MONITOR mode, so it's worth describing
+    /// commands.
+    pub fn has_monitors(&self) -> bool {
+        self.monitors.receiver_count() > 0
+    }
+
+    /// Send the description of a processed command to every monitor.
+    pub fn feed_monitors(&self, line: String) {
+        // No monitors is fine
+        let _ = self.monitors.send(line);
+    }
+
+    /// Start receiving the messages published to `channel`.
+    pub fn subscribe(&self, channel: Bytes) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().unwrap();
+        match channels.get(&channel) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+                channels.insert(channel, sender);
+                receiver
+            }
+        }
+    }
+
+    /// Start receiving the messages published to any channel matching the
+    /// glob-style `pattern`, along with the channel each was sent to.
+    pub fn psubscribe(&self, pattern: Bytes) -> broadcast::Receiver<(Bytes, Bytes)> {
+        let mut patterns = self.patterns.lock().unwrap();
+        match patterns.get(&pattern) {
+            Some(sender) => sender.subscribe(),
+            None => {
+                let (sender, receiver) = broadcast::channel(CHANNEL_CAPACITY);
+                patterns.insert(pattern, sender);
+                receiver
+            }
+        }
+    }
+
+    /// Send `message` to the subscribers of `channel` and of every pattern
+    /// matching it, returning how many there were.
+    pub fn publish(&self, channel: Bytes, message: Bytes) -> usize {
+        let mut receivers = 0;
+
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&channel) {
+            match sender.send(message.clone()) {
+                Ok(count) => receivers += count,
+                // Everyone has unsubscribed, so drop the channel
+                Err(_) => {
+                    channels.remove(&channel);
+                }
+            }
+        }
+        drop(channels);
+
+        let mut patterns = self.patterns.lock().unwrap();
+        patterns.retain(|pattern, sender| {
+            if !glob::matches(pattern, &channel) {
+                return true;
+            }
+            match sender.send((channel.clone(), message.clone())) {
+                Ok(count) => {
+                    receivers += count;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+
+        receivers
+    }
+}
@@ -0,0 +1,279 @@
+//! Replication of the dataset from a master to its replicas.
+//!
+//! A replica connects to its master like any other client and sends
+//! `PSYNC`. The master replies `+FULLRESYNC <replid> 0`, then a bulk string
+//! holding a snapshot of every database. The snapshot is in this server's
+//! own format (see [`persistence`]) rather than RDB, so
+//! only another rustbucket can follow a rustbucket master.
+//!
+//! From then on the master forwards every write command it runs, in the
+//! same form it logs to the append-only file, preceded by a `SELECT`
+//! whenever it ran against a different database than the command before it.
+//!
+//! A replica replaces its dataset with the snapshot by running a `FLUSHALL`
+//! and a `RESTORE` of each key, then runs the forwarded commands. Both go
+//! through the same path as writes from clients, so they reach its own
+//! append-only file and replicas too. Clients of a replica can only read.
+
+use crate::persistence;
+use crate::server::apply_command;
+use crate::{Command, Connection, Db, Frame};
+use bytes::Bytes;
+use rand::Rng;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+/// The reply to a write command sent to a replica by a client.
+pub(crate) const READONLY: &str = "READONLY You can't write against a read only replica";
+
+/// How long a replica waits before reconnecting to its master after the
+/// link drops or can't be made.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// This server's replicas, and its master if it's a replica itself, shared
+/// by every connection.
+#[derive(Debug)]
+pub struct Replication {
+    // Random identifier of the dataset history sent to replicas
+    replid: String,
+    feed: Mutex<Feed>,
+    // Number of replicas in `feed`, readable without waiting for it
+    connected: AtomicUsize,
+    // Master configured with REPLICAOF, `None` while this server is a master
+    master: std::sync::Mutex<Option<MasterLink>>,
+}
+
+/// The task that keeps a replica in sync with its master.
+#[derive(Debug)]
+struct MasterLink {
+    host: String,
+    port: u16,
+    // Whether the initial sync is done and the master's writes are arriving
+    up: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl Drop for MasterLink {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// The replicas write commands are forwarded to. Held while a write command
+/// runs, so each replica gets them in the order they took effect.
+#[derive(Debug)]
+pub(crate) struct Feed {
+    // Each replica's connection id, and the queue its connection sends from
+    replicas: Vec<(u64, mpsc::UnboundedSender<Frame>)>,
+    // Database the last forwarded command ran against
+    selected: Option<usize>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        let mut rng = rand::rng();
+        let replid = (0..20).map(|_| format!("{:02x}", rng.random::<u8>())).collect();
+        let feed = Feed { replicas: Vec::new(), selected: None };
+        Replication {
+            replid,
+            feed: Mutex::new(feed),
+            connected: AtomicUsize::new(0),
+            master: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// The master configured with `REPLICAOF`, if any.
+    pub fn master(&self) -> Option<(String, u16)> {
+        let master = self.master.lock().unwrap();
+        master.as_ref().map(|link| (link.host.clone(), link.port))
+    }
+
+    /// Whether this server replicates a master, and so only serves reads.
+    pub fn is_replica(&self) -> bool {
+        self.master.lock().unwrap().is_some()
+    }
+
+    /// Whether a replica has synced with its master and is receiving its
+    /// writes.
+    pub fn master_link_up(&self) -> bool {
+        let master = self.master.lock().unwrap();
+        master.as_ref().is_some_and(|link| link.up.load(Ordering::Relaxed))
+    }
+
+    /// Start replicating `master` into `db`'s databases, or with `None`
+    /// stop replicating and become a master again, keeping the dataset.
+    /// Asking for the master already being replicated changes nothing.
+    pub fn set_master(&self, db: &Db, master: Option<(String, u16)>) {
+        let mut current = self.master.lock().unwrap();
+        if let (Some(link), Some((host, port))) = (&*current, &master)
+            && link.host == *host
+            && link.port == *port
+        {
+            return;
+        }
+        // Dropping the old link stops it
+        *current = master.map(|(host, port)| {
+            let up = Arc::new(AtomicBool::new(false));
+            let task = tokio::spawn(follow(db.clone(), host.clone(), port, up.clone()));
+            MasterLink { host, port, up, task }
+        });
+    }
+
+    /// The 40 character hex identifier replicas are told at a full resync.
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    /// Number of replicas write commands are forwarded to.
+    pub fn connected_replicas(&self) -> usize {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// Wait for exclusive access to the feed, or `None` if there are no
+    /// replicas to forward to.
+    ///
+    /// Replicas only register while no command runs, so one can't appear
+    /// between this returning `None` and the command finishing.
+    pub(crate) async fn lock(&self) -> Option<MutexGuard<'_, Feed>> {
+        if self.connected_replicas() == 0 {
+            return None;
+        }
+        Some(self.feed.lock().await)
+    }
+
+    /// Start forwarding write commands to the replica on connection `id`,
+    /// returning the queue they arrive on.
+    ///
+    /// Must be called with no command running, right after taking the
+    /// snapshot the replica starts from, or writes would be missed or
+    /// applied twice.
+    pub(crate) async fn register(&self, id: u64) -> mpsc::UnboundedReceiver<Frame> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut feed = self.feed.lock().await;
+        feed.replicas.push((id, sender));
+        // The new replica doesn't know which database comes next
+        feed.selected = None;
+        self.connected.store(feed.replicas.len(), Ordering::Relaxed);
+        receiver
+    }
+
+    /// Stop forwarding to the replica on connection `id`.
+    pub(crate) async fn unregister(&self, id: u64) {
+        let mut feed = self.feed.lock().await;
+        feed.replicas.retain(|(replica, _)| *replica != id);
+        self.connected.store(feed.replicas.len(), Ordering::Relaxed);
+    }
+}
+
+impl Feed {
+    /// Forward `frame`, a command that ran against database `db`, to every
+    /// replica.
+    pub(crate) fn append(&mut self, db: usize, frame: &Frame) {
+        if self.selected != Some(db) {
+            let select = Frame::Array(vec![
+                Frame::Bulk(Bytes::from_static(b"SELECT")),
+                Frame::Bulk(Bytes::from(db.to_string())),
+            ]);
+            self.send(&select);
+        }
+        self.send(frame);
+        self.selected = Some(db);
+    }
+
+    fn send(&self, frame: &Frame) {
+        for (_, replica) in &self.replicas {
+            // A replica that has gone away is unregistered by its connection
+            let _ = replica.send(frame.clone());
+        }
+    }
+}
+
+/// Replicate the master at `host:port` into `db` until aborted,
+/// reconnecting whenever the link drops.
+async fn follow(db: Db, host: String, port: u16, up: Arc<AtomicBool>) {
+    loop {
+        if let Err(err) = sync_with_master(&db, &host, port, &up).await {
+            warn!(cause = ?err, host, port, "replication link to the master failed");
+        }
+        up.store(false, Ordering::Relaxed);
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Connect to the master, replace the dataset with its snapshot and run the
+/// writes it forwards until it closes the connection.
+async fn sync_with_master(db: &Db, host: &str, port: u16, up: &AtomicBool) -> crate::Result<()> {
+    let mut master = Connection::new(TcpStream::connect((host, port)).await?);
+    let psync = ["PSYNC", "?", "-1"].map(|arg| Frame::Bulk(Bytes::from_static(arg.as_bytes())));
+    master.write_frame(&Frame::Array(psync.to_vec())).await?;
+    master.flush().await?;
+    match master.read_frame().await? {
+        Some(Frame::Simple(reply)) if reply.starts_with("FULLRESYNC ") => {}
+        Some(Frame::Error(err)) => return Err(err.into()),
+        reply => return Err(format!("unexpected reply to PSYNC: {:?}", reply).into()),
+    }
+    let Some(Frame::Bulk(snapshot)) = master.read_frame().await? else {
+        return Err("the master didn't send a snapshot".into());
+    };
+
+    let mut connection = Connection::discarding();
+    let resync = resync_commands(&snapshot)?;
+    {
+        // Clients see the whole dataset replaced at once
+        let _guard = db.batch_lock.write().await;
+        for (index, request) in resync {
+            let db = db.select(index).ok_or("snapshot database index is out of range")?;
+            apply_command(Command::from_frame(request.clone())?, request, &db, &mut connection).await?;
+        }
+    }
+    up.store(true, Ordering::Relaxed);
+
+    let mut selected = db.select(0).expect("database 0 always exists");
+    while let Some(request) = master.read_frame().await? {
+        match Command::from_frame(request.clone())? {
+            Command::Select(select) => {
+                selected = usize::try_from(select.index)
+                    .ok()
+                    .and_then(|index| db.select(index))
+                    .ok_or("the master selected a database that doesn't exist")?;
+            }
+            cmd => {
+                let _guard = db.batch_lock.read().await;
+                apply_command(cmd, request, &selected, &mut connection).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The commands that rebuild the dataset in `snapshot` from scratch, each
+/// with the database it runs against: a `FLUSHALL`, then a `RESTORE` of
+/// every key.
+fn resync_commands(snapshot: &[u8]) -> std::io::Result<Vec<(usize, Frame)>> {
+    let bulk = |arg: &'static str| Frame::Bulk(Bytes::from_static(arg.as_bytes()));
+    let mut commands = vec![(0, Frame::Array(vec![bulk("FLUSHALL")]))];
+    persistence::read_snapshot(snapshot, |index, key, value, deadline| {
+        // Zero is no expiry, and otherwise a deadline in Unix milliseconds
+        let restore = Frame::Array(vec![
+            bulk("RESTORE"),
+            Frame::Bulk(key),
+            Frame::Bulk(Bytes::from(deadline.unwrap_or(0).to_string())),
+            Frame::Bulk(Bytes::from(persistence::dump_value(&value))),
+            bulk("ABSTTL"),
+        ]);
+        commands.push((index, restore));
+        Ok(())
+    })?;
+    Ok(commands)
+}
@@ -1,32 +1,175 @@
-use crate::{Command, Connection, Db};
+use crate::acl::DEFAULT_USER;
+use crate::aof::{self, Aof, AofWriter, AppendFsync};
+use crate::cmd::subscription_reply;
+use crate::db::WatchedKey;
+use crate::protocol::{self, Protocol};
+use crate::replication::{Feed, READONLY};
+use crate::shutdown::Shutdown;
+use crate::{Command, Connection, Db, Frame, ServerConfig};
 
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, MutexGuard, Notify, Semaphore};
+use tokio::task::JoinHandle;
 use tracing::{error, instrument};
 
-/// Run the mini-redis server.
+/// Run the mini-redis server with the default configuration.
 ///
 /// Accepts connections from the supplied listener. For each accepted
-/// connection, processing is handled by a "handler" task.
+/// connection, processing is handled by a "handler" task. When `shutdown`
+/// completes, no more connections are accepted and `run` returns once
+/// every handler has finished.
 ///
 /// The `Db` instance is shared across all tasks.
-pub async fn run(listener: TcpListener) -> crate::Result<()> {
-    let db = Db::new();
+pub async fn run(listener: TcpListener, shutdown: impl Future) -> crate::Result<()> {
+    run_with_config(listener, ServerConfig::default(), shutdown).await
+}
+
+/// Run the server with `config`, accepting connections from `listener`
+/// until `shutdown` completes.
+///
+/// The listener is already bound, so `config.bind` is left to the caller.
+pub async fn run_with_config(
+    listener: TcpListener,
+    config: ServerConfig,
+    shutdown: impl Future,
+) -> crate::Result<()> {
+    let mut db = Db::with_databases(config.databases)
+        .with_acl(config.acl())
+        .with_snapshot_path(config.snapshot_path.clone())
+        .with_max_value_len(config.max_bulk_len);
+    // The append-only file is more up to date than any snapshot, so it takes
+    // precedence
+    if let Some(path) = &config.aof_path {
+        if path.exists() {
+            aof::replay(path, &db).await?;
+        }
+        db = db.with_aof(Aof::open(path, config.aof_fsync)?);
+    } else if let Some(path) = &config.snapshot_path
+        && path.exists()
+    {
+        db.load_from(path)?;
+    }
+    db.update_config(|runtime| *runtime = config.runtime());
+    db.stats().set_tcp_port(listener.local_addr()?.port());
+
+    // Dropping `notify_shutdown` tells the handlers to stop. Each handler
+    // holds a clone of `shutdown_complete_tx`, so once they are all gone
+    // `shutdown_complete_rx` stops waiting.
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+    let mut server = Listener {
+        listener,
+        db,
+        config: Arc::new(config.clone()),
+        // Each connection holds a permit for as long as it's open
+        connections: Arc::new(Semaphore::new(config.max_connections)),
+        notify_shutdown,
+        shutdown_complete_tx,
+    };
 
+    let fsync_task = (config.aof_path.is_some() && config.aof_fsync == AppendFsync::EverySec)
+        .then(|| tokio::spawn(fsync_every_second(server.db.clone())));
+    let expire_task = (!config.active_expire_interval.is_zero())
+        .then(|| tokio::spawn(expire_keys(server.db.clone(), config.active_expire_interval)));
+
+    let result = tokio::select! {
+        res = server.run() => res,
+        _ = shutdown => Ok(()),
+    };
+
+    let Listener { db, notify_shutdown, shutdown_complete_tx, .. } = server;
+    drop(notify_shutdown);
+    drop(shutdown_complete_tx);
+
+    // Wait for in-flight connections to finish their current command
+    let _ = shutdown_complete_rx.recv().await;
+
+    if let Some(task) = fsync_task {
+        task.abort();
+    }
+    if let Some(task) = expire_task {
+        task.abort();
+    }
+    // Stop following the master, if this is a replica
+    db.replication().set_master(&db, None);
+    if let Some(aof) = db.aof() {
+        aof.sync().await?;
+    }
+
+    result
+}
+
+/// Fsync the append-only file once a second, for `AppendFsync::EverySec`.
+async fn fsync_every_second(db: Db) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
     loop {
-        // Accept a new socket. This will return a `TcpStream` and the remote
-        // peer's address.
-        let (socket, _) = listener.accept().await?;
+        interval.tick().await;
+        if let Some(aof) = db.aof()
+            && let Err(err) = aof.sync().await
+        {
+            error!(cause = ?err, "failed to fsync the AOF");
+        }
+    }
+}
 
-        // Clone the handle to the hash map.
-        let db = db.clone();
+/// Sweep out expired keys every `period`, for `active_expire_interval`.
+async fn expire_keys(db: Db, period: Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        db.expire_cycle();
+    }
+}
 
-        // Spawn a new task to process the connection.
-        tokio::spawn(async move {
-            // Process the connection. If an error is encountered, log it.
-            if let Err(err) = process(socket, db).await {
-                error!(cause = ?err, "connection error");
-            }
-        });
+/// Accepts connections for `run_with_config`.
+struct Listener {
+    listener: TcpListener,
+    db: Db,
+    config: Arc<ServerConfig>,
+    connections: Arc<Semaphore>,
+    notify_shutdown: broadcast::Sender<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl Listener {
+    /// Accept connections forever, spawning a handler task for each.
+    async fn run(&mut self) -> crate::Result<()> {
+        loop {
+            // Wait for a free slot before accepting, so clients past the
+            // limit queue up in the listen backlog instead of being dropped.
+            // The semaphore is never closed, so this can't fail.
+            let permit = self.connections.clone().acquire_owned().await.unwrap();
+
+            // Accept a new socket. This will return a `TcpStream` and the
+            // remote peer's address.
+            let (socket, _) = self.listener.accept().await?;
+
+            // Clone the handle to the hash map.
+            let db = self.db.clone();
+            let config = self.config.clone();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+
+            // Spawn a new task to process the connection.
+            tokio::spawn(async move {
+                let stats = db.clone();
+                let id = stats.stats().client_connected();
+                // Process the connection. If an error is encountered, log it.
+                if let Err(err) = process(socket, id, db, &config, shutdown).await {
+                    error!(cause = ?err, "connection error");
+                }
+                stats.stats().client_disconnected();
+                drop(permit);
+                drop(shutdown_complete);
+            });
+        }
     }
 }
 
@@ -37,9 +180,14 @@ pub async fn run(listener: TcpListener) -> crate::Result<()> {
 use bytes::Bytes;
 
 struct TransactionState {
-    queued: Vec<Command>,
-    watched: Vec<(Bytes, u64)>,
+    // Queued commands, with the request each came from
+    queued: Vec<(Command, Frame)>,
+    // Keys to check before EXEC runs the queued commands
+    watched: Vec<WatchedKey>,
     active: bool,
+    // Whether a command was rejected while queuing, which makes EXEC
+    // discard the transaction
+    failed: bool,
 }
 
 impl TransactionState {
@@ -48,29 +196,103 @@ impl TransactionState {
             queued: Vec::new(),
             watched: Vec::new(),
             active: false,
+            failed: false,
         }
     }
+
+    /// Leave the transaction, forgetting the queued commands and watched
+    /// keys.
+    fn discard(&mut self) {
+        self.queued.clear();
+        self.watched.clear();
+        self.active = false;
+        self.failed = false;
+    }
 }
 
 /// Process a single connection.
-#[instrument(skip(socket, db))]
-async fn process(socket: TcpStream, db: Db) -> crate::Result<()> {
+#[instrument(skip(socket, db, config, shutdown))]
+async fn process(
+    socket: TcpStream,
+    id: u64,
+    mut db: Db,
+    config: &ServerConfig,
+    mut shutdown: Shutdown,
+) -> crate::Result<()> {
+    let addr = socket.peer_addr()?;
     let mut connection = Connection::new(socket);
+    let killed = connection.register(db.clients().clone(), id);
+    connection.set_limits(config.limits());
     let mut txn_state = TransactionState::new();
+    if !db.acl().requires_auth() {
+        connection.set_user(DEFAULT_USER);
+    }
+
+    while !shutdown.is_shutdown() {
+        // Stop waiting for the next request once the server shuts down
+        let frame = tokio::select! {
+            res = read_frame_until_idle(&mut connection, db.config().timeout) => match res {
+                Ok(frame) => frame,
+                // Tell the client what was wrong with the request before
+                // hanging up, since the rest of the stream can't be trusted
+                Err(err) if err.is::<protocol::Error>() => {
+                    connection.write_frame(&Frame::Error(format!("ERR {}", err))).await?;
+                    connection.flush().await?;
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            },
+            _ = shutdown.recv() => {
+                connection.flush().await?;
+                return Ok(());
+            }
+            // CLIENT KILL
+            _ = killed.notified() => {
+                connection.flush().await?;
+                return Ok(());
+            }
+        };
+        let Some(frame) = frame else {
+            return Ok(());
+        };
 
-    while let Some(frame) = connection.read_frame().await? {
+        // Keep the request around to log to the append-only file and
+        // forward to replicas. One may connect before the command runs, so
+        // it's kept even if there are none yet
+        let request = frame.clone();
+        // Described up front for MONITOR, since parsing consumes the frame
+        let monitor_line = db.pubsub().has_monitors().then(|| monitor_line(&frame, db.index(), addr));
         let cmd = match Command::from_frame(frame) {
             Ok(cmd) => cmd,
             Err(err) => {
+                // A malformed command dooms the transaction it was meant for
+                txn_state.failed |= txn_state.active;
                 let response = crate::Frame::Error(err.to_string());
                 connection.write_frame(&response).await?;
                 continue;
             }
         };
+        db.stats().command_processed();
+
+        // Only authentication is allowed until the client has logged in
+        if connection.user().is_none() && !matches!(cmd, Command::Auth(_) | Command::Hello(_) | Command::Reset(_)) {
+            connection.write_frame(&crate::Frame::Error("NOAUTH Authentication required.".into())).await?;
+            continue;
+        }
+        if let Some(line) = monitor_line {
+            db.pubsub().feed_monitors(line);
+        }
+        // A replica only takes writes from its master
+        if cmd.is_write() && db.replication().is_replica() {
+            txn_state.failed |= txn_state.active;
+            connection.write_frame(&Frame::Error(READONLY.into())).await?;
+            continue;
+        }
 
         match cmd {
             Command::Multi(_) => {
                 if txn_state.active {
+                    // Refused without dooming the transaction, like Redis
                     connection.write_frame(&crate::Frame::Error("ERR MULTI calls can not be nested".into())).await?;
                 } else {
                     txn_state.active = true;
@@ -81,9 +303,7 @@ async fn process(socket: TcpStream, db: Db) -> crate::Result<()> {
                 if !txn_state.active {
                      connection.write_frame(&crate::Frame::Error("ERR DISCARD without MULTI".into())).await?;
                 } else {
-                    txn_state.queued.clear();
-                    txn_state.watched.clear();
-                    txn_state.active = false;
+                    txn_state.discard();
                     connection.write_frame(&crate::Frame::Simple("OK".into())).await?;
                 }
             }
@@ -93,112 +313,750 @@ async fn process(socket: TcpStream, db: Db) -> crate::Result<()> {
                  if txn_state.active {
                      connection.write_frame(&crate::Frame::Error("ERR WATCH inside MULTI is not allowed".into())).await?;
                  } else {
-                     // Capture versions
-                     // We need to lock to read versions?
-                     // versions are atomic, but we might want consistent view?
-                     // Relaxed ordering is fine as we just capture current state.
-                     // A read lock on batch_lock might be good to ensure we don't watch in middle of another EXEC?
-                     // Yes, treat WATCH like a read op.
-                     {
-                         let _guard = db.batch_lock.read().await;
-                         for key in &watch_cmd.match_keys {
-                             let shard_idx = db.get_shard_index(key);
-                             let ver = db.get_shard_version(shard_idx);
-                             txn_state.watched.retain(|(k, _)| k != key); // Replace if existing
-                             txn_state.watched.push((key.clone().into(), ver));
-                         }
+                     // Like a read, so a watch never starts halfway through
+                     // another client's EXEC
+                     let guard = db.batch_lock.read().await;
+                     for key in &watch_cmd.match_keys {
+                         // Watching a key again replaces the old watch
+                         txn_state.watched.retain(|watched| watched.db_index() != db.index() || watched.key() != key);
+                         txn_state.watched.push(db.watch(key.clone()));
                      }
+                     drop(guard);
                      connection.write_frame(&crate::Frame::Simple("OK".into())).await?;
                  }
             }
+            // Allowed inside MULTI too, where it takes effect straight away
+            Command::Unwatch(_) => {
+                txn_state.watched.clear();
+                connection.write_frame(&crate::Frame::Simple("OK".into())).await?;
+            }
             Command::Exec(_) => {
                  if !txn_state.active {
                       connection.write_frame(&crate::Frame::Error("ERR EXEC without MULTI".into())).await?;
+                 } else if txn_state.failed {
+                      txn_state.discard();
+                      let response = "EXECABORT Transaction discarded because of previous errors.";
+                      connection.write_frame(&crate::Frame::Error(response.into())).await?;
                  } else {
-                      // 1. Acquire WRITE lock
-                      let _guard = db.batch_lock.write().await;
+                      // 1. Acquire WRITE lock (held via its own handle since
+                      //    a queued SELECT may switch `db` mid-transaction)
+                      let batch_lock = db.batch_lock.clone();
+                      let _guard = batch_lock.write().await;
                       
                       // 2. Validate watched keys
-                      let mut valid = true;
-                      for (key, ver) in &txn_state.watched {
-                           let shard_idx = db.get_shard_index(key);
-                           let current_ver = db.get_shard_version(shard_idx);
-                           if current_ver != *ver {
-                               valid = false;
-                               break;
-                           }
-                      }
+                      let valid = !txn_state.watched.iter().any(WatchedKey::is_modified);
                       
                       if !valid {
                           // Transaction aborted
-                          connection.write_frame(&crate::Frame::Null).await?; // Nil response for abort
+                          connection.write_frame(&crate::Frame::NullArray).await?; // Nil response for abort
                       } else {
-                          // 3. Execute queued commands
-                          // 3. Execute queued commands
-                          
-                          // We need to capture the output of each command.
-                          // Command::apply writes to connection. We don't want that for EXEC?
-                          // Redis EXEC returns Array of results.
-                          // Our `apply` writes directly to `dst`.
-                          // THIS IS A PROBLEM.
-                          // `apply` currently writes to `connection`.
-                          // If we run `apply`, it will write frames to `connection`.
-                          // But we want to wrap them in an Array frame.
-                          // And `apply` might write Errors, Integers, etc.
-                          // Solution: Create a temporary buffer/Connection to capture output?
-                          // `Connection` wraps a `TcpStream`. hard to mock.
-                          
-                          // Refactor: `apply` should return `Frame`?
-                          // If I change `apply` signature to return `Frame`, it's a huge refactor.
-                          
-                          // Shortcut:
-                          // `EXEC` writes `*N` (Array len).
-                          // Then we invoke `apply` for each command.
-                          // Each `apply` writes its result to the stream.
-                          // Effectively streaming the Array content.
-                          // This is VALID RESP. An Array is `*N\r\n` followed by N frames.
-                          // So we can send `*N` header, then let commands write themselves.
-                          // IF we don't fail in the middle.
-                          // If a command fails (e.g. valid syntax but runtime error), it writes Error frame. That's fine in Array.
-                          
-                          // Wait, what if `apply` fails (returns Err)?
-                          // Then we might have partial array.
-                          // Redis transactions usually don't fail on parsing commands (checked at queue time).
-                          // Runtime errors are sent as Error frames inside the array.
-                          
-                          // So:
-                          // 1. Write `*Len`
-                          // 2. Loop queued: run `apply`.
-                          // 3. If `apply` returns Err (network error?), we are in trouble. But `apply` returns `crate::Result`.
-                          // If network error, connection closes anyway.
-                          
-                          connection.start_array(txn_state.queued.len()).await?; 
-                          for q_cmd in txn_state.queued.drain(..) {
-                               if let Err(e) = q_cmd.apply(&db, &mut connection).await {
-                                   return Err(e);
-                               }
+                          // 3. Execute queued commands, collecting each
+                          //    reply, errors included, into one array
+                          let mut replies = Vec::with_capacity(txn_state.queued.len());
+                          for (q_cmd, request) in txn_state.queued.drain(..) {
+                               // SELECT switches the database for the rest of the transaction
+                               let reply = match q_cmd {
+                                   Command::Select(ref select) => select_db(&mut db, &mut connection, select),
+                                   q_cmd => apply_command(q_cmd, request, &db, &mut connection).await?,
+                               };
+                               replies.push(reply);
                           }
+                          connection.write_frame(&Frame::Array(replies)).await?;
                       }
                       
                       // Cleanup
-                      txn_state.queued.clear();
-                      txn_state.watched.clear();
-                      txn_state.active = false;
+                      txn_state.discard();
                  }
             }
+            Command::Subscribe(_) | Command::PSubscribe(_) if !txn_state.active => {
+                match subscriber_mode(&mut connection, &db, cmd, &mut shutdown, &killed).await? {
+                    ModeExit::Unsubscribed => {}
+                    ModeExit::Reset => reset(&mut connection, &mut db, &mut txn_state).await?,
+                    ModeExit::Closed => return Ok(()),
+                }
+            }
+            Command::Monitor(_) if !txn_state.active => {
+                match monitor_mode(&mut connection, &db, &mut shutdown, &killed).await? {
+                    ModeExit::Reset => reset(&mut connection, &mut db, &mut txn_state).await?,
+                    ModeExit::Unsubscribed | ModeExit::Closed => return Ok(()),
+                }
+            }
+            // The connection is a replica's from now on
+            Command::PSync(_) if !txn_state.active => {
+                return feed_replica(&mut connection, &db, id, &mut shutdown, &killed).await;
+            }
+            Command::Reset(_) => reset(&mut connection, &mut db, &mut txn_state).await?,
+            Command::Select(ref select) if !txn_state.active => {
+                let response = select_db(&mut db, &mut connection, select);
+                connection.write_frame(&response).await?;
+            }
+            // Blocking commands only block outside MULTI
+            _ if !txn_state.active && cmd.blocks_on().is_some() => {
+                match block(cmd, request, &db, &mut connection, &mut shutdown, &killed).await? {
+                    Some(reply) => connection.write_frame(&reply).await?,
+                    None => return Ok(()),
+                }
+            }
+            Command::Unknown(_) | Command::Monitor(_) | Command::PSync(_) if txn_state.active => {
+                txn_state.failed = true;
+                cmd.apply(&db, &mut connection).await?;
+            }
             _ => {
                 if txn_state.active {
-                    txn_state.queued.push(cmd);
+                    txn_state.queued.push((cmd, request));
                     connection.write_frame(&crate::Frame::Simple("QUEUED".into())).await?;
                 } else {
                     // Normal execution
                     // Acquire READ lock
                     let _guard = db.batch_lock.read().await;
-                    cmd.apply(&db, &mut connection).await?;
+                    if cmd.is_write() {
+                        let reply = apply_command(cmd, request, &db, &mut connection).await?;
+                        connection.write_frame(&reply).await?;
+                    } else {
+                        // Left to write its own replies, since an UNSUBSCRIBE
+                        // outside subscriber mode has several
+                        cmd.apply(&db, &mut connection).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Put the connection back the way it was when the client connected:
+/// outside any transaction, with nothing watched, on database 0, speaking
+/// RESP2 and logged out if a password is required.
+async fn reset(
+    connection: &mut Connection,
+    db: &mut Db,
+    txn_state: &mut TransactionState,
+) -> crate::Result<()> {
+    txn_state.discard();
+    if let Some(first) = db.select(0) {
+        *db = first;
+    }
+    connection.set_db(0);
+    connection.set_protocol(Protocol::Resp2);
+    if db.acl().requires_auth() {
+        connection.clear_user();
+    } else {
+        connection.set_user(DEFAULT_USER);
+    }
+    connection.write_frame(&Frame::Simple("RESET".into())).await?;
+    Ok(())
+}
+
+/// Where write commands are logged as they run: the append-only file and
+/// the replicas, whichever are in use.
+struct WriteLog<'a> {
+    aof: Option<MutexGuard<'a, AofWriter>>,
+    replicas: Option<MutexGuard<'a, Feed>>,
+}
+
+impl WriteLog<'_> {
+    /// Take hold of the file and the replica feed, so concurrent writes are
+    /// logged in the order they took effect.
+    async fn lock(db: &Db) -> WriteLog<'_> {
+        let aof = match db.aof() {
+            Some(aof) => Some(aof.lock().await),
+            None => None,
+        };
+        let replicas = db.replication().lock().await;
+        WriteLog { aof, replicas }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.aof.is_none() && self.replicas.is_none()
+    }
+
+    /// Log `frame`, a command that ran against database `db`.
+    fn append(&mut self, db: usize, frame: &Frame) -> io::Result<()> {
+        if let Some(replicas) = &mut self.replicas {
+            replicas.append(db, frame);
+        }
+        match &mut self.aof {
+            Some(aof) => aof.append(db, frame),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Apply `cmd` and return its reply. Writes that need memory first evict
+/// keys to make room under the memory limit, and writes are logged to the
+/// append-only file and forwarded to replicas.
+///
+/// `request` is the frame `cmd` was parsed from, which is what gets logged.
+pub(crate) async fn apply_command(
+    cmd: Command,
+    request: Frame,
+    db: &Db,
+    connection: &mut Connection,
+) -> crate::Result<Frame> {
+    if !cmd.is_write() {
+        return cmd.apply_to_frame(db, connection).await;
+    }
+
+    let mut log = WriteLog::lock(db).await;
+
+    if cmd.uses_memory() {
+        let mut evicted = Vec::new();
+        let room = db.make_room(cmd.incoming_size(), |index, key| evicted.push((index, key)));
+        // Log evictions as deletes, or replaying the file would bring the
+        // keys back
+        for (index, key) in evicted {
+            let del = Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"DEL")), Frame::Bulk(key)]);
+            log.append(index, &del)?;
+        }
+        if let Err(err) = room {
+            return Ok(Frame::Error(err.to_string()));
+        }
+    }
+
+    if log.is_empty() {
+        return cmd.apply_to_frame(db, connection).await;
+    }
+    let reply = match cmd {
+        // Log the members SPOP picked, since replaying the pop itself would
+        // pick different ones
+        Command::SPop(spop) => {
+            let (reply, key, popped) = spop.apply_popped(db).await?;
+            if !popped.is_empty() {
+                let mut srem = vec![Frame::Bulk(Bytes::from_static(b"SREM")), Frame::Bulk(key)];
+                srem.extend(popped.into_iter().map(Frame::Bulk));
+                log.append(db.index(), &Frame::Array(srem))?;
+            }
+            reply
+        }
+        // Log the deadline RESTORE set rather than its TTL, which would
+        // restart from zero on replay
+        Command::Restore(restore) => {
+            let restore = restore.into_absolute();
+            let request = restore.to_frame();
+            let reply = restore.apply(db).await?;
+            log.append(db.index(), &request)?;
+            reply
+        }
+        // Log the pop that happened, rather than a command that may find
+        // nothing and block
+        Command::BLPop(blpop) => {
+            let (reply, popped) = blpop.apply_popped(db).await?;
+            if let Some(key) = popped {
+                let lpop = Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"LPOP")), Frame::Bulk(key)]);
+                log.append(db.index(), &lpop)?;
+            }
+            reply
+        }
+        Command::BRPop(brpop) => {
+            let (reply, popped) = brpop.apply_popped(db).await?;
+            if let Some(key) = popped {
+                let rpop = Frame::Array(vec![Frame::Bulk(Bytes::from_static(b"RPOP")), Frame::Bulk(key)]);
+                log.append(db.index(), &rpop)?;
+            }
+            reply
+        }
+        Command::BLMove(blmove) | Command::BRPopLPush(blmove) => {
+            let lmove = blmove.to_lmove_frame();
+            let (reply, moved) = blmove.apply_moved(db).await?;
+            if moved {
+                log.append(db.index(), &lmove)?;
+            }
+            reply
+        }
+        Command::BLMPop(blmpop) => {
+            let (reply, lmpop) = blmpop.apply_popped(db).await?;
+            if let Some(lmpop) = lmpop {
+                log.append(db.index(), &lmpop)?;
+            }
+            reply
+        }
+        Command::BZMPop(bzmpop) => {
+            let (reply, zmpop) = bzmpop.apply_popped(db).await?;
+            if let Some(zmpop) = zmpop {
+                log.append(db.index(), &zmpop)?;
+            }
+            reply
+        }
+        cmd => {
+            let reply = cmd.apply_to_frame(db, connection).await?;
+            log.append(db.index(), &request)?;
+            reply
+        }
+    };
+    Ok(reply)
+}
+
+/// Run a blocking command like BLPOP: apply it, and each time it finds
+/// nothing to do, wait for a write to one of its keys and apply it again,
+/// until its timeout passes. The batch lock is only held while applying,
+/// so other clients can make the writes being waited for.
+///
+/// Returns the reply, or `None` if the connection should close because the
+/// server is shutting down or the client was killed.
+async fn block(
+    cmd: Command,
+    request: Frame,
+    db: &Db,
+    connection: &mut Connection,
+    shutdown: &mut Shutdown,
+    killed: &Notify,
+) -> crate::Result<Option<Frame>> {
+    let Some((keys, timeout)) = cmd.blocks_on() else {
+        return apply_command(cmd, request, db, connection).await.map(Some);
+    };
+    let blocked = db.block(keys);
+    let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+    loop {
+        // Queued before trying, so a write made meanwhile isn't missed
+        let woken = blocked.woken();
+        let reply = {
+            let _guard = db.batch_lock.read().await;
+            apply_command(cmd.clone(), request.clone(), db, connection).await?
+        };
+        if !matches!(reply, Frame::NullArray) {
+            // Anything left over is for the next client in line
+            blocked.wake_others();
+            return Ok(Some(reply));
+        }
+        // Replies to requests pipelined before this one mustn't wait on it
+        connection.flush().await?;
+        let timed_out = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = woken => {}
+            _ = timed_out => return Ok(Some(Frame::NullArray)),
+            _ = shutdown.recv() => return Ok(None),
+            _ = killed.notified() => return Ok(None),
+        }
+    }
+}
+
+/// Read the next frame like `Connection::read_frame`, but give up with
+/// `Ok(None)`, as if the client had disconnected, once `idle_timeout` passes
+/// without one. A zero timeout waits forever.
+async fn read_frame_until_idle(
+    connection: &mut Connection,
+    idle_timeout: Duration,
+) -> crate::Result<Option<Frame>> {
+    if idle_timeout.is_zero() {
+        return connection.read_frame().await;
+    }
+    tokio::time::timeout(idle_timeout, connection.read_frame())
+        .await
+        .unwrap_or(Ok(None))
+}
+
+/// Point `db` at the database requested by `SELECT` and return the reply.
+fn select_db(db: &mut Db, connection: &mut Connection, select: &crate::cmd::Select) -> crate::Frame {
+    match usize::try_from(select.index).ok().and_then(|idx| db.select(idx)) {
+        Some(selected) => {
+            *db = selected;
+            connection.set_db(db.index());
+            crate::Frame::Simple("OK".into())
+        }
+        None => crate::Frame::Error("ERR DB index is out of range".into()),
+    }
+}
+
+/// Channels and patterns a connection in subscriber mode is listening on.
+///
+/// Each subscription runs a task that forwards its broadcasts, already
+/// encoded as message frames, into a single queue read by the connection.
+/// Dropping the subscriptions stops the tasks.
+struct Subscriptions {
+    // Subscriptions in the order they were made, with their forwarding task
+    channels: Vec<(Bytes, JoinHandle<()>)>,
+    patterns: Vec<(Bytes, JoinHandle<()>)>,
+    sender: mpsc::UnboundedSender<Frame>,
+    messages: mpsc::UnboundedReceiver<Frame>,
+}
+
+impl Subscriptions {
+    fn new() -> Subscriptions {
+        let (sender, messages) = mpsc::unbounded_channel();
+        Subscriptions { channels: Vec::new(), patterns: Vec::new(), sender, messages }
+    }
+
+    /// Number of channels and patterns subscribed to.
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Subscribe to `channel` unless already subscribed, returning the
+    /// number of subscriptions.
+    fn subscribe(&mut self, db: &Db, channel: Bytes) -> usize {
+        if !self.channels.iter().any(|(subscribed, _)| *subscribed == channel) {
+            let receiver = db.pubsub().subscribe(channel.clone());
+            let name = channel.clone();
+            let task = forward(receiver, self.sender.clone(), move |message| {
+                Frame::Push(vec![
+                    Frame::Bulk(Bytes::from_static(b"message")),
+                    Frame::Bulk(name.clone()),
+                    Frame::Bulk(message),
+                ])
+            });
+            self.channels.push((channel, task));
+        }
+        self.count()
+    }
+
+    /// Subscribe to the channels matching `pattern` unless already
+    /// subscribed, returning the number of subscriptions.
+    fn psubscribe(&mut self, db: &Db, pattern: Bytes) -> usize {
+        if !self.patterns.iter().any(|(subscribed, _)| *subscribed == pattern) {
+            let receiver = db.pubsub().psubscribe(pattern.clone());
+            let name = pattern.clone();
+            let task = forward(receiver, self.sender.clone(), move |(channel, message)| {
+                Frame::Push(vec![
+                    Frame::Bulk(Bytes::from_static(b"pmessage")),
+                    Frame::Bulk(name.clone()),
+                    Frame::Bulk(channel),
+                    Frame::Bulk(message),
+                ])
+            });
+            self.patterns.push((pattern, task));
+        }
+        self.count()
+    }
+
+    /// Unsubscribe from `channel`, returning the number of subscriptions
+    /// left.
+    async fn unsubscribe(&mut self, channel: &[u8]) -> usize {
+        cancel(&mut self.channels, channel).await;
+        self.count()
+    }
+
+    /// Unsubscribe from `pattern`, returning the number of subscriptions
+    /// left.
+    async fn punsubscribe(&mut self, pattern: &[u8]) -> usize {
+        cancel(&mut self.patterns, pattern).await;
+        self.count()
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for (_, task) in self.channels.iter().chain(&self.patterns) {
+            task.abort();
+        }
+    }
+}
+
+/// Spawn a task forwarding everything `receiver` gets to `sender` as the
+/// frame built by `to_frame`.
+fn forward<T, F>(
+    mut receiver: broadcast::Receiver<T>,
+    sender: mpsc::UnboundedSender<Frame>,
+    to_frame: F,
+) -> JoinHandle<()>
+where
+    T: Clone + Send + 'static,
+    F: Fn(T) -> Frame + Send + 'static,
+{
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(message) => {
+                    if sender.send(to_frame(message)).is_err() {
+                        break;
+                    }
                 }
+                // A slow subscriber misses messages rather than holding up
+                // the publishers
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
             }
         }
+    })
+}
+
+/// Remove the subscription to `name` from `subscriptions` and stop its task.
+async fn cancel(subscriptions: &mut Vec<(Bytes, JoinHandle<()>)>, name: &[u8]) {
+    if let Some(pos) = subscriptions.iter().position(|(subscribed, _)| subscribed == name) {
+        let (_, task) = subscriptions.remove(pos);
+        task.abort();
+        // Wait for the task to drop its receiver, so a following PUBLISH no
+        // longer counts this connection
+        let _ = task.await;
     }
+}
+
+/// Serve a replica that sent PSYNC: send it a snapshot of every database,
+/// then forward each write command until it disconnects, is killed with
+/// CLIENT KILL or the server shuts down.
+async fn feed_replica(
+    connection: &mut Connection,
+    db: &Db,
+    id: u64,
+    shutdown: &mut Shutdown,
+    killed: &Notify,
+) -> crate::Result<()> {
+    // Taken with every other command held off, so each write is either in
+    // the snapshot or forwarded after it
+    let guard = db.batch_lock.write().await;
+    let snapshot = db.snapshot();
+    let mut writes = db.replication().register(id).await;
+    drop(guard);
 
+    let result = async {
+        let fullresync = format!("FULLRESYNC {} 0", db.replication().replid());
+        connection.write_frame(&Frame::Simple(fullresync)).await?;
+        connection.write_frame(&Frame::Bulk(Bytes::from(snapshot))).await?;
+        loop {
+            tokio::select! {
+                Some(write) = writes.recv() => connection.write_frame(&write).await?,
+                _ = shutdown.recv() => {
+                    // Deliver what has already been forwarded before leaving
+                    connection.flush().await?;
+                    return Ok(());
+                }
+                _ = killed.notified() => {
+                    connection.flush().await?;
+                    return Ok(());
+                }
+                // Replicas don't send anything the master needs, so only
+                // the end of the stream matters
+                frame = connection.read_frame() => {
+                    if frame?.is_none() {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+    .await;
+    db.replication().unregister(id).await;
+    result
+}
+
+/// Why a connection left subscriber or monitor mode.
+enum ModeExit {
+    /// The client unsubscribed from everything.
+    Unsubscribed,
+    /// The client sent RESET, which dropped its subscriptions and leaves the
+    /// rest of the connection state to be reset.
+    Reset,
+    /// The client disconnected, was killed with CLIENT KILL, or the server
+    /// is shutting down.
+    Closed,
+}
+
+/// Serve a connection in subscriber mode, starting with the SUBSCRIBE or
+/// PSUBSCRIBE command `cmd`, until it has no subscriptions left.
+///
+/// Published messages are streamed to the client while only commands that
+/// manage subscriptions are accepted.
+async fn subscriber_mode(
+    connection: &mut Connection,
+    db: &Db,
+    cmd: Command,
+    shutdown: &mut Shutdown,
+    killed: &Notify,
+) -> crate::Result<ModeExit> {
+    let mut subscriptions = Subscriptions::new();
+    let mut next = Some(cmd);
+
+    loop {
+        if let Some(cmd) = next.take() {
+            if let Command::Reset(_) = cmd {
+                return Ok(ModeExit::Reset);
+            }
+            apply_subscribed(connection, db, &mut subscriptions, cmd).await?;
+            if subscriptions.count() == 0 {
+                return Ok(ModeExit::Unsubscribed);
+            }
+        }
+
+        tokio::select! {
+            Some(message) = subscriptions.messages.recv() => {
+                connection.write_frame(&message).await?;
+            }
+            _ = shutdown.recv() => {
+                // Deliver what has already been written before leaving
+                connection.flush().await?;
+                return Ok(ModeExit::Closed);
+            }
+            _ = killed.notified() => {
+                connection.flush().await?;
+                return Ok(ModeExit::Closed);
+            }
+            frame = connection.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Ok(ModeExit::Closed);
+                };
+                match Command::from_frame(frame) {
+                    Ok(cmd) => {
+                        db.stats().command_processed();
+                        next = Some(cmd);
+                    }
+                    Err(err) => connection.write_frame(&Frame::Error(err.to_string())).await?,
+                }
+            }
+        }
+    }
+}
+
+/// Run a command received in subscriber mode.
+async fn apply_subscribed(
+    connection: &mut Connection,
+    db: &Db,
+    subscriptions: &mut Subscriptions,
+    cmd: Command,
+) -> crate::Result<()> {
+    match cmd {
+        Command::Subscribe(subscribe) => {
+            for channel in subscribe.channels {
+                let count = subscriptions.subscribe(db, channel.clone());
+                connection.write_frame(&subscription_reply("subscribe", Some(channel), count)).await?;
+            }
+        }
+        Command::PSubscribe(psubscribe) => {
+            for pattern in psubscribe.patterns {
+                let count = subscriptions.psubscribe(db, pattern.clone());
+                connection.write_frame(&subscription_reply("psubscribe", Some(pattern), count)).await?;
+            }
+        }
+        Command::Unsubscribe(unsubscribe) => {
+            let mut channels = unsubscribe.channels;
+            if channels.is_empty() {
+                channels = subscriptions.channels.iter().map(|(channel, _)| channel.clone()).collect();
+            }
+            if channels.is_empty() {
+                let reply = subscription_reply("unsubscribe", None, subscriptions.count());
+                connection.write_frame(&reply).await?;
+            }
+            for channel in channels {
+                let count = subscriptions.unsubscribe(&channel).await;
+                connection.write_frame(&subscription_reply("unsubscribe", Some(channel), count)).await?;
+            }
+        }
+        Command::PUnsubscribe(punsubscribe) => {
+            let mut patterns = punsubscribe.patterns;
+            if patterns.is_empty() {
+                patterns = subscriptions.patterns.iter().map(|(pattern, _)| pattern.clone()).collect();
+            }
+            if patterns.is_empty() {
+                let reply = subscription_reply("punsubscribe", None, subscriptions.count());
+                connection.write_frame(&reply).await?;
+            }
+            for pattern in patterns {
+                let count = subscriptions.punsubscribe(&pattern).await;
+                connection.write_frame(&subscription_reply("punsubscribe", Some(pattern), count)).await?;
+            }
+        }
+        Command::Ping(ping) => {
+            connection.write_frame(&ping.subscriber_reply()).await?;
+        }
+        cmd => {
+            let response = Frame::Error(format!(
+                "ERR Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / RESET are allowed in this context",
+                cmd.get_name()
+            ));
+            connection.write_frame(&response).await?;
+        }
+    }
     Ok(())
 }
+
+/// Describe a request the way MONITOR shows it: the time, the database and
+/// client address, then each argument quoted.
+fn monitor_line(frame: &Frame, db: usize, addr: SocketAddr) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut line = format!("{}.{:06} [{} {}]", now.as_secs(), now.subsec_micros(), db, addr);
+    let Frame::Array(args) = frame else {
+        return line;
+    };
+    fn arg_bytes(arg: &Frame) -> &[u8] {
+        match arg {
+            Frame::Bulk(bytes) => bytes,
+            Frame::Simple(s) => s.as_bytes(),
+            _ => b"",
+        }
+    }
+    // Passwords aren't shown to monitors
+    let name = args.first().map_or(&b""[..], arg_bytes);
+    let redact = name.eq_ignore_ascii_case(b"auth") || name.eq_ignore_ascii_case(b"hello");
+    for (i, arg) in args.iter().enumerate() {
+        line.push(' ');
+        if redact && i > 0 {
+            line.push_str("\"(redacted)\"");
+        } else {
+            quote(&mut line, arg_bytes(arg));
+        }
+    }
+    line
+}
+
+/// Append `arg` to `out` in double quotes, escaping it like Redis's
+/// `sdscatrepr`.
+fn quote(out: &mut String, arg: &[u8]) {
+    out.push('"');
+    for &byte in arg {
+        match byte {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            byte if byte.is_ascii_graphic() || byte == b' ' => out.push(byte as char),
+            byte => out.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    out.push('"');
+}
+
+/// Serve a connection in monitor mode, streaming a line for every command
+/// the server processes until the client sends RESET or goes away. Nothing
+/// else can run on the connection meanwhile.
+async fn monitor_mode(
+    connection: &mut Connection,
+    db: &Db,
+    shutdown: &mut Shutdown,
+    killed: &Notify,
+) -> crate::Result<ModeExit> {
+    let mut lines = db.pubsub().monitor();
+    connection.write_frame(&Frame::Simple("OK".into())).await?;
+
+    loop {
+        tokio::select! {
+            line = lines.recv() => match line {
+                Ok(line) => connection.write_frame(&Frame::Simple(line)).await?,
+                // A slow monitor misses lines rather than holding up the
+                // server
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return Ok(ModeExit::Closed),
+            },
+            _ = shutdown.recv() => {
+                connection.flush().await?;
+                return Ok(ModeExit::Closed);
+            }
+            _ = killed.notified() => {
+                connection.flush().await?;
+                return Ok(ModeExit::Closed);
+            }
+            frame = connection.read_frame() => {
+                let Some(frame) = frame? else {
+                    return Ok(ModeExit::Closed);
+                };
+                match Command::from_frame(frame) {
+                    Ok(Command::Reset(_)) => return Ok(ModeExit::Reset),
+                    Ok(cmd) => {
+                        let response = Frame::Error(format!(
+                            "ERR Can't execute '{}': only RESET is allowed in MONITOR mode",
+                            cmd.get_name()
+                        ));
+                        connection.write_frame(&response).await?;
+                    }
+                    Err(err) => connection.write_frame(&Frame::Error(err.to_string())).await?,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use tokio::sync::broadcast;
+
+/// Listens for the server shutdown signal.
+///
+/// Shutdown is signalled by dropping the `broadcast::Sender` the receiver
+/// came from, so every handler sees it exactly once. `Shutdown::recv` can
+/// be awaited repeatedly after that and returns straight away.
+#[derive(Debug)]
+pub(crate) struct Shutdown {
+    // `true` once the shutdown signal has been received
+    is_shutdown: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub(crate) fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown { is_shutdown: false, notify }
+    }
+
+    /// Returns `true` if the shutdown signal has been received.
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.is_shutdown
+    }
+
+    /// Wait for the shutdown signal, if it hasn't been received already.
+    pub(crate) async fn recv(&mut self) {
+        if self.is_shutdown {
+            return;
+        }
+        // Only the sender being dropped is expected, which is an error here
+        let _ = self.notify.recv().await;
+        self.is_shutdown = true;
+    }
+}
@@ -0,0 +1,99 @@
+use rand::Rng;
+use std::sync::atomic::{AtomicU16, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Server-wide process information reported by `INFO`.
+///
+/// A single instance is created with the `Db` and shared by every handle.
+#[derive(Debug)]
+pub struct ServerStats {
+    // When the server started, for uptime reporting
+    started_at: Instant,
+    // Random identifier of this server run, regenerated on every start
+    run_id: String,
+    // Port the listener is bound to, recorded by `run`
+    tcp_port: AtomicU16,
+    // Clients connected right now
+    connected_clients: AtomicUsize,
+    total_connections_received: AtomicU64,
+    total_commands_processed: AtomicU64,
+    // Keys evicted to stay under `maxmemory`
+    evicted_keys: AtomicU64,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerStats {
+    /// Create the stats for a new server run, generating its `run_id`.
+    pub fn new() -> ServerStats {
+        let mut rng = rand::rng();
+        let run_id = (0..20).map(|_| format!("{:02x}", rng.random::<u8>())).collect();
+        ServerStats {
+            started_at: Instant::now(),
+            run_id,
+            tcp_port: AtomicU16::new(0),
+            connected_clients: AtomicUsize::new(0),
+            total_connections_received: AtomicU64::new(0),
+            total_commands_processed: AtomicU64::new(0),
+            evicted_keys: AtomicU64::new(0),
+        }
+    }
+
+    /// The 40 character hex identifier of this server run.
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Time elapsed since the server started.
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn tcp_port(&self) -> u16 {
+        self.tcp_port.load(Ordering::Relaxed)
+    }
+
+    pub fn set_tcp_port(&self, port: u16) {
+        self.tcp_port.store(port, Ordering::Relaxed);
+    }
+
+    pub fn connected_clients(&self) -> usize {
+        self.connected_clients.load(Ordering::Relaxed)
+    }
+
+    /// Record a newly accepted connection, returning the id CLIENT ID
+    /// reports for it. Ids count up from 1 and are never reused.
+    pub fn client_connected(&self) -> u64 {
+        self.connected_clients.fetch_add(1, Ordering::Relaxed);
+        self.total_connections_received.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Record a connection closing. Must pair with `client_connected`.
+    pub fn client_disconnected(&self) {
+        self.connected_clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn total_connections_received(&self) -> u64 {
+        self.total_connections_received.load(Ordering::Relaxed)
+    }
+
+    pub fn total_commands_processed(&self) -> u64 {
+        self.total_commands_processed.load(Ordering::Relaxed)
+    }
+
+    pub fn command_processed(&self) {
+        self.total_commands_processed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn evicted_keys(&self) -> u64 {
+        self.evicted_keys.load(Ordering::Relaxed)
+    }
+
+    pub fn key_evicted(&self) {
+        self.evicted_keys.fetch_add(1, Ordering::Relaxed);
+    }
+}
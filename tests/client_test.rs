@@ -0,0 +1,151 @@
+use bytes::Bytes;
+use rustbucket::client::Message;
+use rustbucket::{Client, Frame};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+/// Start a server on a free port and return its address.
+async fn start_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        rustbucket::run(listener, std::future::pending::<()>()).await.unwrap();
+    });
+    addr
+}
+
+#[tokio::test]
+async fn strings_and_keys() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    assert_eq!(client.ping(None).await.unwrap(), "PONG");
+    assert_eq!(client.ping(Some(Bytes::from("hi"))).await.unwrap(), "hi");
+
+    assert_eq!(client.get("greeting").await.unwrap(), None);
+    client.set("greeting", "hello").await.unwrap();
+    assert_eq!(client.get("greeting").await.unwrap(), Some(Bytes::from("hello")));
+
+    assert!(client.exists("greeting").await.unwrap());
+    assert!(!client.exists("missing").await.unwrap());
+    assert!(client.del("greeting").await.unwrap());
+    assert!(!client.del("greeting").await.unwrap());
+    assert_eq!(client.get("greeting").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn collections() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    assert!(client.hset("hash", "a", "1").await.unwrap());
+    assert!(!client.hset("hash", "a", "2").await.unwrap());
+    assert!(client.hset("hash", "b", "3").await.unwrap());
+    assert_eq!(client.hget("hash", "a").await.unwrap(), Some(Bytes::from("2")));
+    assert_eq!(client.hget("hash", "c").await.unwrap(), None);
+    let mut fields = client.hgetall("hash").await.unwrap();
+    fields.sort();
+    assert_eq!(fields, vec![(Bytes::from("a"), Bytes::from("2")), (Bytes::from("b"), Bytes::from("3"))]);
+
+    assert_eq!(client.rpush("list", [Bytes::from("b"), Bytes::from("c")]).await.unwrap(), 2);
+    assert_eq!(client.lpush("list", [Bytes::from("a")]).await.unwrap(), 3);
+    assert_eq!(client.lrange("list", 0, -1).await.unwrap(), vec!["a", "b", "c"]);
+    assert_eq!(client.lpop("list").await.unwrap(), Some(Bytes::from("a")));
+    assert_eq!(client.rpop("list").await.unwrap(), Some(Bytes::from("c")));
+
+    assert_eq!(client.sadd("set", [Bytes::from("x"), Bytes::from("y"), Bytes::from("x")]).await.unwrap(), 2);
+    let mut members = client.smembers("set").await.unwrap();
+    members.sort();
+    assert_eq!(members, vec!["x", "y"]);
+
+    assert_eq!(client.publish("nobody", "listening").await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn error_replies_become_errors() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    client.set("string", "value").await.unwrap();
+    let err = client.lpush("string", [Bytes::from("x")]).await.unwrap_err();
+    assert!(err.to_string().starts_with("WRONGTYPE"), "{}", err);
+    // The connection is still usable afterwards
+    assert_eq!(client.get("string").await.unwrap(), Some(Bytes::from("value")));
+
+    // Raw commands hand error replies back as frames
+    match client.command(&["nosuchcommand"]).await.unwrap() {
+        Frame::Error(msg) => assert!(msg.starts_with("ERR unknown command"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert!(matches!(client.command(&["dbsize"]).await.unwrap(), Frame::Integer(1)));
+}
+
+#[tokio::test]
+async fn pipelined_replies_line_up_with_requests() {
+    let mut client = Client::connect(start_server().await).await.unwrap();
+
+    let replies = client
+        .pipeline()
+        .set("a", "1")
+        .get("a")
+        .rpush("list", "x")
+        .rpush("list", "y")
+        .hset("a", "field", "value")
+        .command(&["lrange", "list", "0", "-1"])
+        .del("a")
+        .get("a")
+        .execute()
+        .await
+        .unwrap();
+
+    assert_eq!(replies.len(), 8);
+    assert_eq!(replies[0], "OK");
+    assert_eq!(replies[1], "1");
+    assert!(matches!(replies[2], Frame::Integer(1)));
+    assert!(matches!(replies[3], Frame::Integer(2)));
+    // A failing command only affects its own reply
+    assert!(matches!(&replies[4], Frame::Error(msg) if msg.starts_with("WRONGTYPE")), "{:?}", replies[4]);
+    match &replies[5] {
+        Frame::Array(items) => assert!(items.len() == 2 && items[0] == "x" && items[1] == "y"),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    assert!(matches!(replies[6], Frame::Integer(1)));
+    assert!(matches!(replies[7], Frame::Null));
+
+    // The client carries on after the pipeline, and an empty one is fine
+    assert!(client.pipeline().execute().await.unwrap().is_empty());
+    assert_eq!(client.lrange("list", 0, -1).await.unwrap(), vec!["x", "y"]);
+}
+
+#[tokio::test]
+async fn subscriber_receives_published_messages() {
+    let addr = start_server().await;
+    let mut publisher = Client::connect(addr).await.unwrap();
+    let subscriber = Client::connect(addr).await.unwrap();
+
+    let mut subscriber = subscriber.subscribe(&["news", "weather"]).await.unwrap();
+    assert_eq!(subscriber.channels(), &["news", "weather"]);
+
+    assert_eq!(publisher.publish("news", "extra").await.unwrap(), 1);
+    let message = subscriber.next_message().await.unwrap().unwrap();
+    assert_eq!(message, Message { channel: "news".into(), payload: "extra".into(), pattern: None });
+
+    // Messages published while a subscription changes aren't lost
+    publisher.publish("weather", "rain").await.unwrap();
+    subscriber.psubscribe(&["sport.*"]).await.unwrap();
+    subscriber.unsubscribe(&["news"]).await.unwrap();
+    assert_eq!(subscriber.channels(), &["weather"]);
+    assert_eq!(subscriber.patterns(), &["sport.*"]);
+    assert_eq!(publisher.publish("news", "ignored").await.unwrap(), 0);
+    publisher.publish("sport.tennis", "ace").await.unwrap();
+
+    let message = subscriber.next_message().await.unwrap().unwrap();
+    assert_eq!((message.channel, message.payload), ("weather".into(), "rain".into()));
+    let message = subscriber.next_message().await.unwrap().unwrap();
+    assert_eq!(
+        message,
+        Message { channel: "sport.tennis".into(), payload: "ace".into(), pattern: Some("sport.*".into()) }
+    );
+
+    subscriber.unsubscribe(&[]).await.unwrap();
+    subscriber.punsubscribe(&[]).await.unwrap();
+    assert!(subscriber.channels().is_empty() && subscriber.patterns().is_empty());
+    assert_eq!(publisher.publish("weather", "sun").await.unwrap(), 0);
+}
@@ -0,0 +1,60 @@
+use bytes::Bytes;
+use rustbucket::{Connection, Frame, Protocol};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+
+/// Encode `frame` with `protocol` and return the raw bytes sent to the peer.
+async fn encode(protocol: Protocol, frame: Frame) -> Vec<u8> {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut peer = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+
+    let mut connection = Connection::new(socket);
+    connection.set_protocol(protocol);
+    connection.write_frame(&frame).await.unwrap();
+    connection.flush().await.unwrap();
+    drop(connection);
+
+    let mut raw = Vec::new();
+    peer.read_to_end(&mut raw).await.unwrap();
+    raw
+}
+
+fn bulk(s: &str) -> Frame {
+    Frame::Bulk(Bytes::from(s.to_string()))
+}
+
+#[tokio::test]
+async fn map_is_flattened_for_resp2() {
+    let map = Frame::Map(vec![(bulk("a"), Frame::Integer(1)), (bulk("b"), Frame::Double(1.5))]);
+
+    assert_eq!(encode(Protocol::Resp2, map.clone()).await, b"*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$3\r\n1.5\r\n");
+    assert_eq!(encode(Protocol::Resp3, map).await, b"%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n,1.5\r\n");
+}
+
+#[tokio::test]
+async fn scalars_are_downgraded_for_resp2() {
+    let cases = [
+        (Frame::Boolean(true), &b":1\r\n"[..], &b"#t\r\n"[..]),
+        (Frame::Boolean(false), b":0\r\n", b"#f\r\n"),
+        (Frame::Null, b"$-1\r\n", b"_\r\n"),
+        (Frame::NullArray, b"*-1\r\n", b"_\r\n"),
+        (Frame::Double(f64::INFINITY), b"$3\r\ninf\r\n", b",inf\r\n"),
+        (Frame::BigNumber("-123".into()), b"$4\r\n-123\r\n", b"(-123\r\n"),
+    ];
+    for (frame, resp2, resp3) in cases {
+        assert_eq!(encode(Protocol::Resp2, frame.clone()).await, resp2, "{:?}", frame);
+        assert_eq!(encode(Protocol::Resp3, frame.clone()).await, resp3, "{:?}", frame);
+    }
+}
+
+#[tokio::test]
+async fn set_and_push_become_arrays_for_resp2() {
+    let set = Frame::Set(vec![bulk("x")]);
+    assert_eq!(encode(Protocol::Resp2, set.clone()).await, b"*1\r\n$1\r\nx\r\n");
+    assert_eq!(encode(Protocol::Resp3, set).await, b"~1\r\n$1\r\nx\r\n");
+
+    let push = Frame::Push(vec![bulk("message"), Frame::Set(vec![])]);
+    assert_eq!(encode(Protocol::Resp2, push.clone()).await, b"*2\r\n$7\r\nmessage\r\n*0\r\n");
+    assert_eq!(encode(Protocol::Resp3, push).await, b">2\r\n$7\r\nmessage\r\n~0\r\n");
+}
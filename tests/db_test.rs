@@ -0,0 +1,139 @@
+use bytes::Bytes;
+use rustbucket::Db;
+use rustbucket::db::DataType;
+
+fn b(s: &str) -> Bytes {
+    Bytes::from(s.to_string())
+}
+
+#[test]
+fn used_memory_tracks_writes() {
+    let db = Db::new();
+    assert_eq!(db.used_memory(), 0);
+
+    db.set(b("key"), Bytes::from(vec![b'x'; 1000]));
+    let after_set = db.used_memory();
+    assert!(after_set > 1000);
+
+    for i in 0..100 {
+        db.rpush(b("list"), vec![Bytes::from(format!("item:{:04}", i))]).unwrap();
+    }
+    // The last push is measured by the next write to its shard
+    db.rpush(b("list"), vec![b("item:0100")]).unwrap();
+    assert!(db.used_memory() > after_set + 100 * 9);
+
+    db.delete(b"list");
+    db.delete(b"key");
+    assert_eq!(db.used_memory(), 0);
+}
+
+#[test]
+fn watch_ignores_other_keys_in_the_shard() {
+    let db = Db::new();
+    let watched = b("watched");
+    let neighbour = (0..)
+        .map(|i| b(&format!("key:{}", i)))
+        .find(|key| db.get_shard_index(key) == db.get_shard_index(&watched))
+        .unwrap();
+
+    let watch = db.watch(watched.clone());
+    db.set(neighbour.clone(), b("v"));
+    db.delete(&neighbour);
+    assert!(!watch.is_modified());
+
+    db.set(watched.clone(), b("v"));
+    assert!(watch.is_modified());
+
+    // A key created and deleted again still counts as modified
+    let watch = db.watch(b("missing"));
+    db.set(b("missing"), b("v"));
+    db.delete(b"missing");
+    assert!(watch.is_modified());
+}
+
+#[test]
+fn mset_sets_all_pairs_at_once() {
+    let db = Db::new();
+    // Enough keys to span every shard
+    let keys: Vec<Bytes> = (0..256).map(|i| b(&format!("key:{}", i))).collect();
+
+    // Racing MSETs of the same keys leave them all from one or the other
+    for _ in 0..100 {
+        let barrier = std::sync::Barrier::new(2);
+        std::thread::scope(|scope| {
+            for value in ["left", "right"] {
+                let (db, keys, barrier) = (&db, &keys, &barrier);
+                scope.spawn(move || {
+                    barrier.wait();
+                    db.mset(keys.iter().map(|key| (key.clone(), b(value))).collect());
+                });
+            }
+        });
+        let first = db.get(&keys[0]).unwrap();
+        assert!(keys.iter().all(|key| db.get(key).unwrap() == first));
+    }
+
+    // A key given twice keeps its last value
+    db.mset(vec![(b("dup"), b("1")), (b("other"), b("2")), (b("dup"), b("3"))]);
+    assert_eq!(db.get(b"dup").unwrap(), Some(b("3")));
+    assert_eq!(db.get(b"other").unwrap(), Some(b("2")));
+}
+
+#[test]
+fn random_key_skips_expired_keys() {
+    let db = Db::new();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    for i in 0..100 {
+        assert!(db.restore(b(&format!("key:{}", i)), DataType::String(b("value")), Some(now + 20), false));
+    }
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert_eq!(db.random_key(), None);
+
+    db.set(b("live"), b("value"));
+    for _ in 0..10 {
+        assert_eq!(db.random_key(), Some(b("live")));
+    }
+}
+
+#[test]
+fn random_picks_are_reproducible_with_a_seeded_rng() {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    let db = Db::new();
+    for i in 0..10 {
+        db.hset(b("hash"), vec![(b(&format!("field:{}", i)), b(&format!("value:{}", i)))]).unwrap();
+        db.zadd(b("zset"), i as f64, b(&format!("member:{}", i)));
+    }
+
+    let picks = |seed| db.hrandfield(b"hash", -20, &mut StdRng::seed_from_u64(seed)).unwrap();
+    assert_eq!(picks(7), picks(7));
+    let picks = |seed| db.zrandmember(b"zset", 3, &mut StdRng::seed_from_u64(seed)).unwrap();
+    assert_eq!(picks(7), picks(7));
+
+    // A negative count repeats picks to reach exactly that many
+    let mut rng = StdRng::seed_from_u64(1);
+    let fields = db.hrandfield(b"hash", -50, &mut rng).unwrap();
+    assert_eq!(fields.len(), 50);
+    let mut distinct: Vec<_> = fields.iter().map(|(field, _)| field.clone()).collect();
+    distinct.sort();
+    distinct.dedup();
+    assert!(distinct.len() < fields.len());
+    for (field, value) in &fields {
+        assert_eq!(db.hget(b"hash", field).as_ref(), Some(value));
+    }
+
+    // A positive count is capped at the size, without repeats
+    let mut members = db.zrandmember(b"zset", 50, &mut rng).unwrap();
+    members.sort_by(|a, b| a.1.total_cmp(&b.1));
+    assert_eq!(members.len(), 10);
+    assert!(members.iter().enumerate().all(|(i, (member, score))| {
+        *member == b(&format!("member:{}", i)) && *score == i as f64
+    }));
+
+    assert!(db.hrandfield(b"missing", -3, &mut rng).unwrap().is_empty());
+    assert!(db.zrandmember(b"hash", 1, &mut rng).is_err());
+}
@@ -0,0 +1,59 @@
+use rustbucket::glob::matches;
+
+fn m(pattern: &str, string: &str) -> bool {
+    matches(pattern.as_bytes(), string.as_bytes())
+}
+
+#[test]
+fn literal_patterns_match_whole_strings() {
+    assert!(m("hello", "hello"));
+    assert!(!m("hello", "hello world"));
+    assert!(!m("ell", "hello"));
+    assert!(m("", ""));
+    assert!(!m("", "a"));
+}
+
+#[test]
+fn star_and_question_mark() {
+    assert!(m("*", ""));
+    assert!(m("*", "anything"));
+    assert!(m("user:*", "user:1"));
+    assert!(!m("user:*", "admin:1"));
+    assert!(m("*:1", "admin:1"));
+    assert!(m("a*b*c", "aXXbYYc"));
+    assert!(!m("a*b*c", "aXXbYY"));
+    assert!(m("h?llo", "hello"));
+    assert!(m("h?llo", "hallo"));
+    assert!(!m("h?llo", "hllo"));
+    assert!(m("**a", "bba"));
+}
+
+#[test]
+fn character_classes() {
+    assert!(m("h[ae]llo", "hello"));
+    assert!(m("h[ae]llo", "hallo"));
+    assert!(!m("h[ae]llo", "hillo"));
+    assert!(m("h[^e]llo", "hallo"));
+    assert!(!m("h[^e]llo", "hello"));
+    assert!(m("h[a-b]llo", "hbllo"));
+    assert!(!m("h[a-b]llo", "hcllo"));
+    // Reversed ranges behave like their ordered counterpart
+    assert!(m("[z-a]", "m"));
+    // A trailing dash is literal
+    assert!(m("[a-]", "-"));
+    assert!(m("[\\]]", "]"));
+}
+
+#[test]
+fn escaping() {
+    assert!(m("a\\*b", "a*b"));
+    assert!(!m("a\\*b", "axb"));
+    assert!(m("\\?", "?"));
+    assert!(!m("\\?", "x"));
+}
+
+#[test]
+fn matches_raw_bytes() {
+    assert!(matches(b"k?y", b"k\xffy"));
+    assert!(matches(b"*", b"\x00\x01"));
+}
@@ -1,17 +1,61 @@
 use bytes::Bytes;
-use rustbucket::{Connection, Frame};
+use rustbucket::notify::KeyspaceEvents;
+use rustbucket::{Connection, Db, Frame, ServerConfig};
 use tokio::net::{TcpListener, TcpStream};
+use std::net::SocketAddr;
+use std::time::Duration;
 
-async fn get_client() -> Connection {
-    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-    let port = listener.local_addr().unwrap().port();
+/// Start a server on a free port and return its address.
+async fn spawn_server() -> SocketAddr {
+    spawn_server_with_config(ServerConfig::default()).await
+}
 
+/// Start a server with `config` on a free port and return its address.
+async fn spawn_server_with_config(config: ServerConfig) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
     tokio::spawn(async move {
-        rustbucket::run(listener).await.unwrap();
+        rustbucket::run_with_config(listener, config, std::future::pending::<()>()).await.unwrap();
     });
+    addr
+}
+
+async fn get_client() -> Connection {
+    Connection::new(TcpStream::connect(spawn_server().await).await.unwrap())
+}
+
+/// Start a server with `config` and connect a client to it.
+async fn get_client_with_config(config: ServerConfig) -> Connection {
+    Connection::new(TcpStream::connect(spawn_server_with_config(config).await).await.unwrap())
+}
+
+/// Build a command frame from its arguments.
+fn cmd(args: &[&str]) -> Frame {
+    Frame::Array(args.iter().map(|arg| Frame::Bulk(Bytes::from(arg.to_string()))).collect())
+}
+
+/// Send a command and read back its reply.
+async fn send(client: &mut Connection, args: &[&str]) -> Frame {
+    client.write_frame(&cmd(args)).await.unwrap();
+    client.read_frame().await.unwrap().unwrap()
+}
+
+/// Collect an array of bulk strings, in reply order.
+fn strings(frame: Frame) -> Vec<String> {
+    match frame {
+        Frame::Array(arr) => arr.iter().map(|f| match f {
+            Frame::Bulk(b) => std::str::from_utf8(b).unwrap().to_string(),
+            other => panic!("Expected Bulk, got {:?}", other),
+        }).collect(),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
 
-    let stream = TcpStream::connect(format!("127.0.0.1:{}", port)).await.unwrap();
-    Connection::new(stream)
+/// Collect an array of bulk strings, sorted, for order-independent comparison.
+fn sorted_strings(frame: Frame) -> Vec<String> {
+    let mut items = strings(frame);
+    items.sort();
+    items
 }
 
 #[tokio::test]
@@ -88,6 +132,59 @@ async fn test_set_get_del() {
     }
 }
 
+#[tokio::test]
+async fn test_del_multiple_keys() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "a", "1"]).await;
+    send(&mut client, &["sadd", "b", "x", "y"]).await;
+
+    assert!(matches!(send(&mut client, &["del", "a", "b", "missing", "a"]).await, Frame::Integer(2)));
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["del", "a"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["del"]).await, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn test_unlink() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "a", "1"]).await;
+    send(&mut client, &["sadd", "b", "x", "y"]).await;
+    // Big enough to be freed in the background
+    let values: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+    let mut args = vec!["rpush", "c"];
+    args.extend(values.iter().map(String::as_str));
+    send(&mut client, &args).await;
+
+    assert!(matches!(send(&mut client, &["unlink", "a", "b", "missing", "c", "a"]).await, Frame::Integer(3)));
+    for key in ["a", "b", "c"] {
+        assert!(matches!(send(&mut client, &["exists", key]).await, Frame::Integer(0)));
+    }
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["unlink", "a"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["unlink"]).await, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn test_get_wrong_type() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["lpush", "list", "a"]).await;
+    match send(&mut client, &["get", "list"]).await {
+        Frame::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{}", e),
+        other => panic!("Expected WRONGTYPE error, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["get", "missing"]).await, Frame::Null));
+
+    // SET overwrites a value of any type
+    send(&mut client, &["set", "list", "v"]).await;
+    match send(&mut client, &["get", "list"]).await {
+        Frame::Bulk(b) => assert_eq!(b, "v"),
+        other => panic!("Expected Bulk, got {:?}", other),
+    }
+}
+
 #[tokio::test]
 async fn test_info() {
     let mut client = get_client().await;
@@ -545,3 +642,3550 @@ async fn test_hash_advanced() {
         _ => panic!("Expected Array [Cursor, Array]"),
     }
 }
+
+#[tokio::test]
+async fn test_hset_hdel_counts() {
+    let mut client = get_client().await;
+
+    // HSET counts only the fields it adds, HDEL only those it removes
+    assert!(matches!(send(&mut client, &["hset", "h", "a", "1", "b", "2"]).await, Frame::Integer(2)));
+    assert!(matches!(send(&mut client, &["hset", "h", "a", "3", "c", "4"]).await, Frame::Integer(1)));
+    assert_eq!(send(&mut client, &["hget", "h", "a"]).await, "3");
+    assert!(matches!(send(&mut client, &["hdel", "h", "a", "missing"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["hdel", "h", "b", "c"]).await, Frame::Integer(2)));
+    assert!(matches!(send(&mut client, &["exists", "h"]).await, Frame::Integer(0)));
+
+    match send(&mut client, &["hset", "h", "a"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR wrong number of arguments for 'hset' command"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    send(&mut client, &["set", "s", "v"]).await;
+    for args in [&["hset", "s", "a", "1"][..], &["hdel", "s", "a"], &["sadd", "s", "a"], &["srem", "s", "a"]] {
+        match send(&mut client, args).await {
+            Frame::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{}", e),
+            other => panic!("Expected WRONGTYPE error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_hstrlen_hgetdel() {
+    let mut client = get_client().await;
+
+    // HSTRLEN counts bytes, not characters
+    send(&mut client, &["hset", "h", "greeting", "héllo wörld"]).await;
+    assert!(matches!(send(&mut client, &["hstrlen", "h", "greeting"]).await, Frame::Integer(13)));
+    assert!(matches!(send(&mut client, &["hstrlen", "h", "missing"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["hstrlen", "missing", "greeting"]).await, Frame::Integer(0)));
+
+    // HGETDEL returns the values while removing their fields
+    send(&mut client, &["hset", "h", "a", "1"]).await;
+    send(&mut client, &["hset", "h", "b", "2"]).await;
+    match send(&mut client, &["hgetdel", "h", "fields", "2", "a", "nope"]).await {
+        Frame::Array(values) => match &values[..] {
+            [Frame::Bulk(a), Frame::Null] => assert_eq!(a, "1"),
+            other => panic!("Expected value and nil, got {:?}", other),
+        },
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    assert_eq!(sorted_strings(send(&mut client, &["hkeys", "h"]).await), vec!["b", "greeting"]);
+
+    // Removing the last fields deletes the key
+    let reply = strings(send(&mut client, &["hgetdel", "h", "FIELDS", "2", "b", "greeting"]).await);
+    assert_eq!(reply, vec!["2", "héllo wörld"]);
+    assert!(matches!(send(&mut client, &["exists", "h"]).await, Frame::Integer(0)));
+
+    match send(&mut client, &["hgetdel", "h", "fields", "2", "a"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR The `numfields` parameter must match the number of arguments"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    send(&mut client, &["rpush", "l", "x"]).await;
+    for args in [&["hstrlen", "l", "x"][..], &["hgetdel", "l", "fields", "1", "x"]] {
+        match send(&mut client, args).await {
+            Frame::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{}", e),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_set_algebra() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["sadd", "s1", "a", "b", "c", "d"]).await;
+    send(&mut client, &["sadd", "s2", "c", "d", "e"]).await;
+    send(&mut client, &["sadd", "s3", "a", "c", "f"]).await;
+
+    // SINTER s1 s2 s3 -> [c]
+    let reply = send(&mut client, &["sinter", "s1", "s2", "s3"]).await;
+    assert_eq!(sorted_strings(reply), vec!["c"]);
+
+    // SUNION s1 s2 s3 -> [a, b, c, d, e, f]
+    let reply = send(&mut client, &["sunion", "s1", "s2", "s3"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a", "b", "c", "d", "e", "f"]);
+
+    // SDIFF s1 s2 s3 -> [b]
+    let reply = send(&mut client, &["sdiff", "s1", "s2", "s3"]).await;
+    assert_eq!(sorted_strings(reply), vec!["b"]);
+
+    // Missing keys behave as empty sets
+    let reply = send(&mut client, &["sinter", "s1", "missing"]).await;
+    assert!(sorted_strings(reply).is_empty());
+    let reply = send(&mut client, &["sdiff", "s1", "missing"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a", "b", "c", "d"]);
+
+    // A non-set key is a WRONGTYPE error
+    send(&mut client, &["set", "str", "v"]).await;
+    match send(&mut client, &["sunion", "s1", "str"]).await {
+        Frame::Error(e) => assert!(e.starts_with("WRONGTYPE")),
+        other => panic!("Expected WRONGTYPE error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_scan_is_scoped_to_selected_db() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "zero:a", "1"]).await;
+    send(&mut client, &["set", "zero:b", "1"]).await;
+
+    assert_eq!(send(&mut client, &["select", "1"]).await, "OK");
+    send(&mut client, &["set", "one:a", "1"]).await;
+
+    // SCAN 0 in db1 -> only db1's key
+    match send(&mut client, &["scan", "0"]).await {
+        Frame::Array(arr) => {
+            assert_eq!(arr[0], "0");
+            assert_eq!(sorted_strings(arr[1].clone()), vec!["one:a"]);
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    let reply = send(&mut client, &["keys", "*"]).await;
+    assert_eq!(sorted_strings(reply), vec!["one:a"]);
+
+    match send(&mut client, &["dbsize"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+
+    // Switching back scans db0 again
+    send(&mut client, &["select", "0"]).await;
+    match send(&mut client, &["scan", "0"]).await {
+        Frame::Array(arr) => assert_eq!(sorted_strings(arr[1].clone()), vec!["zero:a", "zero:b"]),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    match send(&mut client, &["get", "one:a"]).await {
+        Frame::Null => {}
+        other => panic!("Expected Null, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_flushdb_and_flushall_per_database() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "zero:a", "v"]).await;
+    send(&mut client, &["set", "zero:b", "v"]).await;
+    send(&mut client, &["select", "1"]).await;
+    send(&mut client, &["set", "one:a", "v"]).await;
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(1)));
+    send(&mut client, &["select", "0"]).await;
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(2)));
+
+    // FLUSHDB only clears the selected database
+    send(&mut client, &["select", "1"]).await;
+    assert_eq!(send(&mut client, &["flushdb"]).await, "OK");
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+    send(&mut client, &["select", "0"]).await;
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(2)));
+
+    // FLUSHALL clears them all, from whichever is selected
+    send(&mut client, &["select", "2"]).await;
+    send(&mut client, &["set", "two:a", "v"]).await;
+    assert_eq!(send(&mut client, &["flushall"]).await, "OK");
+    for db in ["0", "1", "2"] {
+        send(&mut client, &["select", db]).await;
+        assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+    }
+}
+
+#[tokio::test]
+async fn test_flush_async_and_sync() {
+    let mut client = get_client().await;
+
+    for i in 0..1000 {
+        send(&mut client, &["sadd", &format!("set:{}", i % 10), &i.to_string()]).await;
+    }
+    send(&mut client, &["set", "string", "v"]).await;
+
+    // ASYNC replies once the keys are gone, freeing them afterwards
+    assert_eq!(send(&mut client, &["flushdb", "async"]).await, "OK");
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+    for key in ["set:0", "string"] {
+        assert!(matches!(send(&mut client, &["exists", key]).await, Frame::Integer(0)));
+    }
+
+    send(&mut client, &["set", "again", "v"]).await;
+    assert_eq!(send(&mut client, &["flushall", "SYNC"]).await, "OK");
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+
+    for args in [&["flushdb", "lazy"][..], &["flushall", "async", "sync"]] {
+        match send(&mut client, args).await {
+            Frame::Error(e) => assert_eq!(e, "ERR syntax error"),
+            other => panic!("Expected syntax error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_swapdb() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    send(&mut client, &["set", "shared", "zero"]).await;
+    send(&mut client, &["sadd", "only:zero", "a", "b"]).await;
+    send(&mut client, &["select", "1"]).await;
+    send(&mut client, &["set", "shared", "one"]).await;
+    send(&mut client, &["rpush", "only:one", "x"]).await;
+    let deadline = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64 + 100_000;
+    send(&mut client, &["pexpireat", "only:one", &deadline.to_string()]).await;
+
+    assert_eq!(send(&mut client, &["swapdb", "0", "1"]).await, "OK");
+    assert_eq!(send(&mut client, &["get", "shared"]).await, "zero");
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "only:zero"]).await), vec!["a", "b"]);
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(2)));
+    send(&mut client, &["select", "0"]).await;
+    assert_eq!(send(&mut client, &["get", "shared"]).await, "one");
+    assert_eq!(strings(send(&mut client, &["lrange", "only:one", "0", "-1"]).await), vec!["x"]);
+    match send(&mut client, &["pexpiretime", "only:one"]).await {
+        Frame::Integer(time) => assert_eq!(time, deadline),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["exists", "only:zero"]).await, Frame::Integer(0)));
+
+    // A transaction watching a key on either side aborts
+    assert_eq!(send(&mut client, &["watch", "only:zero"]).await, "OK");
+    assert_eq!(send(&mut other, &["swapdb", "1", "0"]).await, "OK");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["get", "shared"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::NullArray));
+
+    // Clients blocked on a key that arrives by the swap are served
+    client.write_frame(&cmd(&["blpop", "only:one", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(send(&mut other, &["swapdb", "0", "1"]).await, "OK");
+    assert_eq!(strings(client.read_frame().await.unwrap().unwrap()), ["only:one", "x"]);
+
+    match send(&mut client, &["swapdb", "0", "16"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR DB index is out of range"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_set_algebra_store() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["sadd", "s1", "a", "b", "c"]).await;
+    send(&mut client, &["sadd", "s2", "b", "c", "d"]).await;
+
+    // SINTERSTORE dest s1 s2 -> 2, dest = [b, c]
+    match send(&mut client, &["sinterstore", "inter", "s1", "s2"]).await {
+        Frame::Integer(n) => assert_eq!(n, 2),
+        other => panic!("Expected Integer 2, got {:?}", other),
+    }
+    let reply = send(&mut client, &["smembers", "inter"]).await;
+    assert_eq!(sorted_strings(reply), vec!["b", "c"]);
+
+    // SUNIONSTORE dest s1 s2 -> 4
+    match send(&mut client, &["sunionstore", "union", "s1", "s2"]).await {
+        Frame::Integer(n) => assert_eq!(n, 4),
+        other => panic!("Expected Integer 4, got {:?}", other),
+    }
+    let reply = send(&mut client, &["smembers", "union"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a", "b", "c", "d"]);
+
+    // SDIFFSTORE overwrites an existing destination of any type
+    send(&mut client, &["set", "diff", "old"]).await;
+    match send(&mut client, &["sdiffstore", "diff", "s1", "s2"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    let reply = send(&mut client, &["smembers", "diff"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a"]);
+
+    // An empty result deletes the destination
+    match send(&mut client, &["sinterstore", "inter", "s1", "missing"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+    match send(&mut client, &["exists", "inter"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_sintercard() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["sadd", "s1", "a", "b", "c", "d", "e"]).await;
+    send(&mut client, &["sadd", "s2", "b", "c", "d", "e", "f"]).await;
+    send(&mut client, &["sadd", "s3", "c", "d", "e", "g"]).await;
+
+    for (args, expected) in [
+        (&["sintercard", "1", "s1"][..], 5),
+        (&["sintercard", "2", "s1", "s2"], 4),
+        (&["sintercard", "3", "s1", "s2", "s3"], 3),
+        (&["sintercard", "2", "s1", "missing"], 0),
+        // LIMIT caps the count, and zero means no limit
+        (&["sintercard", "3", "s1", "s2", "s3", "LIMIT", "2"], 2),
+        (&["sintercard", "3", "s1", "s2", "s3", "limit", "10"], 3),
+        (&["sintercard", "2", "s1", "s2", "LIMIT", "0"], 4),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Integer(n) => assert_eq!(n, expected, "{:?}", args),
+            other => panic!("Expected Integer, got {:?}", other),
+        }
+    }
+
+    send(&mut client, &["set", "string", "value"]).await;
+    for (args, expected) in [
+        (&["sintercard", "2", "s1", "string"][..], "WRONGTYPE"),
+        (&["sintercard", "0", "s1"], "ERR numkeys should be greater than 0"),
+        (&["sintercard", "3", "s1", "s2"], "ERR Number of keys can't be greater than number of args"),
+        (&["sintercard", "1", "s1", "s2"], "ERR syntax error"),
+        (&["sintercard", "1", "s1", "LIMIT", "-1"], "ERR LIMIT can't be negative"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    let keys = send(&mut client, &["command", "getkeys", "sintercard", "2", "s1", "s2", "LIMIT", "1"]).await;
+    assert_eq!(strings(keys), ["s1", "s2"]);
+}
+
+#[tokio::test]
+async fn test_zadd_options_and_order() {
+    let mut client = get_client().await;
+
+    // inf sorts last and -inf first, equal scores by member
+    send(&mut client, &["zadd", "z", "inf", "top", "1", "b", "1", "a", "-inf", "bottom", "+inf", "also-top"]).await;
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1"]).await), ["bottom", "a", "b", "also-top", "top"]);
+
+    for (args, expected, scores) in [
+        // NX only adds, XX only updates
+        (&["zadd", "z", "NX", "5", "a", "2", "c"][..], 1, ["a:1", "c:2"]),
+        (&["zadd", "z", "XX", "3", "a", "2", "d"], 0, ["a:3", "c:2"]),
+        // GT and LT only move scores one way, but still add members
+        (&["zadd", "z", "GT", "2", "a", "4", "c"], 0, ["a:3", "c:4"]),
+        (&["zadd", "z", "LT", "2", "a", "5", "c", "1", "e"], 1, ["a:2", "c:4"]),
+        // CH counts changed members too
+        (&["zadd", "z", "CH", "GT", "7", "a", "0", "c", "9", "f"], 2, ["a:7", "c:4"]),
+        (&["zadd", "z", "XX", "CH", "7", "a", "8", "c"], 1, ["a:7", "c:8"]),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Integer(n) => assert_eq!(n, expected, "{:?}", args),
+            other => panic!("Expected Integer, got {:?}", other),
+        }
+        let range = strings(send(&mut client, &["zrange", "z", "0", "-1", "withscores"]).await);
+        for score in scores {
+            let (member, score) = score.split_once(':').unwrap();
+            let at = range.iter().position(|m| m == member).unwrap_or_else(|| panic!("{} missing", member));
+            assert_eq!(range[at + 1], score, "{:?}", args);
+        }
+    }
+    // XX never creates the key
+    assert!(matches!(send(&mut client, &["zadd", "new", "XX", "1", "a"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["exists", "new"]).await, Frame::Integer(0)));
+
+    send(&mut client, &["set", "string", "value"]).await;
+    for (args, expected) in [
+        (&["zadd", "z", "nan", "a"][..], "ERR value is not a valid float"),
+        (&["zadd", "z", "NaN", "a"], "ERR value is not a valid float"),
+        (&["zadd", "z", "NX", "XX", "1", "a"], "ERR XX and NX options at the same time are not compatible"),
+        (&["zadd", "z", "GT", "LT", "1", "a"], "ERR GT, LT, and/or NX options at the same time are not compatible"),
+        (&["zadd", "z", "NX", "GT", "1", "a"], "ERR GT, LT, and/or NX options at the same time are not compatible"),
+        (&["zadd", "z", "NX"], "ERR wrong number of arguments for 'zadd' command"),
+        (&["zadd", "string", "1", "a"], "WRONGTYPE"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    // Nothing was stored by the rejected commands
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "0"]).await), ["bottom"]);
+}
+
+#[tokio::test]
+async fn test_zadd_and_zincrby_scores() {
+    let mut client = get_client().await;
+
+    // Scores that aren't floats are an error, not a score of zero
+    for (args, expected) in [
+        (&["zadd", "z", "abc", "member"][..], "ERR value is not a valid float"),
+        (&["zadd", "z", "1", "a", "2b", "b"], "ERR value is not a valid float"),
+        (&["zadd", "z", "1", "a", "2"], "ERR syntax error"),
+        (&["zadd", "z", "NX", "1"], "ERR syntax error"),
+        (&["zincrby", "z", "abc", "member"], "ERR value is not a valid float"),
+        (&["zincrby", "z", "nan", "member"], "ERR value is not a valid float"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    assert!(matches!(send(&mut client, &["exists", "z"]).await, Frame::Integer(0)));
+
+    // A member may look like a number
+    assert!(matches!(send(&mut client, &["zadd", "z", "1", "2", "3", "4"]).await, Frame::Integer(2)));
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1", "withscores"]).await), ["2", "1", "4", "3"]);
+
+    assert_eq!(send(&mut client, &["zincrby", "z", "1.5", "2"]).await, "2.5");
+    assert_eq!(send(&mut client, &["zincrby", "z", "-10", "new"]).await, "-10");
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1"]).await), ["new", "2", "4"]);
+
+    // inf - inf has no score
+    send(&mut client, &["zincrby", "z", "inf", "big"]).await;
+    match send(&mut client, &["zincrby", "z", "-inf", "big"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR resulting score is not a number (NaN)"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "-1", "-1", "withscores"]).await), ["big", "inf"]);
+
+    send(&mut client, &["set", "string", "value"]).await;
+    match send(&mut client, &["zincrby", "string", "1", "a"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_zrangebylex_and_zlexcount() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["zadd", "z", "0", "e", "0", "c", "0", "a", "0", "d", "0", "b"]).await;
+
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "[b", "(d"]).await), ["b", "c"]);
+    assert!(matches!(send(&mut client, &["zlexcount", "z", "[b", "(d"]).await, Frame::Integer(2)));
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "(b", "[d"]).await), ["c", "d"]);
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "-", "+"]).await), ["a", "b", "c", "d", "e"]);
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "[c", "+"]).await), ["c", "d", "e"]);
+    assert!(matches!(send(&mut client, &["zlexcount", "z", "-", "(c"]).await, Frame::Integer(2)));
+    // Bounds needn't be members
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "[bb", "[dd"]).await), ["c", "d"]);
+    assert!(strings(send(&mut client, &["zrangebylex", "z", "+", "-"]).await).is_empty());
+    assert!(matches!(send(&mut client, &["zlexcount", "z", "[d", "[b"]).await, Frame::Integer(0)));
+
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "-", "+", "limit", "1", "2"]).await), ["b", "c"]);
+    assert_eq!(strings(send(&mut client, &["zrangebylex", "z", "-", "+", "LIMIT", "3", "-1"]).await), ["d", "e"]);
+    assert!(strings(send(&mut client, &["zrangebylex", "z", "-", "+", "limit", "-1", "2"]).await).is_empty());
+
+    assert!(strings(send(&mut client, &["zrangebylex", "missing", "-", "+"]).await).is_empty());
+    assert!(matches!(send(&mut client, &["zlexcount", "missing", "-", "+"]).await, Frame::Integer(0)));
+
+    for args in [&["zrangebylex", "z", "b", "[d"][..], &["zlexcount", "z", "[b", "d"], &["zlexcount", "z", "-", "++"]] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, "ERR min or max not valid string range item"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    match send(&mut client, &["zrangebylex", "z", "-", "+", "limit", "1"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("ERR"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    send(&mut client, &["set", "string", "value"]).await;
+    match send(&mut client, &["zlexcount", "string", "-", "+"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_zrange_options_and_zrangestore() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["zadd", "src", "1", "a", "2", "b", "3", "c", "4", "d"]).await;
+
+    // The bottom two, with their scores
+    assert!(matches!(send(&mut client, &["zrangestore", "dst", "src", "0", "1"]).await, Frame::Integer(2)));
+    assert_eq!(strings(send(&mut client, &["zrange", "dst", "0", "-1", "withscores"]).await), ["a", "1", "b", "2"]);
+
+    assert_eq!(strings(send(&mut client, &["zrange", "src", "0", "1", "rev"]).await), ["d", "c"]);
+    assert_eq!(strings(send(&mut client, &["zrange", "src", "(1", "3", "byscore"]).await), ["b", "c"]);
+    assert_eq!(strings(send(&mut client, &["zrange", "src", "+inf", "2", "byscore", "rev", "limit", "1", "5"]).await), ["c", "b"]);
+    assert_eq!(strings(send(&mut client, &["zrange", "src", "[d", "[b", "bylex", "rev"]).await), ["d", "c", "b"]);
+
+    // Replaces a destination of any type, and deletes it when nothing's picked
+    send(&mut client, &["set", "string", "value"]).await;
+    assert!(matches!(send(&mut client, &["zrangestore", "string", "src", "3", "+inf", "byscore"]).await, Frame::Integer(2)));
+    assert_eq!(strings(send(&mut client, &["zrange", "string", "0", "-1", "withscores"]).await), ["c", "3", "d", "4"]);
+    assert!(matches!(send(&mut client, &["zrangestore", "dst", "src", "5", "9", "byscore"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["exists", "dst"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["zrangestore", "dst", "missing", "0", "-1"]).await, Frame::Integer(0)));
+
+    send(&mut client, &["set", "string", "value"]).await;
+    match send(&mut client, &["zrangestore", "dst", "string", "0", "-1"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    for (args, expected) in [
+        (&["zrangestore", "dst", "src", "0", "-1", "withscores"][..], "ERR syntax error"),
+        (&["zrange", "src", "0", "-1", "byscore", "bylex"], "ERR syntax error"),
+        (&["zrange", "src", "0", "-1", "limit", "0", "1"], "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"),
+        (&["zrange", "src", "a", "1", "byscore"], "ERR min or max is not a float"),
+        (&["zrange", "src", "a", "1"], "ERR value is not an integer or out of range"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_zmpop_and_bzmpop() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    /// The key and the `[member, score]` pairs of a ZMPOP reply.
+    fn popped(frame: Frame) -> (String, Vec<Vec<String>>) {
+        match frame {
+            Frame::Array(mut parts) if parts.len() == 2 => {
+                let members = match parts.pop().unwrap() {
+                    Frame::Array(pairs) => pairs.into_iter().map(strings).collect(),
+                    other => panic!("Expected Array, got {:?}", other),
+                };
+                let key = match parts.pop().unwrap() {
+                    Frame::Bulk(key) => String::from_utf8(key.to_vec()).unwrap(),
+                    other => panic!("Expected Bulk, got {:?}", other),
+                };
+                (key, members)
+            }
+            other => panic!("Expected a key and members, got {:?}", other),
+        }
+    }
+
+    // The first set is empty, so the pop comes from the second
+    send(&mut client, &["zadd", "second", "1", "a", "2", "b", "3", "c"]).await;
+    let (key, members) = popped(send(&mut client, &["zmpop", "2", "first", "second", "min"]).await);
+    assert_eq!(key, "second");
+    assert_eq!(members, [["a", "1"]]);
+    let (_, members) = popped(send(&mut client, &["zmpop", "1", "second", "MAX", "COUNT", "5"]).await);
+    assert_eq!(members, [["c", "3"], ["b", "2"]]);
+    assert!(matches!(send(&mut client, &["exists", "second"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["zmpop", "2", "first", "second", "min"]).await, Frame::NullArray));
+
+    for (args, expected) in [
+        (&["zmpop", "0", "first", "min"][..], "ERR numkeys should be greater than 0"),
+        (&["zmpop", "3", "first", "min"], "ERR Number of keys can't be greater than number of args"),
+        (&["zmpop", "1", "first", "middle"], "ERR syntax error"),
+        (&["zmpop", "1", "first", "min", "count", "0"], "ERR count should be greater than 0"),
+        (&["bzmpop", "-1", "1", "first", "min"], "ERR timeout is negative"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    send(&mut client, &["set", "string", "value"]).await;
+    match send(&mut client, &["zmpop", "2", "first", "string", "min"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // BZMPOP waits for a ZADD, and gives up at its timeout
+    client.write_frame(&cmd(&["bzmpop", "0", "2", "first", "second", "max", "count", "2"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut other, &["zadd", "second", "5", "x", "6", "y", "7", "z"]).await;
+    let (key, members) = popped(client.read_frame().await.unwrap().unwrap());
+    assert_eq!(key, "second");
+    assert_eq!(members, [["z", "7"], ["y", "6"]]);
+    assert!(matches!(send(&mut client, &["bzmpop", "0.05", "1", "first", "min"]).await, Frame::NullArray));
+}
+
+#[tokio::test]
+async fn test_info_server_section() {
+    let mut client = get_client().await;
+
+    let info = match send(&mut client, &["info", "server"]).await {
+        Frame::Bulk(b) => std::str::from_utf8(&b).unwrap().to_string(),
+        other => panic!("Expected Bulk, got {:?}", other),
+    };
+    let field = |name: &str| {
+        info.lines()
+            .find_map(|line| line.strip_prefix(&format!("{}:", name)))
+            .unwrap_or_else(|| panic!("missing {} in INFO", name))
+            .to_string()
+    };
+
+    let run_id = field("run_id");
+    assert_eq!(run_id.len(), 40);
+    assert!(run_id.chars().all(|c| c.is_ascii_hexdigit()));
+
+    let uptime: i64 = field("uptime_in_seconds").parse().unwrap();
+    assert!(uptime >= 0);
+    assert_eq!(field("process_id"), std::process::id().to_string());
+    assert_ne!(field("tcp_port"), "0");
+}
+
+#[tokio::test]
+async fn test_info_sections() {
+    let mut client = get_client().await;
+
+    for key in ["a", "b", "c"] {
+        assert_eq!(send(&mut client, &["set", key, "1"]).await, "OK");
+    }
+    let keyspace = send(&mut client, &["info", "keyspace"]).await.to_string();
+    assert!(keyspace.starts_with("# Keyspace\r\n"), "{}", keyspace);
+    assert!(keyspace.lines().any(|line| line.starts_with("db0:keys=3,")), "{}", keyspace);
+    // Only the requested section is returned
+    assert!(!keyspace.contains("# Server"), "{}", keyspace);
+
+    let info = send(&mut client, &["INFO"]).await.to_string();
+    for header in ["# Server", "# Clients", "# Memory", "# Stats", "# Replication", "# Keyspace"] {
+        assert!(info.contains(header), "missing {} in {}", header, info);
+    }
+    assert!(info.contains("connected_clients:1\r\n"), "{}", info);
+    let processed: u64 = info
+        .lines()
+        .find_map(|line| line.strip_prefix("total_commands_processed:"))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(processed >= 5, "{}", info);
+    assert!(!info.contains("used_memory:0\r\n"), "{}", info);
+}
+
+#[tokio::test]
+async fn test_spop_srandmember() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["sadd", "s", "a", "b", "c"]).await;
+
+    // A count larger than the set returns the whole set, without duplicates
+    let reply = send(&mut client, &["srandmember", "s", "10"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a", "b", "c"]);
+
+    // A negative count samples with replacement, so members repeat
+    let reply = send(&mut client, &["srandmember", "s", "-10"]).await;
+    let members = sorted_strings(reply);
+    assert_eq!(members.len(), 10);
+    assert!(members.iter().all(|m| ["a", "b", "c"].contains(&m.as_str())));
+
+    // Huge counts are bounded rather than allocated up front
+    let reply = send(&mut client, &["srandmember", "s", "100000000000000000"]).await;
+    assert_eq!(sorted_strings(reply), vec!["a", "b", "c"]);
+    match send(&mut client, &["srandmember", "s", "-100000000000000000"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR value is out of range"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // Without a count a single member is returned
+    match send(&mut client, &["srandmember", "s"]).await {
+        Frame::Bulk(b) => assert!(["a", "b", "c"].contains(&std::str::from_utf8(&b).unwrap())),
+        other => panic!("Expected Bulk, got {:?}", other),
+    }
+
+    // SPOP without a count removes one member
+    let popped = match send(&mut client, &["spop", "s"]).await {
+        Frame::Bulk(b) => std::str::from_utf8(&b).unwrap().to_string(),
+        other => panic!("Expected Bulk, got {:?}", other),
+    };
+    let remaining = sorted_strings(send(&mut client, &["smembers", "s"]).await);
+    assert_eq!(remaining.len(), 2);
+    assert!(!remaining.contains(&popped));
+
+    // SPOP with a count larger than the set pops everything and deletes the key
+    let reply = send(&mut client, &["spop", "s", "100000000000000000"]).await;
+    assert_eq!(sorted_strings(reply), remaining);
+    match send(&mut client, &["exists", "s"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+
+    // Missing keys
+    assert!(matches!(send(&mut client, &["spop", "s"]).await, Frame::Null));
+    assert!(matches!(send(&mut client, &["srandmember", "s"]).await, Frame::Null));
+    assert!(sorted_strings(send(&mut client, &["srandmember", "s", "-3"]).await).is_empty());
+
+    match send(&mut client, &["spop", "s", "-1"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR value is out of range, must be positive"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_hrandfield_zrandmember() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["hset", "h", "f", "v"]).await;
+    send(&mut client, &["zadd", "z", "1.5", "m"]).await;
+
+    // WITHVALUES and WITHSCORES interleave each pick with its value
+    let reply = strings(send(&mut client, &["hrandfield", "h", "-3", "withvalues"]).await);
+    assert_eq!(reply, vec!["f", "v", "f", "v", "f", "v"]);
+    let reply = strings(send(&mut client, &["zrandmember", "z", "2", "withscores"]).await);
+    assert_eq!(reply, vec!["m", "1.5"]);
+
+    // A negative count allows duplicates, a positive one is capped
+    send(&mut client, &["hset", "h", "g", "w"]).await;
+    let reply = sorted_strings(send(&mut client, &["hrandfield", "h", "-5"]).await);
+    assert_eq!(reply.len(), 5);
+    assert!(reply.iter().all(|f| f == "f" || f == "g"));
+    assert_eq!(sorted_strings(send(&mut client, &["hrandfield", "h", "5"]).await), vec!["f", "g"]);
+
+    match send(&mut client, &["zrandmember", "z"]).await {
+        Frame::Bulk(b) => assert_eq!(b, "m"),
+        other => panic!("Expected Bulk, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["hrandfield", "missing"]).await, Frame::Null));
+    match send(&mut client, &["hrandfield", "h", "1", "withscores"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR syntax error"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["zrandmember", "h"]).await {
+        Frame::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{}", e),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // RESP3 pairs each pick with its value
+    send(&mut client, &["hello", "3"]).await;
+    match send(&mut client, &["zrandmember", "z", "1", "withscores"]).await {
+        Frame::Array(pairs) => match &pairs[..] {
+            [Frame::Array(pair)] => assert!(matches!(&pair[..], [Frame::Bulk(_), Frame::Double(score)] if *score == 1.5)),
+            other => panic!("Expected one pair, got {:?}", other),
+        },
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_smove() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["sadd", "src", "a", "b"]).await;
+    send(&mut client, &["sadd", "dst", "c"]).await;
+
+    // SMOVE src dst a -> 1
+    match send(&mut client, &["smove", "src", "dst", "a"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "src"]).await), vec!["b"]);
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "dst"]).await), vec!["a", "c"]);
+
+    // Not a member of the source -> 0
+    match send(&mut client, &["smove", "src", "dst", "missing"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+
+    // Already in the destination still moves, and an emptied source is deleted
+    send(&mut client, &["sadd", "dst", "b"]).await;
+    match send(&mut client, &["smove", "src", "dst", "b"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    match send(&mut client, &["exists", "src"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "dst"]).await), vec!["a", "b", "c"]);
+
+    // A non-set destination is rejected without touching the source
+    send(&mut client, &["set", "str", "v"]).await;
+    match send(&mut client, &["smove", "dst", "str", "a"]).await {
+        Frame::Error(e) => assert!(e.starts_with("WRONGTYPE")),
+        other => panic!("Expected WRONGTYPE error, got {:?}", other),
+    }
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "dst"]).await), vec!["a", "b", "c"]);
+}
+
+#[tokio::test]
+async fn test_select_validation() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["select", "15"]).await, "OK");
+
+    for index in ["-1", "16", "99999999999"] {
+        match send(&mut client, &["select", index]).await {
+            Frame::Error(e) => assert_eq!(e, "ERR DB index is out of range"),
+            other => panic!("SELECT {} expected out of range error, got {:?}", index, other),
+        }
+    }
+
+    match send(&mut client, &["select", "abc"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR value is not an integer or out of range"),
+        other => panic!("Expected integer error, got {:?}", other),
+    }
+
+    // The failed SELECTs left database 15 selected
+    send(&mut client, &["set", "k", "v"]).await;
+    send(&mut client, &["select", "0"]).await;
+    assert!(matches!(send(&mut client, &["get", "k"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_rename() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["rpush", "src", "a", "b"]).await;
+    send(&mut client, &["set", "dst", "old"]).await;
+
+    // RENAME overwrites the destination, whatever its type
+    assert_eq!(send(&mut client, &["rename", "src", "dst"]).await, "OK");
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), vec!["a", "b"]);
+    match send(&mut client, &["exists", "src"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+
+    // Renaming a key onto itself is a no-op
+    assert_eq!(send(&mut client, &["rename", "dst", "dst"]).await, "OK");
+    match send(&mut client, &["type", "dst"]).await {
+        Frame::Simple(s) => assert_eq!(s, "list"),
+        other => panic!("Expected list, got {:?}", other),
+    }
+
+    // Missing source
+    match send(&mut client, &["rename", "missing", "x"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR no such key"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // RENAMENX refuses to overwrite an existing destination
+    send(&mut client, &["set", "a", "1"]).await;
+    send(&mut client, &["set", "b", "2"]).await;
+    match send(&mut client, &["renamenx", "a", "b"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["get", "a"]).await, "1");
+    assert_eq!(send(&mut client, &["get", "b"]).await, "2");
+
+    match send(&mut client, &["renamenx", "a", "c"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["get", "c"]).await, "1");
+}
+
+#[tokio::test]
+async fn test_copy() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["json.set", "doc", "$", r#"{"a":{"b":[1,2]}}"#]).await;
+    send(&mut client, &["set", "taken", "old"]).await;
+
+    // Existing destination without REPLACE is left alone
+    match send(&mut client, &["copy", "doc", "taken"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["get", "taken"]).await, "old");
+
+    // REPLACE overrides it, with an independent copy of the value
+    match send(&mut client, &["copy", "doc", "taken", "replace"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    send(&mut client, &["json.set", "doc", "$", r#"{"changed":true}"#]).await;
+    assert_eq!(send(&mut client, &["json.get", "taken"]).await, r#"{"a":{"b":[1,2]}}"#);
+
+    // Across databases
+    send(&mut client, &["sadd", "set", "x", "y"]).await;
+    match send(&mut client, &["copy", "set", "set", "db", "3"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    send(&mut client, &["select", "3"]).await;
+    assert_eq!(sorted_strings(send(&mut client, &["smembers", "set"]).await), vec!["x", "y"]);
+    send(&mut client, &["select", "0"]).await;
+
+    match send(&mut client, &["copy", "set", "set"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR source and destination objects are the same"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["copy", "set", "other", "db", "16"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR DB index is out of range"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["copy", "missing", "other"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer 0, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_move() {
+    let mut client = get_client().await;
+    let deadline = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64 + 100_000;
+
+    send(&mut client, &["rpush", "list", "a", "b"]).await;
+    send(&mut client, &["pexpireat", "list", &deadline.to_string()]).await;
+    assert!(matches!(send(&mut client, &["move", "list", "1"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["exists", "list"]).await, Frame::Integer(0)));
+
+    // The value arrives with its type and expiry
+    send(&mut client, &["select", "1"]).await;
+    assert_eq!(strings(send(&mut client, &["lrange", "list", "0", "-1"]).await), vec!["a", "b"]);
+    match send(&mut client, &["pexpiretime", "list"]).await {
+        Frame::Integer(time) => assert_eq!(time, deadline),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // Nothing moves onto an existing key, or from a missing one
+    send(&mut client, &["select", "0"]).await;
+    send(&mut client, &["set", "list", "mine"]).await;
+    assert!(matches!(send(&mut client, &["move", "list", "1"]).await, Frame::Integer(0)));
+    assert_eq!(send(&mut client, &["get", "list"]).await, "mine");
+    assert!(matches!(send(&mut client, &["move", "missing", "1"]).await, Frame::Integer(0)));
+
+    match send(&mut client, &["move", "list", "0"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR source and destination objects are the same"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["move", "list", "16"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR DB index is out of range"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_expiretime() {
+    let mut client = get_client().await;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as i64;
+    let deadline = now + 100_000;
+
+    send(&mut client, &["set", "key", "value"]).await;
+    assert!(matches!(send(&mut client, &["expiretime", "key"]).await, Frame::Integer(-1)));
+    assert!(matches!(send(&mut client, &["pexpiretime", "missing"]).await, Frame::Integer(-2)));
+    assert!(matches!(send(&mut client, &["expiretime", "missing"]).await, Frame::Integer(-2)));
+
+    assert!(matches!(send(&mut client, &["pexpireat", "key", &deadline.to_string()]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["pexpireat", "missing", &deadline.to_string()]).await, Frame::Integer(0)));
+    match send(&mut client, &["pexpiretime", "key"]).await {
+        Frame::Integer(time) => assert_eq!(time, deadline),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    match send(&mut client, &["expiretime", "key"]).await {
+        Frame::Integer(time) => assert_eq!(time, deadline / 1000),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // EXPIREAT takes seconds, and the reply rounds down to them
+    let seconds = deadline / 1000 + 60;
+    send(&mut client, &["expireat", "key", &seconds.to_string()]).await;
+    match send(&mut client, &["pexpiretime", "key"]).await {
+        Frame::Integer(time) => assert_eq!(time, seconds * 1000),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // A deadline in the past deletes the key
+    assert!(matches!(send(&mut client, &["pexpireat", "key", &(now - 1000).to_string()]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["exists", "key"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["expiretime", "key"]).await, Frame::Integer(-2)));
+}
+
+#[tokio::test]
+async fn test_rename_and_copy_keep_expiry() {
+    let mut client = get_client().await;
+
+    // RESTORE is the way to give a key a TTL
+    send(&mut client, &["rpush", "template", "a", "b"]).await;
+    let payload = match send(&mut client, &["dump", "template"]).await {
+        Frame::Bulk(payload) => payload,
+        other => panic!("Expected Bulk, got {:?}", other),
+    };
+    let restore = Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"restore")),
+        Frame::Bulk(Bytes::from_static(b"src")),
+        Frame::Bulk(Bytes::from_static(b"30000")),
+        Frame::Bulk(payload),
+    ]);
+    client.write_frame(&restore).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    let pttl = |frame: Frame| match frame {
+        Frame::Integer(ms) => ms,
+        other => panic!("Expected Integer, got {:?}", other),
+    };
+
+    // RENAME carries the remaining time over, and drops the destination's
+    send(&mut client, &["set", "dst", "old"]).await;
+    assert_eq!(send(&mut client, &["rename", "src", "dst"]).await, "OK");
+    let ttl = pttl(send(&mut client, &["pttl", "dst"]).await);
+    assert!((29_000..=30_000).contains(&ttl), "pttl {}", ttl);
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), vec!["a", "b"]);
+    assert_eq!(pttl(send(&mut client, &["pttl", "src"]).await), -2);
+
+    // So do RENAMENX and COPY, within a database and across them
+    assert!(matches!(send(&mut client, &["renamenx", "dst", "moved"]).await, Frame::Integer(1)));
+    let ttl = pttl(send(&mut client, &["pttl", "moved"]).await);
+    assert!((29_000..=30_000).contains(&ttl), "pttl {}", ttl);
+    assert!(matches!(send(&mut client, &["copy", "moved", "copied"]).await, Frame::Integer(1)));
+    let ttl = pttl(send(&mut client, &["pttl", "copied"]).await);
+    assert!((29_000..=30_000).contains(&ttl), "pttl {}", ttl);
+    assert!(matches!(send(&mut client, &["copy", "moved", "copied", "db", "2"]).await, Frame::Integer(1)));
+    send(&mut client, &["select", "2"]).await;
+    let ttl = pttl(send(&mut client, &["pttl", "copied"]).await);
+    assert!((29_000..=30_000).contains(&ttl), "pttl {}", ttl);
+    send(&mut client, &["select", "0"]).await;
+
+    // A key without a TTL replacing one with a TTL leaves it persistent
+    assert!(matches!(send(&mut client, &["copy", "template", "copied", "replace"]).await, Frame::Integer(1)));
+    assert_eq!(pttl(send(&mut client, &["pttl", "copied"]).await), -1);
+    assert_eq!(send(&mut client, &["rename", "template", "moved"]).await, "OK");
+    assert_eq!(pttl(send(&mut client, &["pttl", "moved"]).await), -1);
+}
+
+#[tokio::test]
+async fn test_copied_json_is_independent() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["json.set", "doc", "$", r#"{"tags":["a"],"user":{"name":"x"}}"#]).await;
+    assert!(matches!(send(&mut client, &["copy", "doc", "copy"]).await, Frame::Integer(1)));
+    match send(&mut client, &["type", "copy"]).await {
+        Frame::Simple(t) => assert_eq!(t, "ReJSON-RL"),
+        other => panic!("Expected Simple, got {:?}", other),
+    }
+
+    // Changing nested parts of the copy leaves the original alone, and the
+    // other way around
+    send(&mut client, &["json.arrappend", "copy", "$.tags", r#""b""#]).await;
+    send(&mut client, &["json.set", "doc", "$.user.name", r#""y""#]).await;
+    assert_eq!(send(&mut client, &["json.get", "doc"]).await, r#"{"tags":["a"],"user":{"name":"y"}}"#);
+    assert_eq!(send(&mut client, &["json.get", "copy"]).await, r#"{"tags":["a","b"],"user":{"name":"x"}}"#);
+}
+
+#[tokio::test]
+async fn test_object_idletime() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "k", "v"]).await;
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+
+    match send(&mut client, &["object", "idletime", "k"]).await {
+        Frame::Integer(idle) => assert!((2..=3).contains(&idle), "idle time {}", idle),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // Reading the key resets its idle time
+    assert_eq!(send(&mut client, &["get", "k"]).await, "v");
+    match send(&mut client, &["object", "idletime", "k"]).await {
+        Frame::Integer(idle) => assert_eq!(idle, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    assert!(matches!(send(&mut client, &["object", "idletime", "missing"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_object_idletime_and_freq_under_lru() {
+    use rustbucket::db::EvictionPolicy;
+
+    let config = ServerConfig { maxmemory_policy: EvictionPolicy::AllKeysLru, ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+
+    send(&mut client, &["set", "k", "v"]).await;
+    assert_eq!(send(&mut client, &["get", "k"]).await, "v");
+    assert!(matches!(send(&mut client, &["object", "idletime", "k"]).await, Frame::Integer(0)));
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    match send(&mut client, &["object", "idletime", "k"]).await {
+        Frame::Integer(idle) => assert!((1..=2).contains(&idle), "idle time {}", idle),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // Frequencies are only tracked under an LFU policy
+    match send(&mut client, &["object", "freq", "k"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("ERR An LFU maxmemory policy is not selected"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["object", "freq", "missing"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_object_freq_under_lfu() {
+    use rustbucket::db::EvictionPolicy;
+
+    let config = ServerConfig { maxmemory_policy: EvictionPolicy::AllKeysLfu, ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+
+    send(&mut client, &["set", "hot", "v"]).await;
+    send(&mut client, &["set", "cold", "v"]).await;
+    let freq = |reply| match reply {
+        Frame::Integer(freq) => freq,
+        other => panic!("Expected Integer, got {:?}", other),
+    };
+    let initial = freq(send(&mut client, &["object", "freq", "cold"]).await);
+
+    // The counter grows logarithmically, so a few hundred reads are plenty
+    for _ in 0..200 {
+        send(&mut client, &["get", "hot"]).await;
+    }
+    let hot = freq(send(&mut client, &["object", "freq", "hot"]).await);
+    assert!(hot > initial + 1, "hot {} vs cold {}", hot, initial);
+    assert_eq!(freq(send(&mut client, &["object", "freq", "cold"]).await), initial);
+    assert!(matches!(send(&mut client, &["object", "freq", "missing"]).await, Frame::Null));
+
+    // Idle times are only tracked under the other policies
+    match send(&mut client, &["object", "idletime", "hot"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("ERR An LFU maxmemory policy is selected"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_touch() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "a", "1"]).await;
+    send(&mut client, &["rpush", "b", "x"]).await;
+    assert!(matches!(send(&mut client, &["touch", "a", "b", "missing"]).await, Frame::Integer(2)));
+    assert!(matches!(send(&mut client, &["touch", "missing"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["touch"]).await, Frame::Error(_)));
+
+    // Touching a key resets its idle time, like reading it
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(matches!(send(&mut client, &["touch", "a"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["object", "idletime", "a"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["object", "idletime", "b"]).await, Frame::Integer(1..)));
+}
+
+#[tokio::test]
+async fn test_randomkey() {
+    let mut client = get_client().await;
+
+    assert!(matches!(send(&mut client, &["randomkey"]).await, Frame::Null));
+
+    let keys = ["a", "b", "c", "d", "e"];
+    for key in keys {
+        send(&mut client, &["set", key, "v"]).await;
+    }
+    for _ in 0..20 {
+        match send(&mut client, &["randomkey"]).await {
+            Frame::Bulk(key) => assert!(keys.contains(&std::str::from_utf8(&key).unwrap())),
+            other => panic!("Expected Bulk, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_object_encoding() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "short", "hello"]).await;
+    send(&mut client, &["set", "number", "12345"]).await;
+    send(&mut client, &["set", "padded", "012345"]).await;
+    send(&mut client, &["set", "long", &"x".repeat(100)]).await;
+    send(&mut client, &["rpush", "list", "a", "b"]).await;
+    send(&mut client, &["zadd", "zset", "1", "a"]).await;
+
+    for (key, encoding) in [
+        ("short", "embstr"),
+        ("number", "int"),
+        ("padded", "embstr"),
+        ("long", "raw"),
+        ("list", "listpack"),
+        ("zset", "listpack"),
+    ] {
+        assert_eq!(send(&mut client, &["object", "encoding", key]).await, encoding, "{}", key);
+    }
+    assert!(matches!(send(&mut client, &["object", "encoding", "missing"]).await, Frame::Null));
+
+    match send(&mut client, &["object", "refcount", "short"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    match send(&mut client, &["object", "bogus", "short"]).await {
+        Frame::Error(e) => assert!(e.starts_with("ERR unknown subcommand 'bogus'"), "{}", e),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_set_encoding_lifecycle() {
+    let mut client = get_client().await;
+    let long = "x".repeat(100);
+
+    send(&mut client, &["sadd", "s", "1", "2", "3"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "s"]).await, "intset");
+
+    // A short string fits a listpack
+    send(&mut client, &["sadd", "small", "1", "2", "a"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "small"]).await, "listpack");
+
+    // A long string doesn't, and removing it doesn't convert back
+    send(&mut client, &["sadd", "s", &long]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "s"]).await, "hashtable");
+    send(&mut client, &["srem", "s", &long]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "s"]).await, "hashtable");
+    send(&mut client, &["spop", "s"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "s"]).await, "hashtable");
+
+    // Too many integers for an intset
+    let members: Vec<String> = (0..513).map(|i| i.to_string()).collect();
+    let mut args = vec!["sadd", "big"];
+    args.extend(members.iter().map(String::as_str));
+    send(&mut client, &args).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "big"]).await, "hashtable");
+}
+
+#[tokio::test]
+async fn test_zset_encoding_crosses_threshold() {
+    let mut client = get_client().await;
+
+    let pairs: Vec<String> = (0..128).flat_map(|i| [(127 - i).to_string(), format!("m{:03}", i)]).collect();
+    let mut args = vec!["zadd", "z"];
+    args.extend(pairs.iter().map(String::as_str));
+    send(&mut client, &args).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "z"]).await, "listpack");
+    let expected: Vec<String> = (0..128).rev().map(|i| format!("m{:03}", i)).collect();
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1"]).await), expected);
+
+    // One more member than a listpack holds
+    send(&mut client, &["zadd", "z", "-1", "first"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "z"]).await, "skiplist");
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "2"]).await), ["first", "m127", "m126"]);
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1"]).await).len(), 129);
+
+    // So does a member too long for one
+    send(&mut client, &["zadd", "long", "1", &"x".repeat(65)]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "long"]).await, "skiplist");
+}
+
+#[tokio::test]
+async fn test_glob_patterns() {
+    let mut client = get_client().await;
+
+    for key in ["user:1", "user:2", "admin:1", "hello", "hallo"] {
+        send(&mut client, &["set", key, "v"]).await;
+    }
+
+    assert_eq!(sorted_strings(send(&mut client, &["keys", "user:*"]).await), vec!["user:1", "user:2"]);
+    assert_eq!(sorted_strings(send(&mut client, &["keys", "h[ae]llo"]).await), vec!["hallo", "hello"]);
+    assert_eq!(sorted_strings(send(&mut client, &["keys", "*:1"]).await), vec!["admin:1", "user:1"]);
+
+    match send(&mut client, &["scan", "0", "match", "user:?"]).await {
+        Frame::Array(mut reply) => {
+            assert_eq!(sorted_strings(reply.remove(1)), vec!["user:1", "user:2"]);
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    send(&mut client, &["hset", "h", "name", "x"]).await;
+    send(&mut client, &["hset", "h", "age", "1"]).await;
+    match send(&mut client, &["hscan", "h", "0", "match", "n*"]).await {
+        Frame::Array(mut reply) => assert_eq!(strings(reply.remove(1)), vec!["name", "x"]),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_scan_cursor() {
+    let mut client = get_client().await;
+
+    for i in 0..1000 {
+        send(&mut client, &["set", &format!("key:{}", i), "v"]).await;
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    let mut cursor = "0".to_string();
+    let mut calls: usize = 0;
+    loop {
+        let reply = send(&mut client, &["scan", &cursor, "count", "50"]).await;
+        let Frame::Array(mut reply) = reply else { panic!("Expected Array, got {:?}", reply) };
+        let keys = strings(reply.remove(1));
+        assert!(keys.len() <= 50, "page of {} keys", keys.len());
+        seen.extend(keys);
+
+        // Churn the keyspace while the scan is in progress
+        send(&mut client, &["set", &format!("new:{}", calls), "v"]).await;
+        send(&mut client, &["del", &format!("new:{}", calls.saturating_sub(1))]).await;
+
+        calls += 1;
+        cursor = reply.remove(0).to_string();
+        if cursor == "0" {
+            break;
+        }
+        assert!(calls < 1000, "scan did not terminate");
+    }
+
+    assert!(calls >= 20, "only {} calls", calls);
+    for i in 0..1000 {
+        assert!(seen.contains(&format!("key:{}", i)), "key:{} missing", i);
+    }
+
+    match send(&mut client, &["scan", "abc"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR invalid cursor"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_copy_types_and_encodings() {
+    let mut client = get_client().await;
+
+    // REPLACE overwrites a destination of another type
+    send(&mut client, &["rpush", "list", "a", "b"]).await;
+    send(&mut client, &["set", "dst", "string"]).await;
+    match send(&mut client, &["copy", "list", "dst", "replace"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["type", "dst"]).await, "list");
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), vec!["a", "b"]);
+
+    // Copies keep the set encoding, including one that never converts back
+    send(&mut client, &["sadd", "ints", "1", "2", "3"]).await;
+    send(&mut client, &["copy", "ints", "ints2"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "ints2"]).await, "intset");
+
+    let long = "x".repeat(100);
+    send(&mut client, &["sadd", "grown", "1", &long]).await;
+    send(&mut client, &["srem", "grown", &long]).await;
+    send(&mut client, &["copy", "grown", "grown2"]).await;
+    assert_eq!(send(&mut client, &["object", "encoding", "grown2"]).await, "hashtable");
+
+    // Same key is only an error within the same database
+    send(&mut client, &["select", "2"]).await;
+    send(&mut client, &["set", "k", "v"]).await;
+    match send(&mut client, &["copy", "k", "k", "db", "2"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR source and destination objects are the same"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["copy", "k", "k", "db", "0"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer 1, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_arity_errors() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["ping"]).await, "PONG");
+    assert_eq!(send(&mut client, &["ping", "a"]).await, "a");
+    match send(&mut client, &["ping", "a", "b"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR wrong number of arguments for 'ping' command"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // Too few arguments report the same error
+    match send(&mut client, &["get"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR wrong number of arguments for 'get' command"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["GET", "a", "b"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR wrong number of arguments for 'get' command"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_replicaof() {
+    let mut client = get_client().await;
+
+    let role = |info: Frame| info.to_string().lines().find(|l| l.starts_with("role:")).map(String::from);
+
+    assert_eq!(send(&mut client, &["replicaof", "no", "one"]).await, "OK");
+    assert_eq!(role(send(&mut client, &["info"]).await).as_deref(), Some("role:master"));
+
+    assert_eq!(send(&mut client, &["slaveof", "127.0.0.1", "6380"]).await, "OK");
+    let info = send(&mut client, &["info"]).await.to_string();
+    assert!(info.contains("role:slave\r\n"), "{}", info);
+    assert!(info.contains("master_host:127.0.0.1\r\n"), "{}", info);
+    assert!(info.contains("master_port:6380\r\n"), "{}", info);
+
+    assert_eq!(send(&mut client, &["REPLICAOF", "NO", "ONE"]).await, "OK");
+    assert_eq!(role(send(&mut client, &["info"]).await).as_deref(), Some("role:master"));
+}
+
+#[tokio::test]
+async fn test_master_feeds_replicas() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut replica = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    send(&mut client, &["set", "before", "1"]).await;
+    send(&mut client, &["sadd", "set", "a", "b"]).await;
+    send(&mut client, &["select", "1"]).await;
+    send(&mut client, &["set", "other", "db"]).await;
+
+    // The replica starts from a snapshot of every database
+    match send(&mut replica, &["psync", "?", "-1"]).await {
+        Frame::Simple(reply) => assert!(reply.starts_with("FULLRESYNC "), "{}", reply),
+        other => panic!("Expected FULLRESYNC, got {:?}", other),
+    }
+    let Some(Frame::Bulk(snapshot)) = replica.read_frame().await.unwrap() else {
+        panic!("Expected the snapshot as a bulk string");
+    };
+    let copy = Db::new();
+    copy.load_snapshot(&snapshot).unwrap();
+    assert_eq!(copy.get(b"before").unwrap().as_deref(), Some(&b"1"[..]));
+    assert_eq!(copy.select(1).unwrap().get(b"other").unwrap().as_deref(), Some(&b"db"[..]));
+
+    let info = send(&mut client, &["info", "replication"]).await.to_string();
+    assert!(info.contains("connected_slaves:1\r\n"), "{}", info);
+
+    // Then gets every write, with the members SPOP picked rather than the
+    // pop itself, and nothing else
+    send(&mut client, &["set", "after", "2"]).await;
+    send(&mut client, &["get", "after"]).await;
+    send(&mut client, &["select", "0"]).await;
+    let popped = strings(send(&mut client, &["spop", "set", "1"]).await);
+    let mut forwarded = Vec::new();
+    for _ in 0..4 {
+        forwarded.push(strings(replica.read_frame().await.unwrap().unwrap()));
+    }
+    let mut srem = vec!["SREM", "set"];
+    srem.extend(popped.iter().map(String::as_str));
+    assert_eq!(forwarded, [vec!["SELECT", "1"], vec!["set", "after", "2"], vec!["SELECT", "0"], srem]);
+
+    drop(replica);
+    for _ in 0..100 {
+        let info = send(&mut client, &["info", "replication"]).await.to_string();
+        if info.contains("connected_slaves:0\r\n") {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("The replica was never unregistered");
+}
+
+#[tokio::test]
+async fn test_replica_follows_master() {
+    let master_addr = spawn_server().await;
+    let mut master = Connection::new(TcpStream::connect(master_addr).await.unwrap());
+    let mut replica = get_client().await;
+
+    send(&mut master, &["set", "a", "1"]).await;
+    send(&mut master, &["sadd", "set", "x", "y"]).await;
+    send(&mut master, &["select", "2"]).await;
+    send(&mut master, &["set", "other", "db"]).await;
+    send(&mut master, &["select", "0"]).await;
+    send(&mut replica, &["set", "stale", "gone after the sync"]).await;
+
+    let port = master_addr.port().to_string();
+    assert_eq!(send(&mut replica, &["replicaof", "127.0.0.1", &port]).await, "OK");
+
+    // The initial sync replaces the replica's dataset with the master's
+    let mut synced = false;
+    for _ in 0..200 {
+        let info = send(&mut replica, &["info", "replication"]).await.to_string();
+        if info.contains("master_link_status:up\r\n") {
+            synced = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(synced, "The replica never synced");
+    assert_eq!(send(&mut replica, &["get", "a"]).await, "1");
+    assert!(matches!(send(&mut replica, &["get", "stale"]).await, Frame::Null));
+    send(&mut replica, &["select", "2"]).await;
+    assert_eq!(send(&mut replica, &["get", "other"]).await, "db");
+    send(&mut replica, &["select", "0"]).await;
+
+    // Then writes on the master show up on the replica
+    send(&mut master, &["set", "b", "2"]).await;
+    send(&mut master, &["spop", "set"]).await;
+    send(&mut master, &["del", "a"]).await;
+    send(&mut master, &["set", "last", "write"]).await;
+    for _ in 0..200 {
+        if send(&mut replica, &["get", "last"]).await == "write" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    for args in [&["keys", "*"][..], &["smembers", "set"]] {
+        assert_eq!(sorted_strings(send(&mut replica, args).await), sorted_strings(send(&mut master, args).await));
+    }
+
+    // Clients of the replica can only read
+    let readonly = "READONLY You can't write against a read only replica";
+    match send(&mut replica, &["set", "b", "3"]).await {
+        Frame::Error(msg) => assert_eq!(msg, readonly),
+        other => panic!("Expected READONLY, got {:?}", other),
+    }
+    assert_eq!(send(&mut replica, &["multi"]).await, "OK");
+    assert!(matches!(send(&mut replica, &["del", "b"]).await, Frame::Error(msg) if msg == readonly));
+    assert!(matches!(send(&mut replica, &["exec"]).await, Frame::Error(msg) if msg.starts_with("EXECABORT")));
+    assert_eq!(send(&mut replica, &["get", "b"]).await, "2");
+
+    // Until it stops replicating, keeping the dataset
+    assert_eq!(send(&mut replica, &["replicaof", "no", "one"]).await, "OK");
+    assert_eq!(send(&mut replica, &["set", "b", "3"]).await, "OK");
+    send(&mut master, &["set", "after", "unlinked"]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(matches!(send(&mut replica, &["get", "after"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_json_set_paths() {
+    let mut client = get_client().await;
+
+    // A new document can only be created at the root
+    match send(&mut client, &["json.set", "doc", "$.a", "1"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR new objects must be created at the root"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["json.set", "doc", "$", r#"{"list":[1,2]}"#]).await, "OK");
+
+    // Nested fields are created along the way
+    assert_eq!(send(&mut client, &["json.set", "doc", "$.a.b", r#""deep""#]).await, "OK");
+    assert_eq!(send(&mut client, &["json.get", "doc", "$.a.b"]).await, r#""deep""#);
+    assert_eq!(send(&mut client, &["json.set", "doc", "$.list[-1]", "3"]).await, "OK");
+    assert_eq!(send(&mut client, &["json.get", "doc", "$.list"]).await, "[1,3]");
+
+    // XX on a missing path and NX on an existing one do nothing
+    assert!(matches!(send(&mut client, &["json.set", "doc", "$.missing", "1", "XX"]).await, Frame::Null));
+    assert!(matches!(send(&mut client, &["json.get", "doc", "$.missing"]).await, Frame::Null));
+    assert!(matches!(send(&mut client, &["json.set", "doc", "$.a.b", "1", "NX"]).await, Frame::Null));
+    assert_eq!(send(&mut client, &["json.set", "doc", "$.a.b", "1", "XX"]).await, "OK");
+    assert_eq!(send(&mut client, &["json.set", "doc", "$.a.c", "2", "NX"]).await, "OK");
+    assert_eq!(send(&mut client, &["json.get", "doc", "$.a"]).await, r#"{"b":1,"c":2}"#);
+
+    // Traversing through a scalar fails and leaves the document alone
+    match send(&mut client, &["json.set", "doc", "$.a.b.c.d", "1"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR path traverses a value that is not an object or array"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["json.set", "doc", "$.x.y[0]", "1"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR path traverses a value that is not an object or array"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["json.get", "doc"]).await, r#"{"a":{"b":1,"c":2},"list":[1,3]}"#);
+}
+
+#[tokio::test]
+async fn test_json_del_type_arrappend() {
+    let mut client = get_client().await;
+
+    let doc = r#"{"user":{"name":"ann","age":30,"score":1.5,"admin":false,"tags":["a"],"nick":null}}"#;
+    send(&mut client, &["json.set", "doc", "$", doc]).await;
+
+    for (path, name) in [
+        ("$", "object"),
+        ("$.user.name", "string"),
+        ("$.user.age", "integer"),
+        ("$.user.score", "number"),
+        ("$.user.admin", "boolean"),
+        ("$.user.tags", "array"),
+        ("$.user.nick", "null"),
+    ] {
+        assert_eq!(send(&mut client, &["json.type", "doc", path]).await, name, "{}", path);
+    }
+    assert!(matches!(send(&mut client, &["json.type", "doc", "$.nope"]).await, Frame::Null));
+
+    // Appending to an array
+    match send(&mut client, &["json.arrappend", "doc", "$.user.tags", r#""b""#, "3"]).await {
+        Frame::Integer(n) => assert_eq!(n, 3),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["json.get", "doc", "$.user.tags"]).await, r#"["a","b",3]"#);
+    match send(&mut client, &["json.arrappend", "doc", "$.user.name", "1"]).await {
+        Frame::Error(e) => assert_eq!(e, "ERR path is not an array"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // Deleting a nested field, then the whole document
+    match send(&mut client, &["json.del", "doc", "$.user.age"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["json.get", "doc", "$.user.age"]).await, Frame::Null));
+    match send(&mut client, &["json.del", "doc", "$.user.age"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    match send(&mut client, &["json.del", "doc"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    match send(&mut client, &["exists", "doc"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_hello() {
+    let mut client = get_client().await;
+
+    // Without a version the reply stays RESP2, so the map is a flat array
+    match send(&mut client, &["hello"]).await {
+        Frame::Array(items) => {
+            assert_eq!(items.len(), 12);
+            assert_eq!(items[0], "server");
+            assert_eq!(items[1], "redis");
+            assert_eq!(items[4], "proto");
+            assert!(matches!(items[5], Frame::Integer(2)));
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    match send(&mut client, &["hello", "3", "auth", "default", "secret"]).await {
+        Frame::Map(entries) => {
+            let field = |name: &str| {
+                entries.iter().find(|(key, _)| *key == name).map(|(_, value)| value).unwrap()
+            };
+            assert_eq!(*field("server"), "redis");
+            assert!(matches!(field("proto"), Frame::Integer(3)));
+            assert_eq!(*field("mode"), "standalone");
+            assert_eq!(*field("role"), "master");
+        }
+        other => panic!("Expected Map, got {:?}", other),
+    }
+
+    // Later replies use RESP3 types too
+    assert!(matches!(send(&mut client, &["get", "missing"]).await, Frame::Null));
+
+    match send(&mut client, &["hello", "4"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("NOPROTO"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    match send(&mut client, &["hello", "2"]).await {
+        Frame::Array(items) => assert!(matches!(items[5], Frame::Integer(2))),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_resp3_replies() {
+    let mut client = get_client().await;
+    send(&mut client, &["hset", "h", "field", "value"]).await;
+    send(&mut client, &["zadd", "z", "1.5", "a", "2", "b"]).await;
+
+    // RESP2 flattens the hash and the scores
+    assert_eq!(strings(send(&mut client, &["hgetall", "h"]).await), vec!["field", "value"]);
+    assert_eq!(
+        strings(send(&mut client, &["zrange", "z", "0", "-1", "withscores"]).await),
+        vec!["a", "1.5", "b", "2"]
+    );
+
+    send(&mut client, &["hello", "3"]).await;
+
+    match send(&mut client, &["hgetall", "h"]).await {
+        Frame::Map(entries) => {
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].0, "field");
+            assert_eq!(entries[0].1, "value");
+        }
+        other => panic!("Expected Map, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["hgetall", "missing"]).await, Frame::Map(e) if e.is_empty()));
+
+    match send(&mut client, &["zrange", "z", "0", "-1", "withscores"]).await {
+        Frame::Array(pairs) => {
+            let pairs: Vec<(String, f64)> = pairs.into_iter().map(|pair| match pair {
+                Frame::Array(pair) => match &pair[..] {
+                    [Frame::Bulk(member), Frame::Double(score)] => {
+                        (std::str::from_utf8(member).unwrap().to_string(), *score)
+                    }
+                    other => panic!("Expected member and score, got {:?}", other),
+                },
+                other => panic!("Expected Array, got {:?}", other),
+            }).collect();
+            assert_eq!(pairs, vec![("a".to_string(), 1.5), ("b".to_string(), 2.0)]);
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    assert_eq!(strings(send(&mut client, &["zrange", "z", "0", "-1"]).await), vec!["a", "b"]);
+}
+
+#[tokio::test]
+async fn test_client_name_and_id() {
+    let addr = spawn_server().await;
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let mut client = Connection::new(stream);
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert!(matches!(send(&mut client, &["client", "getname"]).await, Frame::Null));
+    assert_eq!(send(&mut client, &["client", "setname", "worker-1"]).await, "OK");
+    assert_eq!(send(&mut client, &["client", "getname"]).await, "worker-1");
+    // Names are per connection
+    assert!(matches!(send(&mut other, &["client", "getname"]).await, Frame::Null));
+    match send(&mut client, &["client", "setname", "has space"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR Client names cannot contain spaces, newlines or special characters."),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(send(&mut client, &["client", "getname"]).await, "worker-1");
+
+    // Ids are positive and handed out in connection order
+    let id = match send(&mut client, &["client", "id"]).await {
+        Frame::Integer(id) => id,
+        other => panic!("Expected Integer, got {:?}", other),
+    };
+    assert!(id > 0);
+    match send(&mut other, &["client", "id"]).await {
+        Frame::Integer(other_id) => assert!(other_id > id, "{} then {}", id, other_id),
+        frame => panic!("Expected Integer, got {:?}", frame),
+    }
+
+    send(&mut client, &["select", "3"]).await;
+    let info = send(&mut client, &["client", "info"]).await.to_string();
+    assert!(info.starts_with(&format!("id={} addr={} name=worker-1 db=3 ", id, client_addr)), "{}", info);
+
+    // An empty name clears it, and HELLO can set one too
+    assert_eq!(send(&mut client, &["client", "setname", ""]).await, "OK");
+    assert!(matches!(send(&mut client, &["client", "getname"]).await, Frame::Null));
+    send(&mut client, &["hello", "2", "setname", "via-hello"]).await;
+    assert_eq!(send(&mut client, &["client", "getname"]).await, "via-hello");
+
+    match send(&mut client, &["client", "nope"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("ERR unknown subcommand 'nope'"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_client_list_and_kill() {
+    let addr = spawn_server().await;
+    let mut admin = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let victim_addr = stream.local_addr().unwrap();
+    let mut victim = Connection::new(stream);
+    let id = |frame: Frame| match frame {
+        Frame::Integer(id) => id,
+        other => panic!("Expected Integer, got {:?}", other),
+    };
+    let admin_id = id(send(&mut admin, &["client", "id"]).await);
+    let victim_id = id(send(&mut victim, &["client", "id"]).await);
+    send(&mut victim, &["client", "setname", "victim"]).await;
+    send(&mut victim, &["select", "4"]).await;
+
+    // One line per connection, showing what each has set
+    let list = send(&mut admin, &["client", "list"]).await.to_string();
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", list);
+    assert!(lines[0].starts_with(&format!("id={} ", admin_id)), "{}", list);
+    assert!(
+        lines[1].starts_with(&format!("id={} addr={} name=victim db=4 ", victim_id, victim_addr)),
+        "{}",
+        list
+    );
+
+    assert_eq!(send(&mut admin, &["client", "no-evict", "on"]).await, "OK");
+
+    // Killing by id closes the connection
+    assert!(matches!(send(&mut admin, &["client", "kill", "id", &victim_id.to_string()]).await, Frame::Integer(1)));
+    assert!(victim.read_frame().await.unwrap_or(None).is_none());
+    let list = send(&mut admin, &["client", "list"]).await.to_string();
+    assert_eq!(list.lines().count(), 1, "{}", list);
+
+    // Nothing matches, or only the caller, which is skipped by default
+    assert!(matches!(send(&mut admin, &["client", "kill", "id", &victim_id.to_string()]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut admin, &["client", "kill", "id", &admin_id.to_string()]).await, Frame::Integer(0)));
+    match send(&mut admin, &["client", "kill", "127.0.0.1:1"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR No such client"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // Killing by address, with the old form
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let other_addr = stream.local_addr().unwrap();
+    let mut other = Connection::new(stream);
+    assert_eq!(send(&mut other, &["ping"]).await, "PONG");
+    assert_eq!(send(&mut admin, &["client", "kill", &other_addr.to_string()]).await, "OK");
+    assert!(other.read_frame().await.unwrap_or(None).is_none());
+}
+
+#[tokio::test]
+async fn test_monitor() {
+    let addr = spawn_server().await;
+    let mut monitor = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let stream = TcpStream::connect(addr).await.unwrap();
+    let client_addr = stream.local_addr().unwrap();
+    let mut client = Connection::new(stream);
+
+    assert_eq!(send(&mut monitor, &["monitor"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "greeting", "hello \"world\"\n"]).await, "OK");
+    send(&mut client, &["select", "2"]).await;
+    send(&mut client, &["auth", "secret"]).await;
+
+    // Each line is the time, the database and client, then the quoted
+    // arguments
+    let mut next_line = async || match monitor.read_frame().await.unwrap().unwrap() {
+        Frame::Simple(line) => line,
+        other => panic!("Expected Simple, got {:?}", other),
+    };
+    let line = next_line().await;
+    let (time, rest) = line.split_once(' ').unwrap();
+    let (secs, micros) = time.split_once('.').unwrap();
+    assert!(secs.parse::<u64>().is_ok() && micros.len() == 6, "{}", line);
+    assert_eq!(rest, format!(r#"[0 {}] "set" "greeting" "hello \"world\"\n""#, client_addr));
+    let line = next_line().await;
+    assert!(line.ends_with(&format!(r#"[0 {}] "select" "2""#, client_addr)), "{}", line);
+    // Passwords are hidden, and the database follows SELECT
+    let line = next_line().await;
+    assert!(line.ends_with(&format!(r#"[2 {}] "auth" "(redacted)""#, client_addr)), "{}", line);
+
+    // Nothing but RESET runs on the monitoring connection
+    match send(&mut monitor, &["get", "greeting"]).await {
+        Frame::Error(msg) => assert!(msg.contains("only RESET is allowed"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(send(&mut monitor, &["reset"]).await, "RESET");
+    assert_eq!(send(&mut monitor, &["get", "greeting"]).await, "hello \"world\"\n");
+
+    // And it can't be part of a transaction
+    send(&mut client, &["multi"]).await;
+    match send(&mut client, &["monitor"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR Command not allowed inside a transaction"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    match send(&mut client, &["exec"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("EXECABORT"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_concurrent_lpush_lpop() {
+    let addr = spawn_server().await;
+
+    let pushers: Vec<_> = (0..8)
+        .map(|task| {
+            tokio::spawn(async move {
+                let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+                for i in 0..100 {
+                    let item = format!("{}:{}", task, i);
+                    assert!(matches!(send(&mut client, &["lpush", "queue", &item, &item]).await, Frame::Integer(_)));
+                }
+            })
+        })
+        .collect();
+    let poppers: Vec<_> = (0..4)
+        .map(|_| {
+            tokio::spawn(async move {
+                let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+                let mut popped = Vec::new();
+                for _ in 0..100 {
+                    if let Frame::Bulk(item) = send(&mut client, &["lpop", "queue"]).await {
+                        popped.push(std::str::from_utf8(&item).unwrap().to_string());
+                    }
+                }
+                popped
+            })
+        })
+        .collect();
+
+    for pusher in pushers {
+        pusher.await.unwrap();
+    }
+    let mut items = Vec::new();
+    for popper in poppers {
+        items.extend(popper.await.unwrap());
+    }
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    items.extend(strings(send(&mut client, &["lrange", "queue", "0", "-1"]).await));
+
+    // Every item was pushed twice, and each copy is either popped or left
+    items.sort();
+    let mut expected: Vec<String> = (0..8).flat_map(|task| (0..100).map(move |i| format!("{}:{}", task, i))).collect();
+    expected.extend(expected.clone());
+    expected.sort();
+    assert_eq!(items, expected);
+}
+
+#[tokio::test]
+async fn test_concurrent_sadd() {
+    let addr = spawn_server().await;
+
+    // Tasks overlap on half their members, so each member is added once
+    let tasks: Vec<_> = (0..8)
+        .map(|task| {
+            tokio::spawn(async move {
+                let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+                let mut added = 0;
+                for i in 0..50 {
+                    let shared = format!("shared:{}", i);
+                    let own = format!("{}:{}", task, i);
+                    match send(&mut client, &["sadd", "set", &shared, &own]).await {
+                        Frame::Integer(n) => added += n,
+                        other => panic!("Expected Integer, got {:?}", other),
+                    }
+                }
+                added
+            })
+        })
+        .collect();
+    let mut added = 0;
+    for task in tasks {
+        added += task.await.unwrap();
+    }
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(added, 50 + 8 * 50);
+    assert_eq!(strings(send(&mut client, &["smembers", "set"]).await).len(), 50 + 8 * 50);
+}
+
+#[tokio::test]
+async fn test_publish_subscribe() {
+    let addr = spawn_server().await;
+    let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut publisher = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    // Nobody is listening yet
+    match send(&mut publisher, &["publish", "news", "early"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    subscriber.write_frame(&cmd(&["subscribe", "news", "sports"])).await.unwrap();
+    for (channel, count) in [("news", 1), ("sports", 2)] {
+        match subscriber.read_frame().await.unwrap().unwrap() {
+            Frame::Array(items) => {
+                assert_eq!(items[0], "subscribe");
+                assert_eq!(items[1], channel);
+                assert!(matches!(items[2], Frame::Integer(n) if n == count));
+            }
+            other => panic!("Expected Array, got {:?}", other),
+        }
+    }
+
+    match send(&mut publisher, &["publish", "news", "hello"]).await {
+        Frame::Integer(n) => assert_eq!(n, 1),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    let message = tokio::time::timeout(Duration::from_secs(1), subscriber.read_frame())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(strings(message), vec!["message", "news", "hello"]);
+
+    // Only subscription commands are accepted while subscribed
+    match send(&mut subscriber, &["get", "key"]).await {
+        Frame::Error(msg) => assert!(msg.contains("only (P)SUBSCRIBE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert_eq!(strings(send(&mut subscriber, &["ping"]).await), vec!["pong", ""]);
+
+    match send(&mut subscriber, &["unsubscribe", "news"]).await {
+        Frame::Array(items) => assert!(matches!(items[2], Frame::Integer(1))),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    match send(&mut publisher, &["publish", "news", "gone"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    // Leaving the last channel returns to normal commands
+    match send(&mut subscriber, &["unsubscribe"]).await {
+        Frame::Array(items) => {
+            assert_eq!(items[1], "sports");
+            assert!(matches!(items[2], Frame::Integer(0)));
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    assert!(matches!(send(&mut subscriber, &["get", "key"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_ping_while_subscribed() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = spawn_server().await;
+    let mut raw = TcpStream::connect(addr).await.unwrap();
+    async fn exchange(raw: &mut TcpStream, request: &[u8], reply: &[u8]) {
+        raw.write_all(request).await.unwrap();
+        let mut buf = vec![0; reply.len()];
+        raw.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), String::from_utf8_lossy(reply));
+    }
+
+    // Outside subscriber mode PING is a simple string
+    exchange(&mut raw, b"PING\r\n", b"+PONG\r\n").await;
+    exchange(&mut raw, b"SUBSCRIBE news\r\n", b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n").await;
+
+    // While subscribed it's a two element array, echoing the message if any
+    exchange(&mut raw, b"PING\r\n", b"*2\r\n$4\r\npong\r\n$0\r\n\r\n").await;
+    exchange(&mut raw, b"PING hello\r\n", b"*2\r\n$4\r\npong\r\n$5\r\nhello\r\n").await;
+
+    // Other commands are refused, naming the ones that are allowed
+    let error = b"-ERR Can't execute 'set': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / RESET are allowed in this context\r\n";
+    exchange(&mut raw, b"SET key value\r\n", error).await;
+
+    // Pattern subscriptions count too, and the connection keeps working
+    exchange(&mut raw, b"PSUBSCRIBE n*\r\n", b"*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:2\r\n").await;
+    exchange(&mut raw, b"UNSUBSCRIBE news\r\n", b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:1\r\n").await;
+    exchange(&mut raw, b"PING\r\n", b"*2\r\n$4\r\npong\r\n$0\r\n\r\n").await;
+    exchange(&mut raw, b"PUNSUBSCRIBE\r\n", b"*3\r\n$12\r\npunsubscribe\r\n$2\r\nn*\r\n:0\r\n").await;
+    exchange(&mut raw, b"PING\r\n", b"+PONG\r\n").await;
+}
+
+#[tokio::test]
+async fn test_subscribe_confirmations_on_the_wire() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = spawn_server().await;
+    let mut raw = TcpStream::connect(addr).await.unwrap();
+    async fn exchange(raw: &mut TcpStream, request: &[u8], reply: &[u8]) {
+        raw.write_all(request).await.unwrap();
+        let mut buf = vec![0; reply.len()];
+        raw.read_exact(&mut buf).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), String::from_utf8_lossy(reply));
+    }
+
+    // One confirmation per channel, with the running count
+    exchange(
+        &mut raw,
+        b"SUBSCRIBE news sports\r\n",
+        b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n*3\r\n$9\r\nsubscribe\r\n$6\r\nsports\r\n:2\r\n",
+    )
+    .await;
+    exchange(&mut raw, b"PSUBSCRIBE n*\r\n", b"*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:3\r\n").await;
+
+    // A bare UNSUBSCRIBE confirms every channel it leaves, counting the
+    // pattern still subscribed to
+    exchange(
+        &mut raw,
+        b"UNSUBSCRIBE\r\n",
+        b"*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:2\r\n*3\r\n$11\r\nunsubscribe\r\n$6\r\nsports\r\n:1\r\n",
+    )
+    .await;
+    exchange(&mut raw, b"PUNSUBSCRIBE\r\n", b"*3\r\n$12\r\npunsubscribe\r\n$2\r\nn*\r\n:0\r\n").await;
+
+    // RESP3 sends them as pushes
+    let mut resp3 = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(matches!(send(&mut resp3, &["hello", "3"]).await, Frame::Map(_)));
+    match send(&mut resp3, &["subscribe", "news"]).await {
+        Frame::Push(items) => {
+            assert_eq!(items.len(), 3);
+            assert_eq!(items[0], "subscribe");
+            assert_eq!(items[1], "news");
+            assert!(matches!(items[2], Frame::Integer(1)));
+        }
+        other => panic!("Expected Push, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_psubscribe() {
+    let addr = spawn_server().await;
+    let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut publisher = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    match send(&mut subscriber, &["psubscribe", "news.*"]).await {
+        Frame::Array(items) => {
+            assert_eq!(items[0], "psubscribe");
+            assert_eq!(items[1], "news.*");
+            assert!(matches!(items[2], Frame::Integer(1)));
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    // Exact and pattern subscriptions share one count
+    match send(&mut subscriber, &["subscribe", "news.tech"]).await {
+        Frame::Array(items) => assert!(matches!(items[2], Frame::Integer(2))),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    // Both subscriptions match, so the message is delivered and counted twice
+    match send(&mut publisher, &["publish", "news.tech", "rust"]).await {
+        Frame::Integer(n) => assert_eq!(n, 2),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    let mut received = Vec::new();
+    for _ in 0..2 {
+        let frame = tokio::time::timeout(Duration::from_secs(1), subscriber.read_frame())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        received.push(strings(frame));
+    }
+    received.sort();
+    assert_eq!(received, vec![
+        vec!["message", "news.tech", "rust"],
+        vec!["pmessage", "news.*", "news.tech", "rust"],
+    ]);
+
+    match send(&mut publisher, &["publish", "sports", "ignored"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+
+    match send(&mut subscriber, &["punsubscribe"]).await {
+        Frame::Array(items) => {
+            assert_eq!(items[1], "news.*");
+            assert!(matches!(items[2], Frame::Integer(1)));
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    match send(&mut publisher, &["publish", "news.sport", "missed"]).await {
+        Frame::Integer(n) => assert_eq!(n, 0),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    match send(&mut subscriber, &["unsubscribe"]).await {
+        Frame::Array(items) => assert!(matches!(items[2], Frame::Integer(0))),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+    assert!(matches!(send(&mut subscriber, &["get", "key"]).await, Frame::Null));
+}
+
+#[tokio::test]
+async fn test_acl_auth() {
+    let config = ServerConfig {
+        password: Some("letmein".into()),
+        users: vec![("alice".into(), "wonderland".into())],
+        ..ServerConfig::default()
+    };
+    let addr = spawn_server_with_config(config).await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    match send(&mut client, &["get", "key"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("NOAUTH"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    for args in [&["auth", "alice", "wrong"][..], &["auth", "nobody", "wonderland"], &["auth", "wrong"]] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, "WRONGPASS invalid username-password pair"),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    assert!(matches!(send(&mut client, &["get", "key"]).await, Frame::Error(_)));
+
+    assert_eq!(send(&mut client, &["auth", "alice", "wonderland"]).await, "OK");
+    assert!(matches!(send(&mut client, &["get", "key"]).await, Frame::Null));
+
+    // The default user works with just a password, and through HELLO
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(send(&mut other, &["auth", "letmein"]).await, "OK");
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+    match send(&mut other, &["hello", "3", "auth", "default", "nope"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGPASS"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert!(matches!(send(&mut other, &["hello", "3", "auth", "default", "letmein"]).await, Frame::Map(_)));
+}
+
+#[tokio::test]
+async fn test_keyspace_notifications() {
+    let addr = spawn_server().await;
+    let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert_eq!(send(&mut client, &["config", "set", "notify-keyspace-events", "KEA"]).await, "OK");
+    assert_eq!(strings(send(&mut client, &["config", "get", "notify-keyspace-events"]).await), vec!["notify-keyspace-events", "AKE"]);
+
+    subscriber.write_frame(&cmd(&["subscribe", "__keyevent@0__:set", "__keyspace@0__:list"])).await.unwrap();
+    for _ in 0..2 {
+        subscriber.read_frame().await.unwrap().unwrap();
+    }
+    let mut next_message = async || {
+        let message = tokio::time::timeout(Duration::from_secs(1), subscriber.read_frame()).await;
+        strings(message.unwrap().unwrap().unwrap())
+    };
+
+    assert_eq!(send(&mut client, &["set", "greeting", "hello"]).await, "OK");
+    assert_eq!(next_message().await, vec!["message", "__keyevent@0__:set", "greeting"]);
+
+    // Popping the last element also deletes the key
+    send(&mut client, &["rpush", "list", "a"]).await;
+    send(&mut client, &["lpop", "list"]).await;
+    for event in ["rpush", "lpop", "del"] {
+        assert_eq!(next_message().await, vec!["message", "__keyspace@0__:list", event]);
+    }
+
+    // Only the selected classes are published
+    assert_eq!(send(&mut client, &["config", "set", "notify-keyspace-events", "El"]).await, "OK");
+    send(&mut client, &["set", "greeting", "again"]).await;
+    send(&mut client, &["select", "1"]).await;
+    send(&mut client, &["set", "greeting", "elsewhere"]).await;
+    let message = tokio::time::timeout(Duration::from_millis(100), subscriber.read_frame()).await;
+    assert!(message.is_err(), "{:?}", message);
+    match send(&mut client, &["config", "set", "notify-keyspace-events", "KEQ"]).await {
+        Frame::Error(e) => assert!(e.contains("notify-keyspace-events"), "{}", e),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_active_expiry() {
+    let config = ServerConfig {
+        active_expire_interval: Duration::from_millis(20),
+        notify_keyspace_events: KeyspaceEvents::parse("Ex").unwrap(),
+        ..ServerConfig::default()
+    };
+    let addr = spawn_server_with_config(config).await;
+    let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    subscriber.write_frame(&cmd(&["subscribe", "__keyevent@0__:expired"])).await.unwrap();
+    subscriber.read_frame().await.unwrap().unwrap();
+
+    send(&mut client, &["set", "kept", "value"]).await;
+    let payload = match send(&mut client, &["dump", "kept"]).await {
+        Frame::Bulk(payload) => payload,
+        other => panic!("Expected Bulk, got {:?}", other),
+    };
+    let restore = Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"restore")),
+        Frame::Bulk(Bytes::from_static(b"short")),
+        Frame::Bulk(Bytes::from_static(b"50")),
+        Frame::Bulk(payload),
+    ]);
+    client.write_frame(&restore).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(2)));
+
+    // The key is never looked up again, yet the sweep removes it
+    let message = tokio::time::timeout(Duration::from_secs(1), subscriber.read_frame()).await;
+    assert_eq!(strings(message.unwrap().unwrap().unwrap()), vec!["message", "__keyevent@0__:expired", "short"]);
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(1)));
+}
+
+#[tokio::test]
+async fn test_incr_and_append() {
+    let mut client = get_client().await;
+
+    assert!(matches!(send(&mut client, &["incr", "counter"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["incrby", "counter", "41"]).await, Frame::Integer(42)));
+    assert!(matches!(send(&mut client, &["decr", "counter"]).await, Frame::Integer(41)));
+    assert!(matches!(send(&mut client, &["decrby", "counter", "50"]).await, Frame::Integer(-9)));
+    assert_eq!(send(&mut client, &["get", "counter"]).await, "-9");
+
+    assert!(matches!(send(&mut client, &["append", "text", "hello"]).await, Frame::Integer(5)));
+    assert!(matches!(send(&mut client, &["append", "text", " world"]).await, Frame::Integer(11)));
+    assert_eq!(send(&mut client, &["get", "text"]).await, "hello world");
+    // Appending digits makes a counter of it
+    send(&mut client, &["set", "n", "1"]).await;
+    send(&mut client, &["append", "n", "0"]).await;
+    assert!(matches!(send(&mut client, &["incr", "n"]).await, Frame::Integer(11)));
+
+    send(&mut client, &["set", "max", &i64::MAX.to_string()]).await;
+    send(&mut client, &["rpush", "list", "a"]).await;
+    for (args, expected) in [
+        (&["incr", "text"][..], "ERR value is not an integer or out of range"),
+        (&["set", "padded", " 1"], "OK"),
+        (&["incr", "padded"], "ERR value is not an integer or out of range"),
+        (&["incr", "max"], "ERR increment or decrement would overflow"),
+        (&["decrby", "counter", &i64::MIN.to_string()], "ERR decrement would overflow"),
+        (&["incrby", "counter", "x"], "ERR value is not an integer or out of range"),
+        (&["incr", "list"], "WRONGTYPE"),
+        (&["append", "list", "x"], "WRONGTYPE"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            Frame::Simple(msg) => assert_eq!(msg, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    // A failed update leaves the value alone
+    assert_eq!(send(&mut client, &["get", "max"]).await, i64::MAX.to_string().as_str());
+}
+
+#[tokio::test]
+async fn test_incr_and_append_on_expired_keys() {
+    // With no background sweeps, expired keys linger until they're touched
+    let config = ServerConfig { active_expire_interval: Duration::ZERO, ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+
+    for (key, value) in [("counter", "41"), ("text", "stale")] {
+        send(&mut client, &["set", "template", value]).await;
+        let payload = match send(&mut client, &["dump", "template"]).await {
+            Frame::Bulk(payload) => payload,
+            other => panic!("Expected Bulk, got {:?}", other),
+        };
+        let restore = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"restore")),
+            Frame::Bulk(Bytes::from(key)),
+            Frame::Bulk(Bytes::from_static(b"20")),
+            Frame::Bulk(payload),
+        ]);
+        client.write_frame(&restore).await.unwrap();
+        assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // The old values are gone, not resumed from
+    assert!(matches!(send(&mut client, &["incr", "counter"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["append", "text", "new"]).await, Frame::Integer(3)));
+    assert_eq!(send(&mut client, &["get", "text"]).await, "new");
+    // And so is their TTL
+    assert!(matches!(send(&mut client, &["pttl", "counter"]).await, Frame::Integer(-1)));
+    assert!(matches!(send(&mut client, &["pttl", "text"]).await, Frame::Integer(-1)));
+}
+
+#[tokio::test]
+async fn test_server_config() {
+    let mut client = get_client_with_config(ServerConfig { databases: 2, ..ServerConfig::default() }).await;
+    assert_eq!(send(&mut client, &["select", "1"]).await, "OK");
+    match send(&mut client, &["select", "2"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR DB index is out of range"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // A request larger than the limit closes the connection
+    let mut client = get_client_with_config(ServerConfig { max_frame_size: 64, ..ServerConfig::default() }).await;
+    assert_eq!(send(&mut client, &["set", "key", "small"]).await, "OK");
+    let big = "x".repeat(1024);
+    client.write_frame(&cmd(&["set", "key", &big])).await.unwrap();
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Error(msg)) => assert!(msg.contains("frame too large"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    assert!(client.read_frame().await.unwrap().is_none());
+
+}
+
+#[tokio::test]
+async fn test_max_connections() {
+    let config = ServerConfig { max_connections: 2, ..ServerConfig::default() };
+    let addr = spawn_server_with_config(config).await;
+    let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(send(&mut first, &["ping"]).await, "PONG");
+    assert_eq!(send(&mut second, &["ping"]).await, "PONG");
+
+    // The third connection isn't served while two are open
+    let mut third = Connection::new(TcpStream::connect(addr).await.unwrap());
+    third.write_frame(&cmd(&["ping"])).await.unwrap();
+    third.flush().await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(200), third.read_frame()).await.is_err());
+
+    // Its pending command runs once a slot frees up
+    drop(first);
+    let reply = tokio::time::timeout(Duration::from_secs(5), third.read_frame()).await.unwrap();
+    assert_eq!(reply.unwrap().unwrap(), "PONG");
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+    let server = tokio::spawn(rustbucket::run(listener, shutdown));
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(send(&mut client, &["set", "key", "value"]).await, "OK");
+    let mut subscriber = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert!(matches!(send(&mut subscriber, &["subscribe", "news"]).await, Frame::Array(_)));
+
+    trigger.send(()).unwrap();
+    // `run` waits for both open connections to be closed
+    tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+
+    assert!(client.read_frame().await.unwrap().is_none());
+    assert!(subscriber.read_frame().await.unwrap().is_none());
+    // The port has been released
+    TcpListener::bind(addr).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_oversized_declared_lengths() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let config = ServerConfig { max_array_len: 16, ..ServerConfig::default() };
+    let addr = spawn_server_with_config(config).await;
+
+    // Announcing more data than allowed is rejected straight away rather
+    // than waiting for it to arrive
+    for header in [&b"$999999999999\r\n"[..], b"*1\r\n$999999999999\r\n", b"*17\r\n"] {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        socket.write_all(header).await.unwrap();
+        let mut reply = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), socket.read_to_end(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(reply.starts_with(b"-ERR protocol error"), "{:?}", String::from_utf8_lossy(&reply));
+    }
+}
+
+#[tokio::test]
+async fn test_deeply_nested_frame_is_rejected() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = spawn_server().await;
+
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    let request = [b"*1\r\n".repeat(100_000), b":1\r\n".to_vec()].concat();
+    // The server may close the connection before reading it all, resetting
+    // it, so the error reply isn't guaranteed to arrive
+    let _ = socket.write_all(&request).await;
+    let mut reply = Vec::new();
+    let _ = tokio::time::timeout(Duration::from_secs(5), socket.read_to_end(&mut reply)).await.unwrap();
+    assert!(reply.is_empty() || reply.starts_with(b"-ERR protocol error"), "{:?}", String::from_utf8_lossy(&reply));
+
+    // The server is still up
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    assert_eq!(send(&mut client, &["ping"]).await, "PONG");
+}
+
+#[tokio::test]
+async fn test_idle_timeout() {
+    let config = ServerConfig { idle_timeout: Duration::from_millis(200), ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+
+    // Each request resets the timer
+    for _ in 0..3 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(send(&mut client, &["ping"]).await, "PONG");
+    }
+
+    let closed = tokio::time::timeout(Duration::from_secs(5), client.read_frame()).await.unwrap();
+    assert!(closed.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_save_and_reload() {
+    let path = std::env::temp_dir().join(format!("rustbucket-save-{}.rdb", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig { snapshot_path: Some(path.clone()), ..ServerConfig::default() };
+
+    let mut client = get_client_with_config(config.clone()).await;
+    send(&mut client, &["set", "saved", "yes"]).await;
+    assert_eq!(send(&mut client, &["save"]).await, "OK");
+
+    send(&mut client, &["set", "later", "too"]).await;
+    assert_eq!(send(&mut client, &["bgsave"]).await, "Background saving started");
+    // The background save finishes on its own
+    let mut restarted = None;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut fresh = get_client_with_config(config.clone()).await;
+        if matches!(send(&mut fresh, &["get", "later"]).await, Frame::Bulk(_)) {
+            restarted = Some(fresh);
+            break;
+        }
+    }
+    let mut restarted = restarted.expect("background save never completed");
+    assert_eq!(send(&mut restarted, &["get", "saved"]).await, "yes");
+    std::fs::remove_file(&path).unwrap();
+
+    // Without a snapshot file there is nowhere to save to
+    let mut client = get_client().await;
+    assert!(matches!(send(&mut client, &["save"]).await, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn test_aof_replay() {
+    use rustbucket::aof::AppendFsync;
+
+    let path = std::env::temp_dir().join(format!("rustbucket-aof-{}.aof", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = ServerConfig {
+        aof_path: Some(path.clone()),
+        aof_fsync: AppendFsync::Always,
+        ..ServerConfig::default()
+    };
+
+    let mut client = get_client_with_config(config.clone()).await;
+    send(&mut client, &["set", "string", "value"]).await;
+    send(&mut client, &["hset", "hash", "field", "value"]).await;
+    send(&mut client, &["sadd", "set", "a", "b", "c", "d"]).await;
+    send(&mut client, &["spop", "set"]).await;
+    send(&mut client, &["get", "string"]).await;
+    send(&mut client, &["del", "string"]).await;
+    // Transactions are logged as the commands they ran
+    send(&mut client, &["multi"]).await;
+    send(&mut client, &["rpush", "list", "x", "y"]).await;
+    send(&mut client, &["select", "2"]).await;
+    send(&mut client, &["set", "other", "db"]).await;
+    send(&mut client, &["exec"]).await;
+    send(&mut client, &["select", "0"]).await;
+    let members = sorted_strings(send(&mut client, &["smembers", "set"]).await);
+
+    // A crash in the middle of a write leaves a partial command behind
+    {
+        use std::io::Write;
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"*3\r\n$3\r\nset\r\n$4\r\nlost").unwrap();
+    }
+
+    let mut restarted = get_client_with_config(config.clone()).await;
+    assert!(matches!(send(&mut restarted, &["get", "string"]).await, Frame::Null));
+    assert_eq!(send(&mut restarted, &["hget", "hash", "field"]).await, "value");
+    assert_eq!(sorted_strings(send(&mut restarted, &["smembers", "set"]).await), members);
+    assert_eq!(strings(send(&mut restarted, &["lrange", "list", "0", "-1"]).await), vec!["x", "y"]);
+    send(&mut restarted, &["select", "2"]).await;
+    assert_eq!(send(&mut restarted, &["get", "other"]).await, "db");
+
+    // The partial command was cut off, so new writes replay cleanly
+    send(&mut restarted, &["set", "after", "restart"]).await;
+    let mut again = get_client_with_config(config).await;
+    send(&mut again, &["select", "2"]).await;
+    assert_eq!(send(&mut again, &["get", "after"]).await, "restart");
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn test_dump_restore() {
+    let mut client = get_client().await;
+    send(&mut client, &["hset", "hash", "a", "1"]).await;
+    send(&mut client, &["hset", "hash", "b", "2"]).await;
+
+    let payload = match send(&mut client, &["dump", "hash"]).await {
+        Frame::Bulk(payload) => payload,
+        other => panic!("Expected Bulk, got {:?}", other),
+    };
+    assert!(matches!(send(&mut client, &["dump", "missing"]).await, Frame::Null));
+
+    // RESTORE takes the payload as binary data
+    let restore = |key: &str, ttl: &str, extra: &[&str]| {
+        let mut args = vec![
+            Frame::Bulk(Bytes::from_static(b"restore")),
+            Frame::Bulk(Bytes::from(key.to_string())),
+            Frame::Bulk(Bytes::from(ttl.to_string())),
+            Frame::Bulk(payload.clone()),
+        ];
+        args.extend(extra.iter().map(|arg| Frame::Bulk(Bytes::from(arg.to_string()))));
+        Frame::Array(args)
+    };
+    client.write_frame(&restore("copy", "0", &[])).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    assert_eq!(
+        sorted_strings(send(&mut client, &["hgetall", "copy"]).await),
+        vec!["1", "2", "a", "b"]
+    );
+    assert!(matches!(send(&mut client, &["ttl", "copy"]).await, Frame::Integer(-1)));
+
+    client.write_frame(&restore("copy", "0", &[])).await.unwrap();
+    match client.read_frame().await.unwrap().unwrap() {
+        Frame::Error(msg) => assert_eq!(msg, "BUSYKEY Target key name already exists"),
+        other => panic!("Expected BUSYKEY, got {:?}", other),
+    }
+
+    // A TTL makes the restored key expire
+    send(&mut client, &["set", "copy", "string"]).await;
+    client.write_frame(&restore("copy", "100000", &["replace"])).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    match send(&mut client, &["pttl", "copy"]).await {
+        Frame::Integer(ms) => assert!(ms > 90_000 && ms <= 100_000, "pttl was {}", ms),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["ttl", "copy"]).await, Frame::Integer(100)));
+
+    client.write_frame(&restore("short", "50", &[])).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(matches!(send(&mut client, &["exists", "short"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["ttl", "short"]).await, Frame::Integer(-2)));
+
+    // Payloads that don't check out are refused
+    let mut corrupt = payload.to_vec();
+    corrupt[1] ^= 0xff;
+    let cmd = Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"restore")),
+        Frame::Bulk(Bytes::from_static(b"bad")),
+        Frame::Bulk(Bytes::from_static(b"0")),
+        Frame::Bulk(Bytes::from(corrupt)),
+    ]);
+    client.write_frame(&cmd).await.unwrap();
+    match client.read_frame().await.unwrap().unwrap() {
+        Frame::Error(msg) => assert_eq!(msg, "ERR DUMP payload version or checksum are wrong"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+/// A `DUMP` payload around the type byte and encoded value in `value`.
+fn dump_payload(value: &[u8]) -> Bytes {
+    let mut payload = value.to_vec();
+    payload.extend_from_slice(&1u16.to_le_bytes());
+    let crc = rustbucket::persistence::crc64(0, &payload);
+    payload.extend_from_slice(&crc.to_le_bytes());
+    Bytes::from(payload)
+}
+
+#[tokio::test]
+async fn test_restore_rejects_values_the_keyspace_cannot_hold() {
+    let mut client = get_client().await;
+
+    let nan_score = [&[4, 1, 1, b'm'][..], &f64::NAN.to_le_bytes()].concat();
+    // An empty list, set, hash and sorted set, then a NaN score
+    for value in [&[1, 0][..], &[2, 0], &[3, 0], &[4, 0], &nan_score] {
+        let restore = Frame::Array(vec![
+            Frame::Bulk(Bytes::from_static(b"restore")),
+            Frame::Bulk(Bytes::from_static(b"key")),
+            Frame::Bulk(Bytes::from_static(b"0")),
+            Frame::Bulk(dump_payload(value)),
+        ]);
+        client.write_frame(&restore).await.unwrap();
+        match client.read_frame().await.unwrap().unwrap() {
+            Frame::Error(msg) => assert_eq!(msg, "ERR Bad data format", "{:?}", value),
+            other => panic!("Expected Error for {:?}, got {:?}", value, other),
+        }
+        assert!(matches!(send(&mut client, &["exists", "key"]).await, Frame::Integer(0)));
+    }
+
+    // The same encoding with a real score is fine
+    let score = [&[4, 1, 1, b'm'][..], &1.5f64.to_le_bytes()].concat();
+    let restore = Frame::Array(vec![
+        Frame::Bulk(Bytes::from_static(b"restore")),
+        Frame::Bulk(Bytes::from_static(b"key")),
+        Frame::Bulk(Bytes::from_static(b"0")),
+        Frame::Bulk(dump_payload(&score)),
+    ]);
+    client.write_frame(&restore).await.unwrap();
+    assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    assert_eq!(strings(send(&mut client, &["zrange", "key", "0", "-1", "withscores"]).await), ["m", "1.5"]);
+}
+
+#[tokio::test]
+async fn test_maxmemory_noeviction() {
+    let config = ServerConfig { maxmemory: 20_000, ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+    let value = "x".repeat(1000);
+
+    let mut stored = 0;
+    let error = loop {
+        match send(&mut client, &["set", &format!("key:{}", stored), &value]).await {
+            Frame::Error(msg) => break msg,
+            _ => stored += 1,
+        }
+        assert!(stored < 100, "the limit was never hit");
+    };
+    assert_eq!(error, "OOM command not allowed when used memory > 'maxmemory'");
+    assert!(stored > 0);
+
+    // Nothing was evicted, and commands that free memory still run
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(n) if n == stored));
+    assert!(matches!(send(&mut client, &["del", "key:0"]).await, Frame::Integer(1)));
+    assert_eq!(send(&mut client, &["set", "key:0", "small"]).await, "OK");
+}
+
+#[tokio::test]
+async fn test_maxmemory_rejects_writes_that_would_not_fit() {
+    let config = ServerConfig { maxmemory: 20_000, ..ServerConfig::default() };
+    let mut client = get_client_with_config(config).await;
+
+    // Fill memory most of the way to the cap
+    assert_eq!(send(&mut client, &["set", "filler", &"x".repeat(12_000)]).await, "OK");
+
+    // Room for either value, but not both, so neither is set
+    let value = "y".repeat(5_000);
+    match send(&mut client, &["mset", "a", &value, "b", &value]).await {
+        Frame::Error(msg) => assert_eq!(msg, "OOM command not allowed when used memory > 'maxmemory'"),
+        other => panic!("Expected OOM, got {:?}", other),
+    }
+    assert!(matches!(send(&mut client, &["exists", "a"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["exists", "b"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(1)));
+
+    // A single value that would go over is refused as well
+    let reply = send(&mut client, &["set", "big", &"z".repeat(10_000)]).await;
+    assert!(matches!(reply, Frame::Error(ref msg) if msg.starts_with("OOM")), "{:?}", reply);
+
+    // Writes that fit still go through
+    assert_eq!(send(&mut client, &["mset", "a", "1", "b", "2"]).await, "OK");
+    assert_eq!(send(&mut client, &["get", "b"]).await, "2");
+}
+
+#[tokio::test]
+async fn test_maxmemory_allkeys_random() {
+    use rustbucket::db::EvictionPolicy;
+
+    let config = ServerConfig {
+        maxmemory: 20_000,
+        maxmemory_policy: EvictionPolicy::AllKeysRandom,
+        ..ServerConfig::default()
+    };
+    let mut client = get_client_with_config(config).await;
+    let value = "x".repeat(1000);
+
+    for i in 0..100 {
+        assert_eq!(send(&mut client, &["set", &format!("key:{}", i), &value]).await, "OK");
+    }
+    // The latest write always survives the evictions it caused
+    assert_eq!(send(&mut client, &["get", "key:99"]).await, value.as_str());
+    match send(&mut client, &["dbsize"]).await {
+        Frame::Integer(n) => assert!(n > 0 && n < 100, "dbsize was {}", n),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_maxmemory_allkeys_lfu() {
+    use rustbucket::db::EvictionPolicy;
+
+    let config = ServerConfig {
+        maxmemory: 20_000,
+        maxmemory_policy: EvictionPolicy::AllKeysLfu,
+        // With every key in one database, each sample is a random key of
+        // the whole keyspace rather than of whichever shard is found first
+        databases: 1,
+        ..ServerConfig::default()
+    };
+    let mut client = get_client_with_config(config).await;
+    let value = "x".repeat(1000);
+
+    send(&mut client, &["set", "hot", &value]).await;
+    for _ in 0..200 {
+        send(&mut client, &["get", "hot"]).await;
+    }
+    for i in 0..100 {
+        assert_eq!(send(&mut client, &["set", &format!("key:{}", i), &value]).await, "OK");
+    }
+    // Keys written once are evicted before the one read over and over
+    assert_eq!(send(&mut client, &["get", "hot"]).await, value.as_str());
+    match send(&mut client, &["dbsize"]).await {
+        Frame::Integer(n) => assert!(n > 0 && n < 101, "dbsize was {}", n),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_config_get_set() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["config", "set", "maxmemory", "1000"]).await, "OK");
+    assert_eq!(strings(send(&mut client, &["config", "get", "maxmemory"]).await), vec!["maxmemory", "1000"]);
+
+    // Several parameters at once, with units and any case
+    let reply = send(&mut client, &["CONFIG", "SET", "MaxMemory", "1kb", "maxmemory-policy", "ALLKEYS-LRU"]).await;
+    assert_eq!(reply, "OK");
+    assert_eq!(
+        strings(send(&mut client, &["config", "get", "maxmemory*"]).await),
+        vec!["maxmemory", "1024", "maxmemory-policy", "allkeys-lru"]
+    );
+
+    // Nothing changes when any of the values is bad
+    let reply = send(&mut client, &["config", "set", "timeout", "5", "maxmemory-policy", "bogus"]).await;
+    assert!(matches!(reply, Frame::Error(_)), "{:?}", reply);
+    assert_eq!(strings(send(&mut client, &["config", "get", "timeout"]).await), vec!["timeout", "0"]);
+
+    let reply = send(&mut client, &["config", "set", "nosuchparam", "1"]).await;
+    assert!(matches!(reply, Frame::Error(ref e) if e.contains("nosuchparam")), "{:?}", reply);
+    assert!(strings(send(&mut client, &["config", "get", "nosuchparam"]).await).is_empty());
+
+    // The limit applies straight away
+    assert_eq!(send(&mut client, &["set", "k", "v"]).await, "OK");
+    assert_eq!(send(&mut client, &["config", "set", "maxmemory", "1", "maxmemory-policy", "noeviction"]).await, "OK");
+    assert!(matches!(send(&mut client, &["set", "k2", "v"]).await, Frame::Error(ref e) if e.starts_with("OOM")));
+}
+
+#[tokio::test]
+async fn test_pipelined_writes() {
+    use tokio::io::AsyncWriteExt;
+
+    const COUNT: usize = 10_000;
+
+    let addr = spawn_server().await;
+
+    // Send every request in a single write. The server answers each one as
+    // it is parsed but only flushes once its read buffer holds no complete
+    // request, so the replies go out in a few large writes instead of one
+    // per command.
+    let mut requests = Vec::new();
+    for i in 0..COUNT {
+        let key = format!("key:{}", i);
+        requests.extend_from_slice(format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$1\r\nv\r\n", key.len(), key).as_bytes());
+    }
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream.write_all(&requests).await.unwrap();
+
+    let mut client = Connection::new(stream);
+    for _ in 0..COUNT {
+        assert_eq!(client.read_frame().await.unwrap().unwrap(), "OK");
+    }
+    match send(&mut client, &["dbsize"]).await {
+        Frame::Integer(n) => assert_eq!(n, COUNT as i64),
+        other => panic!("Expected Integer, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_pipelined_reply_before_blocking_pop() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut pusher = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    // Sent in one write, so the SET's reply is buffered when BLPOP blocks
+    client.write_frame(&cmd(&["set", "a", "1"])).await.unwrap();
+    client.write_frame(&cmd(&["blpop", "q", "0"])).await.unwrap();
+    client.flush().await.unwrap();
+    let reply = tokio::time::timeout(Duration::from_secs(1), client.read_frame()).await;
+    assert_eq!(reply.expect("SET's reply waited on BLPOP").unwrap().unwrap(), "OK");
+
+    assert!(matches!(send(&mut pusher, &["rpush", "q", "job"]).await, Frame::Integer(1)));
+    assert_eq!(strings(client.read_frame().await.unwrap().unwrap()), ["q", "job"]);
+}
+
+#[tokio::test]
+async fn test_lpos() {
+    let mut client = get_client().await;
+    send(&mut client, &["rpush", "list", "a", "b", "c", "b", "b", "d"]).await;
+    let integers = |frame: Frame| match frame {
+        Frame::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Frame::Integer(n) => n,
+                other => panic!("Expected Integer, got {:?}", other),
+            })
+            .collect::<Vec<_>>(),
+        other => panic!("Expected Array, got {:?}", other),
+    };
+
+    assert!(matches!(send(&mut client, &["lpos", "list", "b"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["lpos", "list", "z"]).await, Frame::Null));
+    assert!(matches!(send(&mut client, &["lpos", "missing", "b"]).await, Frame::Null));
+
+    // RANK picks a later match, a negative one counting from the tail
+    assert!(matches!(send(&mut client, &["lpos", "list", "b", "rank", "2"]).await, Frame::Integer(3)));
+    assert!(matches!(send(&mut client, &["lpos", "list", "b", "rank", "-1"]).await, Frame::Integer(4)));
+    assert!(matches!(send(&mut client, &["lpos", "list", "b", "rank", "-4"]).await, Frame::Null));
+
+    // COUNT replies with an array, every match for COUNT 0
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "b", "count", "2"]).await), vec![1, 3]);
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "b", "count", "0"]).await), vec![1, 3, 4]);
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "b", "rank", "-1", "count", "2"]).await), vec![4, 3]);
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "z", "count", "0"]).await), Vec::<i64>::new());
+    assert_eq!(integers(send(&mut client, &["lpos", "missing", "b", "count", "1"]).await), Vec::<i64>::new());
+
+    // MAXLEN bounds how many elements are compared
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "b", "count", "0", "maxlen", "4"]).await), vec![1, 3]);
+    assert_eq!(integers(send(&mut client, &["lpos", "list", "a", "rank", "-1", "count", "0", "maxlen", "5"]).await), Vec::<i64>::new());
+
+    for (args, expected) in [
+        (&["lpos", "list", "b", "rank", "0"][..], "ERR RANK can't be zero"),
+        (&["lpos", "list", "b", "count", "-1"], "ERR COUNT can't be negative"),
+        (&["lpos", "list", "b", "maxlen", "-1"], "ERR MAXLEN can't be negative"),
+        (&["lpos", "list", "b", "nope"], "ERR syntax error"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    send(&mut client, &["set", "string", "b"]).await;
+    match send(&mut client, &["lpos", "string", "b"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_command_getkeys() {
+    let mut client = get_client().await;
+
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "set", "foo", "bar"]).await), vec!["foo"]);
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "mset", "a", "1", "b", "2"]).await), vec!["a", "b"]);
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "del", "a", "b", "c"]).await), vec!["a", "b", "c"]);
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "unlink", "a", "b", "c"]).await), vec!["a", "b", "c"]);
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "smove", "src", "dst", "member"]).await), vec!["src", "dst"]);
+    assert_eq!(strings(send(&mut client, &["command", "getkeys", "object", "encoding", "foo"]).await), vec!["foo"]);
+    // Only the keys are reported, not whether they exist
+    assert!(matches!(send(&mut client, &["dbsize"]).await, Frame::Integer(0)));
+
+    for (args, expected) in [
+        (&["command", "getkeys", "georadius", "geo", "0", "0", "1", "km"][..], "ERR Invalid command specified"),
+        (&["command", "getkeys", "mset", "a", "1", "b"], "ERR Invalid arguments specified for command"),
+        (&["command", "getkeys", "get"], "ERR Invalid arguments specified for command"),
+        (&["command", "getkeys", "ping"], "ERR The command has no key arguments"),
+        (&["command", "getkeys"], "ERR wrong number of arguments for 'command|getkeys' command"),
+        (&["command", "nope"], "ERR unknown subcommand 'nope'"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+
+    // MSET itself sets every pair
+    assert_eq!(send(&mut client, &["mset", "a", "1", "b", "2"]).await, "OK");
+    assert_eq!(send(&mut client, &["get", "b"]).await, "2");
+    match send(&mut client, &["mset", "a", "1", "b"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR wrong number of arguments for 'mset' command"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_sort() {
+    let mut client = get_client().await;
+    send(&mut client, &["rpush", "numbers", "10", "2", "-1.5", "3", "2"]).await;
+    send(&mut client, &["sadd", "words", "pear", "apple", "fig", "banana"]).await;
+
+    // Numeric by default, for lists and sets alike
+    assert_eq!(strings(send(&mut client, &["sort", "numbers"]).await), vec!["-1.5", "2", "2", "3", "10"]);
+    send(&mut client, &["sadd", "numberset", "7", "100", "8"]).await;
+    assert_eq!(strings(send(&mut client, &["sort", "numberset"]).await), vec!["7", "8", "100"]);
+    assert!(strings(send(&mut client, &["sort", "missing"]).await).is_empty());
+    match send(&mut client, &["sort", "words"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR One or more scores can't be converted into double"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // ALPHA compares the bytes instead
+    assert_eq!(strings(send(&mut client, &["sort", "words", "alpha"]).await), vec!["apple", "banana", "fig", "pear"]);
+    assert_eq!(strings(send(&mut client, &["sort", "numbers", "alpha"]).await), vec!["-1.5", "10", "2", "2", "3"]);
+
+    // DESC reverses the order before LIMIT takes its slice
+    assert_eq!(strings(send(&mut client, &["sort", "numbers", "desc", "limit", "1", "2"]).await), vec!["3", "2"]);
+    assert_eq!(strings(send(&mut client, &["sort", "words", "alpha", "desc", "limit", "0", "-1"]).await), vec!["pear", "fig", "banana", "apple"]);
+    assert!(strings(send(&mut client, &["sort", "numbers", "limit", "10", "5"]).await).is_empty());
+
+    // The list itself is left alone
+    assert_eq!(strings(send(&mut client, &["lrange", "numbers", "0", "-1"]).await), vec!["10", "2", "-1.5", "3", "2"]);
+
+    for (args, expected) in [
+        (&["sort", "numbers", "by", "weight_*"][..], "ERR SORT BY is not supported"),
+        (&["sort", "numbers", "limit", "0"], "ERR"),
+        (&["sort", "numbers", "nope"], "ERR syntax error"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    send(&mut client, &["set", "string", "1"]).await;
+    match send(&mut client, &["sort", "string"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_lrange_slice_of_large_list() {
+    let mut client = get_client().await;
+
+    const LEN: usize = 100_000;
+    let values: Vec<String> = (0..LEN).map(|i| i.to_string()).collect();
+    for chunk in values.chunks(10_000) {
+        let mut args = vec!["rpush", "big"];
+        args.extend(chunk.iter().map(String::as_str));
+        assert!(matches!(send(&mut client, &args).await, Frame::Integer(_)));
+    }
+
+    // Only the slice is copied out of the list, however long the list is
+    assert_eq!(strings(send(&mut client, &["lrange", "big", "50000", "50002"]).await), vec!["50000", "50001", "50002"]);
+    assert_eq!(strings(send(&mut client, &["lrange", "big", "-2", "-1"]).await), vec!["99998", "99999"]);
+    assert_eq!(strings(send(&mut client, &["lrange", "big", "-1", "100000000"]).await), vec!["99999"]);
+    assert!(strings(send(&mut client, &["lrange", "big", "100000", "100001"]).await).is_empty());
+    match send(&mut client, &["lrange", "big", "0", "-1"]).await {
+        Frame::Array(items) => assert_eq!(items.len(), LEN),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_collection_reads_reject_wrong_type() {
+    let mut client = get_client().await;
+
+    send(&mut client, &["set", "string", "value"]).await;
+    send(&mut client, &["rpush", "list", "a"]).await;
+    for args in [&["hgetall", "string"][..], &["lrange", "string", "0", "-1"], &["smembers", "list"], &["json.type", "list"]] {
+        match send(&mut client, args).await {
+            Frame::Error(e) => assert!(e.starts_with("WRONGTYPE"), "{:?}: {}", args, e),
+            other => panic!("Expected WRONGTYPE for {:?}, got {:?}", args, other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_exec_reports_errors_per_command() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "k", "v"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["sinter", "k"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["smove", "k", "other", "x"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["get", "k"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["rpush", "list", "a", "b"]).await, "QUEUED");
+
+    // A failing command doesn't stop the rest, and its error takes its place
+    // in the reply
+    let replies = match send(&mut client, &["exec"]).await {
+        Frame::Array(replies) => replies,
+        other => panic!("Expected Array, got {:?}", other),
+    };
+    assert_eq!(replies.len(), 5);
+    assert_eq!(replies[0], "OK");
+    for reply in &replies[1..3] {
+        assert!(matches!(reply, Frame::Error(e) if e.starts_with("WRONGTYPE")), "{:?}", reply);
+    }
+    assert_eq!(replies[3], "v");
+    assert!(matches!(replies[4], Frame::Integer(2)), "{:?}", replies[4]);
+
+    // The connection is still in step with its replies
+    assert_eq!(send(&mut client, &["get", "k"]).await, "v");
+}
+
+#[tokio::test]
+async fn test_watch_is_per_key() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert_eq!(send(&mut client, &["watch", "watched"]).await, "OK");
+    // With this many keys, some share the watched key's shard
+    for i in 0..1000 {
+        assert_eq!(send(&mut other, &["set", &format!("other:{}", i), "v"]).await, "OK");
+    }
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "watched", "mine"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+
+    // Modifying the watched key itself still aborts
+    assert_eq!(send(&mut client, &["watch", "watched"]).await, "OK");
+    assert_eq!(send(&mut other, &["set", "watched", "theirs"]).await, "OK");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "watched", "mine"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::NullArray));
+    assert_eq!(send(&mut client, &["get", "watched"]).await, "theirs");
+}
+
+#[tokio::test]
+async fn test_aborted_exec_is_a_null_array() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let addr = spawn_server().await;
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut raw = TcpStream::connect(addr).await.unwrap();
+    async fn exchange(raw: &mut TcpStream, request: &[u8], reply: &[u8]) {
+        raw.write_all(request).await.unwrap();
+        let mut buf = vec![0; reply.len()];
+        raw.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, reply, "{:?}", String::from_utf8_lossy(&buf));
+    }
+
+    exchange(&mut raw, b"WATCH key\r\n", b"+OK\r\n").await;
+    assert_eq!(send(&mut other, &["set", "key", "changed"]).await, "OK");
+    exchange(&mut raw, b"MULTI\r\n", b"+OK\r\n").await;
+    exchange(&mut raw, b"GET key\r\n", b"+QUEUED\r\n").await;
+    exchange(&mut raw, b"EXEC\r\n", b"*-1\r\n").await;
+    // A missing key is still a null bulk string
+    exchange(&mut raw, b"GET missing\r\n", b"$-1\r\n").await;
+}
+
+#[tokio::test]
+async fn test_unwatch() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    // Nothing to forget is fine
+    assert_eq!(send(&mut client, &["unwatch"]).await, "OK");
+
+    assert_eq!(send(&mut client, &["watch", "key"]).await, "OK");
+    assert_eq!(send(&mut client, &["unwatch"]).await, "OK");
+    assert_eq!(send(&mut other, &["set", "key", "theirs"]).await, "OK");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "mine"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+    assert_eq!(send(&mut client, &["get", "key"]).await, "mine");
+
+    // Inside MULTI it forgets the keys without discarding what is queued
+    assert_eq!(send(&mut client, &["watch", "key"]).await, "OK");
+    assert_eq!(send(&mut other, &["set", "key", "theirs"]).await, "OK");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "mine again"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["unwatch"]).await, "OK");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+    assert_eq!(send(&mut client, &["get", "key"]).await, "mine again");
+}
+
+#[tokio::test]
+async fn test_multi_rejects_bad_commands_when_queued() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "v"]).await, "QUEUED");
+    // Unknown commands and wrong arities are refused straight away
+    assert!(matches!(send(&mut client, &["bogus", "arg"]).await, Frame::Error(ref e) if e.starts_with("ERR unknown command")));
+    assert!(matches!(send(&mut client, &["get"]).await, Frame::Error(ref e) if e.starts_with("ERR wrong number")));
+    match send(&mut client, &["exec"]).await {
+        Frame::Error(e) => assert!(e.starts_with("EXECABORT"), "{}", e),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+    // Nothing queued ran, and the transaction is over
+    assert!(matches!(send(&mut client, &["get", "key"]).await, Frame::Null));
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Error(ref e) if e == "ERR EXEC without MULTI"));
+
+    // The next transaction starts clean
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "v"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+
+    // WATCH and a nested MULTI are refused too, but leave the transaction
+    // to run
+    for (refused, error) in [
+        (&["watch", "key"][..], "ERR WATCH inside MULTI is not allowed"),
+        (&["multi"], "ERR MULTI calls can not be nested"),
+    ] {
+        assert_eq!(send(&mut client, &["multi"]).await, "OK");
+        assert_eq!(send(&mut client, &["set", "key", refused[0]]).await, "QUEUED");
+        match send(&mut client, refused).await {
+            Frame::Error(e) => assert_eq!(e, error),
+            other => panic!("Expected Error for {:?}, got {:?}", refused, other),
+        }
+        assert_eq!(send(&mut client, &["get", "key"]).await, "QUEUED");
+        match send(&mut client, &["exec"]).await {
+            Frame::Array(replies) => match &replies[..] {
+                [set, get] => {
+                    assert_eq!(*set, "OK");
+                    assert_eq!(*get, refused[0]);
+                }
+                other => panic!("Expected two replies, got {:?}", other),
+            },
+            other => panic!("Expected Array after {:?}, got {:?}", refused, other),
+        }
+        assert_eq!(send(&mut client, &["get", "key"]).await, refused[0]);
+    }
+}
+
+#[tokio::test]
+async fn test_reset() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["select", "1"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "in db 1"]).await, "OK");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "key", "queued"]).await, "QUEUED");
+    assert_eq!(send(&mut client, &["reset"]).await, "RESET");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Error(ref e) if e == "ERR EXEC without MULTI"));
+
+    // Back on database 0, and the queued SET never ran
+    assert!(matches!(send(&mut client, &["get", "key"]).await, Frame::Null));
+    assert_eq!(send(&mut client, &["select", "1"]).await, "OK");
+    assert_eq!(send(&mut client, &["get", "key"]).await, "in db 1");
+
+    // Also leaves subscriber mode
+    client.write_frame(&cmd(&["subscribe", "news"])).await.unwrap();
+    client.read_frame().await.unwrap().unwrap();
+    assert_eq!(send(&mut client, &["reset"]).await, "RESET");
+    assert_eq!(send(&mut client, &["ping"]).await, "PONG");
+}
+
+#[tokio::test]
+async fn test_time() {
+    let mut client = get_client().await;
+
+    let reply = strings(send(&mut client, &["time"]).await);
+    assert_eq!(reply.len(), 2);
+    let secs: u64 = reply[0].parse().unwrap();
+    // Some time after 2020 and before 2100
+    assert!((1_577_836_800..4_102_444_800).contains(&secs), "{}", secs);
+    let micros: u32 = reply[1].parse().unwrap();
+    assert!(micros < 1_000_000);
+
+    assert!(matches!(send(&mut client, &["time", "extra"]).await, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn test_debug() {
+    let mut client = get_client().await;
+
+    assert_eq!(send(&mut client, &["debug", "sleep", "0"]).await, "OK");
+    assert_eq!(send(&mut client, &["debug", "sleep", "0.01"]).await, "OK");
+    assert_eq!(send(&mut client, &["debug", "set-active-expire", "0"]).await, "OK");
+    assert!(matches!(send(&mut client, &["debug", "sleep", "soon"]).await, Frame::Error(_)));
+    assert!(matches!(send(&mut client, &["debug", "bogus"]).await, Frame::Error(ref e) if e.starts_with("ERR unknown subcommand")));
+}
+
+#[tokio::test]
+async fn test_debug_object() {
+    let mut client = get_client().await;
+    let field = |line: &str, name: &str| {
+        line.split(' ')
+            .find_map(|part| part.strip_prefix(name)?.strip_prefix(':'))
+            .map(str::to_string)
+    };
+    let debug_object = async |client: &mut Connection, key: &str| match send(client, &["debug", "object", key]).await {
+        Frame::Simple(line) => line,
+        other => panic!("Expected Simple, got {:?}", other),
+    };
+
+    send(&mut client, &["hset", "hash", "a", "1", "b", "2"]).await;
+    let line = debug_object(&mut client, "hash").await;
+    assert_eq!(field(&line, "encoding").as_deref(), Some("listpack"), "{}", line);
+    assert_eq!(field(&line, "type").as_deref(), Some("hash"), "{}", line);
+    assert!(field(&line, "serializedlength").unwrap().parse::<usize>().unwrap() > 0, "{}", line);
+    assert!(field(&line, "ql_nodes").is_none(), "{}", line);
+
+    send(&mut client, &["set", "number", "12345"]).await;
+    assert_eq!(field(&debug_object(&mut client, "number").await, "encoding").as_deref(), Some("int"));
+
+    // Long lists are quicklists, with their nodes
+    let values: Vec<String> = (0..200).map(|i| i.to_string()).collect();
+    let mut args = vec!["rpush", "list"];
+    args.extend(values.iter().map(String::as_str));
+    send(&mut client, &args).await;
+    let line = debug_object(&mut client, "list").await;
+    assert_eq!(field(&line, "encoding").as_deref(), Some("quicklist"), "{}", line);
+    assert!(field(&line, "ql_nodes").unwrap().parse::<usize>().unwrap() >= 1, "{}", line);
+
+    match send(&mut client, &["debug", "object", "missing"]).await {
+        Frame::Error(msg) => assert_eq!(msg, "ERR no such key"),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_wait() {
+    let mut client = get_client().await;
+
+    assert!(matches!(send(&mut client, &["wait", "0", "100"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["wait", "1", "-1"]).await, Frame::Error(_)));
+}
+
+#[tokio::test]
+async fn test_setrange_getrange() {
+    let mut client = get_client().await;
+
+    // Writing past the end pads with zero bytes
+    assert!(matches!(send(&mut client, &["setrange", "key", "3", "abc"]).await, Frame::Integer(6)));
+    assert!(matches!(send(&mut client, &["get", "key"]).await, Frame::Bulk(ref b) if &b[..] == b"\0\0\0abc"));
+    assert_eq!(send(&mut client, &["set", "greeting", "Hello World"]).await, "OK");
+    assert!(matches!(send(&mut client, &["setrange", "greeting", "6", "Redis"]).await, Frame::Integer(11)));
+    assert_eq!(send(&mut client, &["get", "greeting"]).await, "Hello Redis");
+
+    // Negative indexes count from the end
+    assert_eq!(send(&mut client, &["getrange", "greeting", "-5", "-1"]).await, "Redis");
+    assert_eq!(send(&mut client, &["getrange", "greeting", "0", "4"]).await, "Hello");
+    assert_eq!(send(&mut client, &["getrange", "greeting", "6", "100"]).await, "Redis");
+    assert_eq!(send(&mut client, &["getrange", "greeting", "5", "2"]).await, "");
+    assert_eq!(send(&mut client, &["getrange", "missing", "0", "-1"]).await, "");
+
+    // An empty value doesn't create the key
+    assert!(matches!(send(&mut client, &["setrange", "empty", "5", ""]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["exists", "empty"]).await, Frame::Integer(0)));
+
+    assert!(matches!(send(&mut client, &["setrange", "key", "-1", "x"]).await, Frame::Error(_)));
+    assert!(matches!(send(&mut client, &["setrange", "key", "536870912", "x"]).await, Frame::Error(ref e) if e.contains("maximum allowed size")));
+    send(&mut client, &["lpush", "list", "a"]).await;
+    assert!(matches!(send(&mut client, &["setrange", "list", "0", "x"]).await, Frame::Error(ref e) if e.starts_with("WRONGTYPE")));
+    assert!(matches!(send(&mut client, &["getrange", "list", "0", "-1"]).await, Frame::Error(ref e) if e.starts_with("WRONGTYPE")));
+}
+
+#[tokio::test]
+async fn test_bit_operations() {
+    let mut client = get_client().await;
+
+    // Bit 7 is the lowest bit of the first byte
+    assert!(matches!(send(&mut client, &["setbit", "bits", "7", "1"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["getbit", "bits", "7"]).await, Frame::Integer(1)));
+    assert!(matches!(send(&mut client, &["getbit", "bits", "6"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["get", "bits"]).await, Frame::Bulk(ref b) if &b[..] == b"\x01"));
+    assert!(matches!(send(&mut client, &["setbit", "bits", "7", "0"]).await, Frame::Integer(1)));
+    // Past the end reads as 0, and setting it grows the string
+    assert!(matches!(send(&mut client, &["getbit", "bits", "100"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["getbit", "missing", "0"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["setbit", "bits", "23", "1"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["get", "bits"]).await, Frame::Bulk(ref b) if &b[..] == b"\0\0\x01"));
+
+    // "foobar" has 26 set bits: 4 in "f", 6 in each "o", 3 in "b", 3 in "a", 4 in "r"
+    assert_eq!(send(&mut client, &["set", "key", "foobar"]).await, "OK");
+    assert!(matches!(send(&mut client, &["bitcount", "key"]).await, Frame::Integer(26)));
+    assert!(matches!(send(&mut client, &["bitcount", "key", "0", "0"]).await, Frame::Integer(4)));
+    assert!(matches!(send(&mut client, &["bitcount", "key", "1", "1"]).await, Frame::Integer(6)));
+    assert!(matches!(send(&mut client, &["bitcount", "key", "-2", "-1"]).await, Frame::Integer(7)));
+    assert!(matches!(send(&mut client, &["bitcount", "key", "5", "30", "bit"]).await, Frame::Integer(17)));
+    assert!(matches!(send(&mut client, &["bitcount", "missing"]).await, Frame::Integer(0)));
+
+    assert!(matches!(send(&mut client, &["setbit", "bits", "0", "2"]).await, Frame::Error(_)));
+    assert!(matches!(send(&mut client, &["setbit", "bits", "-1", "1"]).await, Frame::Error(_)));
+    assert!(matches!(send(&mut client, &["setbit", "bits", "4294967296000", "1"]).await, Frame::Error(_)));
+    assert!(matches!(send(&mut client, &["bitcount", "key", "0"]).await, Frame::Error(_)));
+    send(&mut client, &["lpush", "list", "a"]).await;
+    assert!(matches!(send(&mut client, &["setbit", "list", "0", "1"]).await, Frame::Error(ref e) if e.starts_with("WRONGTYPE")));
+    assert!(matches!(send(&mut client, &["bitcount", "list"]).await, Frame::Error(ref e) if e.starts_with("WRONGTYPE")));
+}
+
+#[tokio::test]
+async fn test_blpop_and_brpop() {
+    let addr = spawn_server().await;
+    let mut pusher = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut first = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut second = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    // Pops straight away from the first key with a list
+    send(&mut pusher, &["rpush", "ready", "a", "b"]).await;
+    assert_eq!(strings(send(&mut first, &["blpop", "missing", "ready", "0"]).await), ["ready", "a"]);
+    assert_eq!(strings(send(&mut first, &["brpop", "ready", "0"]).await), ["ready", "b"]);
+    assert!(matches!(send(&mut first, &["exists", "ready"]).await, Frame::Integer(0)));
+
+    // Waits for a push from another client
+    first.write_frame(&cmd(&["blpop", "queue", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(matches!(send(&mut pusher, &["rpush", "queue", "job"]).await, Frame::Integer(1)));
+    assert_eq!(strings(first.read_frame().await.unwrap().unwrap()), ["queue", "job"]);
+
+    // Each push wakes one waiter, the one that has waited longest
+    first.write_frame(&cmd(&["brpop", "other", "queue", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    second.write_frame(&cmd(&["blpop", "queue", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut pusher, &["lpush", "queue", "one"]).await;
+    assert_eq!(strings(first.read_frame().await.unwrap().unwrap()), ["queue", "one"]);
+    let waiting = tokio::time::timeout(Duration::from_millis(100), second.read_frame()).await;
+    assert!(waiting.is_err(), "second client woke with {:?}", waiting);
+    send(&mut pusher, &["lpush", "queue", "two"]).await;
+    assert_eq!(strings(second.read_frame().await.unwrap().unwrap()), ["queue", "two"]);
+
+    // A push of several values serves several waiters
+    first.write_frame(&cmd(&["blpop", "queue", "0"])).await.unwrap();
+    second.write_frame(&cmd(&["blpop", "queue", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut pusher, &["rpush", "queue", "x", "y"]).await;
+    let mut popped = vec![
+        strings(first.read_frame().await.unwrap().unwrap()).remove(1),
+        strings(second.read_frame().await.unwrap().unwrap()).remove(1),
+    ];
+    popped.sort();
+    assert_eq!(popped, ["x", "y"]);
+
+    // Gives up with a null array once the timeout passes
+    let started = std::time::Instant::now();
+    assert!(matches!(send(&mut first, &["brpop", "queue", "0.1"]).await, Frame::NullArray));
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    // Never blocks inside MULTI
+    send(&mut first, &["multi"]).await;
+    send(&mut first, &["blpop", "queue", "0"]).await;
+    match send(&mut first, &["exec"]).await {
+        Frame::Array(replies) => assert!(matches!(replies[..], [Frame::NullArray])),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    send(&mut pusher, &["set", "string", "value"]).await;
+    for (args, expected) in [
+        (&["blpop", "string", "0"][..], "WRONGTYPE"),
+        (&["blpop", "queue", "-1"], "ERR timeout is negative"),
+        (&["blpop", "queue", "soon"], "ERR timeout is not a float or out of range"),
+        (&["brpop", "queue"], "ERR wrong number of arguments for 'brpop' command"),
+    ] {
+        match send(&mut first, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_lmpop_and_blmpop() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut pusher = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    /// The key and the elements of an LMPOP reply.
+    fn popped(frame: Frame) -> (String, Vec<String>) {
+        match frame {
+            Frame::Array(mut parts) if parts.len() == 2 => {
+                let values = strings(parts.pop().unwrap());
+                let key = match parts.pop().unwrap() {
+                    Frame::Bulk(key) => String::from_utf8(key.to_vec()).unwrap(),
+                    other => panic!("Expected Bulk, got {:?}", other),
+                };
+                (key, values)
+            }
+            other => panic!("Expected a key and elements, got {:?}", other),
+        }
+    }
+
+    // The first list is empty, so the pop comes from the second
+    send(&mut client, &["rpush", "second", "a", "b", "c", "d"]).await;
+    assert_eq!(popped(send(&mut client, &["lmpop", "2", "first", "second", "left"]).await), ("second".into(), vec!["a".into()]));
+    let (key, values) = popped(send(&mut client, &["lmpop", "2", "first", "second", "RIGHT", "COUNT", "2"]).await);
+    assert_eq!(key, "second");
+    assert_eq!(values, ["d", "c"]);
+    let (_, values) = popped(send(&mut client, &["lmpop", "1", "second", "left", "count", "10"]).await);
+    assert_eq!(values, ["b"]);
+    assert!(matches!(send(&mut client, &["exists", "second"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["lmpop", "2", "first", "second", "left"]).await, Frame::NullArray));
+
+    for (args, expected) in [
+        (&["lmpop", "0", "first", "left"][..], "ERR numkeys should be greater than 0"),
+        (&["lmpop", "1", "first", "up"], "ERR syntax error"),
+        (&["lmpop", "1", "first", "left", "count", "-1"], "ERR count should be greater than 0"),
+        (&["blmpop", "x", "1", "first", "left"], "ERR timeout is not a float or out of range"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, expected),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    send(&mut client, &["set", "string", "value"]).await;
+    match send(&mut client, &["lmpop", "2", "string", "first", "left"]).await {
+        Frame::Error(msg) => assert!(msg.starts_with("WRONGTYPE"), "{}", msg),
+        other => panic!("Expected Error, got {:?}", other),
+    }
+
+    // BLMPOP waits for a push, and gives up at its timeout
+    client.write_frame(&cmd(&["blmpop", "0", "2", "first", "second", "left", "count", "2"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut pusher, &["rpush", "second", "x", "y", "z"]).await;
+    let (key, values) = popped(client.read_frame().await.unwrap().unwrap());
+    assert_eq!(key, "second");
+    assert_eq!(values, ["x", "y"]);
+    assert!(matches!(send(&mut client, &["blmpop", "0.05", "1", "first", "left"]).await, Frame::NullArray));
+}
+
+#[tokio::test]
+async fn test_lmove_and_blmove() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut mover = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut popper = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    send(&mut client, &["rpush", "src", "a", "b", "c"]).await;
+    assert_eq!(send(&mut client, &["lmove", "src", "dst", "left", "right"]).await, "a");
+    assert_eq!(send(&mut client, &["rpoplpush", "src", "dst"]).await, "c");
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), ["c", "a"]);
+    // Moving within one list rotates it
+    assert_eq!(send(&mut client, &["lmove", "dst", "dst", "LEFT", "RIGHT"]).await, "c");
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), ["a", "c"]);
+    // The source goes once emptied
+    assert_eq!(send(&mut client, &["lmove", "src", "dst", "left", "left"]).await, "b");
+    assert!(matches!(send(&mut client, &["exists", "src"]).await, Frame::Integer(0)));
+    assert!(matches!(send(&mut client, &["lmove", "src", "dst", "left", "left"]).await, Frame::Null));
+
+    // A blocked BLMOVE is served by a later push to its source
+    mover.write_frame(&cmd(&["blmove", "src", "dst", "right", "left", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut client, &["lpush", "src", "pushed"]).await;
+    assert_eq!(mover.read_frame().await.unwrap().unwrap(), "pushed");
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "0"]).await), ["pushed"]);
+
+    // And the element it moves can wake a client blocked on its destination
+    popper.write_frame(&cmd(&["blpop", "chained", "0"])).await.unwrap();
+    mover.write_frame(&cmd(&["brpoplpush", "src", "chained", "0"])).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    send(&mut client, &["rpush", "src", "link"]).await;
+    assert_eq!(mover.read_frame().await.unwrap().unwrap(), "link");
+    assert_eq!(strings(popper.read_frame().await.unwrap().unwrap()), ["chained", "link"]);
+
+    assert!(matches!(send(&mut mover, &["brpoplpush", "src", "dst", "0.05"]).await, Frame::NullArray));
+
+    send(&mut client, &["set", "string", "value"]).await;
+    for (args, expected) in [
+        (&["lmove", "string", "dst", "left", "left"][..], "WRONGTYPE"),
+        (&["lmove", "dst", "string", "left", "left"], "WRONGTYPE"),
+        (&["blmove", "string", "dst", "left", "left", "0"], "WRONGTYPE"),
+        (&["lmove", "dst", "src", "up", "left"], "ERR syntax error"),
+        (&["blmove", "dst", "src", "left", "left", "-1"], "ERR timeout is negative"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert!(msg.starts_with(expected), "{}", msg),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+    // Nothing moved on error
+    assert_eq!(strings(send(&mut client, &["lrange", "dst", "0", "-1"]).await), ["pushed", "b", "a", "c"]);
+}
+
+#[tokio::test]
+async fn test_numeric_arguments_are_strict() {
+    let mut client = get_client().await;
+    send(&mut client, &["hset", "hash", "f", "v"]).await;
+    send(&mut client, &["sadd", "set", "a"]).await;
+
+    for (args, expected) in [
+        (&["select", "1abc"][..], "ERR value is not an integer or out of range"),
+        (&["getrange", "key", "+0", "1"], "ERR value is not an integer or out of range"),
+        (&["hscan", "hash", "abc"], "ERR invalid cursor"),
+        (&["scan", "-1"], "ERR invalid cursor"),
+        (&["scan", "0", "count", "0"], "ERR syntax error"),
+        (&["hscan", "hash", "0", "count", "-5"], "ERR syntax error"),
+        (&["spop", "set", "-1"], "ERR value is out of range, must be positive"),
+        (&["srandmember", "set", "1x"], "ERR value is not an integer or out of range"),
+        (&["debug", "sleep", "nan"], "ERR value is not a valid float"),
+    ] {
+        match send(&mut client, args).await {
+            Frame::Error(msg) => assert_eq!(msg, expected, "{:?}", args),
+            other => panic!("Expected Error, got {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_collection_writes_share_wrongtype() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    assert_eq!(send(&mut client, &["set", "string", "value"]).await, "OK");
+    assert_eq!(send(&mut client, &["watch", "string"]).await, "OK");
+    for args in [
+        &["hset", "string", "f", "v"][..],
+        &["hdel", "string", "f"],
+        &["hgetdel", "string", "fields", "1", "f"],
+        &["lpush", "string", "a"],
+        &["rpush", "string", "a"],
+        &["lpop", "string"],
+        &["rpop", "string"],
+        &["lmpop", "1", "string", "left"],
+        &["lmove", "string", "dst", "left", "right"],
+        &["sadd", "string", "a"],
+        &["srem", "string", "a"],
+        &["spop", "string"],
+        &["smove", "string", "dst", "a"],
+        &["zadd", "string", "1", "a"],
+        &["zadd", "string", "xx", "1", "a"],
+        &["zincrby", "string", "1", "a"],
+        &["zmpop", "1", "string", "min"],
+        &["json.set", "string", "$", "1"],
+        &["json.del", "string", "$"],
+        &["json.arrappend", "string", "$", "1"],
+    ] {
+        match send(&mut other, args).await {
+            Frame::Error(msg) => assert_eq!(msg, "WRONGTYPE Operation against a key holding the wrong kind of value", "{:?}", args),
+            other => panic!("Expected WRONGTYPE for {:?}, got {:?}", args, other),
+        }
+    }
+
+    // None of the failed writes touched the key, so the watch still holds
+    assert_eq!(send(&mut other, &["get", "string"]).await, "value");
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["set", "string", "mine"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+    assert_eq!(send(&mut client, &["get", "string"]).await, "mine");
+}
+
+#[tokio::test]
+async fn test_writes_that_change_nothing_leave_watches_alone() {
+    let addr = spawn_server().await;
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut other = Connection::new(TcpStream::connect(addr).await.unwrap());
+
+    send(&mut client, &["hset", "hash", "f", "v"]).await;
+    send(&mut client, &["sadd", "set", "a"]).await;
+    send(&mut client, &["zadd", "zset", "1", "a"]).await;
+    send(&mut client, &["json.set", "doc", "$", r#"{"a":1}"#]).await;
+    let keys = ["watch", "hash", "set", "zset", "doc"];
+    assert_eq!(send(&mut client, &keys).await, "OK");
+    for args in [
+        &["hdel", "hash", "missing"][..],
+        &["srem", "set", "missing"],
+        &["smove", "set", "set", "a"],
+        &["smove", "set", "set", "missing"],
+        &["zadd", "zset", "1", "a"],
+        &["zadd", "zset", "xx", "1", "missing"],
+        &["json.del", "doc", "$.missing"],
+        &["json.set", "doc", "$.missing", "2", "xx"],
+        &["json.set", "doc", "$", "2", "nx"],
+        &["json.arrappend", "doc", "$.a", "1"],
+    ] {
+        send(&mut other, args).await;
+    }
+    assert_eq!(send(&mut client, &["multi"]).await, "OK");
+    assert_eq!(send(&mut client, &["ping"]).await, "QUEUED");
+    assert!(matches!(send(&mut client, &["exec"]).await, Frame::Array(_)));
+
+    // Each of these does change its key, and aborts the transaction
+    for args in [
+        &["hdel", "hash", "f"][..],
+        &["smove", "set", "moved", "a"],
+        &["zadd", "zset", "2", "a"],
+        &["json.del", "doc", "$.a"],
+    ] {
+        assert_eq!(send(&mut client, &keys).await, "OK");
+        send(&mut other, args).await;
+        assert_eq!(send(&mut client, &["multi"]).await, "OK");
+        assert_eq!(send(&mut client, &["ping"]).await, "QUEUED");
+        assert!(matches!(send(&mut client, &["exec"]).await, Frame::NullArray), "{:?}", args);
+    }
+}
@@ -0,0 +1,47 @@
+use rustbucket::parse_num::{parse_float, parse_int, parse_uint};
+
+const NOT_AN_INTEGER: Result<i64, &str> = Err("ERR value is not an integer or out of range");
+const NOT_A_FLOAT: Result<f64, &str> = Err("ERR value is not a valid float");
+
+#[test]
+fn int_accepts_canonical_integers() {
+    assert_eq!(parse_int(b"0"), Ok(0));
+    assert_eq!(parse_int(b"42"), Ok(42));
+    assert_eq!(parse_int(b"-42"), Ok(-42));
+    assert_eq!(parse_int(b"9223372036854775807"), Ok(i64::MAX));
+    assert_eq!(parse_int(b"-9223372036854775808"), Ok(i64::MIN));
+}
+
+#[test]
+fn int_rejects_other_spellings() {
+    for arg in ["", "-", "+1", "01", "-0", " 1", "1 ", "12abc", "1.5", "1e3", "abc", "9223372036854775808"] {
+        assert_eq!(parse_int(arg.as_bytes()), NOT_AN_INTEGER, "{:?}", arg);
+    }
+}
+
+#[test]
+fn uint_rejects_negatives() {
+    assert_eq!(parse_uint(b"0"), Ok(0));
+    assert_eq!(parse_uint(b"18446744073709551615"), Ok(u64::MAX));
+    assert_eq!(parse_uint(b"-1"), Err("ERR value is out of range, must be positive"));
+    for arg in ["-", "-0", "-abc", "+1", "01", "18446744073709551616", "1.0"] {
+        assert_eq!(parse_uint(arg.as_bytes()), Err("ERR value is not an integer or out of range"), "{:?}", arg);
+    }
+}
+
+#[test]
+fn float_accepts_decimals_and_infinities() {
+    assert_eq!(parse_float(b"1.5"), Ok(1.5));
+    assert_eq!(parse_float(b"-2"), Ok(-2.0));
+    assert_eq!(parse_float(b"1e3"), Ok(1000.0));
+    assert_eq!(parse_float(b"inf"), Ok(f64::INFINITY));
+    assert_eq!(parse_float(b"+inf"), Ok(f64::INFINITY));
+    assert_eq!(parse_float(b"-inf"), Ok(f64::NEG_INFINITY));
+}
+
+#[test]
+fn float_rejects_nan_and_overflow() {
+    for arg in ["", "abc", "nan", "NaN", "-nan", " 1", "1 ", "1,5", "1e400", "-1e400"] {
+        assert_eq!(parse_float(arg.as_bytes()), NOT_A_FLOAT, "{:?}", arg);
+    }
+}
@@ -0,0 +1,94 @@
+use bytes::Bytes;
+use rustbucket::clock::unix_millis;
+use rustbucket::db::DataType;
+use rustbucket::Db;
+use std::path::PathBuf;
+
+fn b(s: &str) -> Bytes {
+    Bytes::from(s.to_string())
+}
+
+/// A path in the temp directory unique to this test.
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("rustbucket-{}-{}.rdb", name, std::process::id()))
+}
+
+fn sorted(mut items: Vec<Bytes>) -> Vec<Bytes> {
+    items.sort();
+    items
+}
+
+#[tokio::test]
+async fn snapshot_round_trip() {
+    let db = Db::new();
+    db.set(b("string"), b("value"));
+    db.rpush(b("list"), vec![b("a"), b("b")]).unwrap();
+    db.sadd(b("set"), vec![b("1"), b("2"), b("three")]).unwrap();
+    db.hset(b("hash"), vec![(b("field"), b("value"))]).unwrap();
+    db.zadd(b("zset"), 1.5, b("one"));
+    db.zadd(b("zset"), -2.0, b("two"));
+    db.set_value(b("json"), DataType::Json(serde_json::json!({"a": [1, "x", null]})));
+    db.select(3).unwrap().set(b("other"), b("db"));
+    let deadline = unix_millis() + 60_000;
+    db.restore(b("expiring"), DataType::String(b("soon")), Some(deadline), false);
+    db.restore(b("expired"), DataType::String(b("gone")), Some(unix_millis() + 20), false);
+    std::thread::sleep(std::time::Duration::from_millis(40));
+
+    let path = temp_path("round-trip");
+    db.snapshot_to(&path).unwrap();
+    let loaded = Db::new();
+    loaded.load_from(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(sorted(loaded.keys()), sorted(db.keys()));
+    assert_eq!(loaded.get(b"string").unwrap(), Some(b("value")));
+    assert_eq!(loaded.lrange(b"list", 0, -1).unwrap(), vec![b("a"), b("b")]);
+    assert_eq!(sorted(loaded.smembers(b"set")), sorted(db.smembers(b"set")));
+    assert_eq!(loaded.encoding(b"set"), db.encoding(b"set"));
+    assert_eq!(loaded.hgetall(b"hash"), db.hgetall(b"hash"));
+    assert_eq!(loaded.zrange(b"zset", 0, -1, true), vec![(b("two"), -2.0), (b("one"), 1.5)]);
+    match loaded.read_value(b"json", DataType::clone) {
+        Some(DataType::Json(json)) => assert_eq!(json, serde_json::json!({"a": [1, "x", null]})),
+        other => panic!("Expected Json, got {:?}", other),
+    }
+    assert_eq!(loaded.select(3).unwrap().get(b"other").unwrap(), Some(b("db")));
+    assert!(loaded.select(1).unwrap().is_empty());
+    assert_eq!(loaded.expiry(b"expiring"), Some(Some(deadline)));
+    assert_eq!(loaded.expiry(b"string"), Some(None));
+    assert_eq!(loaded.expiry(b"expired"), None);
+}
+
+#[tokio::test]
+async fn load_rejects_corrupt_snapshots() {
+    let db = Db::new();
+    db.set(b("key"), b("value"));
+    let path = temp_path("corrupt");
+    db.snapshot_to(&path).unwrap();
+
+    let mut data = std::fs::read(&path).unwrap();
+    let middle = data.len() / 2;
+    data[middle] ^= 0xff;
+    std::fs::write(&path, &data).unwrap();
+    assert!(Db::new().load_from(&path).is_err());
+
+    std::fs::write(&path, b"RBKT").unwrap();
+    assert!(Db::new().load_from(&path).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn load_rejects_empty_collections_and_nan_scores() {
+    let path = temp_path("invalid-values");
+    let nan_score = [&[4, 1, b'k', 1, 1, b'm'][..], &f64::NAN.to_le_bytes()].concat();
+    // A list, set, hash and sorted set with no entries, then a NaN score
+    for entry in [&[1, 1, b'k', 0][..], &[2, 1, b'k', 0], &[3, 1, b'k', 0], &[4, 1, b'k', 0], &nan_score] {
+        let mut data = [&b"RBKT\x01"[..], entry, &[0xff]].concat();
+        let crc = rustbucket::persistence::crc64(0, &data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        std::fs::write(&path, &data).unwrap();
+        let db = Db::new();
+        assert!(db.load_from(&path).is_err(), "{:?}", entry);
+        assert!(db.is_empty());
+    }
+    std::fs::remove_file(&path).unwrap();
+}
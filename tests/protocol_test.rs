@@ -0,0 +1,282 @@
+use proptest::prelude::*;
+use rustbucket::Frame;
+use rustbucket::protocol::Limits;
+use std::io::Cursor;
+
+/// Fragments that frequently appear in RESP input, so generated inputs
+/// reach deep into the parser rather than failing on the first byte.
+fn resp_fragment() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        Just(b"*".to_vec()),
+        Just(b"$".to_vec()),
+        Just(b"+".to_vec()),
+        Just(b"-".to_vec()),
+        Just(b":".to_vec()),
+        Just(b"%".to_vec()),
+        Just(b"~".to_vec()),
+        Just(b">".to_vec()),
+        Just(b",".to_vec()),
+        Just(b"#".to_vec()),
+        Just(b"_".to_vec()),
+        Just(b"(".to_vec()),
+        Just(b"\r\n".to_vec()),
+        Just(b"-1".to_vec()),
+        (0u32..20).prop_map(|n| n.to_string().into_bytes()),
+        any::<u64>().prop_map(|n| n.to_string().into_bytes()),
+        prop::collection::vec(any::<u8>(), 0..8),
+    ]
+}
+
+fn resp_like() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(resp_fragment(), 0..16).prop_map(|parts| parts.concat())
+}
+
+/// `check` accepting a buffer must mean `parse` accepts the same bytes.
+fn assert_check_implies_parse(bytes: &[u8]) {
+    let mut cursor = Cursor::new(bytes);
+    if Frame::check(&mut cursor).is_ok() {
+        cursor.set_position(0);
+        if let Err(err) = Frame::parse(&mut cursor) {
+            panic!("check accepted {:?} but parse failed: {}", bytes, err);
+        }
+    }
+}
+
+/// A frame with `prefixes` aggregates nested around `:1`, each holding one
+/// entry, outermost first.
+fn nested(prefixes: &[u8]) -> Vec<u8> {
+    prefixes.iter().rev().fold(b":1\r\n".to_vec(), |inner, &prefix| {
+        let mut frame = vec![prefix];
+        frame.extend_from_slice(b"1\r\n");
+        frame.extend(inner);
+        if prefix == b'%' {
+            frame.extend_from_slice(b"+v\r\n");
+        }
+        frame
+    })
+}
+
+proptest! {
+    #[test]
+    fn parser_rejects_deep_nesting(
+        prefixes in prop::collection::vec(prop::sample::select(&b"*~>%"[..]), 0..400),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let bytes = nested(&prefixes);
+        let max_depth = Limits::default().max_depth;
+        match Frame::parse_from_bytes(&bytes) {
+            Ok(frame) => prop_assert!(prefixes.len() <= max_depth && frame.is_some()),
+            Err(_) => prop_assert!(prefixes.len() > max_depth),
+        }
+        // A truncated frame waits for more only while within the limit
+        let partial = &bytes[..cut.index(bytes.len())];
+        let _ = Frame::parse_from_bytes(partial);
+        assert_check_implies_parse(partial);
+    }
+
+    #[test]
+    fn parser_never_panics_on_arbitrary_bytes(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+        let _ = Frame::parse_from_bytes(&bytes);
+        assert_check_implies_parse(&bytes);
+    }
+
+    #[test]
+    fn parser_never_panics_on_resp_like_bytes(bytes in resp_like()) {
+        let _ = Frame::parse_from_bytes(&bytes);
+        assert_check_implies_parse(&bytes);
+    }
+}
+
+#[test]
+fn parse_from_bytes_complete_and_incomplete() {
+    match Frame::parse_from_bytes(b"*2\r\n$3\r\nget\r\n$3\r\nfoo\r\n").unwrap() {
+        Some(Frame::Array(parts)) => {
+            assert_eq!(parts.len(), 2);
+            assert_eq!(parts[0], "get");
+            assert_eq!(parts[1], "foo");
+        }
+        other => panic!("Expected Array, got {:?}", other),
+    }
+
+    assert!(Frame::parse_from_bytes(b"*2\r\n$3\r\nget\r\n").unwrap().is_none());
+    assert!(Frame::parse_from_bytes(b"").unwrap().is_none());
+}
+
+#[test]
+fn parse_from_bytes_rejects_malformed_frames() {
+    // Only `$-1` is a valid negative bulk length
+    assert!(Frame::parse_from_bytes(b"$-2\r\n").is_err());
+    // Simple strings must be valid UTF-8
+    assert!(Frame::parse_from_bytes(b"+\xff\r\n").is_err());
+    // A length that overflows when the trailing CRLF is added
+    assert!(Frame::parse_from_bytes(b"$18446744073709551615\r\n").is_err());
+}
+
+#[test]
+fn parse_resp3_frames() {
+    let frame = Frame::parse_from_bytes(b"%2\r\n+a\r\n#t\r\n+b\r\n_\r\n").unwrap();
+    match frame {
+        Some(Frame::Map(entries)) => {
+            assert_eq!(entries.len(), 2);
+            assert_eq!(entries[0].0, "a");
+            assert!(matches!(entries[0].1, Frame::Boolean(true)));
+            assert_eq!(entries[1].0, "b");
+            assert!(matches!(entries[1].1, Frame::Null));
+        }
+        other => panic!("Expected Map, got {:?}", other),
+    }
+
+    match Frame::parse_from_bytes(b",-1.5\r\n").unwrap() {
+        Some(Frame::Double(d)) => assert_eq!(d, -1.5),
+        other => panic!("Expected Double, got {:?}", other),
+    }
+    match Frame::parse_from_bytes(b"(-12345678901234567890\r\n").unwrap() {
+        Some(Frame::BigNumber(digits)) => assert_eq!(digits, "-12345678901234567890"),
+        other => panic!("Expected BigNumber, got {:?}", other),
+    }
+    assert!(matches!(Frame::parse_from_bytes(b"~1\r\n:1\r\n").unwrap(), Some(Frame::Set(_))));
+    assert!(matches!(Frame::parse_from_bytes(b">1\r\n:1\r\n").unwrap(), Some(Frame::Push(_))));
+
+    assert!(Frame::parse_from_bytes(b"#x\r\n").is_err());
+    assert!(Frame::parse_from_bytes(b",abc\r\n").is_err());
+    assert!(Frame::parse_from_bytes(b"(12a\r\n").is_err());
+    assert!(Frame::parse_from_bytes(b"%1\r\n+a\r\n").unwrap().is_none());
+}
+
+#[test]
+fn check_enforces_declared_length_limits() {
+    let limits = Limits { max_bulk_len: 4, max_array_len: 2, ..Limits::default() };
+    let check = |bytes: &[u8]| Frame::check_with_limits(&mut Cursor::new(bytes), &limits);
+
+    assert!(check(b"$4\r\nabcd\r\n").is_ok());
+    assert!(check(b"*2\r\n:1\r\n:2\r\n").is_ok());
+    // Rejected from the header alone, without the rest of the frame
+    assert!(matches!(check(b"$5\r\n"), Err(rustbucket::protocol::Error::Other(_))));
+    assert!(matches!(check(b"*3\r\n"), Err(rustbucket::protocol::Error::Other(_))));
+    assert!(matches!(check(b"%3\r\n"), Err(rustbucket::protocol::Error::Other(_))));
+    assert!(matches!(check(b"*1\r\n$5\r\n"), Err(rustbucket::protocol::Error::Other(_))));
+
+    // The default limits reject huge bulk strings too
+    assert!(Frame::parse_from_bytes(b"$999999999999\r\n").is_err());
+    // A long array within them waits for its entries
+    assert!(Frame::parse_from_bytes(b"*1000000\r\n").unwrap().is_none());
+}
+
+#[test]
+fn check_rejects_arrays_that_cannot_fit_in_a_frame() {
+    let is_rejected = |bytes: &[u8], limits: &Limits| {
+        matches!(
+            Frame::check_with_limits(&mut Cursor::new(bytes), limits),
+            Err(rustbucket::protocol::Error::Other(_))
+        )
+    };
+
+    // A billion entries take at least 3GB, more than a 512MB frame, so the
+    // header alone is refused rather than reserving room for them
+    assert!(is_rejected(b"*1000000000\r\n", &Limits::default()));
+    assert!(is_rejected(b"%1000000000\r\n", &Limits::default()));
+    assert!(is_rejected(b"*1\r\n*1000000000\r\n", &Limits::default()));
+    let small = Limits { max_frame_size: 30, ..Limits::default() };
+    assert!(is_rejected(b"*11\r\n", &small));
+    assert!(matches!(
+        Frame::check_with_limits(&mut Cursor::new(&b"*10\r\n"[..]), &small),
+        Err(rustbucket::protocol::Error::Incomplete)
+    ));
+}
+
+#[test]
+fn check_rejects_deeply_nested_frames() {
+    let max_depth = Limits::default().max_depth;
+    let nested = |depth: usize| [b"*1\r\n".repeat(depth), b":1\r\n".to_vec()].concat();
+
+    assert!(Frame::parse_from_bytes(&nested(max_depth)).unwrap().is_some());
+    // Rejected at the first aggregate too deep, without recursing further
+    for depth in [max_depth + 1, 100_000] {
+        match Frame::parse_from_bytes(&nested(depth)) {
+            Err(err) => assert_eq!(err.to_string(), "protocol error; aggregates nested too deeply"),
+            other => panic!("Expected an error at depth {}, got {:?}", depth, other),
+        }
+    }
+    assert!(Frame::parse_from_bytes(&b"*1\r\n".repeat(100_000)).is_err());
+
+    let shallow = Limits { max_depth: 2, ..Limits::default() };
+    let check = |bytes: &[u8]| Frame::check_with_limits(&mut Cursor::new(bytes), &shallow);
+    assert!(check(b"*1\r\n%1\r\n:1\r\n:2\r\n").is_ok());
+    assert!(check(b"*1\r\n%1\r\n~0\r\n").is_err());
+}
+
+#[test]
+fn check_rejects_negative_array_lengths() {
+    for bytes in [&b"*-2\r\n"[..], b"*-1000000000\r\n", b"~-1\r\n", b"%-1\r\n", b">-1\r\n"] {
+        match Frame::parse_from_bytes(bytes) {
+            Err(err) => assert_eq!(err.to_string(), "protocol error; invalid multibulk length"),
+            other => panic!("Expected an error for {:?}, got {:?}", bytes, other),
+        }
+    }
+    // Except the null array
+    assert!(matches!(Frame::parse_from_bytes(b"*-1\r\n").unwrap(), Some(Frame::NullArray)));
+}
+
+/// The arguments of an inline command, as `parse_from_bytes` splits it.
+fn inline_args(line: &[u8]) -> Vec<Vec<u8>> {
+    match Frame::parse_from_bytes(line).unwrap() {
+        Some(Frame::Array(parts)) => parts
+            .into_iter()
+            .map(|part| match part {
+                Frame::Bulk(bytes) => bytes.to_vec(),
+                other => panic!("Expected Bulk, got {:?}", other),
+            })
+            .collect(),
+        other => panic!("Expected Array, got {:?}", other),
+    }
+}
+
+#[test]
+fn inline_commands_split_on_whitespace() {
+    assert_eq!(inline_args(b"get  foo\t\r\n"), vec![b"get".to_vec(), b"foo".to_vec()]);
+    assert!(inline_args(b"   \r\n").is_empty());
+    // Bytes that aren't UTF-8 are kept as they are
+    assert_eq!(inline_args(b"set k \xff\r\n"), vec![b"set".to_vec(), b"k".to_vec(), vec![0xff]]);
+    assert!(Frame::parse_from_bytes(b"get foo").unwrap().is_none());
+}
+
+#[test]
+fn inline_commands_honor_quotes() {
+    assert_eq!(
+        inline_args(b"set foo \"a b c\"\r\n"),
+        vec![b"set".to_vec(), b"foo".to_vec(), b"a b c".to_vec()]
+    );
+    assert_eq!(inline_args(b"set foo 'a b'\r\n")[2], b"a b");
+    assert_eq!(inline_args(b"set foo \"\"\r\n")[2], b"");
+    // Double quotes take escapes, including binary bytes
+    assert_eq!(inline_args(b"set foo \"\\x00\\xfF\\n\\t\\\\\\q\"\r\n")[2], b"\x00\xff\n\t\\q");
+    assert_eq!(inline_args(b"set foo \"\\x4g\"\r\n")[2], b"x4g");
+    // Single quotes only unescape a single quote
+    assert_eq!(inline_args(b"set foo 'it\\'s \\n'\r\n")[2], b"it's \\n");
+    // A quote may open partway through an argument
+    assert_eq!(inline_args(b"set foo a\"b c\"\r\n")[2], b"ab c");
+}
+
+#[test]
+fn inline_commands_escape_quotes() {
+    assert_eq!(inline_args(b"set foo \"say \\\"hi\\\"\"\r\n")[2], b"say \"hi\"");
+    assert_eq!(inline_args(b"set foo \"it's\"\r\n")[2], b"it's");
+    assert_eq!(inline_args(b"set foo 'say \"hi\"'\r\n")[2], b"say \"hi\"");
+}
+
+#[test]
+fn inline_commands_reject_unbalanced_quotes() {
+    for line in [
+        &b"set foo \"abc\r\n"[..],
+        b"set foo 'abc\r\n",
+        b"set foo \"abc\\\"\r\n",
+        // A closing quote must end the argument
+        b"set foo \"abc\"def\r\n",
+        b"set foo 'abc'def\r\n",
+    ] {
+        match Frame::parse_from_bytes(line) {
+            Err(err) => assert_eq!(err.to_string(), "protocol error; unbalanced quotes in request"),
+            other => panic!("Expected an error for {:?}, got {:?}", line, other),
+        }
+    }
+}
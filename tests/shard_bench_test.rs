@@ -0,0 +1,69 @@
+//! Throughput of a concurrent GET/SET mix on `Db`, with a bare `DashMap`
+//! alongside as a baseline.
+//!
+//! This isn't a like-for-like comparison: `Db` also checks expiry, tracks
+//! memory use and access times, and keeps WATCH versions up to date,
+//! none of which the map does. The gap between the two is an upper bound
+//! on what the locking costs, not a measure of it.
+//!
+//! Ignored by default, as it takes a while and only prints numbers. Run
+//! with `cargo test --release --test shard_bench_test -- --ignored
+//! --nocapture` to see them.
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use rustbucket::Db;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 20_000;
+const KEYS: usize = 1_000;
+
+/// Run `op` from every thread, returning operations per second. Every
+/// fourth operation is a write.
+fn run_mix<T: Send + Sync + 'static>(target: Arc<T>, op: fn(&T, Bytes, bool)) -> f64 {
+    let keys: Arc<Vec<Bytes>> = Arc::new((0..KEYS).map(|i| Bytes::from(format!("key:{}", i))).collect());
+    let started = Instant::now();
+    let threads: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let (target, keys) = (target.clone(), keys.clone());
+            std::thread::spawn(move || {
+                for i in 0..OPS_PER_THREAD {
+                    let key = keys[(i * 31 + thread * 7) % KEYS].clone();
+                    op(&target, key, i % 4 == 0);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    (THREADS * OPS_PER_THREAD) as f64 / started.elapsed().max(Duration::from_nanos(1)).as_secs_f64()
+}
+
+#[test]
+#[ignore = "benchmark; prints throughput numbers"]
+fn concurrent_get_set_throughput() {
+    let db = Arc::new(Db::new());
+    let db_ops = run_mix(db.clone(), |db, key, write| {
+        if write {
+            db.set(key, Bytes::from_static(b"value"));
+        } else {
+            let _ = db.get(&key);
+        }
+    });
+
+    let map = Arc::new(DashMap::<Bytes, Bytes>::new());
+    let dashmap_ops = run_mix(map.clone(), |map, key, write| {
+        if write {
+            map.insert(key, Bytes::from_static(b"value"));
+        } else {
+            let _ = map.get(&key).map(|value| value.clone());
+        }
+    });
+
+    println!("Db shards: {:.0} ops/s, DashMap: {:.0} ops/s", db_ops, dashmap_ops);
+    // Both saw the same writes
+    assert_eq!(db.len(), map.len());
+}